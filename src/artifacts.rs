@@ -0,0 +1,191 @@
+use std::{
+    collections::HashSet,
+    fs::{create_dir_all, read_dir, read_to_string, File},
+    io::{BufRead, BufReader, Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{cargo, cmake, exec, BuildSystem, Invocation, Project, Verb};
+
+/// A file the build produces
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Artifact {
+    pub path: PathBuf,
+    /// What it is, like `bin` or `lib` for cargo and `executable` or `shared_library` for
+    /// CMake, if the build system tells
+    pub kind: Option<String>,
+}
+
+/// The files the build of `invocation` in `project` produces: the ones cargo reports while
+/// building, the outputs of the targets of a CMake build directory from its file API, or the
+/// configured `artifacts` for other build systems
+pub fn find(project: &Project, invocation: &Invocation) -> Result<Vec<Artifact>> {
+    let Some(detection) = invocation.detection(project)? else {
+        return Err(exec::no_build_system("No build system found"));
+    };
+    if cmake::is_configured(&detection.directory) {
+        return from_cmake(&detection.directory);
+    }
+    if detection.build_system == BuildSystem::Cargo {
+        return from_cargo(project, invocation);
+    }
+    if project.config.artifacts.is_empty() {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "{} does not tell what it builds; list the outputs under `artifacts` in the \
+                 configuration",
+                detection.build_system.name()
+            ),
+        ));
+    }
+    let root = project.root();
+    Ok(project
+        .config
+        .artifacts
+        .iter()
+        .map(|path| Artifact {
+            path: root.join(path),
+            kind: None,
+        })
+        .collect())
+}
+
+// Build with cargo and collect the artifacts it reports for the packages of the workspace
+fn from_cargo(project: &Project, invocation: &Invocation) -> Result<Vec<Artifact>> {
+    let invocation = Invocation {
+        verb: Verb::Build,
+        ..invocation.clone()
+    };
+    let Some(mut plan) = invocation.plan(project)? else {
+        return Ok(Vec::new());
+    };
+    let directory = plan.cwd.clone().unwrap_or_else(|| project.cwd.clone());
+    let members: HashSet<String> = cargo::metadata(&directory)?["workspace_members"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(str::to_string)
+        .collect();
+    // The rendered diagnostics go to stderr, leaving stdout to the messages
+    plan.arg("--message-format=json-render-diagnostics");
+    let mut child = plan.command().stdout(Stdio::piped()).spawn()?;
+    let mut artifacts = Vec::new();
+    let stdout = child.stdout.take().unwrap();
+    for line in BufReader::new(stdout).lines() {
+        let Ok(message) = serde_json::from_str::<Value>(&line?) else {
+            continue;
+        };
+        if message["reason"] != "compiler-artifact"
+            || !message["package_id"]
+                .as_str()
+                .is_some_and(|id| members.contains(id))
+        {
+            continue;
+        }
+        let kinds = message["target"]["kind"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let kind = kinds.first().and_then(Value::as_str).map(str::to_string);
+        if kind.as_deref() == Some("custom-build") {
+            continue;
+        }
+        match message["executable"].as_str() {
+            Some(executable) => artifacts.push(Artifact {
+                path: PathBuf::from(executable),
+                kind,
+            }),
+            // Metadata only serves later compilations
+            None => artifacts.extend(
+                message["filenames"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str)
+                    .filter(|file| !file.ends_with(".rmeta"))
+                    .map(|file| Artifact {
+                        path: PathBuf::from(file),
+                        kind: kind.clone(),
+                    }),
+            ),
+        }
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(Error::other(format!("The build failed with {}", status)));
+    }
+    Ok(artifacts)
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    serde_json::from_str(&read_to_string(path)?).map_err(Error::other)
+}
+
+// The outputs of the targets of the CMake build directory `dir`, from the code model its file
+// API writes when CMake is asked for it
+fn from_cmake(dir: &Path) -> Result<Vec<Artifact>> {
+    let api = dir.join(".cmake/api/v1");
+    let query = api.join("query/codemodel-v2");
+    let replies = api.join("reply");
+    if !query.exists() || !replies.exists() {
+        create_dir_all(api.join("query"))?;
+        File::create(&query)?;
+        // CMake only answers queries when it configures
+        let status = Command::new("cmake")
+            .arg(dir)
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(Error::other(format!(
+                "Reconfiguring {} failed with {}",
+                dir.display(),
+                status
+            )));
+        }
+    }
+    let mut indices = Vec::new();
+    for entry in read_dir(&replies)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with("index-") && name.ends_with(".json") {
+            indices.push(name);
+        }
+    }
+    // Named after the time they were written
+    indices.sort();
+    let Some(index) = indices.last() else {
+        return Err(Error::other("CMake wrote no code model"));
+    };
+    let index = read_json(&replies.join(index))?;
+    let Some(codemodel) = index["reply"]["codemodel-v2"]["jsonFile"].as_str() else {
+        return Err(Error::other("CMake wrote no code model"));
+    };
+    let codemodel = read_json(&replies.join(codemodel))?;
+    let mut artifacts = Vec::new();
+    // Multi-config generators describe each configuration; the first is the default one
+    let targets = codemodel["configurations"][0]["targets"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    for target in targets {
+        let Some(file) = target["jsonFile"].as_str() else {
+            continue;
+        };
+        let target = read_json(&replies.join(file))?;
+        let kind = target["type"].as_str().map(str::to_lowercase);
+        for artifact in target["artifacts"].as_array().into_iter().flatten() {
+            if let Some(path) = artifact["path"].as_str() {
+                artifacts.push(Artifact {
+                    path: dir.join(path),
+                    kind: kind.clone(),
+                });
+            }
+        }
+    }
+    Ok(artifacts)
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::BTreeMap,
+    fs::read_to_string,
+    io::{Error, Result},
+    path::{Path, PathBuf},
+};
+
+use crate::{cmake, config::BranchSwitch, exec, state, Invocation, Project, Verb};
+
+// The HEAD of the checkout at the last build in each build directory
+type State = BTreeMap<PathBuf, String>;
+
+/// The branch checked out in the git repository `dir` is in, or the commit when none is, read
+/// from its `HEAD` rather than asking git, which takes longer than the rest of a no-op build
+pub fn head(dir: &Path) -> Option<String> {
+    let dot_git = dir
+        .ancestors()
+        .map(|dir| dir.join(".git"))
+        .find(|dot_git| dot_git.exists())?;
+    // A worktree or submodule has a file pointing to its git directory instead
+    let git_dir = match read_to_string(&dot_git) {
+        Ok(contents) => {
+            let linked = Path::new(contents.strip_prefix("gitdir:")?.trim());
+            dot_git.parent()?.join(linked)
+        }
+        Err(_) => dot_git,
+    };
+    let head = read_to_string(git_dir.join("HEAD")).ok()?;
+    let head = head.trim();
+    Some(match head.strip_prefix("ref:") {
+        Some(reference) => {
+            let reference = reference.trim();
+            reference
+                .strip_prefix("refs/heads/")
+                .unwrap_or(reference)
+                .to_string()
+        }
+        None => head.chars().take(12).collect(),
+    })
+}
+
+/// Check whether the git branch changed since the last build in the build directory of
+/// `project`, and warn about it, clean the build directory or configure it again, as the
+/// configuration says
+pub fn check(project: &Project, invocation: &Invocation) -> Result<()> {
+    let action = project
+        .config
+        .on_branch_switch
+        .unwrap_or(BranchSwitch::Warn);
+    if action == BranchSwitch::Ignore {
+        return Ok(());
+    }
+    let Some(detection) = invocation.detection(project)? else {
+        return Ok(());
+    };
+    let (Some(head), Some(path)) = (head(&project.root()), state::cache_file("branches.json"))
+    else {
+        return Ok(());
+    };
+    let dir = &detection.directory;
+    let mut state: State = state::load(&path);
+    let previous = state.insert(dir.clone(), head.clone());
+    // Without the state the next build only sees no switch
+    let _ = state::store(&path, &state);
+    let Some(previous) = previous.filter(|previous| *previous != head) else {
+        return Ok(());
+    };
+    // Which is then configured again before the build, as it has not been
+    let configured = detection.source.is_some() && cmake::is_configured(dir);
+    match action {
+        BranchSwitch::Ignore => Ok(()),
+        BranchSwitch::Warn => {
+            eprintln!(
+                "Switched from {} to {} since the last build in {}; if the build fails \
+                 oddly, `sm clean` starts it afresh, and `on_branch_switch = \"clean\"` in \
+                 the configuration does on every switch",
+                previous,
+                head,
+                dir.display()
+            );
+            Ok(())
+        }
+        BranchSwitch::Reconfigure if configured => {
+            eprintln!(
+                "Configuring {} again, as the branch switched from {} to {}",
+                dir.display(),
+                previous,
+                head
+            );
+            cmake::reset(dir)
+        }
+        BranchSwitch::Clean | BranchSwitch::Reconfigure => {
+            eprintln!(
+                "Cleaning {}, as the branch switched from {} to {}",
+                dir.display(),
+                previous,
+                head
+            );
+            let clean = Invocation {
+                verb: Verb::Clean,
+                targets: Vec::new(),
+                ..invocation.clone()
+            };
+            let Some(plan) = clean.plan(project)? else {
+                return Ok(());
+            };
+            let status = exec::run(plan.command())?;
+            match status.success() {
+                true => Ok(()),
+                false => Err(Error::other(format!(
+                    "Cleaning {} failed with {}",
+                    dir.display(),
+                    status
+                ))),
+            }
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs::read_dir,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cmake,
+    config::{Config, CustomBuildSystem},
+    exec, plugin, CommandPlan,
+};
+
+/// What the build system is asked to do
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verb {
+    #[default]
+    Build,
+    Test,
+    Clean,
+}
+
+impl Verb {
+    pub fn name(self) -> &'static str {
+        match self {
+            Verb::Build => "build",
+            Verb::Test => "test",
+            Verb::Clean => "clean",
+        }
+    }
+}
+
+/// Everything a build system needs to know to construct its command
+#[derive(Debug, Serialize)]
+pub struct BuildOptions<'a> {
+    pub verb: Verb,
+    pub threads: usize,
+    pub directory: PathBuf,
+    pub targets: &'a [String],
+    pub args: Vec<String>,
+    pub profile: Option<&'a str>,
+    /// The platform to build for, like a target triple, instead of the one smartmake runs on
+    pub cross_target: Option<&'a str>,
+    /// Whether the build takes its jobs from the make jobserver in `MAKEFLAGS`, so it is not
+    /// given a number of them
+    pub jobserver: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(from = "String", into = "String")]
+pub enum BuildSystem {
+    Make,
+    Ninja,
+    Cargo,
+    /// A CMake build directory driven through `cmake --build`, for the generators smartmake
+    /// cannot run the build tool of itself
+    CMake,
+    /// A build system defined in the configuration or by a plugin, by name
+    Custom(String),
+}
+
+impl From<String> for BuildSystem {
+    fn from(name: String) -> BuildSystem {
+        match name.as_str() {
+            "make" => BuildSystem::Make,
+            "ninja" => BuildSystem::Ninja,
+            "cargo" => BuildSystem::Cargo,
+            "cmake" => BuildSystem::CMake,
+            _ => BuildSystem::Custom(name),
+        }
+    }
+}
+
+impl From<BuildSystem> for String {
+    fn from(build_system: BuildSystem) -> String {
+        build_system.name().to_string()
+    }
+}
+
+impl BuildSystem {
+    /// The build system a file called `s` belongs to, ignoring case on Windows, whose file
+    /// systems do too
+    pub fn from_filename(s: &str) -> Option<BuildSystem> {
+        if cfg!(windows) {
+            return [
+                "Makefile",
+                "GNUmakefile",
+                "BSDmakefile",
+                "build.ninja",
+                "Cargo.toml",
+            ]
+            .into_iter()
+            .find(|marker| marker.eq_ignore_ascii_case(s))
+            .and_then(BuildSystem::from_filename_exact);
+        }
+        BuildSystem::from_filename_exact(s)
+    }
+
+    fn from_filename_exact(s: &str) -> Option<BuildSystem> {
+        match s {
+            "makefile" | "Makefile" | "GNUmakefile" | "BSDmakefile" => Some(BuildSystem::Make),
+            "build.ninja" => Some(BuildSystem::Ninja),
+            "Cargo.toml" => Some(BuildSystem::Cargo),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            BuildSystem::Make => "make",
+            BuildSystem::Ninja => "ninja",
+            BuildSystem::Cargo => "cargo",
+            BuildSystem::CMake => "cmake",
+            BuildSystem::Custom(name) => name,
+        }
+    }
+
+    // The make that reads the makefile in `dir`, unless the configuration names one: GNU make
+    // for a `GNUmakefile` on the BSDs, whose `make` is their own, and BSD make for a
+    // `BSDmakefile` elsewhere, as each make reads the file named after it before a `Makefile`
+    fn make_program(dir: &Path, config: &Config) -> String {
+        if let Some(program) = &config.make_program {
+            return program.clone();
+        }
+        let bsd = cfg!(any(
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd",
+            target_os = "dragonfly"
+        ));
+        if bsd && dir.join("GNUmakefile").exists() {
+            return "gmake".to_string();
+        }
+        if !bsd && !cfg!(windows) && dir.join("BSDmakefile").exists() {
+            return "bmake".to_string();
+        }
+        let program = match cfg!(windows) && exec::find_program("make").is_none() {
+            // The make of MinGW, or else the one of Visual Studio
+            true => ["mingw32-make", "nmake"]
+                .into_iter()
+                .find(|program| exec::find_program(program).is_some())
+                .unwrap_or("make"),
+            false => "make",
+        };
+        program.to_string()
+    }
+
+    fn build_make_command(options: &BuildOptions, config: &Config) -> CommandPlan {
+        let program = BuildSystem::make_program(&options.directory, config);
+        let makefile = custom_makefile(&options.directory, config);
+        if program == "nmake" {
+            return BuildSystem::build_nmake_command(options, makefile);
+        }
+        let mut command = CommandPlan::new(program);
+        if !options.jobserver {
+            command.arg("-j").arg(options.threads.to_string());
+        }
+        command.arg("-C").arg(&options.directory);
+        // Read from the directory `-C` changed to
+        if let Some(makefile) = makefile {
+            command.arg("-f").arg(makefile);
+        }
+        command.args(&options.args);
+        if options.verb != Verb::Build {
+            command.arg(options.verb.name());
+        }
+        command.args(options.targets);
+        command
+    }
+
+    // nmake has neither jobs nor `-C`, and builds the makefile in the directory it runs in
+    fn build_nmake_command(options: &BuildOptions, makefile: Option<OsString>) -> CommandPlan {
+        let mut command = CommandPlan::new("nmake");
+        command.arg("/NOLOGO");
+        if let Some(makefile) = makefile {
+            command.arg("/F").arg(makefile);
+        }
+        command.args(&options.args);
+        if options.verb != Verb::Build {
+            command.arg(options.verb.name());
+        }
+        command.args(options.targets);
+        command.current_dir(&options.directory);
+        command
+    }
+
+    fn build_ninja_command(options: &BuildOptions, config: &Config) -> CommandPlan {
+        let mut command = CommandPlan::new(ninja_program(config.ninja_program.as_deref()));
+        if !options.jobserver {
+            command.arg("-j").arg(options.threads.to_string());
+        }
+        command.arg("-C").arg(&options.directory);
+        command.args(&options.args);
+        match options.verb {
+            Verb::Build => {}
+            Verb::Test => {
+                command.arg("test");
+            }
+            Verb::Clean => {
+                command.arg("-t").arg("clean");
+            }
+        }
+        command.args(options.targets);
+        command
+    }
+
+    fn build_cargo_command(options: &BuildOptions) -> CommandPlan {
+        let mut command = CommandPlan::new("cargo");
+        command.arg(options.verb.name());
+        // `cargo clean` runs no jobs
+        if !options.jobserver && options.verb != Verb::Clean {
+            command.arg("-j").arg(options.threads.to_string());
+        }
+        if let Some(target) = options.cross_target {
+            command.arg("--target").arg(target);
+        }
+        match options.verb {
+            // The targets of cargo are its binaries, and those of `cargo test` filter the tests
+            Verb::Build => {
+                for target in options.targets {
+                    command.arg("--bin").arg(target);
+                }
+            }
+            Verb::Test => {
+                command.args(options.targets);
+            }
+            Verb::Clean => {}
+        }
+        command.args(&options.args);
+        // In a workspace, cargo builds the member it is started in
+        command.current_dir(&options.directory);
+        command
+    }
+
+    fn build_cmake_command(options: &BuildOptions) -> CommandPlan {
+        // Multi-config generators build the configuration the profile names, like `Release`
+        // for `release`, which the others ignore
+        let config = options
+            .profile
+            .map(|profile| cmake::build_type(profile).unwrap_or(profile));
+        // CMake builds the tests, but only CTest runs them
+        if options.verb == Verb::Test {
+            let mut command = CommandPlan::new("ctest");
+            command.arg("--test-dir").arg(&options.directory);
+            command.arg("--parallel").arg(options.threads.to_string());
+            if let Some(config) = config {
+                command.arg("--build-config").arg(config);
+            }
+            command.args(&options.args);
+            return command;
+        }
+        let mut command = CommandPlan::new("cmake");
+        command.arg("--build").arg(&options.directory);
+        if !options.jobserver {
+            command.arg("--parallel").arg(options.threads.to_string());
+        }
+        if let Some(config) = config {
+            command.arg("--config").arg(config);
+        }
+        command.args(&options.args);
+        let targets = match options.verb {
+            Verb::Clean => &["clean".to_string()][..],
+            _ => options.targets,
+        };
+        if !targets.is_empty() {
+            command.arg("--target").args(targets);
+        }
+        command
+    }
+
+    fn build_custom_command(
+        custom: &CustomBuildSystem,
+        options: &BuildOptions,
+    ) -> Option<CommandPlan> {
+        let template = match options.verb {
+            Verb::Build => Some(&custom.build),
+            Verb::Test => custom.test.as_ref(),
+            Verb::Clean => custom.clean.as_ref(),
+        }?;
+        let mut words = expand_template(template, options).into_iter();
+        let mut command = CommandPlan::new(wrapper(words.next()?, &options.directory));
+        command.args(words);
+        command.args(&options.args);
+        command.current_dir(&options.directory);
+        Some(command)
+    }
+
+    /// The command that only downloads what the build with `options` needs, so that it can
+    /// then run offline, if this build system has one: `cargo fetch`, or the `fetch` command
+    /// of a build system defined in the configuration
+    pub fn fetch(&self, options: &BuildOptions, config: &Config) -> Option<CommandPlan> {
+        let mut command = match self {
+            // For every platform, so that building for another one needs nothing more
+            BuildSystem::Cargo => {
+                let mut command = CommandPlan::new("cargo");
+                command.arg("fetch");
+                command
+            }
+            BuildSystem::Custom(name) => {
+                let template = config.build_systems.get(name)?.fetch.as_ref()?;
+                let mut words = expand_template(template, options).into_iter();
+                let mut command = CommandPlan::new(wrapper(words.next()?, &options.directory));
+                command.args(words);
+                command
+            }
+            _ => return None,
+        };
+        command.current_dir(&options.directory);
+        command.envs(config.env_for(self));
+        Some(command)
+    }
+
+    /// The command that carries out `options` with this build system
+    pub fn plan(&self, options: &BuildOptions, config: &Config) -> Result<CommandPlan> {
+        let command = match self {
+            BuildSystem::Make => Some(BuildSystem::build_make_command(options, config)),
+            BuildSystem::Ninja => Some(BuildSystem::build_ninja_command(options, config)),
+            BuildSystem::Cargo => Some(BuildSystem::build_cargo_command(options)),
+            BuildSystem::CMake => Some(BuildSystem::build_cmake_command(options)),
+            BuildSystem::Custom(name) => match config.build_systems.get(name) {
+                Some(custom) => BuildSystem::build_custom_command(custom, options),
+                None => match plugin::command(name, options)? {
+                    Some(command) => Some(command),
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("Unknown build system {}", name),
+                        ))
+                    }
+                },
+            },
+        };
+        let Some(mut command) = command else {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} has no {} command", self.name(), options.verb.name()),
+            ));
+        };
+        command.envs(config.env_for(self));
+        Ok(command)
+    }
+}
+
+/// The make the build of the makefile in `dir` runs, told with `-f` about a makefile it does
+/// not read by itself, to ask it about the makefile rather than build. nmake cannot be asked.
+pub fn make_command(dir: &Path, config: &Config) -> Result<CommandPlan> {
+    let program = BuildSystem::make_program(dir, config);
+    if program == "nmake" {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "nmake cannot tell what a makefile builds",
+        ));
+    }
+    let mut command = CommandPlan::new(program);
+    if let Some(makefile) = custom_makefile(dir, config) {
+        command.arg("-f").arg(makefile);
+    }
+    Ok(command)
+}
+
+/// Whether a file called `name` is a makefile by one of the names the configuration gives them
+pub fn is_custom_makefile(name: &OsStr, config: &Config) -> bool {
+    config
+        .makefiles
+        .iter()
+        .any(|pattern| matches_marker(pattern, name))
+}
+
+/// The makefile in `dir` that make has to be told about with `-f`: when there is none by the
+/// names make reads by itself, the first one by a name the configuration gives makefiles, in
+/// the order of those names and then by name
+pub fn custom_makefile(dir: &Path, config: &Config) -> Option<OsString> {
+    if config.makefiles.is_empty() {
+        return None;
+    }
+    let mut names: Vec<OsString> = read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.file_name())
+        .collect();
+    if names
+        .iter()
+        .any(|name| name.to_str().and_then(BuildSystem::from_filename) == Some(BuildSystem::Make))
+    {
+        return None;
+    }
+    names.sort();
+    config.makefiles.iter().find_map(|pattern| {
+        names
+            .iter()
+            .find(|name| matches_marker(pattern, name))
+            .cloned()
+    })
+}
+
+/// The ninja that runs `build.ninja` files: the one the configuration pins, or `ninja`, or else
+/// samurai or n2, which read the same files, for the minimal images that only have one of them
+pub fn ninja_program(pinned: Option<&str>) -> String {
+    if let Some(program) = pinned {
+        return program.to_string();
+    }
+    ["ninja", "samu", "n2"]
+        .into_iter()
+        .find(|program| exec::find_program(program).is_some())
+        .unwrap_or("ninja")
+        .to_string()
+}
+
+// The program a command template starts with, with the `./gradlew` and `./mvnw` wrappers in
+// `dir` swapped for the `gradlew.bat` and `mvnw.cmd` next to them on Windows, which cannot run
+// the shell scripts
+fn wrapper(program: String, dir: &Path) -> String {
+    if !cfg!(windows) {
+        return program;
+    }
+    let name = program.strip_prefix("./").unwrap_or(&program);
+    let script = match name {
+        "gradlew" => "gradlew.bat",
+        "mvnw" => "mvnw.cmd",
+        _ => return program,
+    };
+    match dir.join(script).is_file() {
+        true => dir.join(script).to_string_lossy().into_owned(),
+        false => program,
+    }
+}
+
+/// Split a command template into words and fill in its placeholders. A word that is exactly
+/// `{target}` becomes one word per target, and words left empty are dropped.
+fn expand_template(template: &str, options: &BuildOptions) -> Vec<String> {
+    let mut words = Vec::new();
+    for word in template.split_whitespace() {
+        if word == "{target}" {
+            words.extend(options.targets.iter().cloned());
+            continue;
+        }
+        let word = word
+            .replace("{threads}", &options.threads.to_string())
+            .replace("{dir}", &options.directory.to_string_lossy())
+            .replace("{target}", &options.targets.join(" "))
+            .replace("{profile}", options.profile.unwrap_or_default())
+            .replace("{cross_target}", options.cross_target.unwrap_or_default());
+        if !word.is_empty() {
+            words.push(word);
+        }
+    }
+    words
+}
+
+/// Match a file name against a pattern where `*` matches any run of characters and `?` any
+/// single character, ignoring case on Windows. Names that are not valid UTF-8 match too, byte
+/// by byte.
+pub fn matches_marker<S: AsRef<OsStr>>(pattern: &str, name: S) -> bool {
+    let name = name.as_ref();
+    if cfg!(windows) {
+        let name = match name.to_str() {
+            Some(name) => name.to_lowercase().into_bytes(),
+            None => name.as_encoded_bytes().to_ascii_lowercase(),
+        };
+        return matches_bytes(pattern.to_lowercase().as_bytes(), &name);
+    }
+    matches_bytes(pattern.as_bytes(), name.as_encoded_bytes())
+}
+
+// The length of the character `name` starts with, or 1 for a byte that starts none
+fn char_len(name: &[u8]) -> usize {
+    let len = match name.first() {
+        Some(0xc0..=0xdf) => 2,
+        Some(0xe0..=0xef) => 3,
+        Some(0xf0..=0xf7) => 4,
+        _ => 1,
+    };
+    match name
+        .get(..len)
+        .is_some_and(|char| str::from_utf8(char).is_ok())
+    {
+        true => len,
+        false => 1,
+    }
+}
+
+fn matches_bytes(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| matches_bytes(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && matches_bytes(rest, &name[char_len(name)..]),
+        Some((c, rest)) => name.first() == Some(c) && matches_bytes(rest, &name[1..]),
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    env::var,
+    process::{Command, Stdio},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{BuildSystem, CommandPlan, Detection};
+
+/// A compiler cache that builds run their compilers through
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum CompilerCache {
+    Ccache,
+    Sccache,
+    /// No compiler cache, even if the configuration names one
+    Off,
+}
+
+/// How many compilations a compiler cache could answer from the cache
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CompilerCache {
+    pub fn name(self) -> &'static str {
+        match self {
+            CompilerCache::Ccache => "ccache",
+            CompilerCache::Sccache => "sccache",
+            CompilerCache::Off => "off",
+        }
+    }
+
+    /// Make the build in `detection` run its compilers through this cache: make gets `CC` and
+    /// `CXX` with the cache in front, and cargo gets `RUSTC_WRAPPER`. CMake gets it as its
+    /// compiler launcher when it configures, see [`crate::Invocation::compiler_launcher`].
+    pub fn apply(self, plan: &mut CommandPlan, detection: &Detection) {
+        match (self, &detection.build_system) {
+            (CompilerCache::Off, _) => {}
+            // ccache cannot cache rustc
+            (CompilerCache::Sccache, BuildSystem::Cargo) => {
+                plan.envs([("RUSTC_WRAPPER".to_string(), self.name().to_string())]);
+            }
+            _ => wrap_compilers(plan, detection, self.name()),
+        }
+    }
+
+    /// The statistics the cache has kept so far, if it can be asked for them
+    pub fn stats(self) -> Option<CacheStats> {
+        let mut command = Command::new(self.name());
+        match self {
+            CompilerCache::Ccache => command.arg("--print-stats"),
+            CompilerCache::Sccache => command.args(["--show-stats", "--stats-format", "json"]),
+            CompilerCache::Off => return None,
+        };
+        let output = command.stderr(Stdio::null()).output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let output = String::from_utf8_lossy(&output.stdout);
+        let mut stats = CacheStats::default();
+        match self {
+            CompilerCache::Ccache => {
+                for line in output.lines() {
+                    let Some((key, value)) = line.split_once('\t') else {
+                        continue;
+                    };
+                    let value: u64 = value.trim().parse().unwrap_or(0);
+                    match key {
+                        "direct_cache_hit" | "preprocessed_cache_hit" => stats.hits += value,
+                        "cache_miss" => stats.misses += value,
+                        _ => {}
+                    }
+                }
+            }
+            CompilerCache::Sccache => {
+                let json: Value = serde_json::from_str(&output).ok()?;
+                // Counted separately for each language
+                let total = |key: &str| -> u64 {
+                    json["stats"][key]["counts"]
+                        .as_object()
+                        .map(|counts| counts.values().filter_map(Value::as_u64).sum())
+                        .unwrap_or(0)
+                };
+                stats.hits = total("cache_hits");
+                stats.misses = total("cache_misses");
+            }
+            CompilerCache::Off => {}
+        }
+        Some(stats)
+    }
+}
+
+/// Put `launcher` in front of the C and C++ compilers of the make build in `detection`, in
+/// `CC` and `CXX`
+pub fn wrap_compilers(plan: &mut CommandPlan, detection: &Detection, launcher: &str) {
+    if detection.build_system == BuildSystem::Make {
+        for (variable, compiler) in [("CC", "cc"), ("CXX", "c++")] {
+            let compiler = match plan.env.get(variable) {
+                Some(compiler) => compiler.clone(),
+                None => var(variable).unwrap_or(compiler.to_string()),
+            };
+            plan.envs([(variable.to_string(), format!("{} {}", launcher, compiler))]);
+        }
+    }
+}
+
+impl CacheStats {
+    /// The compilations counted since `earlier`
+    pub fn since(self, earlier: CacheStats) -> CacheStats {
+        CacheStats {
+            hits: self.hits.saturating_sub(earlier.hits),
+            misses: self.misses.saturating_sub(earlier.misses),
+        }
+    }
+
+    /// The share of compilations that were cache hits, from 0 to 1
+    pub fn hit_rate(self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        (total > 0).then(|| self.hits as f64 / total as f64)
+    }
+}
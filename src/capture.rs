@@ -0,0 +1,196 @@
+use std::{
+    collections::BTreeMap,
+    env::{current_dir, var_os, vars_os},
+    fs::{read_to_string, write},
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{toolchain, CommandPlan, Project};
+
+/// Everything about a build that could make it come out differently elsewhere: the command,
+/// the whole environment, the versions of the tools and the revision of the sources
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Capture {
+    /// When the build started, in seconds since the Unix epoch
+    pub started: u64,
+    pub command: String,
+    pub program: String,
+    pub args: Vec<String>,
+    /// The directory the command ran in
+    pub cwd: PathBuf,
+    /// All the environment variables of the command, inherited ones included, except secrets
+    pub env: BTreeMap<String, String>,
+    /// The variables left out of `env` for looking like secrets, which a replay takes from
+    /// its own environment
+    #[serde(default)]
+    pub secrets: Vec<String>,
+    /// The variables left out of `env` for not being UTF-8, which a replay takes from its own
+    /// environment as well
+    #[serde(default)]
+    pub not_utf8: Vec<String>,
+    /// The versions of the build program and the programs under `[requires]`, where the ones
+    /// that do not tell have none
+    pub tools: BTreeMap<String, Option<String>>,
+    /// The commit the sources were at, if they are in a git repository
+    pub revision: Option<String>,
+    /// Whether the sources had changes that were not committed
+    pub dirty: bool,
+}
+
+// Parts of the names of environment variables that hold secrets, which are not written down
+const SECRETS: &[&str] = &["TOKEN", "SECRET", "PASSWORD", "API_KEY", "CREDENTIAL"];
+
+pub(crate) fn is_secret(name: &str) -> bool {
+    let name = name.to_uppercase();
+    SECRETS.iter().any(|secret| name.contains(secret))
+}
+
+// The output of `git` with `args` in `dir`, if it succeeded
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// The versions of the programs in `tools`
+fn versions<'a>(tools: impl IntoIterator<Item = &'a str>) -> BTreeMap<String, Option<String>> {
+    tools
+        .into_iter()
+        .map(|tool| (tool.to_string(), toolchain::version(tool)))
+        .collect()
+}
+
+impl Capture {
+    /// Capture the build that `plan` runs in `project`
+    pub fn new(project: &Project, plan: &CommandPlan) -> Result<Capture> {
+        let cwd = match &plan.cwd {
+            Some(cwd) => cwd.clone(),
+            None => current_dir()?,
+        };
+        let mut not_utf8 = Vec::new();
+        let mut env: BTreeMap<String, String> = vars_os()
+            .filter_map(
+                |(name, value)| match (name.into_string(), value.into_string()) {
+                    (Ok(name), Ok(value)) => Some((name, value)),
+                    (name, _) => {
+                        not_utf8
+                            .push(name.unwrap_or_else(|name| name.to_string_lossy().into_owned()));
+                        None
+                    }
+                },
+            )
+            .collect();
+        env.extend(plan.env.clone());
+        let secrets = env
+            .keys()
+            .filter(|name| is_secret(name))
+            .cloned()
+            .collect::<Vec<_>>();
+        env.retain(|name, _| !is_secret(name));
+        let program = plan.program.to_string_lossy().into_owned();
+        let tools = versions(
+            [program.as_str()]
+                .into_iter()
+                .chain(project.config.requires.keys().map(String::as_str)),
+        );
+        let root = project.root();
+        Ok(Capture {
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            command: plan.to_string(),
+            program,
+            args: plan
+                .args
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            cwd,
+            env,
+            secrets,
+            not_utf8,
+            tools,
+            revision: git(&root, &["rev-parse", "HEAD"]),
+            dirty: git(&root, &["status", "--porcelain"]).is_some_and(|status| !status.is_empty()),
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Capture> {
+        serde_json::from_str(&read_to_string(path)?).map_err(|error| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), error),
+            )
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write(path, serde_json::to_vec_pretty(self)?)
+    }
+
+    /// How the machine differs from the captured build now: tools of other versions, and
+    /// sources at another revision
+    pub fn differences(&self) -> Vec<String> {
+        let mut differences = Vec::new();
+        let now = versions(self.tools.keys().map(String::as_str));
+        for (tool, version) in &self.tools {
+            let current = &now[tool];
+            if current != version {
+                let show = |version: &Option<String>| match version {
+                    Some(version) => version.clone(),
+                    None => "none".to_string(),
+                };
+                differences.push(format!(
+                    "{} was {}, and is {} now",
+                    tool,
+                    show(version),
+                    show(current)
+                ));
+            }
+        }
+        let revision = git(&self.cwd, &["rev-parse", "HEAD"]);
+        if revision != self.revision {
+            differences.push(format!(
+                "The sources were at {}, and are at {} now",
+                self.revision.as_deref().unwrap_or("no revision"),
+                revision.as_deref().unwrap_or("no revision")
+            ));
+        }
+        if self.dirty {
+            differences.push("The sources had uncommitted changes".to_string());
+        }
+        differences
+    }
+
+    /// The captured command, in the captured environment alone, along with the secrets and the
+    /// variables that are not UTF-8 it had from the current one
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .current_dir(&self.cwd)
+            .env_clear()
+            .envs(&self.env);
+        for name in self.secrets.iter().chain(&self.not_utf8) {
+            if let Some(value) = var_os(name) {
+                command.env(name, value);
+            }
+        }
+        command
+    }
+}
@@ -0,0 +1,71 @@
+use std::{
+    fs::read_to_string,
+    io::{Error, Result},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use serde_json::Value;
+
+/// What `cargo metadata` tells about the workspace that the crate in `dir` belongs to, without
+/// its dependencies
+pub fn metadata(dir: &Path) -> Result<Value> {
+    let output = Command::new("cargo")
+        .args(["metadata", "--format-version", "1", "--no-deps"])
+        .current_dir(dir)
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "cargo metadata failed with {}",
+            output.status
+        )));
+    }
+    serde_json::from_slice(&output.stdout).map_err(Error::other)
+}
+
+/// The names of the packages of the Cargo workspace that the crate in `dir` belongs to
+pub fn packages(dir: &Path) -> Result<Vec<String>> {
+    let mut names: Vec<_> = metadata(dir)?["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|package| package["name"].as_str())
+        .map(str::to_string)
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+// The manifest in `dir`, if it has one that parses
+fn manifest(dir: &Path) -> Option<toml::Table> {
+    read_to_string(dir.join("Cargo.toml")).ok()?.parse().ok()
+}
+
+/// The name of the package in `dir` and the root of the Cargo workspace it is a member of, if
+/// it is one of another directory. Cargo takes the nearest manifest above it with a
+/// `[workspace]` for that, unless the package names its root or the root excludes it.
+pub fn workspace_member(dir: &Path) -> Option<(String, PathBuf)> {
+    let package = manifest(dir)?;
+    let package = package.get("package")?;
+    let name = package.get("name")?.as_str()?.to_string();
+    if let Some(root) = package.get("workspace").and_then(|root| root.as_str()) {
+        return Some((name, dir.join(root)));
+    }
+    for root in dir.ancestors().skip(1) {
+        let Some(workspace) =
+            manifest(root).and_then(|manifest| manifest.get("workspace").cloned())
+        else {
+            continue;
+        };
+        let excluded = workspace
+            .get("exclude")
+            .and_then(|exclude| exclude.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|exclude| exclude.as_str())
+            .any(|exclude| dir.starts_with(root.join(exclude)));
+        return (!excluded).then(|| (name, root.to_path_buf()));
+    }
+    None
+}
@@ -0,0 +1,298 @@
+use std::{
+    env::{temp_dir, var_os},
+    fs::{create_dir_all, exists, remove_file, rename},
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    process::{id, Command, Stdio},
+};
+
+use crate::{cargo, config::CiCacheConfig, exec, sandbox, workspace, BuildSystem, Project};
+
+/// Where `sm cache save` keeps its archives, and `sm cache restore` finds them
+#[derive(Clone, Debug, PartialEq)]
+pub enum Store {
+    Directory(PathBuf),
+    /// A bucket of S3 or of an S3-compatible store at `endpoint`, through the AWS CLI
+    S3 {
+        url: String,
+        endpoint: Option<String>,
+    },
+}
+
+// Run `command`, failing with what it is for when it fails
+fn run(mut command: Command, what: &str) -> Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = match command.stdin(Stdio::null()).status() {
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            return Err(exec::missing_tool(format!(
+                "{} needs {} on PATH",
+                what, program
+            )))
+        }
+        status => status?,
+    };
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::other(format!("{} failed with {}", what, status))),
+    }
+}
+
+fn aws(endpoint: &Option<String>) -> Command {
+    let mut command = Command::new("aws");
+    command.arg("s3");
+    if let Some(endpoint) = endpoint {
+        command.arg("--endpoint-url").arg(endpoint);
+    }
+    command
+}
+
+impl Store {
+    /// The store `store` names, a directory relative to `base` or an `s3://bucket/prefix` URL,
+    /// or else the one in the configuration
+    pub fn new(store: Option<&str>, config: &CiCacheConfig, base: &Path) -> Result<Store> {
+        let Some(store) = store.or(config.store.as_deref()) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "No cache store: pass --store or set store under [ci_cache]",
+            ));
+        };
+        Ok(match store.starts_with("s3://") {
+            true => Store::S3 {
+                url: store.trim_end_matches('/').to_string(),
+                endpoint: config.endpoint.clone(),
+            },
+            false => Store::Directory(sandbox::expand(Path::new(store), base)),
+        })
+    }
+
+    // Put the file at `path` into the store as `name`
+    fn put(&self, path: &Path, name: &str) -> Result<()> {
+        match self {
+            Store::Directory(dir) => {
+                create_dir_all(dir)?;
+                // Through a temporary name, so that a restore never finds half an archive
+                let partial = dir.join(format!("{}.{}.partial", name, id()));
+                let mut copy = Command::new("cp");
+                copy.arg(path).arg(&partial);
+                run(copy, "Saving the cache")?;
+                rename(&partial, dir.join(name))
+            }
+            Store::S3 { url, endpoint } => {
+                let mut copy = aws(endpoint);
+                copy.arg("cp")
+                    .arg("--only-show-errors")
+                    .arg(path)
+                    .arg(format!("{}/{}", url, name));
+                run(copy, "Uploading the cache")
+            }
+        }
+    }
+
+    // Get `name` from the store into `path`, or `false` if the store does not have it
+    fn get(&self, name: &str, path: &Path) -> Result<bool> {
+        match self {
+            Store::Directory(dir) => {
+                let archive = dir.join(name);
+                if !exists(&archive)? {
+                    return Ok(false);
+                }
+                let mut copy = Command::new("cp");
+                copy.arg(archive).arg(path);
+                run(copy, "Restoring the cache")?;
+                Ok(true)
+            }
+            Store::S3 { url, endpoint } => {
+                let object = format!("{}/{}", url, name);
+                // `aws s3 ls` fails for objects that do not exist
+                let listed = aws(endpoint)
+                    .arg("ls")
+                    .arg(&object)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .is_ok_and(|status| status.success());
+                if !listed {
+                    return Ok(false);
+                }
+                let mut copy = aws(endpoint);
+                copy.arg("cp")
+                    .arg("--only-show-errors")
+                    .arg(&object)
+                    .arg(path);
+                run(copy, "Downloading the cache")?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+// The directories cached paths are archived relative to, so that a cache restores into a
+// checkout or a home directory somewhere else, named as in the names of the archives
+fn roots(project: &Path) -> Vec<(&'static str, PathBuf)> {
+    let mut roots = vec![("project", project.to_path_buf())];
+    if let Some(home) = var_os("HOME") {
+        roots.push(("home", PathBuf::from(home)));
+    }
+    roots.push(("system", PathBuf::from("/")));
+    roots
+}
+
+// A directory in an environment variable, or the one in the home directory otherwise
+fn tool_home(variable: &str, default: &str) -> Option<PathBuf> {
+    var_os(variable)
+        .map(PathBuf::from)
+        .or_else(|| var_os("HOME").map(|home| Path::new(&home).join(default)))
+}
+
+// The directories a project with `build_system` in `directory` keeps its build outputs in
+fn build_paths(build_system: &BuildSystem, directory: &Path, root: &Path) -> Vec<PathBuf> {
+    match build_system {
+        BuildSystem::Cargo => {
+            let target = cargo::metadata(directory)
+                .ok()
+                .and_then(|metadata| metadata["target_directory"].as_str().map(PathBuf::from))
+                .unwrap_or_else(|| directory.join("target"));
+            let mut paths = vec![target];
+            if let Some(cargo_home) = tool_home("CARGO_HOME", ".cargo") {
+                paths.push(cargo_home.join("registry/index"));
+                paths.push(cargo_home.join("registry/cache"));
+                paths.push(cargo_home.join("git/db"));
+            }
+            paths
+        }
+        // Caching a build directory that is the project itself would cache the sources too
+        _ if directory == root => Vec::new(),
+        BuildSystem::CMake | BuildSystem::Make | BuildSystem::Ninja => {
+            vec![directory.to_path_buf()]
+        }
+        BuildSystem::Custom(_) => Vec::new(),
+    }
+}
+
+/// The paths worth caching between CI runs for the projects in and below the one of
+/// `project`: the build directories, the registries of cargo and the caches of Gradle, and the
+/// `paths` under `[ci_cache]`, where they exist
+pub fn paths(project: &Project) -> Result<Vec<PathBuf>> {
+    let root = project.root();
+    let base = project.config_dir.clone().unwrap_or_else(|| root.clone());
+    let mut paths: Vec<PathBuf> = project
+        .config
+        .ci_cache
+        .paths
+        .iter()
+        .map(|path| sandbox::expand(path, &base))
+        .collect();
+    for subproject in workspace::discover(&root, &project.config)? {
+        let dir = subproject.project.root();
+        if let Some(detection) = &subproject.project.detection {
+            paths.extend(build_paths(
+                &detection.build_system,
+                &detection.directory,
+                &dir,
+            ));
+        }
+    }
+    let gradle = [
+        "build.gradle",
+        "build.gradle.kts",
+        "settings.gradle",
+        "settings.gradle.kts",
+    ];
+    if gradle.iter().any(|file| root.join(file).exists()) {
+        if let Some(gradle_home) = tool_home("GRADLE_USER_HOME", ".gradle") {
+            paths.push(gradle_home.join("caches"));
+            paths.push(gradle_home.join("wrapper"));
+        }
+    }
+    paths.retain(|path| path.exists());
+    paths.sort();
+    paths.dedup();
+    // A path inside another one is in its archive already
+    let all = paths.clone();
+    paths.retain(|path| {
+        !all.iter()
+            .any(|other| other != path && path.starts_with(other))
+    });
+    Ok(paths)
+}
+
+// Fail unless `key` can be part of the name of a file
+fn check_key(key: &str) -> Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "._-+".contains(c);
+    match !key.is_empty() && !key.starts_with('.') && key.chars().all(valid) {
+        true => Ok(()),
+        false => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Invalid cache key: {}: use letters, digits, `.`, `_`, `-` and `+`",
+                key
+            ),
+        )),
+    }
+}
+
+// A file in the temporary directory for an archive on its way, removed when dropped
+struct Temporary(PathBuf);
+
+impl Temporary {
+    fn new(name: &str) -> Temporary {
+        Temporary(temp_dir().join(format!("smartmake-{}-{}", id(), name)))
+    }
+}
+
+impl Drop for Temporary {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.0);
+    }
+}
+
+/// Archive the [`paths`] of `project` into `store` under `key`, one archive for each of the
+/// directories they are relative to, and return the paths
+pub fn save(project: &Project, store: &Store, key: &str) -> Result<Vec<PathBuf>> {
+    check_key(key)?;
+    let paths = paths(project)?;
+    let mut left = paths.clone();
+    for (root_name, root) in roots(&project.root()) {
+        let (inside, outside): (Vec<_>, Vec<_>) =
+            left.into_iter().partition(|path| path.starts_with(&root));
+        left = outside;
+        if inside.is_empty() {
+            continue;
+        }
+        let name = format!("{}-{}.tar.gz", key, root_name);
+        let archive = Temporary::new(&name);
+        let mut tar = Command::new("tar");
+        tar.arg("-czf").arg(&archive.0).arg("-C").arg(&root);
+        for path in &inside {
+            let relative = path.strip_prefix(&root).unwrap_or(path);
+            tar.arg(match relative.as_os_str().is_empty() {
+                true => Path::new("."),
+                false => relative,
+            });
+        }
+        run(tar, "Archiving the cache")?;
+        store.put(&archive.0, &name)?;
+    }
+    Ok(paths)
+}
+
+/// Unpack the archives saved under `key` in `store` into the project and the home directory,
+/// or return `false` if there are none
+pub fn restore(project: &Project, store: &Store, key: &str) -> Result<bool> {
+    check_key(key)?;
+    let mut found = false;
+    for (root_name, root) in roots(&project.root()) {
+        let name = format!("{}-{}.tar.gz", key, root_name);
+        let archive = Temporary::new(&name);
+        if !store.get(&name, &archive.0)? {
+            continue;
+        }
+        create_dir_all(&root)?;
+        let mut tar = Command::new("tar");
+        tar.arg("-xzf").arg(&archive.0).arg("-C").arg(&root);
+        run(tar, "Unpacking the cache")?;
+        found = true;
+    }
+    Ok(found)
+}
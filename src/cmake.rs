@@ -0,0 +1,220 @@
+use std::{
+    fs::{canonicalize, read_to_string, remove_dir_all, remove_file},
+    io::{stderr, stdin, Error, ErrorKind, IsTerminal, Result},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    build_system::ninja_program, exec, exec::find_program, BuildSystem, CommandPlan, Detection,
+};
+
+/// The file that marks a CMake source directory
+const CMAKE_LISTS: &str = "CMakeLists.txt";
+
+/// The file CMake writes into a build directory it configured
+const CMAKE_CACHE: &str = "CMakeCache.txt";
+
+// The value of `key` in the CMake cache of `dir`
+fn cache_value(dir: &Path, key: &str) -> Option<String> {
+    let cache = read_to_string(dir.join(CMAKE_CACHE)).ok()?;
+    cache.lines().find_map(|line| {
+        let (name, value) = line.split_once('=')?;
+        let (name, _) = name.split_once(':')?;
+        (name == key).then(|| value.to_string())
+    })
+}
+
+// Whether `a` and `b` are the same directory, by any path
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (canonicalize(a), canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// The source directory the CMake build directory `dir` was configured from, if it is still
+/// there
+pub fn home(dir: &Path) -> Option<PathBuf> {
+    let home = PathBuf::from(cache_value(dir, "CMAKE_HOME_DIRECTORY")?);
+    home.join(CMAKE_LISTS).is_file().then_some(home)
+}
+
+/// Why the configured CMake build directory `dir` cannot be built as it is, if it cannot:
+/// the source or the build directory moved since it was configured, it was configured with
+/// another generator than `generator`, or the build files of its generator are missing
+pub fn stale(dir: &Path, generator: Option<&str>) -> Option<String> {
+    if let Some(configured) = cache_value(dir, "CMAKE_CACHEFILE_DIR") {
+        if !same_dir(Path::new(&configured), dir) {
+            return Some(format!(
+                "it was configured in {}, and moved since",
+                configured
+            ));
+        }
+    }
+    if let Some(source) = cache_value(dir, "CMAKE_HOME_DIRECTORY") {
+        if !Path::new(&source).join(CMAKE_LISTS).is_file() {
+            return Some(format!("its source in {} is gone", source));
+        }
+    }
+    let configured = cache_value(dir, "CMAKE_GENERATOR")?;
+    if let Some(generator) = generator.filter(|generator| *generator != configured) {
+        return Some(format!(
+            "it was configured with the {} generator, not {}",
+            configured, generator
+        ));
+    }
+    let build_file = match build_system(Some(&configured)) {
+        BuildSystem::Ninja => "build.ninja",
+        BuildSystem::Make => "Makefile",
+        _ => return None,
+    };
+    (!dir.join(build_file).is_file()).then(|| format!("its {} is missing", build_file))
+}
+
+/// Why the configured CMake build directory `dir` does not run its compilers through
+/// `launcher`, if it does not: CMake takes the compiler launcher only when it configures a
+/// build directory from scratch
+pub fn without_launcher(dir: &Path, launcher: &str) -> Option<String> {
+    if !is_configured(dir) {
+        return None;
+    }
+    ["CMAKE_C_COMPILER_LAUNCHER", "CMAKE_CXX_COMPILER_LAUNCHER"]
+        .into_iter()
+        .find(|key| {
+            cache_value(dir, key).is_none_or(|configured| {
+                Path::new(&configured).file_name() != Some(launcher.as_ref())
+            })
+        })
+        .map(|key| format!("it was configured without {} as its {}", launcher, key))
+}
+
+/// Throw away what CMake configured in `dir`, so that it can be configured from scratch
+pub fn reset(dir: &Path) -> Result<()> {
+    remove_file(dir.join(CMAKE_CACHE))?;
+    match remove_dir_all(dir.join("CMakeFiles")) {
+        Err(error) if error.kind() != ErrorKind::NotFound => Err(error),
+        _ => Ok(()),
+    }
+}
+
+/// Ask `question` on the terminal, taking anything but yes as no, and no terminal as no
+pub fn confirm(question: &str) -> bool {
+    if !stdin().is_terminal() || !stderr().is_terminal() {
+        return false;
+    }
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// The build directory smartmake configures a CMake project in, relative to its source
+pub const BUILD_DIR: &str = "build";
+
+/// The top CMake source directory of the project `dir` is in, if `dir` is part of one: the
+/// outermost directory with a `CMakeLists.txt` in the same repository
+pub fn source_root(dir: &Path) -> Option<PathBuf> {
+    if !dir.join(CMAKE_LISTS).is_file() {
+        return None;
+    }
+    let mut root = dir;
+    for ancestor in dir.ancestors() {
+        if ancestor.join(CMAKE_LISTS).is_file() {
+            root = ancestor;
+        }
+        if ancestor.join(".git").exists() {
+            break;
+        }
+    }
+    Some(root.to_path_buf())
+}
+
+/// The CMake build type a profile called `name` stands for, if any, like `Release` for
+/// `release`
+pub fn build_type(name: &str) -> Option<&'static str> {
+    ["Debug", "Release", "RelWithDebInfo", "MinSizeRel"]
+        .into_iter()
+        .find(|build_type| build_type.eq_ignore_ascii_case(name))
+}
+
+/// Whether `dir` is a build directory CMake configured
+pub fn is_configured(dir: &Path) -> bool {
+    dir.join(CMAKE_CACHE).is_file()
+}
+
+/// The CMake generator builds are configured with: `configured`, or else Ninja if it, samurai
+/// or n2 is installed, or the `ninja_program` the configuration pins, or else the default of
+/// CMake, which is Makefiles except on Windows, where it is the newest Visual Studio
+pub fn generator(configured: Option<&str>, ninja: Option<&str>) -> Option<String> {
+    match configured {
+        Some(generator) => Some(generator.to_string()),
+        None if find_program(&ninja_program(ninja)).is_some() => Some("Ninja".to_string()),
+        None if cfg!(windows) => None,
+        None => Some("Unix Makefiles".to_string()),
+    }
+}
+
+/// The build system that builds what `generator` writes. Multi-config generators, like Visual
+/// Studio, Xcode and Ninja Multi-Config, are driven through CMake, which knows their
+/// configurations.
+fn build_system(generator: Option<&str>) -> BuildSystem {
+    match generator {
+        Some("Ninja") => BuildSystem::Ninja,
+        Some("Unix Makefiles" | "MinGW Makefiles" | "MSYS Makefiles") => BuildSystem::Make,
+        _ => BuildSystem::CMake,
+    }
+}
+
+/// The build of the CMake project with its source in `source` in the build directory
+/// `directory`, which smartmake configures with `generator` before building if CMake has not
+/// yet
+pub fn detection(source: &Path, directory: PathBuf, generator: Option<&str>) -> Detection {
+    Detection {
+        build_system: build_system(generator),
+        directory,
+        source: Some(source.to_path_buf()),
+    }
+}
+
+/// The command that configures the build directory of `detection` with `generator`, if it is
+/// a CMake build that has not been configured yet. `args` are passed on to CMake, like a
+/// toolchain file.
+pub fn configure_plan(
+    detection: &Detection,
+    generator: Option<&str>,
+    args: &[String],
+) -> Option<CommandPlan> {
+    let source = detection.source.as_ref()?;
+    if is_configured(&detection.directory) {
+        return None;
+    }
+    let mut command = CommandPlan::new("cmake");
+    command
+        .arg("-S")
+        .arg(source)
+        .arg("-B")
+        .arg(&detection.directory);
+    if let Some(generator) = generator {
+        command.arg("-G").arg(generator);
+    }
+    command.args(args);
+    Some(command)
+}
+
+/// Configure the build directory of `detection` first if it needs it, showing the output of
+/// CMake
+pub fn configure(detection: &Detection, generator: Option<&str>, args: &[String]) -> Result<()> {
+    let Some(plan) = configure_plan(detection, generator, args) else {
+        return Ok(());
+    };
+    exec::require("cmake".as_ref())?;
+    exec::message(&format!("Configuring {}", detection.directory.display()));
+    let status = exec::run(plan.command())?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::other(format!(
+            "Configuring the CMake project failed with {}",
+            status
+        ))),
+    }
+}
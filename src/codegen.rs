@@ -0,0 +1,137 @@
+use std::{
+    collections::BTreeMap,
+    fs::read_dir,
+    io::{Error, Result},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{build_system::matches_marker, exec, state, Project};
+
+// What a code generator last ran with: its command and the modification times of its inputs
+#[derive(Deserialize, PartialEq, Serialize)]
+struct Stamp {
+    command: String,
+    inputs: Vec<(PathBuf, Option<u64>)>,
+}
+
+// The stamps of each project root, keyed by the name of the step
+type State = BTreeMap<PathBuf, BTreeMap<String, Stamp>>;
+
+// Add the paths below `dir` that match the components of `pattern` to `found`
+fn walk(dir: &Path, pattern: &[String], found: &mut Vec<PathBuf>) {
+    let Some((first, rest)) = pattern.split_first() else {
+        if dir.exists() {
+            found.push(dir.to_path_buf());
+        }
+        return;
+    };
+    if first == "**" {
+        walk(dir, rest, found);
+    }
+    let Ok(entries) = read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        if first != "**" {
+            if matches_marker(first, &name) {
+                walk(&path, rest, found);
+            }
+        // Hidden directories, like `.git`, are only searched when named
+        } else if !name.as_encoded_bytes().starts_with(b".") && path.is_dir() {
+            walk(&path, pattern, found);
+        }
+    }
+}
+
+/// The paths that match `pattern`, where `*` and `?` match within a name and `**` any number
+/// of directories, in order
+pub fn glob(pattern: &Path) -> Vec<PathBuf> {
+    let mut dir = PathBuf::new();
+    let mut components = pattern.components().peekable();
+    while let Some(component) = components.peek() {
+        if component.as_os_str().to_string_lossy().contains(['*', '?']) {
+            break;
+        }
+        dir.push(component);
+        components.next();
+    }
+    let rest: Vec<String> = components
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    let mut found = Vec::new();
+    walk(&dir, &rest, &mut found);
+    found.sort();
+    found.dedup();
+    found
+}
+
+/// Run the `codegen` steps of `project` that have not run since their command or one of their
+/// inputs changed, or whose outputs are missing, one after the other with the shell in the
+/// project root. The first one that fails is an error.
+pub fn run(project: &Project) -> Result<()> {
+    let steps = &project.config.codegen;
+    if steps.is_empty() {
+        return Ok(());
+    }
+    let root = project.root();
+    let base = project.config_dir.clone().unwrap_or_else(|| root.clone());
+    let path = state::cache_file("codegen.json");
+    let mut state: State = path.as_deref().map(state::load).unwrap_or_default();
+    let stamps = state.entry(root.clone()).or_default();
+    stamps.retain(|name, _| steps.contains_key(name));
+    let mut result = Ok(());
+    for (name, step) in steps {
+        let mut inputs: Vec<PathBuf> = step
+            .inputs
+            .iter()
+            .flat_map(|pattern| glob(&base.join(pattern)))
+            .collect();
+        inputs.sort();
+        inputs.dedup();
+        let stamp = Stamp {
+            command: step.command.clone(),
+            inputs: inputs
+                .into_iter()
+                .map(|input| {
+                    let time = state::modified(&input);
+                    (input, time)
+                })
+                .collect(),
+        };
+        let missing = step
+            .outputs
+            .iter()
+            .any(|pattern| glob(&base.join(pattern)).is_empty());
+        if !missing && stamps.get(name) == Some(&stamp) {
+            continue;
+        }
+        exec::message(&format!("Generating {}: {}", name, step.command));
+        let mut command = exec::shell(&step.command);
+        command.current_dir(&root);
+        let status = match exec::run(command) {
+            Ok(status) => status,
+            Err(error) => {
+                result = Err(error);
+                break;
+            }
+        };
+        if !status.success() {
+            stamps.remove(name);
+            result = Err(Error::other(format!(
+                "codegen step {} failed with {}",
+                name, status
+            )));
+            break;
+        }
+        stamps.insert(name.clone(), stamp);
+    }
+    // Without the stamps the generators only run again
+    if let Some(path) = &path {
+        let _ = state::store(path, &state);
+    }
+    result
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::BTreeMap,
+    env::temp_dir,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    time::Duration,
+};
+
+use crate::{exec, history::format_duration, timings::Timing};
+
+// The output of `git` with `args` in `dir`, or why it failed
+fn git(dir: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?;
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+        false => Err(Error::other(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
+
+/// The two revisions of `A..B`, where a missing `B` is `HEAD` as with git
+pub fn parse_range(range: &str) -> Result<(String, String)> {
+    match range.split_once("..") {
+        Some((before, after)) if !before.is_empty() && !after.starts_with('.') => Ok((
+            before.to_string(),
+            match after {
+                "" => "HEAD".to_string(),
+                after => after.to_string(),
+            },
+        )),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a range of two revisions, like main..HEAD", range),
+        )),
+    }
+}
+
+/// The top of the git repository `dir` is in, and the path of `dir` inside it
+pub fn location(dir: &Path) -> Result<(PathBuf, PathBuf)> {
+    let toplevel = git(dir, &["rev-parse", "--show-toplevel"])?;
+    let prefix = git(dir, &["rev-parse", "--show-prefix"])?;
+    Ok((PathBuf::from(toplevel), PathBuf::from(prefix)))
+}
+
+const PREFIX: &str = "smartmake-compare-";
+
+/// Remove the worktrees that earlier runs of `sm compare` left behind in `repository`, when
+/// they were killed before they could, and make git forget the ones already deleted
+pub fn remove_stale(repository: &Path) -> Result<()> {
+    let list = git(repository, &["worktree", "list", "--porcelain"])?;
+    for path in list
+        .lines()
+        .filter_map(|line| line.strip_prefix("worktree "))
+    {
+        let path = Path::new(path);
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        // Named after the process that added it, which may still be comparing
+        let Some(pid) = name
+            .strip_prefix(PREFIX)
+            .and_then(|rest| rest.split('-').next())
+        else {
+            continue;
+        };
+        let running = pid.parse().is_ok_and(exec::is_running);
+        if path.starts_with(temp_dir()) && !running {
+            git(
+                repository,
+                &["worktree", "remove", "--force", &path.to_string_lossy()],
+            )?;
+        }
+    }
+    git(repository, &["worktree", "prune"]).map(|_| ())
+}
+
+/// A revision checked out on its own in a temporary directory, removed again when dropped
+pub struct Worktree {
+    repository: PathBuf,
+    pub dir: PathBuf,
+    /// The commit it checked out, abbreviated
+    pub commit: String,
+}
+
+impl Worktree {
+    /// Check `revision` of `repository` out in a new worktree, without touching the current
+    /// checkout
+    pub fn add(repository: &Path, revision: &str, name: &str) -> Result<Worktree> {
+        let commit = git(
+            repository,
+            &[
+                "rev-parse",
+                "--verify",
+                "--quiet",
+                &format!("{}^{{commit}}", revision),
+            ],
+        )
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("No revision {}", revision)))?;
+        let dir = temp_dir().join(format!("{}{}-{}", PREFIX, process::id(), name));
+        let path = dir.to_string_lossy();
+        git(
+            repository,
+            &["worktree", "add", "--detach", "--quiet", &path, &commit],
+        )?;
+        Ok(Worktree {
+            repository: repository.to_path_buf(),
+            dir,
+            commit: commit.chars().take(12).collect(),
+        })
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        let path = self.dir.to_string_lossy();
+        if let Err(error) = git(&self.repository, &["worktree", "remove", "--force", &path]) {
+            eprintln!("Could not remove {}: {}", self.dir.display(), error);
+        }
+    }
+}
+
+/// How long a target took to build at each revision, if it was built at all
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delta {
+    pub target: String,
+    pub before: Option<Duration>,
+    pub after: Option<Duration>,
+}
+
+impl Delta {
+    // How much longer it took, in seconds, which is negative when it got faster
+    fn seconds(&self) -> f64 {
+        self.after.unwrap_or_default().as_secs_f64() - self.before.unwrap_or_default().as_secs_f64()
+    }
+}
+
+// How long each target took, adding up the times of a target built several times
+fn durations(timings: &[Timing]) -> BTreeMap<&str, Duration> {
+    let mut durations = BTreeMap::new();
+    for timing in timings {
+        *durations.entry(timing.target.as_str()).or_default() += timing.duration;
+    }
+    durations
+}
+
+/// The targets of both builds, the ones whose time changed most first
+pub fn deltas(before: &[Timing], after: &[Timing]) -> Vec<Delta> {
+    let (before, after) = (durations(before), durations(after));
+    let mut deltas: Vec<Delta> = before
+        .keys()
+        .chain(after.keys().filter(|target| !before.contains_key(*target)))
+        .map(|target| Delta {
+            target: target.to_string(),
+            before: before.get(target).copied(),
+            after: after.get(target).copied(),
+        })
+        .collect();
+    deltas.sort_by(|a, b| b.seconds().abs().total_cmp(&a.seconds().abs()));
+    deltas
+}
+
+/// The change from `before` to `after`, like `+1.2s (+8%)`
+pub fn change(before: Duration, after: Duration) -> String {
+    let sign = match after >= before {
+        true => '+',
+        false => '-',
+    };
+    let difference = format_duration(after.abs_diff(before));
+    match before.is_zero() {
+        true => format!("{}{}", sign, difference),
+        false => format!(
+            "{}{} ({:+.0}%)",
+            sign,
+            difference,
+            (after.as_secs_f64() / before.as_secs_f64() - 1.0) * 100.0
+        ),
+    }
+}
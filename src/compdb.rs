@@ -0,0 +1,109 @@
+use std::{
+    collections::HashSet,
+    fs::{exists, read},
+    io::{Error, ErrorKind, Result},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use serde_json::Value;
+
+use crate::{
+    build_system::ninja_program,
+    config::Config,
+    exec::{self, find_program},
+    BuildSystem, Detection,
+};
+
+/// The name clangd and other tools look for the compilation database under
+pub const COMPDB: &str = "compile_commands.json";
+
+fn read_compdb(path: &Path) -> Result<Vec<Value>> {
+    serde_json::from_slice(&read(path)?).map_err(|error| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), error),
+        )
+    })
+}
+
+fn run(command: Command) -> Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = exec::run(command)?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::other(format!("{} failed with {}", program, status))),
+    }
+}
+
+/// The entries of the compilation database for the build in `detection`, generating it the
+/// way its build system allows: by having CMake export it, from ninja's build log, or by
+/// watching make with bear or compiledb
+pub fn generate(detection: &Detection, config: &Config, threads: usize) -> Result<Vec<Value>> {
+    let dir = &detection.directory;
+    let compdb = dir.join(COMPDB);
+    if exists(dir.join("CMakeCache.txt"))? {
+        let mut command = Command::new("cmake");
+        command
+            .arg("-DCMAKE_EXPORT_COMPILE_COMMANDS=ON")
+            .arg(".")
+            .current_dir(dir);
+        run(command)?;
+        return read_compdb(&compdb);
+    }
+    match &detection.build_system {
+        BuildSystem::Ninja => {
+            let output = Command::new(ninja_program(config.ninja_program.as_deref()))
+                .arg("-C")
+                .arg(dir)
+                .arg("-t")
+                .arg("compdb")
+                .stderr(Stdio::inherit())
+                .output()?;
+            if !output.status.success() {
+                return Err(Error::other(format!(
+                    "ninja -t compdb failed with {}",
+                    output.status
+                )));
+            }
+            serde_json::from_slice(&output.stdout).map_err(Error::other)
+        }
+        BuildSystem::Make if find_program("bear").is_some() => {
+            let mut command = Command::new("bear");
+            command.arg("--output").arg(&compdb).arg("--").arg("make");
+            command
+                .arg("-j")
+                .arg(threads.to_string())
+                .arg("-C")
+                .arg(dir);
+            run(command)?;
+            read_compdb(&compdb)
+        }
+        BuildSystem::Make if find_program("compiledb").is_some() => {
+            // compiledb reads what make would run without building anything
+            let mut command = Command::new("compiledb");
+            command.arg("-n").arg("-o").arg(&compdb).arg("make");
+            command.arg("-C").arg(dir);
+            run(command)?;
+            read_compdb(&compdb)
+        }
+        BuildSystem::Make => Err(exec::missing_tool(
+            "A compilation database for make needs bear or compiledb on PATH",
+        )),
+        build_system => Err(Error::new(
+            ErrorKind::Unsupported,
+            format!("{} has no compilation database", build_system.name()),
+        )),
+    }
+}
+
+/// Combine the compilation databases of several projects, keeping the first command for
+/// each file
+pub fn merge(databases: Vec<Vec<Value>>) -> Vec<Value> {
+    let mut seen = HashSet::new();
+    databases
+        .into_iter()
+        .flatten()
+        .filter(|entry| seen.insert((entry["directory"].to_string(), entry["file"].to_string())))
+        .collect()
+}
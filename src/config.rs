@@ -0,0 +1,876 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env::var_os,
+    fmt::{self, Display, Formatter},
+    fs::{exists, read_to_string},
+    io::{Error, ErrorKind, Result},
+    iter::once,
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::CompilerCache, detect::above_ceiling, distributed::Distributor,
+    environment::Environment, limits, plugin, remote::RemoteConfig, sandbox::Sandbox, toolchain,
+    BuildSystem,
+};
+
+/// The name of the per-project configuration file
+pub const PROJECT_CONFIG: &str = ".smartmake.toml";
+
+// The user configuration, relative to the configuration directory
+const GLOBAL_CONFIG: &str = "smartmake/config.toml";
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Whether the configuration files in the parent directories are left out, instead of
+    /// this one being layered over them
+    pub root: bool,
+    /// The build system to use instead of detecting one
+    pub build_system: Option<BuildSystem>,
+    /// The directory to build in, relative to the configuration file
+    pub directory: Option<PathBuf>,
+    /// The build systems to choose first, by name, when a directory has the files of several
+    pub prefer: Vec<String>,
+    /// The only build systems to detect, by name, when not empty
+    pub enable: Vec<String>,
+    /// The build systems never to detect, by name, like a `Makefile` that only runs containers
+    pub disable: Vec<String>,
+    /// The build systems to build one after the other, by name and in this order, in the top
+    /// directory of the project, like CMake for the native code next to npm for the UI,
+    /// instead of only the one detected
+    pub polyglot: Vec<String>,
+    /// The names of the build directories looked for in the current directory and its
+    /// parents, where `*` matches anything, instead of `build`, `out`, `builddir` and
+    /// `cmake-build-*`
+    pub build_dirs: Vec<String>,
+    /// Names of makefiles make is told about with `-f`, like `Makefile.linux` or `*.mk`, for
+    /// the directories without a `Makefile`
+    pub makefiles: Vec<String>,
+    /// Whether the search for the build system follows symbolic links, walking up the
+    /// directories they lead to, rather than the path the project was reached through
+    pub follow_symlinks: Option<bool>,
+    /// The targets to build when none are given on the command line
+    pub targets: Vec<String>,
+    /// Extra arguments passed to the build system
+    pub args: Vec<String>,
+    /// Environment variables set for the build system
+    pub env: HashMap<String, String>,
+    /// The number of threads to build with
+    pub jobs: Option<usize>,
+    /// Whether builds without a number of threads share the machine with the other builds
+    /// running on it, instead of each using all of it
+    pub share_jobs: Option<bool>,
+    /// Whether to show a progress bar with the estimated time remaining
+    pub progress: Option<bool>,
+    /// Whether to show the progress of the build in the tab or taskbar of the terminal and in
+    /// its title
+    pub terminal_progress: Option<bool>,
+    /// How many logs of the last builds are kept for `sm last`, or 0 for none
+    pub keep_logs: Option<usize>,
+    /// The files the build produces, relative to the configuration file, for `sm artifacts`
+    /// with build systems that do not tell
+    pub artifacts: Vec<String>,
+    /// Whether to print a summary of the errors and warnings after the build
+    pub summary: Option<bool>,
+    /// Whether to show a desktop notification when a build finishes
+    pub notify: Option<bool>,
+    /// How long a build has to take for the notification, in seconds
+    pub notify_after: Option<u64>,
+    /// The compiler cache to build with
+    pub cache: Option<CompilerCache>,
+    /// The tool `--distributed` spreads the compilations over a build farm with
+    pub distributed: Option<Distributor>,
+    /// The number of threads to build with when distributed, instead of what the tool reports
+    pub distributed_jobs: Option<usize>,
+    /// The generator CMake projects without a build directory are configured with, instead of
+    /// Ninja, or Makefiles if Ninja is not installed
+    pub cmake_generator: Option<String>,
+    /// The make that builds makefiles, like `gmake` for GNU makefiles on a BSD, instead of
+    /// the one the name of the makefile calls for
+    pub make_program: Option<String>,
+    /// The ninja that builds `build.ninja` files, like `samu` or `n2`, instead of `ninja` or,
+    /// without it, whichever of them is installed
+    pub ninja_program: Option<String>,
+    /// Whether to download what the build needs before building, as `sm fetch` does, so that
+    /// the build itself runs offline
+    pub prefetch: Option<bool>,
+    /// The container image to build in
+    pub container: Option<String>,
+    /// The program that runs the container, instead of podman or docker
+    pub container_runtime: Option<String>,
+    /// Where builds get their environment from: the development shell of a `flake.nix`, an
+    /// `.envrc`, the Python environment of the project, which is the default, or none
+    pub use_environment: Option<Environment>,
+    /// Shell commands run in the project root before each build
+    pub pre_build: Vec<String>,
+    /// Shell commands run in the project root after each build, with its exit code in
+    /// `SMARTMAKE_EXIT_CODE`
+    pub post_build: Vec<String>,
+    /// Whether a failing hook fails the build, instead of only being reported
+    pub fatal_hooks: Option<bool>,
+    /// The memory the build may use, like `8G`, before the kernel stops it
+    pub max_memory: Option<String>,
+    /// The CPU time the build may use, as a number of CPUs or a percentage of one
+    pub cpu_quota: Option<String>,
+    /// The sandbox to build in
+    pub sandbox: Option<Sandbox>,
+    /// Directories a sandboxed build may write to besides the project and its build
+    /// directory, relative to the configuration file or to `~`
+    pub sandbox_writable: Vec<PathBuf>,
+    /// The versions of the programs the build needs, like `>= 3.24` for `cmake`, keyed by
+    /// the program
+    pub requires: BTreeMap<String, String>,
+    /// Settings for each build system, keyed by its name
+    pub backends: HashMap<String, BackendConfig>,
+    /// Build systems defined by the user, keyed by their name
+    pub build_systems: BTreeMap<String, CustomBuildSystem>,
+    /// Code generators run before the build when their inputs changed, keyed by their name
+    pub codegen: BTreeMap<String, CodegenStep>,
+    /// What to do before building when the git branch changed since the last build in the
+    /// build directory, instead of warning
+    pub on_branch_switch: Option<BranchSwitch>,
+    /// Settings for `sm watch`
+    pub watch: WatchConfig,
+    /// Which warnings to leave out of the output
+    pub warnings: WarningsConfig,
+    /// Rules that hide, highlight or rewrite the lines of the output that match them, in order
+    pub output_filters: Vec<OutputFilter>,
+    /// The OTLP/HTTP endpoint to send a trace of each build to, like
+    /// `http://localhost:4318/v1/traces`
+    pub otel_endpoint: Option<String>,
+    /// Where `sm cache` keeps the build outputs between CI runs, and which ones
+    pub ci_cache: CiCacheConfig,
+    /// Settings that `--profile <name>` adds to the build, keyed by that name
+    pub profile: BTreeMap<String, ProfileConfig>,
+    /// How to build for the platforms `--target <name>` names, keyed by that name
+    pub cross: BTreeMap<String, CrossConfig>,
+    /// What `--features <name>` means for build systems other than cargo, keyed by that name
+    pub features: BTreeMap<String, FeatureConfig>,
+    /// The configurations `sm matrix` builds, keyed by their name
+    pub matrix: BTreeMap<String, MatrixEntry>,
+    /// Machines to build on with `--remote <name>`, keyed by that name
+    pub remotes: BTreeMap<String, RemoteConfig>,
+    /// Settings for the projects `sm all` finds, keyed by their directory relative to this
+    /// configuration
+    pub projects: BTreeMap<String, SubprojectConfig>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubprojectConfig {
+    /// The projects that have to be built before this one
+    pub depends: Vec<String>,
+}
+
+/// How to build for another platform
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CrossConfig {
+    /// The build directory for the platform, relative to the configuration file, like a CMake
+    /// build directory configured with a toolchain file
+    pub directory: Option<PathBuf>,
+    /// The CMake toolchain file the build directory is configured with, relative to the
+    /// configuration file, when smartmake configures it
+    pub toolchain_file: Option<PathBuf>,
+    /// Extra arguments passed to the build system
+    pub args: Vec<String>,
+    /// Environment variables set for the build system, like `CROSS_COMPILE` for make
+    pub env: HashMap<String, String>,
+}
+
+/// An optional part of the project, like a Cargo feature, for a build system without them
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureConfig {
+    /// Arguments that turn it on, like `WITH_GUI=1` for make
+    pub args: Vec<String>,
+    /// Environment variables that turn it on
+    pub env: HashMap<String, String>,
+}
+
+/// One configuration of the build matrix, like a debug or a release build
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatrixEntry {
+    /// The build directory of this configuration, relative to the configuration file
+    pub directory: Option<PathBuf>,
+    /// The profile to build with
+    pub profile: Option<String>,
+    /// Extra arguments passed to the build system
+    pub args: Vec<String>,
+    /// Environment variables set for the build system
+    pub env: HashMap<String, String>,
+    /// The targets to build, instead of the configured ones
+    pub targets: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatchConfig {
+    /// Patterns of file and directory names whose changes do not trigger a rebuild
+    pub ignore: Vec<String>,
+    /// How long to wait for changes to settle before rebuilding, in milliseconds
+    pub debounce: Option<u64>,
+    /// Whether to clear the screen before each rebuild
+    pub clear: Option<bool>,
+}
+
+/// What a build does when the git branch changed since the last build in its build directory,
+/// which can leave it with incremental state that does not fit the sources anymore
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BranchSwitch {
+    /// Build as usual
+    Ignore,
+    /// Warn that the branch changed
+    Warn,
+    /// Clean the build directory first
+    Clean,
+    /// Configure a CMake build directory again from scratch first, and clean the others
+    Reconfigure,
+}
+
+/// What an output filter does with the lines that match it
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterAction {
+    /// Leave the line out
+    Hide,
+    /// Show the line in bold color
+    Highlight,
+    /// Replace the matches with `replace`
+    Rewrite,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutputFilter {
+    /// The regular expression the lines are matched against, without their colors
+    #[serde(rename = "match")]
+    pub pattern: String,
+    pub action: FilterAction,
+    /// What `rewrite` replaces the matches with, where `$1` is the first group
+    #[serde(default)]
+    pub replace: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WarningsConfig {
+    /// Whether to show a warning only the first time it is reported for a file and line, like
+    /// when a header is compiled with each file that includes it
+    pub dedupe: Option<bool>,
+    /// Regular expressions for warnings to leave out, matched against the line that reports
+    /// them, location included
+    pub suppress: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CiCacheConfig {
+    /// The directory the archives are kept in, relative to the configuration file or to `~`, or
+    /// an `s3://bucket/prefix` URL
+    pub store: Option<String>,
+    /// The endpoint of an S3-compatible store other than AWS, like MinIO
+    pub endpoint: Option<String>,
+    /// More paths to cache besides the ones of the build systems, relative to the
+    /// configuration file or to `~`
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    /// Arguments always passed to this build system
+    pub args: Vec<String>,
+    /// Environment variables always set for this build system
+    pub env: HashMap<String, String>,
+}
+
+/// A named set of arguments and environment variables for the build, like one for building
+/// with sanitizers, with the ones that only make sense for one build system kept apart
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileConfig {
+    /// The directory to build in, relative to the configuration file, instead of `build/<name>`
+    /// for CMake projects and the detected one for others
+    pub directory: Option<PathBuf>,
+    /// Arguments passed to any build system
+    pub args: Vec<String>,
+    /// Environment variables set for any build system
+    pub env: HashMap<String, String>,
+    /// The memory the build may use, like `8G`, before the kernel stops it
+    pub max_memory: Option<String>,
+    /// The CPU time the build may use, as a number of CPUs or a percentage of one
+    pub cpu_quota: Option<String>,
+    /// Settings for each build system, keyed by its name
+    pub backends: HashMap<String, BackendConfig>,
+}
+
+impl ProfileConfig {
+    /// The arguments for `build_system`: the general ones followed by its own
+    pub fn args_for(&self, build_system: &BuildSystem) -> Vec<String> {
+        let mut args = self.args.clone();
+        if let Some(backend) = self.backends.get(build_system.name()) {
+            args.extend(backend.args.iter().cloned());
+        }
+        args
+    }
+
+    /// The environment variables for `build_system`, where its own win over the general ones
+    pub fn env_for(&self, build_system: &BuildSystem) -> HashMap<String, String> {
+        let mut env = self.env.clone();
+        if let Some(backend) = self.backends.get(build_system.name()) {
+            env.extend(backend.env.clone());
+        }
+        env
+    }
+}
+
+/// A build system that smartmake does not know about. The commands are templates where
+/// `{threads}`, `{dir}`, `{target}`, `{profile}` and `{cross_target}` are filled in before running
+/// them.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CustomBuildSystem {
+    /// File names, or glob patterns of them, that mark a project using this build system
+    pub markers: Vec<String>,
+    /// The command that builds the project
+    pub build: String,
+    /// The command that runs the tests
+    pub test: Option<String>,
+    /// The command that removes the build outputs
+    pub clean: Option<String>,
+    /// The command that only downloads what the build needs, like `go mod download` or
+    /// `npm ci --ignore-scripts`
+    pub fetch: Option<String>,
+}
+
+/// A code generator, like `protoc` for the `.proto` files of the project
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CodegenStep {
+    /// The shell command that generates the code, run in the project root
+    pub command: String,
+    /// Glob patterns of the files it reads, relative to the configuration file, where `**`
+    /// matches any number of directories
+    pub inputs: Vec<String>,
+    /// Glob patterns of the files it writes; it runs again when one of them matches nothing
+    #[serde(default)]
+    pub outputs: Vec<String>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config> {
+        let path = path.as_ref();
+        toml::from_str(&read_to_string(path)?).map_err(|error| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), error),
+            )
+        })
+    }
+
+    /// The user configuration in `$XDG_CONFIG_HOME`, falling back to `~/.config`
+    pub fn global_path() -> Option<PathBuf> {
+        var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .map(|dir| dir.join(GLOBAL_CONFIG))
+    }
+
+    pub fn load_global() -> Result<Config> {
+        match Config::global_path() {
+            Some(path) if exists(&path)? => {
+                crate::debug!("config", "User configuration {}", path.display());
+                Config::load(path)
+            }
+            _ => Ok(Config::default()),
+        }
+    }
+
+    /// Load the project configuration for `path` layered over the user configuration,
+    /// together with the project directory if there is one
+    pub fn resolve<P: AsRef<Path>>(path: P) -> Result<(Config, Option<PathBuf>)> {
+        let global = Config::load_global()?;
+        Ok(match Config::find(path)? {
+            Some((project, dir)) => (project.merge(global), Some(dir)),
+            None => (global, None),
+        })
+    }
+
+    /// Layer this configuration over `defaults`: settings present here win, extra arguments
+    /// and environment variables are combined. `root`, `build_system` and `directory` are not
+    /// taken from `defaults`, as they describe the project of this file only.
+    pub fn merge(self, defaults: Config) -> Config {
+        let mut env = defaults.env;
+        env.extend(self.env);
+        let mut backends = defaults.backends;
+        for (name, backend) in self.backends {
+            let merged = backends.entry(name).or_default();
+            merged.args.extend(backend.args);
+            merged.env.extend(backend.env);
+        }
+        let mut requires = defaults.requires;
+        requires.extend(self.requires);
+        let mut build_systems = defaults.build_systems;
+        build_systems.extend(self.build_systems);
+        let mut codegen = defaults.codegen;
+        codegen.extend(self.codegen);
+        let mut profile = defaults.profile;
+        profile.extend(self.profile);
+        let mut cross = defaults.cross;
+        cross.extend(self.cross);
+        let mut features = defaults.features;
+        features.extend(self.features);
+        let mut matrix = defaults.matrix;
+        matrix.extend(self.matrix);
+        let mut remotes = defaults.remotes;
+        remotes.extend(self.remotes);
+        let mut projects = defaults.projects;
+        projects.extend(self.projects);
+        Config {
+            root: self.root,
+            build_system: self.build_system,
+            directory: self.directory,
+            prefer: if self.prefer.is_empty() {
+                defaults.prefer
+            } else {
+                self.prefer
+            },
+            enable: if self.enable.is_empty() {
+                defaults.enable
+            } else {
+                self.enable
+            },
+            disable: if self.disable.is_empty() {
+                defaults.disable
+            } else {
+                self.disable
+            },
+            polyglot: if self.polyglot.is_empty() {
+                defaults.polyglot
+            } else {
+                self.polyglot
+            },
+            build_dirs: if self.build_dirs.is_empty() {
+                defaults.build_dirs
+            } else {
+                self.build_dirs
+            },
+            makefiles: if self.makefiles.is_empty() {
+                defaults.makefiles
+            } else {
+                self.makefiles
+            },
+            follow_symlinks: self.follow_symlinks.or(defaults.follow_symlinks),
+            targets: if self.targets.is_empty() {
+                defaults.targets
+            } else {
+                self.targets
+            },
+            args: [defaults.args, self.args].concat(),
+            env,
+            jobs: self.jobs.or(defaults.jobs),
+            share_jobs: self.share_jobs.or(defaults.share_jobs),
+            progress: self.progress.or(defaults.progress),
+            terminal_progress: self.terminal_progress.or(defaults.terminal_progress),
+            keep_logs: self.keep_logs.or(defaults.keep_logs),
+            artifacts: if self.artifacts.is_empty() {
+                defaults.artifacts
+            } else {
+                self.artifacts
+            },
+            summary: self.summary.or(defaults.summary),
+            on_branch_switch: self.on_branch_switch.or(defaults.on_branch_switch),
+            notify: self.notify.or(defaults.notify),
+            notify_after: self.notify_after.or(defaults.notify_after),
+            cache: self.cache.or(defaults.cache),
+            distributed: self.distributed.or(defaults.distributed),
+            distributed_jobs: self.distributed_jobs.or(defaults.distributed_jobs),
+            cmake_generator: self.cmake_generator.or(defaults.cmake_generator),
+            make_program: self.make_program.or(defaults.make_program),
+            ninja_program: self.ninja_program.or(defaults.ninja_program),
+            prefetch: self.prefetch.or(defaults.prefetch),
+            container: self.container.or(defaults.container),
+            container_runtime: self.container_runtime.or(defaults.container_runtime),
+            use_environment: self.use_environment.or(defaults.use_environment),
+            max_memory: self.max_memory.or(defaults.max_memory),
+            cpu_quota: self.cpu_quota.or(defaults.cpu_quota),
+            sandbox: self.sandbox.or(defaults.sandbox),
+            sandbox_writable: [defaults.sandbox_writable, self.sandbox_writable].concat(),
+            pre_build: [defaults.pre_build, self.pre_build].concat(),
+            post_build: [defaults.post_build, self.post_build].concat(),
+            fatal_hooks: self.fatal_hooks.or(defaults.fatal_hooks),
+            requires,
+            backends,
+            build_systems,
+            codegen,
+            watch: WatchConfig {
+                ignore: [defaults.watch.ignore, self.watch.ignore].concat(),
+                debounce: self.watch.debounce.or(defaults.watch.debounce),
+                clear: self.watch.clear.or(defaults.watch.clear),
+            },
+            warnings: WarningsConfig {
+                dedupe: self.warnings.dedupe.or(defaults.warnings.dedupe),
+                suppress: [defaults.warnings.suppress, self.warnings.suppress].concat(),
+            },
+            output_filters: [defaults.output_filters, self.output_filters].concat(),
+            otel_endpoint: self.otel_endpoint.or(defaults.otel_endpoint),
+            ci_cache: CiCacheConfig {
+                store: self.ci_cache.store.or(defaults.ci_cache.store),
+                endpoint: self.ci_cache.endpoint.or(defaults.ci_cache.endpoint),
+                paths: [defaults.ci_cache.paths, self.ci_cache.paths].concat(),
+            },
+            profile,
+            cross,
+            features,
+            matrix,
+            remotes,
+            projects,
+        }
+    }
+
+    /// The extra arguments for `build_system`: its per-backend arguments followed by the general ones
+    pub fn args_for(&self, build_system: &BuildSystem) -> Vec<String> {
+        let mut args = self
+            .backends
+            .get(build_system.name())
+            .map(|backend| backend.args.clone())
+            .unwrap_or_default();
+        args.extend(self.args.iter().cloned());
+        args
+    }
+
+    /// Whether detection may choose `build_system`: it is enabled, or none are, and not
+    /// disabled
+    pub fn allows(&self, build_system: &BuildSystem) -> bool {
+        let name = build_system.name();
+        (self.enable.is_empty() || self.enable.iter().any(|enabled| enabled == name))
+            && !self.disable.iter().any(|disabled| disabled == name)
+    }
+
+    /// The environment variables for `build_system`: the general ones, overridden by its
+    /// per-backend ones
+    pub fn env_for(&self, build_system: &BuildSystem) -> HashMap<String, String> {
+        let mut env = self.env.clone();
+        if let Some(backend) = self.backends.get(build_system.name()) {
+            env.extend(backend.env.clone());
+        }
+        env
+    }
+
+    /// Find the nearest project configuration in `path` or one of its ancestors, layered over
+    /// the ones further up as in [`Config::find_all`], returning it together with the directory
+    /// it was found in
+    pub fn find<P: AsRef<Path>>(path: P) -> Result<Option<(Config, PathBuf)>> {
+        let mut files = Config::find_all(path)?.into_iter();
+        let Some((mut config, dir)) = files.next() else {
+            return Ok(None);
+        };
+        for (outer, outer_dir) in files {
+            config = config.merge(outer.rebase(&outer_dir));
+        }
+        Ok(Some((config, dir)))
+    }
+
+    /// Every configuration file that applies to `path` with its directory, the nearest first:
+    /// the ones in it and in each of its parents, up to one that sets `root = true`
+    pub fn find_all<P: AsRef<Path>>(path: P) -> Result<Vec<(Config, PathBuf)>> {
+        let mut dir = path.as_ref().to_path_buf();
+        let mut found = Vec::new();
+        loop {
+            let file = dir.join(PROJECT_CONFIG);
+            // Past directories that cannot be read, which `sm explain` tells about
+            let exists = exists(&file).unwrap_or_else(|error| {
+                crate::debug!("config", "Passing over {}: {}", dir.display(), error);
+                false
+            });
+            if exists {
+                crate::debug!("config", "Layering {}", file.display());
+                let config = Config::load(file)?;
+                let root = config.root;
+                found.push((config, dir.clone()));
+                if root {
+                    return Ok(found);
+                }
+            }
+            if !dir.pop() || above_ceiling(&dir) {
+                return Ok(found);
+            }
+        }
+    }
+
+    /// The configuration files that apply to `path`, in the order they are layered: the user
+    /// configuration, and then the project ones from the furthest to the nearest. A file that
+    /// does not parse is taken not to set `root`, for [`Config::check`] to tell about.
+    pub fn files<P: AsRef<Path>>(path: P) -> Vec<PathBuf> {
+        let mut dir = path.as_ref().to_path_buf();
+        let mut files = Vec::new();
+        loop {
+            let file = dir.join(PROJECT_CONFIG);
+            if exists(&file).unwrap_or(false) {
+                let root = Config::load(&file).is_ok_and(|config| config.root);
+                files.push(file);
+                if root {
+                    break;
+                }
+            }
+            if !dir.pop() || above_ceiling(&dir) {
+                break;
+            }
+        }
+        if let Some(global) = Config::global_path().filter(|path| exists(path).unwrap_or(false)) {
+            files.push(global);
+        }
+        files.reverse();
+        files
+    }
+
+    /// The problems with the configuration file at `path`: what does not parse or is not a
+    /// setting, and values smartmake cannot use, like a regular expression that does not
+    /// compile or a build system it does not know. `build_systems` are the ones the other
+    /// files that apply define.
+    pub fn check(path: &Path, build_systems: &[String]) -> Result<Vec<Problem>> {
+        let text = read_to_string(path)?;
+        let problem = |position, message| Problem {
+            path: path.to_path_buf(),
+            position,
+            message,
+        };
+        let config: Config = match toml::from_str(&text) {
+            Ok(config) => config,
+            Err(error) => {
+                let position = error.span().map(|span| position(&text, span.start));
+                return Ok(vec![problem(position, error.message().to_string())]);
+            }
+        };
+        let mut problems: Vec<Problem> = config
+            .problems(build_systems)
+            .into_iter()
+            .map(|(value, message)| problem(locate(&text, &value), message))
+            .collect();
+        problems.sort_by_key(|problem| problem.position);
+        Ok(problems)
+    }
+
+    // The values of this configuration smartmake cannot use, each with what is wrong with it
+    fn problems(&self, build_systems: &[String]) -> Vec<(String, String)> {
+        let known = |name: &str| {
+            !matches!(BuildSystem::from(name.to_string()), BuildSystem::Custom(_))
+                || self.build_systems.contains_key(name)
+                || build_systems.iter().any(|other| other == name)
+                || plugin::find(name).is_some()
+        };
+        let mut problems = Vec::new();
+        let names = self
+            .build_system
+            .iter()
+            .map(|build_system| build_system.name())
+            .chain(self.prefer.iter().map(String::as_str))
+            .chain(self.enable.iter().map(String::as_str))
+            .chain(self.disable.iter().map(String::as_str))
+            .chain(self.polyglot.iter().map(String::as_str))
+            .chain(self.backends.keys().map(String::as_str));
+        for name in names {
+            if !known(name) {
+                problems.push((
+                    name.to_string(),
+                    format!(
+                        "There is no build system called {}; one can be defined under \
+                         [build_systems.{}]",
+                        name, name
+                    ),
+                ));
+            }
+        }
+        let patterns = self
+            .output_filters
+            .iter()
+            .map(|filter| &filter.pattern)
+            .chain(&self.warnings.suppress);
+        for pattern in patterns {
+            if let Err(error) = Regex::new(pattern) {
+                // Without the drawing of where in the expression, which takes several lines
+                let error = error.to_string();
+                let error = error.lines().last().unwrap_or_default();
+                let error = error.strip_prefix("error: ").unwrap_or(error);
+                problems.push((
+                    pattern.clone(),
+                    format!("Invalid regular expression {}: {}", pattern, error),
+                ));
+            }
+        }
+        let limits = once((&self.max_memory, &self.cpu_quota)).chain(
+            self.profile
+                .values()
+                .map(|profile| (&profile.max_memory, &profile.cpu_quota)),
+        );
+        for (max_memory, cpu_quota) in limits {
+            if let Some(size) = max_memory
+                .as_ref()
+                .filter(|size| !limits::is_memory_size(size))
+            {
+                problems.push((
+                    size.clone(),
+                    format!("Invalid memory size {}, which is like 8G or 50%", size),
+                ));
+            }
+            if let Some(quota) = cpu_quota
+                .as_ref()
+                .filter(|quota| limits::cpu_percent(quota).is_none())
+            {
+                problems.push((
+                    quota.clone(),
+                    format!(
+                        "Invalid CPU quota {}, which is a number of CPUs like 2 or a \
+                         percentage of one like 250%",
+                        quota
+                    ),
+                ));
+            }
+        }
+        for (program, requirement) in &self.requires {
+            if toolchain::satisfies("0", requirement).is_err() {
+                problems.push((
+                    requirement.clone(),
+                    format!(
+                        "Invalid version requirement {} for {}, which is like >= 3.24",
+                        requirement, program
+                    ),
+                ));
+            }
+        }
+        problems
+    }
+
+    /// This configuration as TOML, without the settings that are left empty
+    pub fn to_toml(&self) -> Result<String> {
+        let mut value = toml::Value::try_from(self).map_err(Error::other)?;
+        prune(&mut value);
+        toml::to_string_pretty(&value).map_err(Error::other)
+    }
+
+    // This configuration from a file in `dir` with its paths made absolute, so that they keep
+    // pointing at the same files beneath the configuration of a project further down
+    fn rebase(mut self, dir: &Path) -> Config {
+        let rebase = |path: &Path| match path.starts_with("~") {
+            true => path.to_path_buf(),
+            false => dir.join(path),
+        };
+        let rebase_name = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        self.artifacts = self
+            .artifacts
+            .iter()
+            .map(|path| rebase_name(path))
+            .collect();
+        self.sandbox_writable = self
+            .sandbox_writable
+            .iter()
+            .map(|path| rebase(path))
+            .collect();
+        self.ci_cache.paths = self
+            .ci_cache
+            .paths
+            .iter()
+            .map(|path| rebase(path))
+            .collect();
+        self.ci_cache.store = self
+            .ci_cache
+            .store
+            .map(|store| match store.starts_with("s3://") {
+                true => store,
+                false => rebase(Path::new(&store)).to_string_lossy().into_owned(),
+            });
+        for profile in self.profile.values_mut() {
+            profile.directory = profile.directory.as_deref().map(rebase);
+        }
+        for cross in self.cross.values_mut() {
+            cross.directory = cross.directory.as_deref().map(rebase);
+            cross.toolchain_file = cross.toolchain_file.as_deref().map(rebase);
+        }
+        for entry in self.matrix.values_mut() {
+            entry.directory = entry.directory.as_deref().map(rebase);
+        }
+        for step in self.codegen.values_mut() {
+            step.inputs = step.inputs.iter().map(|path| rebase_name(path)).collect();
+            step.outputs = step.outputs.iter().map(|path| rebase_name(path)).collect();
+        }
+        self.projects = self
+            .projects
+            .into_iter()
+            .map(|(name, mut settings)| {
+                settings.depends = settings
+                    .depends
+                    .iter()
+                    .map(|name| rebase_name(name))
+                    .collect();
+                (rebase_name(&name), settings)
+            })
+            .collect();
+        self
+    }
+}
+
+/// Something wrong with a configuration file, and where in it when that is known
+#[derive(Debug)]
+pub struct Problem {
+    pub path: PathBuf,
+    /// The line and column, counting from 1
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl Display for Problem {
+    // Like a compiler tells about an error, for editors to jump to
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(
+                f,
+                "{}:{}:{}: {}",
+                self.path.display(),
+                line,
+                column,
+                self.message
+            ),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+// Leave the empty lists and tables out of `value`, the settings that were not given
+fn prune(value: &mut toml::Value) {
+    if let toml::Value::Table(table) = value {
+        for (_, value) in table.iter_mut() {
+            prune(value);
+        }
+        table.retain(|_, value| match value {
+            toml::Value::Array(array) => !array.is_empty(),
+            toml::Value::Table(table) => !table.is_empty(),
+            _ => true,
+        });
+    }
+}
+
+// The line and column of the byte at `offset` in `text`
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, column)
+}
+
+// Where `value` is written in `text`, as a string, for a setting that parsed but cannot be
+// used. One with escapes in it is written otherwise, and not found.
+fn locate(text: &str, value: &str) -> Option<(usize, usize)> {
+    [format!("\"{}\"", value), format!("'{}'", value)]
+        .iter()
+        .filter_map(|quoted| text.find(quoted.as_str()))
+        .min()
+        .map(|offset| position(text, offset + 1))
+}
@@ -0,0 +1,57 @@
+use std::{io::Result, path::Path};
+
+use crate::{
+    exec::{self, find_program},
+    CommandPlan,
+};
+
+/// The container runtimes smartmake can run builds with, in the order they are looked for
+const RUNTIMES: &[&str] = &["podman", "docker"];
+
+/// The container runtime to use: `configured` if given, or else the first one on PATH
+pub fn runtime(configured: Option<&str>) -> Result<String> {
+    if let Some(runtime) = configured {
+        return Ok(runtime.to_string());
+    }
+    RUNTIMES
+        .iter()
+        .find(|runtime| find_program(runtime).is_some())
+        .map(|runtime| runtime.to_string())
+        .ok_or_else(|| exec::missing_tool("Building in a container needs podman or docker on PATH"))
+}
+
+/// Run `plan` in a container of `image` instead, with `root` mounted at the same path so the
+/// paths in the command stay valid, as the current user so the build outputs belong to them
+pub fn wrap(plan: CommandPlan, runtime: &str, image: &str, root: &Path, cwd: &Path) -> CommandPlan {
+    let mut command = CommandPlan::new(runtime);
+    command.arg("run").arg("--rm").arg("--init");
+    // Without `-it`: the build runs with no input and its output captured, which docker and
+    // podman refuse to give a terminal to, and they pass Ctrl-C on to `--init` either way
+    let mount = root.to_string_lossy();
+    command.arg("--volume").arg(format!("{}:{}", mount, mount));
+    command
+        .arg("--workdir")
+        .arg(plan.cwd.as_deref().unwrap_or(cwd));
+    // Rootless podman maps the current user into the container itself
+    if Path::new(runtime)
+        .file_name()
+        .is_some_and(|name| name == "podman")
+    {
+        command.arg("--userns=keep-id");
+    } else {
+        // Docker on Windows keeps its containers in a virtual machine, without the users of the
+        // host to run as
+        #[cfg(unix)]
+        {
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            command.arg("--user").arg(format!("{}:{}", uid, gid));
+        }
+    }
+    let mut env = plan.env.iter().collect::<Vec<_>>();
+    env.sort();
+    for (key, value) in env {
+        command.arg("--env").arg(format!("{}={}", key, value));
+    }
+    command.arg(image).arg(plan.program).args(plan.args);
+    command
+}
@@ -0,0 +1,338 @@
+// Windows has no Unix domain sockets for the daemon to listen on
+#![cfg_attr(not(unix), allow(dead_code, unused_imports))]
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::{
+    collections::HashMap,
+    env::{temp_dir, var_os, vars_os},
+    fs::{metadata, remove_file},
+    io::{stderr, stdout, BufRead, BufReader, Error, ErrorKind, Result, Write},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Sender},
+        Arc,
+    },
+    thread,
+    time::{Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codegen,
+    config::Config,
+    detect::explain_build_dir,
+    exec::{self, Event, Execution, Stream},
+    history,
+    hooks::{self, Stage},
+    lock::{BuildLock, JobShare},
+    project::{Invocation, Project},
+    Verb,
+};
+
+/// A build request sent by a client, one JSON object per line
+#[derive(Deserialize, Serialize)]
+struct Request {
+    cwd: PathBuf,
+    /// The environment of the client, which the build runs in
+    env: Vec<(String, String)>,
+    invocation: Invocation,
+}
+
+/// What the daemon sends back while handling a request, one JSON object per line
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Reply {
+    /// The number of builds that have to finish before this one starts
+    Queued(usize),
+    /// The command that is being run
+    Started(String),
+    /// A line of output, without its line ending
+    Stdout(String),
+    Stderr(String),
+    /// The request could not be carried out, and the client exits with `code`, like smartmake
+    /// itself would have for the same error
+    Error {
+        message: String,
+        code: i32,
+    },
+    /// The build finished with this exit code
+    Exit(i32),
+}
+
+struct Job {
+    request: Request,
+    replies: Sender<Reply>,
+}
+
+/// The socket the daemon listens on: `$SMARTMAKE_SOCKET`, or `smartmake.sock` in
+/// `$XDG_RUNTIME_DIR` or the temporary directory
+pub fn default_socket() -> PathBuf {
+    if let Some(socket) = var_os("SMARTMAKE_SOCKET") {
+        return PathBuf::from(socket);
+    }
+    match var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("smartmake.sock"),
+        // The user id keeps the sockets of different users in a shared directory apart
+        None => temp_dir().join(format!("smartmake-{}.sock", exec::user_id())),
+    }
+}
+
+/// The exit code a shell would report for `status`
+pub fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .or(exec::signal(&status).map(|signal| 128 + signal))
+        .unwrap_or(1)
+}
+
+fn send<W: Write>(writer: &mut W, reply: &Reply) -> Result<()> {
+    let mut line = serde_json::to_vec(reply).map_err(Error::other)?;
+    line.push(b'\n');
+    writer.write_all(&line)
+}
+
+// The modification times that cached detection results depend on: every directory the
+// search for the build system looked in, the one it chose, the directories the configuration
+// was looked up through and the configuration files
+#[derive(PartialEq)]
+struct Fingerprint(Vec<Option<SystemTime>>);
+
+// The paths whose modification times make up the fingerprint of `project`, which creating,
+// removing or renaming a marker file, a build directory or a configuration file changes
+fn watched(project: &Project) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = explain_build_dir(&project.cwd, &project.config)
+        .map(|(steps, _)| steps.into_iter().map(|step| step.directory).collect())
+        .unwrap_or_default();
+    if let Some(detection) = &project.detection {
+        paths.push(detection.directory.clone());
+        paths.extend(detection.source.clone());
+    }
+    paths.extend(project.cwd.ancestors().map(Path::to_path_buf));
+    paths.extend(Config::files(&project.cwd));
+    paths.extend(Config::global_path());
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+impl Fingerprint {
+    fn of(paths: &[PathBuf]) -> Fingerprint {
+        Fingerprint(
+            paths
+                .iter()
+                .map(|path| metadata(path).and_then(|metadata| metadata.modified()).ok())
+                .collect(),
+        )
+    }
+}
+
+// Detection results by directory, kept until a directory or configuration they depend on
+// changes
+#[derive(Default)]
+struct Cache(HashMap<PathBuf, (Vec<PathBuf>, Fingerprint, Project)>);
+
+impl Cache {
+    fn project(&mut self, cwd: &Path) -> Result<&Project> {
+        let fresh = match self.0.get(cwd) {
+            Some((paths, fingerprint, _)) => *fingerprint == Fingerprint::of(paths),
+            None => false,
+        };
+        if !fresh {
+            let project = Project::find(cwd)?;
+            let paths = watched(&project);
+            let fingerprint = Fingerprint::of(&paths);
+            self.0
+                .insert(cwd.to_path_buf(), (paths, fingerprint, project));
+        }
+        Ok(&self.0[cwd].2)
+    }
+}
+
+// Carry out `request` as smartmake would have in the client: with the build directory locked
+// against other builds, and the hooks and code generators run around the build
+fn build(cache: &mut Cache, request: Request, replies: &Sender<Reply>) -> Result<ExitStatus> {
+    let project = cache.project(&request.cwd)?;
+    let invocation = &request.invocation;
+    let Some(plan) = invocation.plan(project)? else {
+        return Err(exec::no_build_system("No build system found"));
+    };
+    let _lock = match invocation.detection(project)? {
+        Some(detection) => Some(BuildLock::acquire(&detection.directory, true)?),
+        None => None,
+    };
+    let _share = JobShare::register(invocation.threads(project)?)?;
+    let hooked = invocation.verb != Verb::Clean;
+    if hooked {
+        hooks::run(project, Stage::PreBuild, None)?;
+        codegen::run(project)?;
+    }
+    invocation.configure(project)?;
+    let _ = replies.send(Reply::Started(plan.to_string()));
+    let mut command = plan.command();
+    command.env_clear().envs(request.env).envs(&plan.env);
+    command.current_dir(plan.cwd.as_ref().unwrap_or(&request.cwd));
+    let started = SystemTime::now();
+    let timer = Instant::now();
+    let execution = Execution::spawn(command)?;
+    for event in execution.events() {
+        match event {
+            // Sent as JSON, which only has text
+            Event::Line(Stream::Stdout, line) => {
+                let _ = replies.send(Reply::Stdout(exec::text(&line).into_owned()));
+            }
+            Event::Line(Stream::Stderr, line) => {
+                let _ = replies.send(Reply::Stderr(exec::text(&line).into_owned()));
+            }
+            // Only builds on a pseudo-terminal have a status
+            Event::Status(_) => {}
+            Event::Finished(status) => {
+                history::record(project, invocation, started, timer.elapsed(), status);
+                if hooked {
+                    hooks::run(project, Stage::PostBuild, Some(exit_code(status)))?;
+                }
+                return Ok(status);
+            }
+        }
+    }
+    Err(Error::other("The build stopped without an exit status"))
+}
+
+#[cfg(unix)]
+fn handle(stream: UnixStream, jobs: Sender<Job>, pending: Arc<AtomicUsize>) -> Result<()> {
+    let mut line = String::new();
+    // Connections that only check whether a daemon is listening send nothing
+    if BufReader::new(&stream).read_line(&mut line)? == 0 {
+        return Ok(());
+    }
+    let request: Request = serde_json::from_str(&line).map_err(Error::other)?;
+    let mut writer = &stream;
+    send(
+        &mut writer,
+        &Reply::Queued(pending.fetch_add(1, Ordering::SeqCst)),
+    )?;
+    let (replies, received) = channel();
+    jobs.send(Job { request, replies }).map_err(Error::other)?;
+    for reply in received {
+        // A client that went away does not stop its build
+        let _ = send(&mut writer, &reply);
+    }
+    Ok(())
+}
+
+/// Listen on `socket` for build requests and run them one at a time, in the order they
+/// arrived
+#[cfg(unix)]
+pub fn serve<P: AsRef<Path>>(socket: P) -> Result<()> {
+    let socket = socket.as_ref();
+    if UnixStream::connect(socket).is_ok() {
+        return Err(Error::new(
+            ErrorKind::AddrInUse,
+            format!("A daemon is already listening on {}", socket.display()),
+        ));
+    }
+    // The socket of a daemon that is no longer running
+    let _ = remove_file(socket);
+    let listener = UnixListener::bind(socket)?;
+    let pending = Arc::new(AtomicUsize::new(0));
+    let (jobs, queue) = channel::<Job>();
+    let worker_pending = pending.clone();
+    thread::spawn(move || {
+        let mut cache = Cache::default();
+        for job in queue {
+            // What configuring and the hooks print goes to the client as well
+            let replies = job.replies.clone();
+            exec::redirect(Some(Box::new(move |stream, line| {
+                let line = exec::text(line).into_owned();
+                let _ = replies.send(match stream {
+                    Stream::Stdout => Reply::Stdout(line),
+                    Stream::Stderr => Reply::Stderr(line),
+                });
+            })));
+            let reply = match build(&mut cache, job.request, &job.replies) {
+                Ok(status) => Reply::Exit(exit_code(status)),
+                Err(error) => Reply::Error {
+                    message: error.to_string(),
+                    code: exec::error_exit_code(&error),
+                },
+            };
+            exec::redirect(None);
+            let _ = job.replies.send(reply);
+            worker_pending.fetch_sub(1, Ordering::SeqCst);
+        }
+    });
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let jobs = jobs.clone();
+        let pending = pending.clone();
+        thread::spawn(move || {
+            if let Err(error) = handle(stream, jobs, pending) {
+                eprintln!("{}", error);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Ask the daemon on `socket` to carry out `invocation` in `cwd`, showing its output as it
+/// arrives, and return the exit code of the build
+#[cfg(unix)]
+pub fn request<P: AsRef<Path>>(socket: P, cwd: PathBuf, invocation: Invocation) -> Result<i32> {
+    let socket = socket.as_ref();
+    let mut stream = UnixStream::connect(socket).map_err(|error| {
+        Error::new(
+            error.kind(),
+            format!("Cannot reach the daemon on {}: {}", socket.display(), error),
+        )
+    })?;
+    let env = vars_os()
+        .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+        .collect();
+    let mut line = serde_json::to_vec(&Request {
+        cwd,
+        env,
+        invocation,
+    })
+    .map_err(Error::other)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    for line in BufReader::new(stream).lines() {
+        match serde_json::from_str(&line?).map_err(Error::other)? {
+            Reply::Queued(0) | Reply::Started(_) => {}
+            Reply::Queued(1) => eprintln!("Waiting for another build to finish"),
+            Reply::Queued(ahead) => eprintln!("Waiting for {} other builds to finish", ahead),
+            Reply::Stdout(line) => writeln!(stdout(), "{}", line)?,
+            Reply::Stderr(line) => writeln!(stderr(), "{}", line)?,
+            Reply::Error { message, code } => {
+                eprintln!("{}", message);
+                return Ok(code);
+            }
+            Reply::Exit(code) => return Ok(code),
+        }
+    }
+    Err(Error::new(
+        ErrorKind::UnexpectedEof,
+        "The daemon closed the connection",
+    ))
+}
+
+#[cfg(not(unix))]
+pub fn serve<P: AsRef<Path>>(_socket: P) -> Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+pub fn request<P: AsRef<Path>>(_socket: P, _cwd: PathBuf, _invocation: Invocation) -> Result<i32> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        "The daemon needs the domain sockets of Unix",
+    )
+}
@@ -0,0 +1,101 @@
+use std::{
+    fmt::Arguments,
+    fs::{File, OpenOptions},
+    io::{stderr, Result, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Instant,
+};
+
+/// How much `-v` tells about what smartmake does
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    /// The decisions it takes, the commands it runs and how long each phase took, with `-v`
+    Debug = 1,
+    /// Every directory and file it looked at, and the environment of the build, with `-vv`
+    Trace = 2,
+}
+
+// The level that is logged up to, or 0 for nothing
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+
+// The file the log goes to instead of standard error
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+
+// When logging started, which the times of the messages count from
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// Log the messages up to `verbosity`, the number of `-v` given, to standard error or
+/// appended to `file`. A file alone logs at the debug level.
+pub fn init(verbosity: u8, file: Option<&Path>) -> Result<()> {
+    START.get_or_init(Instant::now);
+    if let Some(path) = file {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        *FILE.lock().unwrap_or_else(|error| error.into_inner()) = Some(file);
+    }
+    let verbosity = match (verbosity, file) {
+        (0, Some(_)) => Level::Debug as u8,
+        (verbosity, _) => verbosity,
+    };
+    LEVEL.store(verbosity, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Whether messages of `level` are logged
+pub fn enabled(level: Level) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level as u8
+}
+
+/// Log `message` about `area`, like `detect`, with the time since logging started. The
+/// [`debug!`](crate::debug!) and [`trace!`](crate::trace!) macros only format it when it is
+/// logged.
+pub fn write(level: Level, area: &str, message: Arguments) {
+    let elapsed = START.get().map(Instant::elapsed).unwrap_or_default();
+    let level = match level {
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    };
+    let line = format!(
+        "[{:8.3}s {} {}] {}\n",
+        elapsed.as_secs_f64(),
+        level,
+        area,
+        message
+    );
+    // Logging never fails what is logged
+    match FILE
+        .lock()
+        .unwrap_or_else(|error| error.into_inner())
+        .as_mut()
+    {
+        Some(file) => {
+            let _ = file.write_all(line.as_bytes());
+        }
+        None => {
+            let _ = stderr().write_all(line.as_bytes());
+        }
+    }
+}
+
+/// Log a decision, a command or a timing about an area with `-v`, formatted like `format!`
+#[macro_export]
+macro_rules! debug {
+    ($area:expr, $($arg:tt)*) => {
+        if $crate::debug::enabled($crate::debug::Level::Debug) {
+            $crate::debug::write($crate::debug::Level::Debug, $area, format_args!($($arg)*));
+        }
+    };
+}
+
+/// Log a detail about an area with `-vv`, formatted like `format!`
+#[macro_export]
+macro_rules! trace {
+    ($area:expr, $($arg:tt)*) => {
+        if $crate::debug::enabled($crate::debug::Level::Trace) {
+            $crate::debug::write($crate::debug::Level::Trace, $area, format_args!($($arg)*));
+        }
+    };
+}
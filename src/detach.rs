@@ -0,0 +1,213 @@
+use std::{
+    env::current_exe,
+    ffi::OsString,
+    fs::{create_dir_all, read, read_dir, remove_file, File},
+    io::{stdout, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{daemon::exit_code, exec, logs, state};
+
+/// How many finished background builds are remembered per project
+const KEEP: usize = 10;
+
+/// A build started with `--detach`, as `sm status` shows it
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Detached {
+    /// The process id of the `sm` that waits for the build, by which it is attached to
+    pub id: u32,
+    /// The arguments of the `sm` that builds
+    pub args: Vec<String>,
+    pub cwd: PathBuf,
+    /// When it started, in seconds since the epoch
+    pub started: u64,
+    /// The file its output goes to
+    pub output: PathBuf,
+    /// How it exited, once it did
+    pub exit_code: Option<i32>,
+}
+
+impl Detached {
+    /// Whether it is still building: it did not record an exit code, and the `sm` waiting for
+    /// it still runs, which it would not after a crash or a reboot
+    pub fn running(&self) -> bool {
+        self.exit_code.is_none() && exec::is_running(self.id)
+    }
+
+    /// The last line of its output so far, like the progress ninja and cargo show
+    pub fn last_line(&self) -> Option<String> {
+        let output = read(&self.output).ok()?;
+        let text = String::from_utf8_lossy(&output);
+        text.lines()
+            .rev()
+            .flat_map(|line| line.rsplit('\r'))
+            .find(|line| !line.trim().is_empty())
+            .map(str::to_string)
+    }
+}
+
+// The directory the background builds of the project in `root` keep their state and output
+// in, next to its logs
+fn dir(root: &Path) -> Result<PathBuf> {
+    logs::dir(root)
+        .map(|dir| dir.join("detached"))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                "There is no home directory to keep the output of background builds in",
+            )
+        })
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// The background builds of the project in `root`, oldest first
+pub fn list(root: &Path) -> Result<Vec<Detached>> {
+    let entries = match read_dir(dir(root)?) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+    let mut builds = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "json")
+        {
+            // One that is being written is read again next time
+            if let Some(detached) = read(&path)
+                .ok()
+                .and_then(|contents| serde_json::from_slice::<Detached>(&contents).ok())
+            {
+                builds.push(detached);
+            }
+        }
+    }
+    builds.sort_by_key(|detached| (detached.started, detached.id));
+    Ok(builds)
+}
+
+/// Start `sm` with `args` in `cwd` in the background, in a session of its own without a
+/// terminal and with its output going to a file next to the logs of the project in `root`,
+/// and return it once it started
+pub fn start(root: &Path, cwd: &Path, args: Vec<OsString>) -> Result<Detached> {
+    let dir = dir(root)?;
+    create_dir_all(&dir)?;
+    let finished: Vec<Detached> = list(root)?
+        .into_iter()
+        .filter(|detached| !detached.running())
+        .collect();
+    for old in &finished[..finished.len().saturating_sub(KEEP - 1)] {
+        let _ = remove_file(dir.join(format!("{}.json", old.id)));
+        let _ = remove_file(&old.output);
+    }
+    let started = now();
+    let output = dir.join(format!("{}-{}.out", started, process::id()));
+    let file = File::create(&output)?;
+    let mut command = Command::new(current_exe()?);
+    command
+        .arg("supervise")
+        .arg(&output)
+        .arg("--")
+        .args(&args)
+        .current_dir(cwd)
+        .stdin(Stdio::null())
+        .stdout(file.try_clone()?)
+        .stderr(file);
+    // Away from the terminal, so that closing it does not hang up the build
+    #[cfg(unix)]
+    unsafe {
+        use std::os::unix::process::CommandExt;
+        command.pre_exec(|| match libc::setsid() {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    // Without a console, and out of the reach of the Ctrl-C of the one it was started from
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const DETACHED_PROCESS: u32 = 0x8;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x200;
+        command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+    }
+    let child = command.spawn()?;
+    let detached = Detached {
+        id: child.id(),
+        args: args
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect(),
+        cwd: cwd.to_path_buf(),
+        started,
+        output,
+        exit_code: None,
+    };
+    state::store(&dir.join(format!("{}.json", detached.id)), &detached)?;
+    Ok(detached)
+}
+
+/// Run `sm` with `args` as the background build that `start` started, and record how it
+/// exited once it did, returning its exit code
+pub fn supervise(output: &Path, args: &[OsString]) -> Result<i32> {
+    let status = Command::new(current_exe()?)
+        .args(args)
+        .stdin(Stdio::null())
+        .status()?;
+    let code = exit_code(status);
+    let path = output.with_file_name(format!("{}.json", process::id()));
+    // `start` records the build once it was spawned, which may not have happened yet
+    for _ in 0..50 {
+        if path.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    let mut detached: Detached = serde_json::from_slice(&read(&path)?)?;
+    detached.exit_code = Some(code);
+    state::store(&path, &detached)?;
+    Ok(code)
+}
+
+/// Print the output of `detached` so far and then as it comes, until it finished, returning
+/// its exit code
+pub fn attach(detached: &Detached) -> Result<i32> {
+    let mut file = File::open(&detached.output)?;
+    let mut position = 0;
+    let mut buffer = [0; 8192];
+    let path = detached
+        .output
+        .with_file_name(format!("{}.json", detached.id));
+    loop {
+        // Whether it finished is looked at before reading, so the last output is not missed
+        let current: Option<Detached> = read(&path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok());
+        let done = current.as_ref().is_none_or(|current| !current.running());
+        file.seek(SeekFrom::Start(position))?;
+        loop {
+            let length = file.read(&mut buffer)?;
+            if length == 0 {
+                break;
+            }
+            stdout().write_all(&buffer[..length])?;
+            position += length as u64;
+        }
+        stdout().flush()?;
+        if done {
+            return Ok(current.and_then(|current| current.exit_code).unwrap_or(1));
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,623 @@
+use std::{
+    collections::BTreeMap,
+    env::var_os,
+    fs::{canonicalize, exists, read_dir, symlink_metadata},
+    io::Result,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    build_system::{is_custom_makefile, matches_marker},
+    cmake,
+    config::Config,
+    plugin, state, BuildSystem,
+};
+
+/// A build system found in a directory
+#[derive(Clone, Debug, PartialEq)]
+pub struct Detection {
+    pub build_system: BuildSystem,
+    pub directory: PathBuf,
+    /// The CMake source directory that `directory` is configured from, if smartmake has to
+    /// configure it before building
+    pub source: Option<PathBuf>,
+}
+
+/// A directory that `find_build_dir` looked in, and what it found there
+#[derive(Clone, Debug)]
+pub struct SearchStep {
+    pub directory: PathBuf,
+    /// Why it looked there, like `the build subdirectory`
+    pub reason: &'static str,
+    pub exists: bool,
+    /// Why the directory could not be read, if it could not, and was passed over
+    pub unreadable: Option<String>,
+    /// The build systems it found, each with the marker file or the plugin that told, where
+    /// the first one is the one it chose
+    pub found: Vec<(String, BuildSystem)>,
+    /// The build systems it found that the configuration does not allow, with their marker
+    /// files
+    pub disabled: Vec<(String, BuildSystem)>,
+}
+
+/// The order build systems are chosen in when one directory has the files of several, unless
+/// the configuration prefers others: the ones the configuration defines, then ninja, whose
+/// files are generated for a build, make, which often wraps the others, cargo, and CMake
+const PRIORITY: &[&str] = &["ninja", "make", "cargo", "cmake"];
+
+// Where `build_system` comes in the order of preference, first the ones in `prefer`
+fn rank(build_system: &BuildSystem, prefer: &[String]) -> usize {
+    let name = build_system.name();
+    if let Some(position) = prefer.iter().position(|preferred| preferred == name) {
+        return position;
+    }
+    prefer.len()
+        + match build_system {
+            BuildSystem::Custom(_) => 0,
+            _ => {
+                1 + PRIORITY
+                    .iter()
+                    .position(|builtin| *builtin == name)
+                    .unwrap_or(PRIORITY.len())
+            }
+        }
+}
+
+// Every build system that can build `path`, each with the file name or plugin it was told by,
+// in the order of preference, adding the ones the configuration disables to `disabled`
+fn detect_with<P: AsRef<Path>>(
+    path: P,
+    config: &Config,
+    plugins: &[String],
+    disabled: &mut Vec<(String, BuildSystem)>,
+) -> Result<Vec<(String, Detection)>> {
+    let path = path.as_ref();
+    let detection = |build_system| Detection {
+        build_system,
+        directory: path.to_path_buf(),
+        source: None,
+    };
+    let mut detections = Vec::new();
+    for entry in read_dir(path)? {
+        let name = entry?.file_name();
+        for (custom_name, custom) in &config.build_systems {
+            if custom
+                .markers
+                .iter()
+                .any(|marker| matches_marker(marker, &name))
+            {
+                detections.push((
+                    name.to_string_lossy().into_owned(),
+                    detection(BuildSystem::Custom(custom_name.clone())),
+                ));
+            }
+        }
+        // The built-in marker files all have names in ASCII
+        if let Some(build_system) = name.to_str().and_then(BuildSystem::from_filename) {
+            detections.push((name.to_string_lossy().into_owned(), detection(build_system)));
+        } else if is_custom_makefile(&name, config) {
+            detections.push((
+                name.to_string_lossy().into_owned(),
+                detection(BuildSystem::Make),
+            ));
+        }
+    }
+    let (allowed, left_out): (Vec<_>, Vec<_>) = detections
+        .into_iter()
+        .partition(|(_, detection)| config.allows(&detection.build_system));
+    let mut detections = allowed;
+    // Plugins are only asked once no marker file matched, as they have to be spawned
+    if detections.is_empty() {
+        for name in plugins {
+            let build_system = BuildSystem::Custom(name.clone());
+            if config.allows(&build_system) && plugin::detect(name, path)? {
+                detections.push((format!("plugin {}", name), detection(build_system)));
+            }
+        }
+    }
+    // The order of the files in a directory is up to the file system, so the same tree is
+    // detected the same way on every machine only in an order of their own
+    detections.sort_by_cached_key(|(marker, detection)| {
+        (
+            rank(&detection.build_system, &config.prefer),
+            detection.build_system.name().to_string(),
+            marker.clone(),
+        )
+    });
+    let mut left_out: Vec<_> = left_out
+        .into_iter()
+        .map(|(marker, detection)| (marker, detection.build_system))
+        .collect();
+    left_out.sort_by(|(marker, build_system), (other_marker, other)| {
+        (build_system.name(), marker).cmp(&(other.name(), other_marker))
+    });
+    disabled.extend(left_out);
+    Ok(detections)
+}
+
+/// Every build system that can build the directory `path`
+pub fn detect<P: AsRef<Path>>(path: P, config: &Config) -> Result<Vec<Detection>> {
+    Ok(
+        detect_with(path, config, &plugin::discover(), &mut Vec::new())?
+            .into_iter()
+            .map(|(_, detection)| detection)
+            .collect(),
+    )
+}
+
+/// Whether `find_build_dir` may answer from the detection cache
+static CACHE: AtomicBool = AtomicBool::new(true);
+
+/// Whether the search for the build system follows symbolic links
+static FOLLOW_SYMLINKS: AtomicBool = AtomicBool::new(true);
+
+/// The most directories the detection cache remembers
+const CACHE_SIZE: usize = 256;
+
+/// Make `find_build_dir` look for the build system every time instead of using the cache
+pub fn disable_detection_cache() {
+    CACHE.store(false, Ordering::Relaxed);
+}
+
+/// Make `find_build_dir` walk up the path it was given, symbolic links and all, instead of the
+/// directories they lead to, and pass over the build subdirectories that are links
+pub fn disable_symlink_following() {
+    FOLLOW_SYMLINKS.store(false, Ordering::Relaxed);
+}
+
+/// The directory the searches for the build system and the configuration stop at, both as
+/// given and with its symbolic links resolved
+static CEILING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Make the searches for the build system and the configuration look no further up than
+/// `dir`, like for a checkout in a temporary directory, whose parents belong to nobody in
+/// particular. This turns the detection cache off, as it does not know about the ceiling.
+pub fn set_search_ceiling(dir: &Path) {
+    disable_detection_cache();
+    let mut ceiling = CEILING.lock().unwrap();
+    *ceiling = vec![dir.to_path_buf()];
+    ceiling.extend(canonicalize(dir));
+}
+
+/// Whether `dir` is above the directory the searches stop at, if there is one
+pub(crate) fn above_ceiling(dir: &Path) -> bool {
+    let ceiling = CEILING.lock().unwrap();
+    !ceiling.is_empty() && !ceiling.iter().any(|ceiling| dir.starts_with(ceiling))
+}
+
+fn follows_symlinks(config: &Config) -> bool {
+    FOLLOW_SYMLINKS.load(Ordering::Relaxed) && config.follow_symlinks.unwrap_or(true)
+}
+
+// Where the search for `start` begins: the directory the symbolic links in its path lead to,
+// or without following them the path itself, where the current directory is the `$PWD` of
+// the shell, as the operating system only knows it with its links resolved
+fn starting_point(start: &Path, follow: bool) -> PathBuf {
+    let resolved = canonicalize(start).ok();
+    if follow {
+        return resolved.unwrap_or_else(|| start.to_path_buf());
+    }
+    match var_os("PWD").map(PathBuf::from) {
+        Some(pwd)
+            if pwd.is_absolute() && resolved.is_some() && canonicalize(&pwd).ok() == resolved =>
+        {
+            pwd
+        }
+        _ => start.to_path_buf(),
+    }
+}
+
+// What `find_build_dir` found for a directory, with the modification times of every directory
+// it looked in on the way. Creating, removing or renaming a marker file changes them.
+#[derive(Deserialize, Serialize)]
+struct Cached {
+    build_system: Option<String>,
+    directory: Option<PathBuf>,
+    /// The custom build systems of the configuration it was found with
+    markers: Vec<(String, Vec<String>)>,
+    /// The build systems the configuration it was found with prefers
+    #[serde(default)]
+    prefer: Vec<String>,
+    /// The build systems the configuration it was found with enables and disables
+    #[serde(default)]
+    enable: Vec<String>,
+    #[serde(default)]
+    disable: Vec<String>,
+    /// The build directories the configuration it was found with names
+    #[serde(default)]
+    build_dirs: Vec<String>,
+    /// The names of makefiles the configuration it was found with gives
+    #[serde(default)]
+    makefiles: Vec<String>,
+    /// Whether it followed symbolic links
+    #[serde(default)]
+    follow_symlinks: bool,
+    checked: Vec<(PathBuf, Option<u64>)>,
+    /// When it was last used, in seconds since the epoch
+    used: u64,
+}
+
+fn markers(config: &Config) -> Vec<(String, Vec<String>)> {
+    config
+        .build_systems
+        .iter()
+        .map(|(name, custom)| (name.clone(), custom.markers.clone()))
+        .collect()
+}
+
+/// Find the build system for `start`: the directory itself, one of its build subdirectories,
+/// or the nearest ancestor with a build system, or with one in a build subdirectory within the
+/// same repository. The result is cached until one of the directories it
+/// looked in changes, so that looking it up again in a deep tree takes a few `stat` calls
+pub fn find_build_dir<P: AsRef<Path>>(start: P, config: &Config) -> Result<Option<Detection>> {
+    let start = start.as_ref();
+    let path = match CACHE.load(Ordering::Relaxed) {
+        true => state::cache_file("detection.json"),
+        false => None,
+    };
+    let Some(path) = path else {
+        return search(start, config, &mut Vec::new());
+    };
+    let mut cache: BTreeMap<PathBuf, Cached> = state::load(&path);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    if let Some(cached) = cache.get_mut(start) {
+        let fresh = cached.markers == markers(config)
+            && cached.prefer == config.prefer
+            && cached.enable == config.enable
+            && cached.disable == config.disable
+            && cached.build_dirs == config.build_dirs
+            && cached.makefiles == config.makefiles
+            && cached.follow_symlinks == follows_symlinks(config)
+            && cached
+                .checked
+                .iter()
+                .all(|(dir, time)| state::modified(dir) == *time);
+        if fresh {
+            crate::debug!(
+                "detect",
+                "Using what was found for {} before, as nothing it looked at changed",
+                start.display()
+            );
+            cached.used = now;
+            let detection = match (&cached.build_system, &cached.directory) {
+                (Some(build_system), Some(directory)) => Some(Detection {
+                    build_system: BuildSystem::from(build_system.clone()),
+                    directory: directory.clone(),
+                    source: None,
+                }),
+                _ => None,
+            };
+            let _ = state::store(&path, &cache);
+            return Ok(detection);
+        }
+    }
+
+    let mut steps = Vec::new();
+    let detection = search(start, config, &mut steps)?;
+    let checked: Vec<_> = steps
+        .into_iter()
+        .map(|step| {
+            let time = state::modified(&step.directory);
+            (step.directory, time)
+        })
+        .collect();
+    // A directory changed within the last second could change again without its time
+    // changing on file systems with coarse timestamps. A CMake project that still has to be
+    // configured is found quickly, and its build system depends on the configuration.
+    let settled = checked
+        .iter()
+        .all(|(_, time)| time.is_none_or(|time| time / 1_000_000_000 + 1 < now))
+        && detection
+            .as_ref()
+            .is_none_or(|detection| detection.source.is_none());
+    if settled {
+        cache.insert(
+            start.to_path_buf(),
+            Cached {
+                build_system: detection
+                    .as_ref()
+                    .map(|detection| detection.build_system.name().to_string()),
+                directory: detection
+                    .as_ref()
+                    .map(|detection| detection.directory.clone()),
+                markers: markers(config),
+                prefer: config.prefer.clone(),
+                enable: config.enable.clone(),
+                disable: config.disable.clone(),
+                build_dirs: config.build_dirs.clone(),
+                makefiles: config.makefiles.clone(),
+                follow_symlinks: follows_symlinks(config),
+                checked,
+                used: now,
+            },
+        );
+        while cache.len() > CACHE_SIZE {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, cached)| cached.used)
+                .map(|(dir, _)| dir.clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+        // The cache only saves time, so a build goes ahead without it
+        let _ = state::store(&path, &cache);
+    }
+    Ok(detection)
+}
+
+/// Look for the build system of `start` as [`find_build_dir`] does, but without the cache,
+/// and tell every directory it looked in along the way
+pub fn explain_build_dir<P: AsRef<Path>>(
+    start: P,
+    config: &Config,
+) -> Result<(Vec<SearchStep>, Option<Detection>)> {
+    let mut steps = Vec::new();
+    let detection = search(start.as_ref(), config, &mut steps)?;
+    Ok((steps, detection))
+}
+
+// Look for a build system in `dir`, adding it to `steps` with what was found there. With
+// `cmake`, a `CMakeLists.txt` competes with the other files, and wins as a detection with
+// `dir` as its source.
+fn look(
+    dir: &Path,
+    reason: &'static str,
+    config: &Config,
+    plugins: &[String],
+    cmake: bool,
+    steps: &mut Vec<SearchStep>,
+) -> Result<Option<Detection>> {
+    // A directory that cannot be read, like an automount that does not answer or the home of
+    // another user, is passed over, as the ones around it may still tell, and `sm explain`
+    // shows why
+    let readable = exists(dir).and_then(|exists| match exists {
+        true => read_dir(dir).map(|_| true),
+        false => Ok(false),
+    });
+    let exists = match readable {
+        Ok(exists) => exists,
+        Err(error) => {
+            crate::debug!(
+                "detect",
+                "Passing over {}, which cannot be read: {}",
+                dir.display(),
+                error
+            );
+            steps.push(SearchStep {
+                directory: dir.to_path_buf(),
+                reason,
+                exists: true,
+                unreadable: Some(error.to_string()),
+                found: Vec::new(),
+                disabled: Vec::new(),
+            });
+            return Ok(None);
+        }
+    };
+    let mut disabled = Vec::new();
+    let mut detections = match exists {
+        true => detect_with(dir, config, plugins, &mut disabled)?,
+        false => Vec::new(),
+    };
+    let cmake = cmake && dir.join("CMakeLists.txt").exists();
+    if cmake && !config.allows(&BuildSystem::CMake) {
+        disabled.push(("CMakeLists.txt".to_string(), BuildSystem::CMake));
+    } else if cmake && !detections.is_empty() {
+        let position = detections
+            .iter()
+            .position(|(_, detection)| {
+                rank(&BuildSystem::CMake, &config.prefer)
+                    < rank(&detection.build_system, &config.prefer)
+            })
+            .unwrap_or(detections.len());
+        let detection = Detection {
+            build_system: BuildSystem::CMake,
+            directory: dir.to_path_buf(),
+            source: Some(dir.to_path_buf()),
+        };
+        detections.insert(position, ("CMakeLists.txt".to_string(), detection));
+    }
+    crate::trace!(
+        "detect",
+        "Looked in {} ({}): {:?}, leaving out {:?}",
+        dir.display(),
+        reason,
+        detections
+            .iter()
+            .map(|(marker, detection)| (marker, detection.build_system.name()))
+            .collect::<Vec<_>>(),
+        disabled
+            .iter()
+            .map(|(marker, build_system)| (marker, build_system.name()))
+            .collect::<Vec<_>>()
+    );
+    steps.push(SearchStep {
+        directory: dir.to_path_buf(),
+        reason,
+        exists,
+        unreadable: None,
+        found: detections
+            .iter()
+            .map(|(marker, detection)| (marker.clone(), detection.build_system.clone()))
+            .collect(),
+        disabled,
+    });
+    Ok(detections
+        .into_iter()
+        .next()
+        .map(|(_, detection)| detection))
+}
+
+/// The names of the build directories looked for when the configuration names none
+const BUILD_DIRS: &[&str] = &["build", "out", "builddir", "cmake-build-*"];
+
+// The build directories in `dir`, in the order the configuration names them, and those a
+// pattern matches by name. Following symbolic links, one that leads back to `dir` or above
+// it is left out, as the search looks there anyway; otherwise every link is.
+fn build_dirs(dir: &Path, config: &Config, follow: bool) -> Vec<PathBuf> {
+    let defaults = BUILD_DIRS.iter().map(|name| name.to_string()).collect();
+    let names = match config.build_dirs.is_empty() {
+        true => defaults,
+        false => config.build_dirs.clone(),
+    };
+    let mut dirs = Vec::new();
+    for name in names {
+        if !name.contains(['*', '?']) {
+            dirs.push(dir.join(name));
+            continue;
+        }
+        let Ok(entries) = read_dir(dir) else {
+            continue;
+        };
+        let mut matched: Vec<PathBuf> = entries
+            .flatten()
+            .filter(|entry| matches_marker(&name, entry.file_name()))
+            .map(|entry| entry.path())
+            .collect();
+        matched.sort();
+        dirs.extend(matched);
+    }
+    dirs.retain(|build_dir| match follow {
+        true => canonicalize(build_dir)
+            .is_ok_and(|resolved| resolved.is_dir() && !dir.starts_with(resolved)),
+        false => symlink_metadata(build_dir).is_ok_and(|metadata| metadata.is_dir()),
+    });
+    dirs.dedup();
+    dirs
+}
+
+// The files that only build directories have: the cache of CMake, the log of ninja and the
+// introspection files of meson
+const BUILD_DIR_MARKERS: &[&str] = &["CMakeCache.txt", ".ninja_log", "meson-info"];
+
+// Look for the build system of `start` in the directories `find_build_dir` promises, adding
+// each one it looked in to `steps`
+fn search(start: &Path, config: &Config, steps: &mut Vec<SearchStep>) -> Result<Option<Detection>> {
+    let detection = search_dirs(start, config, steps)?;
+    match &detection {
+        Some(detection) => crate::debug!(
+            "detect",
+            "Found {} in {} after looking in {} directories",
+            detection.build_system.name(),
+            detection.directory.display(),
+            steps.len()
+        ),
+        None => crate::debug!("detect", "Found no build system for {}", start.display()),
+    }
+    Ok(detection)
+}
+
+fn search_dirs(
+    start: &Path,
+    config: &Config,
+    steps: &mut Vec<SearchStep>,
+) -> Result<Option<Detection>> {
+    let plugins = plugin::discover();
+    // Its parents are then those of the directory the links lead to, which every path to it
+    // agrees on, and walking up them ends at the root
+    let follow = follows_symlinks(config);
+    let mut cwd = starting_point(start, follow);
+    if cwd != start {
+        crate::trace!(
+            "detect",
+            "Looking from {} for {}",
+            cwd.display(),
+            start.display()
+        );
+    }
+    // A build directory builds itself, like the build tool would, rather than the sources it
+    // is configured from
+    let inside = BUILD_DIR_MARKERS
+        .iter()
+        .any(|marker| cwd.join(marker).exists());
+    let reason = "the directory itself";
+    match look(&cwd, reason, config, &plugins, !inside, steps)? {
+        // A CMake project preferred over the other files builds in its build directory
+        Some(detection) if detection.source.is_some() => {}
+        Some(detection) => return Ok(Some(detection)),
+        None => {}
+    }
+    if inside {
+        // Configured for a build tool that smartmake only reaches through CMake
+        if cmake::is_configured(&cwd) && config.allows(&BuildSystem::CMake) {
+            steps.push(SearchStep {
+                directory: cwd.clone(),
+                reason: "a CMake build directory",
+                exists: true,
+                unreadable: None,
+                found: vec![("CMakeCache.txt".to_string(), BuildSystem::CMake)],
+                disabled: Vec::new(),
+            });
+            return Ok(Some(Detection {
+                build_system: BuildSystem::CMake,
+                directory: cwd,
+                source: None,
+            }));
+        }
+        return Ok(None);
+    }
+    for build_dir in build_dirs(&cwd, config, follow) {
+        let reason = "its build subdirectory";
+        if let Some(detection) = look(&build_dir, reason, config, &plugins, false, steps)? {
+            return Ok(Some(detection));
+        }
+    }
+    // A CMake project builds in the build directory of its top source directory, which is
+    // configured first if there is none yet
+    let source = cmake::source_root(&cwd).filter(|_| config.allows(&BuildSystem::CMake));
+    if let Some(source) = source {
+        steps.push(SearchStep {
+            directory: source.clone(),
+            reason: "the top CMake source directory",
+            exists: true,
+            unreadable: None,
+            found: vec![("CMakeLists.txt".to_string(), BuildSystem::CMake)],
+            disabled: Vec::new(),
+        });
+        let build_dir = source.join(cmake::BUILD_DIR);
+        let reason = "the build directory of the CMake project";
+        if let Some(detection) = look(&build_dir, reason, config, &plugins, false, steps)? {
+            return Ok(Some(detection));
+        }
+        let generator = cmake::generator(
+            config.cmake_generator.as_deref(),
+            config.ninja_program.as_deref(),
+        );
+        let mut detection = cmake::detection(&source, build_dir, generator.as_deref());
+        // Configured for a build tool that smartmake only reaches through CMake
+        if cmake::is_configured(&detection.directory) {
+            detection.build_system = BuildSystem::CMake;
+            detection.source = None;
+        }
+        return Ok(Some(detection));
+    }
+    // The build directories above the root of the repository belong to other projects
+    let mut in_repository = !cwd.join(".git").exists();
+    while cwd.pop() && !above_ceiling(&cwd) {
+        let reason = "a parent directory";
+        if let Some(detection) = look(&cwd, reason, config, &plugins, false, steps)? {
+            return Ok(Some(detection));
+        }
+        if in_repository {
+            for build_dir in build_dirs(&cwd, config, follow) {
+                let reason = "the build subdirectory of a parent directory";
+                if let Some(detection) = look(&build_dir, reason, config, &plugins, false, steps)? {
+                    return Ok(Some(detection));
+                }
+            }
+        }
+        in_repository &= !cwd.join(".git").exists();
+    }
+    Ok(None)
+}
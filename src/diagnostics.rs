@@ -0,0 +1,423 @@
+use std::{
+    collections::HashSet,
+    io::{Error, ErrorKind, Result},
+    mem::take,
+    path::PathBuf,
+};
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{config::WarningsConfig, exec::Stream};
+
+/// How serious a compiler diagnostic is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// An error or warning reported by a compiler
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// The source file it is about, if it names one
+    pub file: Option<PathBuf>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    /// The error code, like `E0425` from rustc or `C2065` from MSVC
+    pub code: Option<String>,
+    pub message: String,
+    /// The lines of output that make up the diagnostic, starting with the one that reported it
+    #[serde(skip)]
+    pub text: Vec<String>,
+}
+
+// Keywords that start a diagnostic, after the location if there is one
+const KEYWORDS: &[(&str, Severity)] = &[
+    ("fatal error", Severity::Error),
+    ("error", Severity::Error),
+    ("warning", Severity::Warning),
+];
+
+// Messages of lines that summarize a build rather than report a diagnostic
+const SUMMARIES: &[&str] = &[
+    "could not compile",
+    "aborting due to",
+    "build failed",
+    "warnings emitted",
+    "warning emitted",
+];
+
+/// Remove terminal color codes from a line of output
+pub fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            stripped.push(c);
+        } else if chars.next() == Some('[') {
+            // Skip to the final byte of the sequence
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    stripped
+}
+
+// Split `file:line:column`, `file:line`, `file(line,column)` or `file(line)` into its parts
+fn parse_location(location: &str) -> Option<(PathBuf, u32, Option<u32>)> {
+    if let Some(inner) = location.strip_suffix(')') {
+        let (file, numbers) = inner.rsplit_once('(')?;
+        let mut numbers = numbers.split(',');
+        let line = numbers.next()?.trim().parse().ok()?;
+        let column = numbers.next().and_then(|column| column.trim().parse().ok());
+        return Some((PathBuf::from(file), line, column));
+    }
+    let (rest, last) = location.rsplit_once(':')?;
+    let last = last.parse().ok()?;
+    match rest.rsplit_once(':') {
+        Some((file, line)) if !file.is_empty() => match line.parse() {
+            Ok(line) => Some((PathBuf::from(file), line, Some(last))),
+            Err(_) => Some((PathBuf::from(rest), last, None)),
+        },
+        _ => Some((PathBuf::from(rest), last, None)),
+    }
+}
+
+// Split off the error code after the keyword: `[E0425]` from rustc or ` C2065` from MSVC
+fn parse_code(after: &str) -> (Option<String>, &str) {
+    if let Some(rest) = after.strip_prefix('[') {
+        if let Some((code, rest)) = rest.split_once(']') {
+            return (Some(code.to_string()), rest);
+        }
+    }
+    if let Some(rest) = after.strip_prefix(' ') {
+        let end = rest.find(':').unwrap_or(0);
+        let code = &rest[..end];
+        let mut chars = code.chars();
+        if chars.next().is_some_and(|c| c.is_ascii_uppercase())
+            && code.len() > 1
+            && chars.all(|c| c.is_ascii_alphanumeric())
+        {
+            return (Some(code.to_string()), &rest[end..]);
+        }
+    }
+    (None, after)
+}
+
+/// Parse the line that starts a gcc, clang, rustc or MSVC diagnostic, such as
+/// `main.c:3:5: error: expected ';'` or `error[E0425]: cannot find value`
+pub fn parse(line: &str) -> Option<Diagnostic> {
+    let line = strip_ansi(line);
+    let mut found: Option<(usize, Severity, Option<String>, String)> = None;
+    for &(keyword, severity) in KEYWORDS {
+        for (start, _) in line.match_indices(keyword) {
+            let before = &line[..start];
+            if !(before.is_empty() || before.ends_with(": ")) {
+                continue;
+            }
+            let (code, rest) = parse_code(&line[start + keyword.len()..]);
+            let Some(message) = rest.strip_prefix(':') else {
+                continue;
+            };
+            if found.as_ref().is_none_or(|(earlier, ..)| start < *earlier) {
+                found = Some((start, severity, code, message.trim().to_string()));
+            }
+            break;
+        }
+    }
+    let (start, severity, code, message) = found?;
+    if SUMMARIES.iter().any(|summary| message.contains(summary))
+        || (message.contains(" generated ") && message.contains(" warning"))
+    {
+        return None;
+    }
+    let location = line[..start].trim_end_matches(": ");
+    let (file, line_number, column) = match parse_location(location) {
+        Some((file, line, column)) => (Some(file), Some(line), column),
+        None => (None, None, None),
+    };
+    Some(Diagnostic {
+        severity,
+        file,
+        line: line_number,
+        column,
+        code,
+        message,
+        text: vec![line.clone()],
+    })
+}
+
+/// The target named by a line in which a build tool reports that building it failed: ninja's
+/// `FAILED: main.o`, make's `make: *** [Makefile:2: all] Error 1` or cargo's
+/// ``error: could not compile `demo` ``
+pub fn failed_target(line: &str) -> Option<String> {
+    let line = strip_ansi(line);
+    if let Some(rest) = line.strip_prefix("FAILED: ") {
+        // Newer ninja puts the exit code first, as in `FAILED: [code=1] main.o`
+        let rest = match rest.strip_prefix("[code=") {
+            Some(rest) => rest.split_once("] ")?.1,
+            None => rest,
+        };
+        return Some(rest.trim().to_string());
+    }
+    if let Some((_, rest)) = line.split_once("could not compile `") {
+        return Some(rest.split_once('`')?.0.to_string());
+    }
+    let (tool, rest) = line.split_once(": *** [")?;
+    if !tool.starts_with("make") && !tool.starts_with("gmake") {
+        return None;
+    }
+    let (target, _) = rest.split_once("] ")?;
+    // GNU make 4 puts the makefile and line first
+    Some(match target.rsplit_once(": ") {
+        Some((_, target)) => target.to_string(),
+        None => target.to_string(),
+    })
+}
+
+/// The test named by a line in which a test runner reports that it failed: libtest's
+/// `test tests::parse ... FAILED` or the `  1 - parse (Failed)` that CTest sums up with
+pub fn failed_test(line: &str) -> Option<String> {
+    let line = strip_ansi(line);
+    if let Some(name) = line
+        .strip_prefix("test ")
+        .and_then(|rest| rest.strip_suffix(" ... FAILED"))
+    {
+        return Some(name.to_string());
+    }
+    let (number, rest) = line.trim().split_once(" - ")?;
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (name, status) = rest.rsplit_once(" (")?;
+    status.ends_with(')').then(|| name.to_string())
+}
+
+/// The severity of a line of build output that reports a diagnostic
+pub fn severity(line: &str) -> Option<Severity> {
+    parse(line).map(|diagnostic| diagnostic.severity)
+}
+
+// Whether `line` is part of the diagnostic before it, like a source excerpt or a note
+fn is_continuation(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    line.starts_with(char::is_whitespace)
+        || trimmed.starts_with("= ")
+        || trimmed.starts_with("note:")
+        || trimmed.starts_with("help:")
+        || line.contains(": note: ")
+        || trimmed
+            .split_once(" |")
+            .is_some_and(|(number, _)| number.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Gathers diagnostics from build output, one line at a time
+#[derive(Default)]
+pub struct Collector {
+    // The diagnostic whose lines are still being read
+    current: Option<Diagnostic>,
+}
+
+impl Collector {
+    /// Take in a line of output, returning the diagnostic before it once it is complete
+    pub fn observe(&mut self, line: &str) -> Option<Diagnostic> {
+        if let Some(diagnostic) = parse(line) {
+            return self.current.replace(diagnostic);
+        }
+        let stripped = strip_ansi(line);
+        if stripped.trim().is_empty() || !is_continuation(&stripped) {
+            return self.current.take();
+        }
+        if let Some(current) = &mut self.current {
+            // rustc gives the location on the line after the message
+            if let Some(location) = stripped.trim_start().strip_prefix("--> ") {
+                if current.file.is_none() {
+                    if let Some((file, line, column)) = parse_location(location.trim()) {
+                        current.file = Some(file);
+                        current.line = Some(line);
+                        current.column = column;
+                    }
+                }
+            }
+            current.text.push(stripped);
+        }
+        None
+    }
+
+    /// The diagnostic still being read when the output ended
+    pub fn finish(&mut self) -> Option<Diagnostic> {
+        self.current.take()
+    }
+
+    /// Whether a diagnostic is being read, so that a continuation line belongs to it
+    pub fn is_reading(&self) -> bool {
+        self.current.is_some()
+    }
+}
+
+// Where a warning was reported, and what it said
+type Place = (Option<PathBuf>, Option<u32>, Option<u32>, String);
+
+/// Leaves warnings out of build output: the ones reported again for the same place, and the
+/// ones matching a suppression pattern. Errors always pass.
+pub struct WarningFilter {
+    dedupe: bool,
+    suppress: Vec<Regex>,
+    collector: Collector,
+    // The lines of the diagnostic being read, as they came
+    pending: Vec<(Stream, String)>,
+    seen: HashSet<Place>,
+    /// How many warnings were left out for having been reported before
+    pub duplicates: usize,
+    /// How many warnings were left out for matching a suppression pattern
+    pub suppressed: usize,
+}
+
+impl WarningFilter {
+    /// The filter `config` asks for, if it asks for one
+    pub fn new(config: &WarningsConfig) -> Result<Option<WarningFilter>> {
+        let dedupe = config.dedupe.unwrap_or(false);
+        if !dedupe && config.suppress.is_empty() {
+            return Ok(None);
+        }
+        let suppress = config
+            .suppress
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|error| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid warning suppression pattern: {}", error),
+                    )
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Some(WarningFilter {
+            dedupe,
+            suppress,
+            collector: Collector::default(),
+            pending: Vec::new(),
+            seen: HashSet::new(),
+            duplicates: 0,
+            suppressed: 0,
+        }))
+    }
+
+    // The lines of `diagnostic` if it is to be shown, or none
+    fn decide(&mut self, diagnostic: Diagnostic) -> Vec<(Stream, String)> {
+        let lines = take(&mut self.pending);
+        if diagnostic.severity == Severity::Error {
+            return lines;
+        }
+        if self
+            .suppress
+            .iter()
+            .any(|pattern| pattern.is_match(&diagnostic.text[0]))
+        {
+            self.suppressed += 1;
+            return Vec::new();
+        }
+        let place = (
+            diagnostic.file,
+            diagnostic.line,
+            diagnostic.column,
+            diagnostic.message,
+        );
+        if self.dedupe && !self.seen.insert(place) {
+            self.duplicates += 1;
+            return Vec::new();
+        }
+        lines
+    }
+
+    /// Take in a line of output, returning the lines to show so far. The lines of a diagnostic
+    /// are held back until it is complete.
+    pub fn observe(&mut self, stream: Stream, line: &str) -> Vec<(Stream, String)> {
+        let starts = parse(line).is_some();
+        let reading = self.collector.is_reading();
+        let mut shown = Vec::new();
+        let complete = self.collector.observe(line);
+        let continues = !starts && reading && complete.is_none();
+        if let Some(diagnostic) = complete {
+            let left_out = self.duplicates + self.suppressed;
+            shown = self.decide(diagnostic);
+            // The blank line rustc puts after a diagnostic goes with it
+            if self.duplicates + self.suppressed > left_out && strip_ansi(line).trim().is_empty() {
+                return shown;
+            }
+        }
+        match starts || continues {
+            true => self.pending.push((stream, line.to_string())),
+            false => shown.push((stream, line.to_string())),
+        }
+        shown
+    }
+
+    /// The lines still held back when the output ended, if they are to be shown
+    pub fn finish(&mut self) -> Vec<(Stream, String)> {
+        match self.collector.finish() {
+            Some(diagnostic) => self.decide(diagnostic),
+            None => take(&mut self.pending),
+        }
+    }
+}
+
+fn count(n: usize, what: &str) -> String {
+    match n {
+        1 => format!("1 {}", what),
+        _ => format!("{} {}s", n, what),
+    }
+}
+
+// How many errors and warnings there are, leaving out the kind there are none of
+fn counts(diagnostics: &[&Diagnostic]) -> String {
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+    match (errors, warnings) {
+        (_, 0) => count(errors, "error"),
+        (0, _) => count(warnings, "warning"),
+        _ => format!("{}, {}", count(errors, "error"), count(warnings, "warning")),
+    }
+}
+
+/// A short report of `diagnostics`: how many errors and warnings there were in total and
+/// in each file, followed by the whole of the first error
+pub fn summary(diagnostics: &[Diagnostic]) -> String {
+    let mut files: Vec<(Option<&PathBuf>, Vec<&Diagnostic>)> = Vec::new();
+    for diagnostic in diagnostics {
+        let file = diagnostic.file.as_ref();
+        match files.iter_mut().find(|(other, _)| *other == file) {
+            Some((_, group)) => group.push(diagnostic),
+            None => files.push((file, vec![diagnostic])),
+        }
+    }
+    let mut summary = counts(&diagnostics.iter().collect::<Vec<_>>());
+    summary.push('\n');
+    for (file, group) in &files {
+        let name = match file {
+            Some(file) => file.display().to_string(),
+            None => "(no file)".to_string(),
+        };
+        summary.push_str(&format!("  {}: {}\n", name, counts(group)));
+    }
+    if let Some(first) = diagnostics
+        .iter()
+        .find(|diagnostic| diagnostic.severity == Severity::Error)
+    {
+        summary.push_str("First error:\n");
+        for line in &first.text {
+            summary.push_str(line);
+            summary.push('\n');
+        }
+    }
+    summary
+}
@@ -0,0 +1,63 @@
+use std::process::{Command, Stdio};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{wrap_compilers, CompilerCache},
+    CommandPlan, Detection,
+};
+
+/// A tool that spreads compilations over the machines of a build farm
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Distributor {
+    Distcc,
+    Icecc,
+}
+
+impl Distributor {
+    pub fn name(self) -> &'static str {
+        match self {
+            Distributor::Distcc => "distcc",
+            Distributor::Icecc => "icecc",
+        }
+    }
+
+    /// How many compilations the build farm can run at once, as far as the tool can tell
+    pub fn jobs(self) -> Option<usize> {
+        match self {
+            // Counted from the hosts in DISTCC_HOSTS or the hosts file
+            Distributor::Distcc => {
+                let output = Command::new("distcc")
+                    .arg("-j")
+                    .stderr(Stdio::null())
+                    .output()
+                    .ok()?;
+                if !output.status.success() {
+                    return None;
+                }
+                String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+            }
+            // The icecream scheduler does not tell its size to the command line
+            Distributor::Icecc => None,
+        }
+    }
+
+    /// Make the C and C++ compilers of the build in `detection` run through this tool, behind
+    /// `cache` if there is one
+    pub fn apply(
+        self,
+        plan: &mut CommandPlan,
+        detection: &Detection,
+        cache: Option<CompilerCache>,
+    ) {
+        match cache {
+            // ccache hands the compilations it cannot answer on to this
+            Some(CompilerCache::Ccache) => {
+                plan.envs([("CCACHE_PREFIX".to_string(), self.name().to_string())]);
+            }
+            _ => wrap_compilers(plan, detection, self.name()),
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    env::{join_paths, split_paths, var_os},
+    fs::read_to_string,
+    io::{Error, ErrorKind, Result},
+    iter::once,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    exec::{self, find_program},
+    CommandPlan,
+};
+
+/// Where a project gets the environment with its toolchain from
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    /// The development shell of the `flake.nix` of the project, through `nix develop`
+    Nix,
+    /// The `.envrc` of the project, through `direnv exec`
+    Direnv,
+    /// The Python environment of the project: its `.venv`, its poetry environment or the conda
+    /// environment of its `environment.yml`
+    Python,
+    /// The environment smartmake runs in
+    None,
+}
+
+/// The directory of the virtual environment of a Python project
+const VENV: &str = ".venv";
+
+/// The file that describes the conda environment of a project
+const CONDA_ENVIRONMENT: &str = "environment.yml";
+
+// The name of the conda environment `environment.yml` in `dir` describes
+fn conda_name(dir: &Path) -> Result<String> {
+    let path = dir.join(CONDA_ENVIRONMENT);
+    read_to_string(&path)?
+        .lines()
+        .find_map(|line| line.strip_prefix("name:"))
+        .map(|name| name.trim().trim_matches(['"', '\'']).to_string())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} does not name its environment", path.display()),
+            )
+        })
+}
+
+// The nearest directory from `cwd` up to `root` that provides `environment`, as the ones
+// above the project belong to others
+fn find(environment: Environment, cwd: &Path, root: &Path) -> Option<PathBuf> {
+    let start = match cwd.starts_with(root) {
+        true => cwd,
+        false => root,
+    };
+    start
+        .ancestors()
+        .take_while(|dir| dir.starts_with(root))
+        .find(|dir| match environment {
+            // A flake without a development shell has no environment to build in
+            Environment::Nix => {
+                read_to_string(dir.join("flake.nix")).is_ok_and(|flake| flake.contains("devShell"))
+            }
+            Environment::Direnv => dir.join(".envrc").is_file(),
+            Environment::Python => {
+                dir.join(VENV).is_dir()
+                    || dir.join("poetry.lock").is_file()
+                    || dir.join(CONDA_ENVIRONMENT).is_file()
+            }
+            Environment::None => false,
+        })
+        .map(Path::to_path_buf)
+}
+
+/// Run `plan` in the `environment` of the project in `root`, found from `cwd` up to `root`,
+/// if the project provides one. A project without a flake, an `.envrc` or a Python
+/// environment builds as it is, so the setting can be made for all projects at once in the
+/// global configuration.
+pub fn wrap(
+    plan: CommandPlan,
+    environment: Environment,
+    cwd: &Path,
+    root: &Path,
+) -> Result<CommandPlan> {
+    let Some(dir) = find(environment, cwd, root) else {
+        return Ok(plan);
+    };
+    // Already activated by the user, who may want another one than the project has. The base
+    // environment of conda is often activated for every shell.
+    let activated = var_os("VIRTUAL_ENV").is_some()
+        || var_os("CONDA_DEFAULT_ENV").is_some_and(|name| name != "base");
+    if environment == Environment::Python && activated {
+        return Ok(plan);
+    }
+    let venv = dir.join(VENV);
+    let mut command = match environment {
+        Environment::Nix => {
+            let mut command = tool("nix")?;
+            command.arg("develop").arg(&dir).arg("--command");
+            command
+        }
+        Environment::Direnv => {
+            let mut command = tool("direnv")?;
+            command.arg("exec").arg(&dir);
+            command
+        }
+        // A virtual environment is activated by putting its programs first on PATH, which are
+        // in Scripts on Windows
+        Environment::Python if venv.is_dir() => {
+            let mut plan = plan;
+            let bin = venv.join(if cfg!(windows) { "Scripts" } else { "bin" });
+            let path = var_os("PATH").unwrap_or_default();
+            let path = join_paths(once(bin).chain(split_paths(&path)))
+                .map_err(|error| Error::new(ErrorKind::InvalidInput, error))?;
+            plan.envs([
+                ("PATH".to_string(), path.to_string_lossy().into_owned()),
+                (
+                    "VIRTUAL_ENV".to_string(),
+                    venv.to_string_lossy().into_owned(),
+                ),
+            ]);
+            return Ok(plan);
+        }
+        Environment::Python if dir.join("poetry.lock").is_file() => {
+            let mut command = tool("poetry")?;
+            command.arg("--directory").arg(&dir).arg("run");
+            command
+        }
+        Environment::Python => {
+            let mut command = tool("conda")?;
+            // Without --no-capture-output conda holds back the output until the build is done
+            command
+                .arg("run")
+                .arg("--no-capture-output")
+                .arg("-n")
+                .arg(conda_name(&dir)?);
+            command
+        }
+        Environment::None => return Ok(plan),
+    };
+    command.arg(plan.program).args(plan.args);
+    command.envs(plan.env);
+    if let Some(cwd) = plan.cwd {
+        command.current_dir(cwd);
+    }
+    Ok(command)
+}
+
+// A command running `program`, which the project needs for its environment
+fn tool(program: &str) -> Result<CommandPlan> {
+    match find_program(program) {
+        Some(_) => Ok(CommandPlan::new(program)),
+        None => Err(exec::missing_tool(format!(
+            "{} is not installed, but the project gets its environment from it",
+            program
+        ))),
+    }
+}
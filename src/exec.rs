@@ -0,0 +1,884 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    env::{split_paths, var_os},
+    ffi::OsStr,
+    fs::{metadata, Metadata},
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Result},
+    mem::take,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering},
+        mpsc::{channel, Receiver, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+    fs::File,
+    io::{stdin, IsTerminal},
+    os::{
+        fd::{FromRawFd, OwnedFd},
+        unix::{
+            fs::PermissionsExt,
+            process::{CommandExt, ExitStatusExt},
+        },
+    },
+    ptr::null_mut,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Which output stream of the build a line came from
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Something that happened while a build was running
+#[derive(Debug)]
+pub enum Event {
+    /// A line of output, without its line ending, as the build wrote it: compilers do not
+    /// always write UTF-8, like the Latin-1 paths of an old one, and [`text`] turns it into
+    /// text where it is shown
+    Line(Stream, Vec<u8>),
+    /// The line a build on a terminal is still writing, or rewriting in place like a progress
+    /// bar, as far as it got. It is replaced by the next status or line.
+    Status(String),
+    /// The build exited, after all of its output was delivered
+    Finished(ExitStatus),
+}
+
+/// A line of output as text, with what is not UTF-8 in it replaced
+pub fn text(line: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(line)
+}
+
+// The line a build on a terminal is still writing as text, without the start of a character
+// it has yet to finish writing
+#[cfg(unix)]
+fn status_text(line: &[u8]) -> String {
+    let complete = match std::str::from_utf8(line) {
+        Err(error) if error.error_len().is_none() => &line[..error.valid_up_to()],
+        _ => line,
+    };
+    text(complete).into_owned()
+}
+
+// The process groups of the running builds, to pass the signals smartmake gets on to. Empty
+// slots are 0; atomics rather than a lock since the signal handler reads them.
+static GROUPS: [AtomicI32; 64] = [const { AtomicI32::new(0) }; 64];
+
+// The last signal that asked smartmake to stop while a build ran, or 0
+static RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+// The signals that are passed on to the builds
+#[cfg(unix)]
+const FORWARDED: [libc::c_int; 3] = [libc::SIGINT, libc::SIGQUIT, libc::SIGTERM];
+// Windows has no Ctrl-\
+#[cfg(not(unix))]
+const FORWARDED: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+// The signal that kills whatever is left of a build
+#[cfg(unix)]
+const KILL: libc::c_int = libc::SIGKILL;
+#[cfg(not(unix))]
+const KILL: libc::c_int = libc::SIGTERM;
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    RECEIVED.store(signal, Ordering::SeqCst);
+    signal_groups(signal);
+    // Windows puts the default handler back before calling this one
+    #[cfg(not(unix))]
+    unsafe {
+        libc::signal(
+            signal,
+            forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+/// The signal that stopped a command that exited with `status`, if one did
+#[cfg(unix)]
+pub fn signal(status: &ExitStatus) -> Option<i32> {
+    status.signal()
+}
+
+/// The signal that stopped a command that exited with `status`, which Windows has none of
+#[cfg(not(unix))]
+pub fn signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+/// The id of the user smartmake runs as, that keeps the files of different users in a shared
+/// temporary directory apart
+#[cfg(unix)]
+pub fn user_id() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+/// The id of the user smartmake runs as, 0 on Windows, which gives each user a temporary
+/// directory of their own
+#[cfg(not(unix))]
+pub fn user_id() -> u32 {
+    0
+}
+
+/// Whether the process `pid` is still running
+#[cfg(unix)]
+pub fn is_running(pid: u32) -> bool {
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+/// Whether the process `pid` is still running
+#[cfg(not(unix))]
+pub fn is_running(pid: u32) -> bool {
+    // For a process that is not running, tasklist prints a message instead of its line
+    Command::new("tasklist")
+        .args(["/NH", "/FO", "CSV", "/FI"])
+        .arg(format!("PID eq {}", pid))
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .is_ok_and(|output| {
+            String::from_utf8_lossy(&output.stdout).contains(&format!("\"{}\"", pid))
+        })
+}
+
+/// The signal that asked smartmake to stop while a build ran, if there was one
+pub fn interrupted() -> Option<i32> {
+    match RECEIVED.load(Ordering::SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+/// Exit codes of smartmake itself, which scripts can tell from those of a build that failed,
+/// as builds rarely exit with them
+pub mod code {
+    /// The command line was wrong, or named a target, profile or project that does not exist
+    pub const USAGE: i32 = 64;
+    /// There is no build system to build with here
+    pub const NO_BUILD_SYSTEM: i32 = 66;
+    /// What smartmake was asked to do is not supported here
+    pub const UNSUPPORTED: i32 = 69;
+    /// A file or another thing smartmake needs is not there, like sysexits' `EX_UNAVAILABLE`,
+    /// which it shares with [`UNSUPPORTED`]
+    pub const UNAVAILABLE: i32 = 69;
+    /// Reading or writing something failed
+    pub const IO: i32 = 74;
+    /// smartmake was not allowed to read or write something
+    pub const PERMISSION: i32 = 77;
+    /// A configuration file is broken
+    pub const CONFIG: i32 = 78;
+    /// The build ran longer than `--timeout` and was stopped, like GNU timeout reports it
+    pub const TIMEOUT: i32 = 124;
+    /// The build tool, or another program the build needs, is not installed, like shells
+    /// report it
+    pub const MISSING_TOOL: i32 = 127;
+}
+
+// Whether the build was stopped for running longer than its time limit
+static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+/// The exit code smartmake finishes with after a build that exited with `code`: that of the
+/// signal if it was told to stop, the way shells report it, or [`code::TIMEOUT`] if it ran out
+/// of time
+pub fn final_exit_code(code: i32) -> i32 {
+    if TIMED_OUT.load(Ordering::SeqCst) {
+        return code::TIMEOUT;
+    }
+    interrupted().map_or(code, |signal| 128 + signal)
+}
+
+// The failures whose exit code the kind of the error does not tell
+#[derive(Debug)]
+enum Failure {
+    NoBuildSystem,
+    MissingTool,
+}
+
+#[derive(Debug)]
+struct Classified {
+    failure: Failure,
+    message: String,
+}
+
+impl std::fmt::Display for Classified {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Classified {}
+
+fn classified(failure: Failure, message: String) -> Error {
+    Error::new(ErrorKind::NotFound, Classified { failure, message })
+}
+
+/// The error for finding no build system to build with, that smartmake exits with
+/// [`code::NO_BUILD_SYSTEM`] for
+pub fn no_build_system(message: impl Into<String>) -> Error {
+    classified(Failure::NoBuildSystem, message.into())
+}
+
+/// The error for a program that is not installed, that smartmake exits with
+/// [`code::MISSING_TOOL`] for
+pub fn missing_tool(message: impl Into<String>) -> Error {
+    classified(Failure::MissingTool, message.into())
+}
+
+/// The exit code smartmake finishes with when `error` kept it from building: one of [`code`]
+/// for what went wrong, like [`code::CONFIG`] for a broken configuration, so that scripts can
+/// tell it apart from a build that failed, or 1 when a command it ran failed
+pub fn error_exit_code(error: &Error) -> i32 {
+    let failure = error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<Classified>());
+    if let Some(classified) = failure {
+        return match classified.failure {
+            Failure::NoBuildSystem => code::NO_BUILD_SYSTEM,
+            Failure::MissingTool => code::MISSING_TOOL,
+        };
+    }
+    match error.kind() {
+        ErrorKind::Other => 1,
+        // Malformed data, mostly configuration files
+        ErrorKind::InvalidData => code::CONFIG,
+        ErrorKind::InvalidInput => code::USAGE,
+        ErrorKind::NotFound => code::UNAVAILABLE,
+        ErrorKind::Unsupported => code::UNSUPPORTED,
+        ErrorKind::PermissionDenied => code::PERMISSION,
+        ErrorKind::Interrupted => 128 + libc::SIGINT,
+        _ => code::IO,
+    }
+}
+
+/// Stop the builds once smartmake ran for `limit`: they get SIGTERM, and what is left of them
+/// SIGKILL a few seconds later, when smartmake exits with [`code::TIMEOUT`] if it did not
+/// already
+pub fn time_limit(limit: Duration) {
+    thread::spawn(move || {
+        thread::sleep(limit);
+        TIMED_OUT.store(true, Ordering::SeqCst);
+        eprintln!("The build ran out of time after {}s", limit.as_secs());
+        signal_groups(libc::SIGTERM);
+        thread::sleep(Duration::from_secs(5));
+        signal_groups(KILL);
+        std::process::exit(code::TIMEOUT);
+    });
+}
+
+fn signal_groups(signal: libc::c_int) {
+    for group in &GROUPS {
+        let group = group.load(Ordering::SeqCst);
+        if group > 0 {
+            signal_group(group, signal);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn signal_group(group: i32, signal: libc::c_int) {
+    unsafe {
+        libc::kill(-group, signal);
+    }
+}
+
+// Windows has neither process groups nor signals to send. Ctrl-C already reaches every
+// program on the console, and any other signal ends the build with the programs it started.
+#[cfg(not(unix))]
+fn signal_group(group: i32, signal: libc::c_int) {
+    if signal != libc::SIGINT {
+        let _ = Command::new("taskkill")
+            .args(["/T", "/F", "/PID"])
+            .arg(group.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+    }
+}
+
+// That `program` is not installed, with the command that installs it if there is one
+fn not_installed(program: &str) -> Error {
+    missing_tool(match crate::install::hint(program) {
+        Some(hint) => format!(
+            "{} is not installed, or not on PATH; `{}` installs it",
+            program, hint
+        ),
+        None => format!("{} is not installed, or not on PATH", program),
+    })
+}
+
+/// Check that `program` is installed, before running anything of a build that needs it. A
+/// path to a program is left for running it to check.
+pub fn require(program: &OsStr) -> Result<()> {
+    if Path::new(program).components().count() > 1 {
+        return Ok(());
+    }
+    let program = program.to_string_lossy();
+    match find_program(&program) {
+        Some(_) => Ok(()),
+        None => Err(not_installed(&program)),
+    }
+}
+
+// The error for a command that could not be started: for one that does not exist, that it
+// is not installed
+fn spawn_error(command: &Command, error: Error) -> Error {
+    match error.kind() {
+        ErrorKind::NotFound => not_installed(&command.get_program().to_string_lossy()),
+        _ => error,
+    }
+}
+
+/// Passes Ctrl-C, Ctrl-\ and SIGTERM on to the process groups of the builds while it exists,
+/// instead of letting them stop smartmake, so that smartmake outlives the builds to report
+/// how they ended
+pub struct ForegroundGuard {
+    previous: [libc::sighandler_t; FORWARDED.len()],
+}
+
+impl ForegroundGuard {
+    pub fn new() -> ForegroundGuard {
+        let handler = forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        let previous = FORWARDED.map(|signal| unsafe { libc::signal(signal, handler) });
+        ForegroundGuard { previous }
+    }
+}
+
+impl Default for ForegroundGuard {
+    fn default() -> Self {
+        ForegroundGuard::new()
+    }
+}
+
+impl Drop for ForegroundGuard {
+    fn drop(&mut self) {
+        for (signal, previous) in FORWARDED.into_iter().zip(self.previous) {
+            unsafe {
+                libc::signal(signal, previous);
+            }
+        }
+    }
+}
+
+// The process group of a spawned command, that signals are passed on to while it exists
+struct ProcessGroup {
+    id: i32,
+    slot: Option<usize>,
+}
+
+impl ProcessGroup {
+    fn register(id: u32) -> ProcessGroup {
+        let id = id as i32;
+        let slot = GROUPS.iter().position(|group| {
+            group
+                .compare_exchange(0, id, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+        });
+        ProcessGroup { id, slot }
+    }
+
+    // Once the command exited with `status`: if it was stopped, kill whatever it left
+    // running, like the compilers of a recursive make that did not wait for them
+    fn finish(&self, status: &ExitStatus) {
+        if signal(status).is_some() || interrupted().is_some() {
+            signal_group(self.id, KILL);
+        }
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            GROUPS[slot].store(0, Ordering::SeqCst);
+        }
+    }
+}
+
+// Make the process group `group` the foreground one of the terminal on stdin, so that it gets
+// Ctrl-C and can read from the terminal
+#[cfg(unix)]
+fn give_terminal(group: libc::pid_t) {
+    unsafe {
+        // Changing it from the background would otherwise stop the process
+        let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        libc::tcsetpgrp(libc::STDIN_FILENO, group);
+        libc::signal(libc::SIGTTOU, previous);
+    }
+}
+
+// Wait for `child` in process group `group`, which has the terminal. If it is stopped with
+// Ctrl-Z, stop smartmake as well and hand the terminal back to it once smartmake continues.
+#[cfg(unix)]
+fn wait_foreground(child: &Child, group: libc::pid_t) -> Result<ExitStatus> {
+    loop {
+        let status = wait4(child.id(), libc::WUNTRACED)?;
+        if !libc::WIFSTOPPED(status) {
+            return Ok(ExitStatus::from_raw(status));
+        }
+        unsafe {
+            give_terminal(libc::getpgrp());
+            libc::raise(libc::SIGTSTP);
+            give_terminal(group);
+            libc::kill(-group, libc::SIGCONT);
+        }
+    }
+}
+
+// The most memory one of the commands waited for since `reset_max_rss` used, in the units of
+// `ru_maxrss`
+static MAX_RSS: AtomicU64 = AtomicU64::new(0);
+
+// Wait for the process `pid` with `options` and return its raw status, adding the memory it
+// used to `MAX_RSS` once it exited
+#[cfg(unix)]
+fn wait4(pid: u32, options: libc::c_int) -> Result<libc::c_int> {
+    loop {
+        let mut status = 0;
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::wait4(pid as libc::pid_t, &mut status, options, &mut usage) } < 0 {
+            let error = Error::last_os_error();
+            match error.kind() {
+                ErrorKind::Interrupted => continue,
+                _ => return Err(error),
+            }
+        }
+        if !libc::WIFSTOPPED(status) {
+            MAX_RSS.fetch_max(usage.ru_maxrss as u64, Ordering::SeqCst);
+        }
+        return Ok(status);
+    }
+}
+
+// Wait for `child` to exit
+#[cfg(unix)]
+fn wait(child: &mut Child) -> Result<ExitStatus> {
+    wait4(child.id(), 0).map(ExitStatus::from_raw)
+}
+
+// Wait for `child` to exit, without measuring the memory it used, which Windows does not
+// report for the processes a program started
+#[cfg(not(unix))]
+fn wait(child: &mut Child) -> Result<ExitStatus> {
+    child.wait()
+}
+
+/// The CPU time that the commands smartmake ran and waited for spent so far, together with
+/// everything they waited for: in user space and in the kernel
+#[cfg(unix)]
+pub fn children_cpu_time() -> (Duration, Duration) {
+    let usage = children_usage();
+    let time = |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+    (time(usage.ru_utime), time(usage.ru_stime))
+}
+
+/// The CPU time that the commands smartmake ran spent so far, which Windows does not report:
+/// none
+#[cfg(not(unix))]
+pub fn children_cpu_time() -> (Duration, Duration) {
+    (Duration::ZERO, Duration::ZERO)
+}
+
+/// Start measuring the memory of a build over, for [`max_rss`]
+pub fn reset_max_rss() {
+    MAX_RSS.store(0, Ordering::SeqCst);
+}
+
+/// The most memory that one of the commands smartmake ran and waited for since
+/// [`reset_max_rss`], or one of the ones they waited for, used at once, in bytes. Builds that
+/// run at the same time, as with `sm all`, count together.
+pub fn max_rss() -> u64 {
+    let max_rss = MAX_RSS.load(Ordering::SeqCst);
+    // In kilobytes everywhere else
+    match cfg!(target_os = "macos") {
+        true => max_rss,
+        false => max_rss * 1024,
+    }
+}
+
+#[cfg(unix)]
+fn children_usage() -> libc::rusage {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage);
+    }
+    usage
+}
+
+/// Whether `path` is a file that can be run
+pub fn is_executable(path: &Path) -> bool {
+    metadata(path)
+        .map(|metadata| metadata.is_file() && executable(&metadata))
+        .unwrap_or(false)
+}
+
+#[cfg(unix)]
+fn executable(metadata: &Metadata) -> bool {
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+// Windows tells programs by their extension, like the ones `find_program` tries
+#[cfg(not(unix))]
+fn executable(_metadata: &Metadata) -> bool {
+    true
+}
+
+/// The executable called `name` on PATH, if there is one, on Windows with one of the
+/// extensions in `PATHEXT`, like `.exe`
+pub fn find_program(name: &str) -> Option<PathBuf> {
+    let extensions: Vec<String> = match cfg!(windows) {
+        true => var_os("PATHEXT")
+            .map(|extensions| extensions.to_string_lossy().into_owned())
+            .unwrap_or_else(|| ".COM;.EXE;.BAT;.CMD".to_string())
+            .split(';')
+            .map(str::to_lowercase)
+            .chain([String::new()])
+            .collect(),
+        false => vec![String::new()],
+    };
+    split_paths(&var_os("PATH").unwrap_or_default())
+        .flat_map(|dir| {
+            extensions
+                .iter()
+                .map(move |extension| dir.join(format!("{}{}", name, extension)))
+        })
+        .find(|path| is_executable(path))
+}
+
+/// The command that runs `script` with the shell: `sh -c`, or `cmd /C` on Windows
+pub fn shell(script: &str) -> Command {
+    let mut command = match cfg!(windows) {
+        true => {
+            let mut command = Command::new("cmd");
+            command.arg("/C");
+            command
+        }
+        false => {
+            let mut command = Command::new("sh");
+            command.arg("-c");
+            command
+        }
+    };
+    command.arg(script);
+    command
+}
+
+/// Run `command` in the foreground and wait for it. It runs in a process group of its own that
+/// gets the terminal, so that Ctrl-C reaches everything it started, and whatever is left of
+/// it is killed if it was stopped.
+#[cfg(unix)]
+pub fn run(mut command: Command) -> Result<ExitStatus> {
+    if is_redirected() {
+        return run_redirected(command);
+    }
+    let _guard = ForegroundGuard::new();
+    let terminal =
+        stdin().is_terminal() && unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() };
+    command.process_group(0);
+    if terminal {
+        // In the command as well, so that it has the terminal before it can read from it
+        unsafe {
+            command.pre_exec(|| {
+                give_terminal(libc::getpid());
+                Ok(())
+            });
+        }
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|error| spawn_error(&command, error))?;
+    let group = ProcessGroup::register(child.id());
+    let status = match terminal {
+        true => {
+            give_terminal(child.id() as libc::pid_t);
+            let status = wait_foreground(&child, group.id);
+            give_terminal(unsafe { libc::getpgrp() });
+            status?
+        }
+        false => wait(&mut child)?,
+    };
+    group.finish(&status);
+    Ok(status)
+}
+
+/// Run `command` in the foreground and wait for it, and kill whatever is left of it if it was
+/// stopped. Windows passes Ctrl-C on to it by itself, as it shares the console.
+#[cfg(not(unix))]
+pub fn run(mut command: Command) -> Result<ExitStatus> {
+    if is_redirected() {
+        return run_redirected(command);
+    }
+    let _guard = ForegroundGuard::new();
+    let mut child = command
+        .spawn()
+        .map_err(|error| spawn_error(&command, error))?;
+    let group = ProcessGroup::register(child.id());
+    let status = wait(&mut child)?;
+    group.finish(&status);
+    Ok(status)
+}
+
+// Receives the output of a command line by line
+type Sink = Box<dyn Fn(Stream, &[u8])>;
+
+thread_local! {
+    // Where the commands `run` runs on this thread write to instead of the terminal, if
+    // anywhere else
+    static REDIRECTED: RefCell<Option<Sink>> = const { RefCell::new(None) };
+}
+
+/// Send the output of the commands [`run`] runs on this thread, and the [`message`]s, to
+/// `sink` line by line instead of the terminal, or to the terminal again for `None`, as the
+/// daemon does for the clients it builds for
+pub fn redirect(sink: Option<Sink>) {
+    REDIRECTED.with(|redirected| *redirected.borrow_mut() = sink);
+}
+
+fn is_redirected() -> bool {
+    REDIRECTED.with(|redirected| redirected.borrow().is_some())
+}
+
+// Send `line` of `stream` where the output of this thread is redirected to, returning whether
+// it is
+fn send_redirected(stream: Stream, line: &[u8]) -> bool {
+    REDIRECTED.with(|redirected| match &*redirected.borrow() {
+        Some(sink) => {
+            sink(stream, line);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Tell the user `message` on standard error, or where [`redirect`] sends the output to
+pub fn message(message: &str) {
+    if !send_redirected(Stream::Stderr, message.as_bytes()) {
+        eprintln!("{}", message);
+    }
+}
+
+// Run `command` with its output going where it is redirected to
+fn run_redirected(command: Command) -> Result<ExitStatus> {
+    let execution = Execution::spawn(command)?;
+    for event in execution.events() {
+        match event {
+            Event::Line(stream, line) => {
+                send_redirected(stream, &line);
+            }
+            Event::Status(_) => {}
+            Event::Finished(status) => return Ok(status),
+        }
+    }
+    Err(Error::other("The command stopped without an exit status"))
+}
+
+/// A running build whose output is captured line by line
+pub struct Execution {
+    pid: u32,
+    finished: Arc<AtomicBool>,
+    events: Receiver<Event>,
+}
+
+fn forward<R: Read + Send + 'static>(
+    reader: R,
+    stream: Stream,
+    events: Sender<Event>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        let mut line = Vec::new();
+        while let Ok(length) = reader.read_until(b'\n', &mut line) {
+            if length == 0 {
+                break;
+            }
+            while line
+                .last()
+                .is_some_and(|&byte| byte == b'\n' || byte == b'\r')
+            {
+                line.pop();
+            }
+            if events.send(Event::Line(stream, take(&mut line))).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+// Like `forward`, for the output of a program that writes to a terminal. Each time it stops
+// writing, the line it is in the middle of goes out as a status, and a carriage return starts
+// that line over.
+#[cfg(unix)]
+fn forward_terminal<R: Read + Send + 'static>(
+    mut reader: R,
+    events: Sender<Event>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        let mut line = Vec::new();
+        let mut carriage_return = false;
+        // Reading fails rather than ending once the program and its children closed the terminal
+        while let Ok(length @ 1..) = reader.read(&mut buffer) {
+            for &byte in &buffer[..length] {
+                match byte {
+                    b'\n' => {
+                        if events
+                            .send(Event::Line(Stream::Stdout, take(&mut line)))
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                    b'\r' => {}
+                    _ => {
+                        if carriage_return {
+                            line.clear();
+                        }
+                        line.push(byte);
+                    }
+                }
+                carriage_return = byte == b'\r';
+            }
+            if !line.is_empty() && events.send(Event::Status(status_text(&line))).is_err() {
+                return;
+            }
+        }
+        if !line.is_empty() {
+            let _ = events.send(Event::Line(Stream::Stdout, line));
+        }
+    })
+}
+
+// A new pseudo-terminal as large as the one smartmake runs in: the side smartmake reads the
+// output from, and the side the build writes to. Neither is inherited by spawned commands.
+#[cfg(unix)]
+fn open_pty() -> Result<(OwnedFd, OwnedFd)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let sized = unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    let (mut controller, mut terminal) = (0, 0);
+    let opened = unsafe {
+        libc::openpty(
+            &mut controller,
+            &mut terminal,
+            null_mut(),
+            null_mut(),
+            if sized { &mut size } else { null_mut() },
+        )
+    };
+    if opened != 0 {
+        return Err(Error::last_os_error());
+    }
+    let fds = unsafe {
+        (
+            OwnedFd::from_raw_fd(controller),
+            OwnedFd::from_raw_fd(terminal),
+        )
+    };
+    for fd in [controller, terminal] {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(fds)
+}
+
+// How long the output of a command that exited is still read
+const DRAIN: Duration = Duration::from_millis(250);
+
+impl Execution {
+    /// Start `command` with its output captured
+    pub fn spawn(mut command: Command) -> Result<Execution> {
+        #[cfg(unix)]
+        command.process_group(0);
+        let mut child = command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|error| spawn_error(&command, error))?;
+        let (sender, events) = channel();
+        let out = forward(child.stdout.take().unwrap(), Stream::Stdout, sender.clone());
+        let err = forward(child.stderr.take().unwrap(), Stream::Stderr, sender.clone());
+        Ok(Execution::wait(child, vec![out, err], sender, events))
+    }
+
+    /// Start `command` with its output captured from a pseudo-terminal, so that it keeps the
+    /// colors and progress bars it shows on a terminal. Both of its output streams arrive as
+    /// [`Stream::Stdout`].
+    #[cfg(unix)]
+    pub fn spawn_pty(mut command: Command) -> Result<Execution> {
+        let (controller, terminal) = open_pty()?;
+        let child = command
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(terminal.try_clone()?))
+            .stderr(Stdio::from(terminal))
+            .spawn()
+            .map_err(|error| spawn_error(&command, error))?;
+        // The output ends only once nothing holds the terminal side open any more
+        drop(command);
+        let (sender, events) = channel();
+        let output = forward_terminal(File::from(controller), sender.clone());
+        Ok(Execution::wait(child, vec![output], sender, events))
+    }
+
+    /// Start `command` with its output captured, as [`Execution::spawn`] does: the consoles
+    /// of Windows are not the pseudo-terminals of Unix
+    #[cfg(not(unix))]
+    pub fn spawn_pty(command: Command) -> Result<Execution> {
+        Execution::spawn(command)
+    }
+
+    // Deliver the exit status of `child` once it exited and the `readers` delivered the output
+    // it left, or after waiting `DRAIN` for them: a program the build left running in the
+    // background, like a compiler server, keeps the pipes it inherited open long after
+    fn wait(
+        mut child: Child,
+        readers: Vec<thread::JoinHandle<()>>,
+        sender: Sender<Event>,
+        events: Receiver<Event>,
+    ) -> Execution {
+        let pid = child.id();
+        let group = ProcessGroup::register(pid);
+        let finished = Arc::new(AtomicBool::new(false));
+        let waiter_finished = finished.clone();
+        thread::spawn(move || {
+            let status = wait(&mut child);
+            let deadline = Instant::now() + DRAIN;
+            for reader in readers {
+                while !reader.is_finished() && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+            if let Ok(status) = status {
+                group.finish(&status);
+                waiter_finished.store(true, Ordering::SeqCst);
+                let _ = sender.send(Event::Finished(status));
+            }
+        });
+        Execution {
+            pid,
+            finished,
+            events,
+        }
+    }
+
+    /// The output of the build, ending with [`Event::Finished`]
+    pub fn events(&self) -> &Receiver<Event> {
+        &self.events
+    }
+
+    /// Ask the build and everything it started to stop, if it is still running
+    pub fn kill(&self) {
+        if !self.finished.load(Ordering::SeqCst) {
+            signal_group(self.pid as i32, libc::SIGTERM);
+        }
+    }
+}
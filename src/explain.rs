@@ -0,0 +1,219 @@
+use std::{io::Result, process::ExitStatus, time::Duration};
+
+use crate::{
+    diagnostics::strip_ansi,
+    exec::{text, Stream},
+    output::Sink,
+    BuildSystem,
+};
+
+/// Why the build system rebuilt a target
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reason {
+    pub target: String,
+    /// What changed, like `src/main.c changed` or `it did not exist`
+    pub cause: String,
+}
+
+/// The arguments that make `build_system` tell why it rebuilds each target, if it can
+pub fn args(build_system: &BuildSystem) -> Option<&'static [&'static str]> {
+    match build_system {
+        BuildSystem::Ninja => Some(&["-d", "explain"]),
+        BuildSystem::Make => Some(&["--trace"]),
+        // Cargo tells which fingerprint is dirty, and why, when verbose
+        BuildSystem::Cargo => Some(&["-v"]),
+        _ => None,
+    }
+}
+
+// A cause that names the input that changed, or says the target was missing
+fn changed(input: &str) -> String {
+    match input {
+        "" => "an input changed".to_string(),
+        input => format!("{} changed", input),
+    }
+}
+
+const MISSING: &str = "it did not exist";
+
+// Why ninja rebuilds a target, from a line of `ninja -d explain`
+fn parse_ninja(explanation: &str) -> Option<Reason> {
+    let reason = |target: &str, cause: String| {
+        Some(Reason {
+            target: target.to_string(),
+            cause,
+        })
+    };
+    if let Some(target) = explanation
+        .strip_prefix("output ")
+        .and_then(|rest| rest.strip_suffix(" doesn't exist"))
+    {
+        // Phony targets without inputs never exist, and always run
+        if target.ends_with(" of phony edge with no inputs") {
+            return None;
+        }
+        return reason(target, MISSING.to_string());
+    }
+    if let Some(target) = explanation.strip_prefix("command line changed for ") {
+        return reason(target, "its command changed".to_string());
+    }
+    // `output T older than most recent input I (1 vs 2)`, also after `restat of ` and as
+    // `recorded mtime of T older than most recent input I`
+    for prefix in ["output ", "restat of output ", "recorded mtime of "] {
+        let Some(rest) = explanation.strip_prefix(prefix) else {
+            continue;
+        };
+        if let Some((target, input)) = rest.split_once(" older than most recent input ") {
+            let input = input.split(" (").next().unwrap_or(input);
+            return reason(target, changed(input));
+        }
+    }
+    None
+}
+
+// Why make rebuilds a target, from a line of `make --trace`
+fn parse_make(line: &str) -> Option<Reason> {
+    if let Some((_, rest)) = line.split_once(": target '") {
+        let target = rest.strip_suffix("' does not exist")?;
+        return Some(Reason {
+            target: target.to_string(),
+            cause: MISSING.to_string(),
+        });
+    }
+    let (_, rest) = line.split_once(": update target '")?;
+    let (target, cause) = rest.split_once("' due to: ")?;
+    let cause = match cause {
+        "target does not exist" => MISSING.to_string(),
+        inputs => changed(inputs),
+    };
+    Some(Reason {
+        target: target.to_string(),
+        cause,
+    })
+}
+
+// Why cargo rebuilds a package, from a `Dirty` line of `cargo build -v`
+fn parse_cargo(line: &str) -> Option<Reason> {
+    let rest = line.trim_start().strip_prefix("Dirty ")?;
+    let (package, cause) = rest.split_once("): ")?;
+    let target = package.split(" (").next().unwrap_or(package);
+    // Leave out the timestamps of the file that changed
+    let cause = match cause
+        .strip_suffix(')')
+        .and_then(|cause| cause.rsplit_once(" ("))
+    {
+        Some((cause, _)) => cause,
+        None => cause,
+    };
+    Some(Reason {
+        target: target.to_string(),
+        cause: cause.to_string(),
+    })
+}
+
+/// Whether `line` is part of what `build_system` prints to explain its rebuilds, rather than
+/// the output of the build
+pub fn is_explanation(build_system: &BuildSystem, line: &str) -> bool {
+    let line = strip_ansi(line);
+    match build_system {
+        BuildSystem::Ninja => line.starts_with("ninja explain: "),
+        BuildSystem::Make => line.contains(": update target '") || line.contains(": target '"),
+        BuildSystem::Cargo => {
+            let line = line.trim_start();
+            line.starts_with("Dirty ") || line.starts_with("Fresh ")
+        }
+        _ => false,
+    }
+}
+
+/// Why `build_system` rebuilds a target, from a line it printed with [`args`]
+pub fn parse(build_system: &BuildSystem, line: &str) -> Option<Reason> {
+    let line = strip_ansi(line);
+    match build_system {
+        BuildSystem::Ninja => parse_ninja(line.strip_prefix("ninja explain: ")?),
+        BuildSystem::Make => parse_make(&line),
+        BuildSystem::Cargo => parse_cargo(&line),
+        _ => None,
+    }
+}
+
+/// Passes the output on to `sinks` without the lines that explain the rebuilds, and prints
+/// what they explained once the build finished
+pub struct Explained {
+    build_system: BuildSystem,
+    sinks: Vec<Box<dyn Sink>>,
+    reasons: Vec<Reason>,
+}
+
+impl Explained {
+    pub fn new(build_system: BuildSystem, sinks: Vec<Box<dyn Sink>>) -> Explained {
+        Explained {
+            build_system,
+            sinks,
+            reasons: Vec::new(),
+        }
+    }
+}
+
+impl Sink for Explained {
+    fn line(&mut self, stream: Stream, line: &str) -> Result<()> {
+        self.bytes(stream, line.as_bytes())
+    }
+
+    fn bytes(&mut self, stream: Stream, line: &[u8]) -> Result<()> {
+        let text = text(line);
+        if !is_explanation(&self.build_system, &text) {
+            for sink in self.sinks.iter_mut() {
+                sink.bytes(stream, line)?;
+            }
+            return Ok(());
+        }
+        if let Some(reason) = parse(&self.build_system, &text) {
+            // The first reason given for a target is the one that counts
+            if !self
+                .reasons
+                .iter()
+                .any(|other| other.target == reason.target)
+            {
+                self.reasons.push(reason);
+            }
+        }
+        Ok(())
+    }
+
+    fn status(&mut self, status: &str) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.status(status)?;
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.tick()?;
+        }
+        Ok(())
+    }
+
+    fn finished(&mut self, status: ExitStatus, duration: Duration) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.finished(status, duration)?;
+        }
+        eprintln!();
+        match self.reasons.len() {
+            0 => eprintln!("Nothing was out of date"),
+            1 => eprintln!("Rebuilt 1 target:"),
+            n => eprintln!("Rebuilt {} targets:", n),
+        }
+        let width = self
+            .reasons
+            .iter()
+            .map(|reason| reason.target.chars().count())
+            .max()
+            .unwrap_or(0);
+        for reason in &self.reasons {
+            eprintln!("  {:<width$}  {}", reason.target, reason.cause);
+        }
+        Ok(())
+    }
+}
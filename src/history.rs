@@ -0,0 +1,326 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    env::var_os,
+    fs::{create_dir_all, read_to_string, OpenOptions},
+    io::{ErrorKind, Result, Write},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{daemon::exit_code, Invocation, Project, Verb};
+
+// The history, relative to the data directory
+const HISTORY: &str = "smartmake/history.jsonl";
+
+/// One finished build
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Record {
+    /// The root directory of the project
+    pub project: PathBuf,
+    pub build_system: String,
+    pub verb: Verb,
+    pub targets: Vec<String>,
+    pub threads: usize,
+    /// When the build started, in seconds since the Unix epoch
+    pub started: u64,
+    /// How long the build took, in seconds
+    pub duration: f64,
+    pub exit_code: i32,
+    /// The CPU time the build spent in user space and in the kernel, in seconds, where it
+    /// was measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<f64>,
+    /// The most memory one process of the build used at once, in bytes, where it was measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rss: Option<u64>,
+    /// How much work the build did, in the unit of its build system, where it was counted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub work: Option<u64>,
+    /// The targets and tests that failed, and then passed when retried right away
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub flaky: Vec<String>,
+}
+
+impl Record {
+    pub fn new(
+        project: &Project,
+        invocation: &Invocation,
+        started: SystemTime,
+        duration: Duration,
+        status: ExitStatus,
+    ) -> Record {
+        Record {
+            project: project.root(),
+            build_system: project
+                .detection
+                .as_ref()
+                .map(|detection| detection.build_system.name().to_string())
+                .unwrap_or_default(),
+            verb: invocation.verb,
+            targets: invocation.targets(project).to_vec(),
+            threads: invocation.threads(project).unwrap_or(1),
+            started: started
+                .duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs())
+                .unwrap_or(0),
+            duration: duration.as_secs_f64(),
+            exit_code: exit_code(status),
+            user: None,
+            system: None,
+            max_rss: None,
+            work: None,
+            flaky: Vec::new(),
+        }
+    }
+}
+
+/// The history in `$XDG_DATA_HOME`, falling back to `~/.local/share`
+pub fn path() -> Option<PathBuf> {
+    var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .map(|dir| dir.join(HISTORY))
+}
+
+/// Add a build to the history
+pub fn append(record: &Record) -> Result<()> {
+    let Some(path) = path() else {
+        return Ok(());
+    };
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    // Appending a whole line at once keeps lines from concurrent builds apart
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(&line)
+}
+
+/// Add a build to the history, warning instead of failing when that is not possible
+pub fn record(
+    project: &Project,
+    invocation: &Invocation,
+    started: SystemTime,
+    duration: Duration,
+    status: ExitStatus,
+) {
+    save(&Record::new(project, invocation, started, duration, status));
+}
+
+/// Add `record` to the history, warning instead of failing when that is not possible
+pub fn save(record: &Record) {
+    if let Err(error) = append(record) {
+        eprintln!("Cannot record the build in the history: {}", error);
+    }
+}
+
+/// Every build in the history, oldest first. Lines that cannot be read are skipped.
+pub fn load() -> Result<Vec<Record>> {
+    let Some(path) = path() else {
+        return Ok(Vec::new());
+    };
+    match read_to_string(path) {
+        Ok(text) => Ok(text
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(error),
+    }
+}
+
+/// How much work the last successful build of `project` with the same build system, verb and
+/// targets did, if it was counted
+pub fn previous_work(
+    records: &[Record],
+    project: &Project,
+    invocation: &Invocation,
+) -> Option<u64> {
+    let root = project.root();
+    let build_system = project.detection.as_ref()?.build_system.name();
+    let targets = invocation.targets(project);
+    records
+        .iter()
+        .rev()
+        .find(|record| {
+            record.exit_code == 0
+                && record.project == root
+                && record.build_system == build_system
+                && record.verb == invocation.verb
+                && record.targets == targets
+                && record.work.is_some()
+        })?
+        .work
+}
+
+/// How often each target or test of the project in `root` was flaky
+pub fn flaky<'a>(
+    records: impl IntoIterator<Item = &'a Record>,
+    root: &Path,
+) -> BTreeMap<String, usize> {
+    let mut flaky = BTreeMap::new();
+    for record in records.into_iter().filter(|record| record.project == root) {
+        for name in &record.flaky {
+            *flaky.entry(name.clone()).or_default() += 1;
+        }
+    }
+    flaky
+}
+
+/// How long a build of `project` is expected to take: the median of its last successful
+/// builds with the same build system and verb
+pub fn expected_duration(
+    records: &[Record],
+    project: &Project,
+    invocation: &Invocation,
+) -> Option<Duration> {
+    const RECENT: usize = 10;
+    let root = project.root();
+    let build_system = project.detection.as_ref()?.build_system.name();
+    let mut durations: Vec<f64> = records
+        .iter()
+        .rev()
+        .filter(|record| {
+            record.exit_code == 0
+                && record.project == root
+                && record.build_system == build_system
+                && record.verb == invocation.verb
+        })
+        .take(RECENT)
+        .map(|record| record.duration)
+        .collect();
+    if durations.is_empty() {
+        return None;
+    }
+    durations.sort_by(f64::total_cmp);
+    Some(seconds(percentile(&durations, 50.0)))
+}
+
+/// Build durations and failures of one project
+#[derive(Debug)]
+pub struct Stats {
+    pub project: PathBuf,
+    pub builds: usize,
+    pub failures: usize,
+    pub mean: Duration,
+    pub median: Duration,
+    pub p90: Duration,
+    pub max: Duration,
+    pub total: Duration,
+    /// The targets and tests that were flaky, with how often, the most often first
+    pub flaky: Vec<(String, usize)>,
+}
+
+// A duration from the history, which may have been edited by hand
+fn seconds(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds).unwrap_or_default()
+}
+
+// The nearest-rank percentile of sorted durations
+fn percentile(sorted: &[f64], percent: f64) -> f64 {
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Summarize the history per project, the projects that took the longest overall first
+pub fn stats(records: &[Record]) -> Vec<Stats> {
+    let mut projects: BTreeMap<&Path, Vec<&Record>> = BTreeMap::new();
+    for record in records {
+        projects.entry(&record.project).or_default().push(record);
+    }
+    let mut stats: Vec<Stats> = projects
+        .into_iter()
+        .map(|(project, records)| {
+            let mut durations: Vec<f64> = records.iter().map(|record| record.duration).collect();
+            durations.sort_by(f64::total_cmp);
+            let total: f64 = durations.iter().sum();
+            let mut flaky: Vec<_> = flaky(records.iter().copied(), project)
+                .into_iter()
+                .collect();
+            flaky.sort_by_key(|(_, count)| Reverse(*count));
+            Stats {
+                project: project.to_path_buf(),
+                builds: records.len(),
+                failures: records
+                    .iter()
+                    .filter(|record| record.exit_code != 0)
+                    .count(),
+                mean: seconds(total / durations.len() as f64),
+                median: seconds(percentile(&durations, 50.0)),
+                p90: seconds(percentile(&durations, 90.0)),
+                max: seconds(durations[durations.len() - 1]),
+                total: seconds(total),
+                flaky,
+            }
+        })
+        .collect();
+    stats.sort_by_key(|stats| Reverse(stats.total));
+    stats
+}
+
+/// The smallest, mean and standard deviation of some measurements
+#[derive(Clone, Copy, Debug)]
+pub struct Spread {
+    pub min: Duration,
+    pub mean: Duration,
+    pub stddev: Duration,
+}
+
+/// How `samples` are spread, or `None` if there are none
+pub fn spread(samples: &[Duration]) -> Option<Spread> {
+    let min = samples.iter().min()?;
+    let seconds: Vec<f64> = samples.iter().map(Duration::as_secs_f64).collect();
+    let mean = seconds.iter().sum::<f64>() / seconds.len() as f64;
+    let variance = seconds
+        .iter()
+        .map(|sample| (sample - mean).powi(2))
+        .sum::<f64>()
+        / seconds.len() as f64;
+    Some(Spread {
+        min: *min,
+        mean: self::seconds(mean),
+        stddev: self::seconds(variance.sqrt()),
+    })
+}
+
+/// A size the way people read it: `512 B`, `14.2 MiB` or `1.5 GiB`
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in units {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    match unit {
+        "B" => format!("{} B", bytes),
+        _ => format!("{:.1} {}", size, unit),
+    }
+}
+
+/// A duration the way people read it: `850ms`, `12.3s`, `4m05s` or `1h12m`
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60)
+    } else if seconds >= 60 {
+        format!("{}m{:02}s", seconds / 60, seconds % 60)
+    } else if seconds >= 1 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
@@ -0,0 +1,54 @@
+use std::io::{Error, Result};
+
+use crate::{exec, Project};
+
+/// When configured commands run around a build
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    PreBuild,
+    PostBuild,
+}
+
+impl Stage {
+    /// The configuration key that lists the commands
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::PreBuild => "pre_build",
+            Stage::PostBuild => "post_build",
+        }
+    }
+}
+
+/// Run the `stage` commands of `project` one after the other with the shell, in the project
+/// root. Post-build commands get the exit code of the build in `SMARTMAKE_EXIT_CODE`. The first
+/// one that fails stops the rest, and is an error unless `fatal_hooks = false` is configured, in
+/// which case it is only reported.
+pub fn run(project: &Project, stage: Stage, exit_code: Option<i32>) -> Result<()> {
+    let commands = match stage {
+        Stage::PreBuild => &project.config.pre_build,
+        Stage::PostBuild => &project.config.post_build,
+    };
+    for script in commands {
+        let mut command = exec::shell(script);
+        command.current_dir(project.root());
+        if let Some(code) = exit_code {
+            command.env("SMARTMAKE_EXIT_CODE", code.to_string());
+        }
+        let status = exec::run(command)?;
+        if status.success() {
+            continue;
+        }
+        let message = format!(
+            "{} command `{}` failed with {}",
+            stage.name(),
+            script,
+            status
+        );
+        if project.config.fatal_hooks.unwrap_or(true) {
+            return Err(Error::other(message));
+        }
+        exec::message(&message);
+        break;
+    }
+    Ok(())
+}
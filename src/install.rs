@@ -0,0 +1,74 @@
+use std::fs::read_to_string;
+
+/// The package managers smartmake can tell how to install a missing program with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PackageManager {
+    Apt,
+    Dnf,
+    Brew,
+    Winget,
+}
+
+impl PackageManager {
+    /// The package manager of the system smartmake runs on: Homebrew on macOS, winget on
+    /// Windows, and on Linux the one the distribution in `/etc/os-release`, or the one it is
+    /// derived from, comes with
+    pub fn detect() -> Option<PackageManager> {
+        if cfg!(target_os = "macos") {
+            return Some(PackageManager::Brew);
+        }
+        if cfg!(windows) {
+            return Some(PackageManager::Winget);
+        }
+        let release = read_to_string("/etc/os-release").ok()?;
+        let ids: Vec<&str> = release
+            .lines()
+            .filter_map(|line| {
+                line.strip_prefix("ID=")
+                    .or_else(|| line.strip_prefix("ID_LIKE="))
+            })
+            .flat_map(|ids| ids.trim_matches('"').split_whitespace())
+            .collect();
+        ids.iter().find_map(|id| match *id {
+            "debian" | "ubuntu" => Some(PackageManager::Apt),
+            "fedora" | "rhel" | "centos" => Some(PackageManager::Dnf),
+            _ => None,
+        })
+    }
+
+    // The package that has `program` in it, for the programs smartmake builds with
+    fn package(self, program: &str) -> Option<&'static str> {
+        let [apt, dnf, brew, winget] = match program {
+            "make" => ["make", "make", "make", "GnuWin32.Make"],
+            "bmake" => ["bmake", "bmake", "bmake", ""],
+            "ninja" => ["ninja-build", "ninja-build", "ninja", "Ninja-build.Ninja"],
+            "cmake" | "ctest" => ["cmake", "cmake", "cmake", "Kitware.CMake"],
+            "cargo" => ["cargo", "cargo", "rust", "Rustlang.Rustup"],
+            _ => return None,
+        };
+        let package = match self {
+            PackageManager::Apt => apt,
+            PackageManager::Dnf => dnf,
+            PackageManager::Brew => brew,
+            PackageManager::Winget => winget,
+        };
+        Some(package).filter(|package| !package.is_empty())
+    }
+
+    fn command(self, package: &str) -> String {
+        match self {
+            PackageManager::Apt => format!("sudo apt install {}", package),
+            PackageManager::Dnf => format!("sudo dnf install {}", package),
+            PackageManager::Brew => format!("brew install {}", package),
+            PackageManager::Winget => format!("winget install {}", package),
+        }
+    }
+}
+
+/// The command that installs `program` on this system, if smartmake knows the package it is in
+pub fn hint(program: &str) -> Option<String> {
+    let manager = PackageManager::detect()?;
+    manager
+        .package(program)
+        .map(|package| manager.command(package))
+}
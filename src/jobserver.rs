@@ -0,0 +1,102 @@
+use std::{
+    env::var,
+    io::{Error, Result},
+};
+
+use crate::{build_system::ninja_program, config::Config, toolchain, BuildSystem};
+
+/// The jobserver smartmake runs under, as the `--jobserver-auth` in `MAKEFLAGS` of a make that
+/// started it names it, like `3,4` or `fifo:/tmp/GMfifo1234`
+pub fn inherited() -> Option<String> {
+    let flags = var("MAKEFLAGS").ok()?;
+    flags.split_whitespace().find_map(|flag| {
+        flag.strip_prefix("--jobserver-auth=")
+            .or_else(|| flag.strip_prefix("--jobserver-fds="))
+            .map(str::to_string)
+    })
+}
+
+/// Whether `build_system` takes its jobs from a jobserver in `MAKEFLAGS` when it is not told
+/// a number of them: make, cargo and CMake through them, and ninja from 1.13 on, but neither
+/// samurai nor n2
+pub fn joins(build_system: &BuildSystem, config: &Config) -> bool {
+    match build_system {
+        BuildSystem::Make | BuildSystem::Cargo | BuildSystem::CMake => true,
+        BuildSystem::Ninja => {
+            ninja_program(config.ninja_program.as_deref()) == "ninja"
+                && toolchain::version("ninja")
+                    .is_some_and(|version| toolchain::version_numbers(&version) >= vec![1, 13])
+        }
+        BuildSystem::Custom(_) => false,
+    }
+}
+
+/// A jobserver smartmake hosts for a build, as GNU make does: a pipe holding a token for each
+/// job beyond the first, which the build and the builds it starts in turn take a token from
+/// before running another job and give back after. It closes when dropped.
+#[cfg(unix)]
+pub struct Jobserver {
+    read: i32,
+    write: i32,
+    jobs: usize,
+}
+
+#[cfg(unix)]
+impl Jobserver {
+    /// A jobserver for `jobs` jobs at once
+    pub fn new(jobs: usize) -> Result<Jobserver> {
+        let mut fds = [0; 2];
+        // Without close-on-exec, so that the build inherits both ends
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        let jobserver = Jobserver {
+            read: fds[0],
+            write: fds[1],
+            jobs,
+        };
+        let tokens = vec![b'+'; jobs.saturating_sub(1)];
+        if !tokens.is_empty()
+            && unsafe { libc::write(fds[1], tokens.as_ptr().cast(), tokens.len()) } < 0
+        {
+            return Err(Error::last_os_error());
+        }
+        Ok(jobserver)
+    }
+
+    /// The `MAKEFLAGS` that point the build at this jobserver, in the spellings of make 4.2
+    /// and of the ones before it
+    pub fn makeflags(&self) -> String {
+        format!(
+            "-j{} --jobserver-fds={},{} --jobserver-auth={},{}",
+            self.jobs, self.read, self.write, self.read, self.write
+        )
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Jobserver {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Jobserver;
+
+#[cfg(not(unix))]
+impl Jobserver {
+    pub fn new(_jobs: usize) -> Result<Jobserver> {
+        Err(Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Hosting a jobserver needs the pipes of Unix",
+        ))
+    }
+
+    pub fn makeflags(&self) -> String {
+        String::new()
+    }
+}
@@ -0,0 +1,71 @@
+//! Detect the build system a project uses and construct the command that builds it.
+
+pub mod artifacts;
+pub mod branch;
+mod build_system;
+pub mod cache;
+pub mod capture;
+pub mod cargo;
+pub mod ci_cache;
+pub mod cmake;
+pub mod codegen;
+pub mod compare;
+pub mod compdb;
+pub mod config;
+pub mod container;
+pub mod daemon;
+pub mod debug;
+pub mod detach;
+mod detect;
+pub mod diagnostics;
+pub mod distributed;
+pub mod environment;
+pub mod exec;
+pub mod explain;
+pub mod history;
+pub mod hooks;
+pub mod install;
+pub mod jobserver;
+pub mod limits;
+pub mod lock;
+pub mod logs;
+pub mod matrix;
+pub mod notify;
+pub mod output;
+pub mod picker;
+mod plan;
+pub mod plugin;
+pub mod polyglot;
+pub mod progress;
+mod project;
+pub mod registry;
+pub mod remote;
+pub mod retry;
+pub mod sandbox;
+pub mod single_file;
+pub mod state;
+pub mod targets;
+pub mod telemetry;
+pub mod timings;
+pub mod toolchain;
+pub mod tui;
+pub mod update;
+pub mod watch;
+pub mod work;
+pub mod workspace;
+
+pub use build_system::{BuildOptions, BuildSystem, Verb};
+pub use detect::{
+    detect, disable_detection_cache, disable_symlink_following, explain_build_dir, find_build_dir,
+    set_search_ceiling, Detection, SearchStep,
+};
+pub use plan::CommandPlan;
+pub use project::{Invocation, Project};
+
+/// Split the global job budget between the projects that build concurrently, so that
+/// together they never use more than `threads` jobs
+pub fn jobs_per_project(threads: usize, projects: usize, per_project: Option<usize>) -> usize {
+    per_project
+        .unwrap_or(threads / projects.max(1))
+        .clamp(1, threads.max(1))
+}
@@ -0,0 +1,84 @@
+use std::io::{Error, ErrorKind, Result};
+
+use crate::{
+    exec::{self, find_program},
+    CommandPlan,
+};
+
+/// Whether `size` is a byte count as systemd takes it, with an optional K, M, G or T suffix,
+/// or a percentage of the memory of the machine
+pub fn is_memory_size(size: &str) -> bool {
+    let digits = size.strip_suffix(['K', 'M', 'G', 'T', '%']).unwrap_or(size);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// The CPU quota as a percentage of one CPU, given as one or as a number of CPUs
+pub fn cpu_percent(quota: &str) -> Option<String> {
+    match quota.strip_suffix('%') {
+        Some(percent) => percent
+            .parse::<u32>()
+            .ok()
+            .map(|percent| percent.to_string()),
+        None => quota
+            .parse::<f64>()
+            .ok()
+            .filter(|cpus| *cpus > 0.0)
+            .map(|cpus| ((cpus * 100.0).round() as u32).to_string()),
+    }
+}
+
+/// Run `plan` in a transient systemd scope that holds the build to `max_memory`, like `8G`, and
+/// to `cpu_quota`, a number of CPUs or a percentage of one like `250%`. The kernel stops a build
+/// that goes over the memory limit instead of letting it take down the machine.
+pub fn wrap(
+    plan: CommandPlan,
+    max_memory: Option<&str>,
+    cpu_quota: Option<&str>,
+) -> Result<CommandPlan> {
+    if !cfg!(target_os = "linux") {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Resource limits need the cgroups of Linux",
+        ));
+    }
+    if find_program("systemd-run").is_none() {
+        return Err(exec::missing_tool("Resource limits need systemd-run"));
+    }
+    let mut command = CommandPlan::new("systemd-run");
+    command.arg("--scope").arg("--quiet");
+    // Unprivileged users get their scopes from their own systemd instance
+    if exec::user_id() != 0 {
+        command.arg("--user");
+    }
+    if let Some(size) = max_memory {
+        if !is_memory_size(size) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid memory size {}", size),
+            ));
+        }
+        // Without swap a build over the limit fails right away rather than crawling
+        command
+            .arg("--property")
+            .arg(format!("MemoryMax={}", size))
+            .arg("--property")
+            .arg("MemorySwapMax=0");
+    }
+    if let Some(quota) = cpu_quota {
+        let Some(percent) = cpu_percent(quota) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid CPU quota {}", quota),
+            ));
+        };
+        command
+            .arg("--property")
+            .arg(format!("CPUQuota={}%", percent));
+    }
+    command.arg("--").arg(plan.program).args(plan.args);
+    command.envs(plan.env);
+    if let Some(cwd) = plan.cwd {
+        command.current_dir(cwd);
+    }
+    Ok(command)
+}
@@ -0,0 +1,144 @@
+use std::{
+    env::{temp_dir, var_os},
+    fs::{create_dir_all, read_dir, read_to_string, remove_file, rename, File, TryLockError},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{Error, ErrorKind, Result, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A hold on a build directory that keeps other smartmake builds out of it until dropped
+#[derive(Debug)]
+pub struct BuildLock {
+    // The lock goes away with the file, also when smartmake is killed
+    _file: File,
+}
+
+// The directory smartmake keeps its locks in, in `$XDG_RUNTIME_DIR` or the temporary
+// directory
+fn runtime_dir() -> PathBuf {
+    match var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("smartmake"),
+        None => temp_dir().join(format!("smartmake-{}", crate::exec::user_id())),
+    }
+}
+
+// The directory of the lock files of the build directories
+fn lock_dir() -> PathBuf {
+    runtime_dir().join("locks")
+}
+
+// The directory with a file for each running build, holding the number of jobs it runs
+fn builds_dir() -> PathBuf {
+    runtime_dir().join("builds")
+}
+
+// Tells apart the builds one smartmake runs at once
+static BUILDS: AtomicUsize = AtomicUsize::new(0);
+
+/// A running build, whose jobs the builds that start after it on the machine leave to it
+/// until it is dropped
+#[derive(Debug)]
+pub struct JobShare {
+    // Locked for as long as the build runs, so that a killed build no longer counts
+    _file: File,
+    path: PathBuf,
+}
+
+impl JobShare {
+    /// Tell the builds that start from now on that this one runs `jobs` jobs
+    pub fn register(jobs: usize) -> Result<JobShare> {
+        let dir = builds_dir();
+        create_dir_all(&dir)?;
+        let name = format!(
+            "{}-{}",
+            process::id(),
+            BUILDS.fetch_add(1, Ordering::Relaxed)
+        );
+        // Locked before it is named, so that it never looks like it belongs to a dead build
+        let partial = dir.join(format!("{}.new", name));
+        let mut file = File::create(&partial)?;
+        file.lock()?;
+        write!(file, "{}", jobs)?;
+        let path = dir.join(name);
+        rename(&partial, &path)?;
+        Ok(JobShare { _file: file, path })
+    }
+}
+
+impl Drop for JobShare {
+    fn drop(&mut self) {
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// The number of jobs the other builds on the machine run, and how many builds they are,
+/// cleaning up after the ones that were killed
+pub fn running() -> (usize, usize) {
+    let Ok(entries) = read_dir(builds_dir()) else {
+        return (0, 0);
+    };
+    let (mut jobs, mut builds) = (0, 0);
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_some() {
+            continue;
+        }
+        let Ok(file) = File::open(&path) else {
+            continue;
+        };
+        match file.try_lock_shared() {
+            Err(TryLockError::WouldBlock) => {
+                jobs += read_to_string(&path)
+                    .ok()
+                    .and_then(|count| count.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                builds += 1;
+            }
+            Ok(()) => {
+                let _ = remove_file(&path);
+            }
+            Err(TryLockError::Error(_)) => {}
+        }
+    }
+    (jobs, builds)
+}
+
+/// The jobs a build that starts now gets of the `threads` of the machine: those the other
+/// builds leave free, but never less than an equal share with them
+pub fn share(threads: usize) -> usize {
+    let (jobs, builds) = running();
+    let fair = threads / (builds + 1);
+    threads.saturating_sub(jobs).max(fair).max(1)
+}
+
+impl BuildLock {
+    /// Lock `dir` for a build. If another build holds it, wait for that to finish if `wait`
+    /// is set, or else fail.
+    pub fn acquire(dir: &Path, wait: bool) -> Result<BuildLock> {
+        let locks = lock_dir();
+        create_dir_all(&locks)?;
+        let mut hasher = DefaultHasher::new();
+        dir.hash(&mut hasher);
+        let file = File::create(locks.join(format!("{:016x}.lock", hasher.finish())))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) if wait => {
+                crate::exec::message(&format!(
+                    "Waiting for another build in {} to finish",
+                    dir.display()
+                ));
+                file.lock()?;
+            }
+            Err(TryLockError::WouldBlock) => {
+                return Err(Error::new(
+                    ErrorKind::WouldBlock,
+                    format!("Another build in {} is running", dir.display()),
+                ))
+            }
+            Err(TryLockError::Error(error)) => return Err(error),
+        }
+        Ok(BuildLock { _file: file })
+    }
+}
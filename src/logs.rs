@@ -0,0 +1,115 @@
+use std::{
+    fs::{create_dir_all, read_dir, remove_file, File},
+    io::{BufWriter, Error, ErrorKind, Result, Write},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    daemon::exit_code,
+    exec::Stream,
+    history::{format_duration, path as history_path},
+    output::Sink,
+    CommandPlan,
+};
+
+/// How many logs are kept per project when the configuration does not say
+pub const KEEP_LOGS: usize = 10;
+
+/// The directory with the logs of the builds of the project in `root`, next to the history.
+/// It is named after the path of the project, with `%` for the separators.
+pub fn dir(root: &Path) -> Option<PathBuf> {
+    let name = root
+        .to_string_lossy()
+        .trim_start_matches('/')
+        .replace('/', "%");
+    Some(history_path()?.parent()?.join("logs").join(name))
+}
+
+// The logs in `dir`, oldest first
+fn logs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+    let mut logs = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "log") {
+            logs.push(path);
+        }
+    }
+    // Named after when the build started, with the same number of digits
+    logs.sort();
+    Ok(logs)
+}
+
+/// The log of the last build of the project in `root`, if one was kept
+pub fn latest(root: &Path) -> Result<Option<PathBuf>> {
+    match dir(root) {
+        Some(dir) => Ok(logs(&dir)?.pop()),
+        None => Ok(None),
+    }
+}
+
+/// Writes the output of a build to a new log of its project, and removes the oldest logs so
+/// that only `keep` remain
+pub struct Log {
+    file: BufWriter<File>,
+    path: PathBuf,
+}
+
+impl Log {
+    pub fn new(root: &Path, plan: &CommandPlan, keep: usize) -> Result<Log> {
+        let Some(dir) = dir(root) else {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "There is no home directory to keep the logs in",
+            ));
+        };
+        create_dir_all(&dir)?;
+        let old = logs(&dir)?;
+        for path in &old[..old.len().saturating_sub(keep.saturating_sub(1))] {
+            remove_file(path)?;
+            // Along with the environment `--capture-env` kept next to it
+            let _ = remove_file(path.with_extension("json"));
+        }
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = dir.join(format!("{:016}.log", started));
+        let mut file = BufWriter::new(File::create(&path)?);
+        writeln!(file, "$ {}", plan)?;
+        Ok(Log { file, path })
+    }
+
+    /// The file the log is written to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Sink for Log {
+    fn line(&mut self, stream: Stream, line: &str) -> Result<()> {
+        self.bytes(stream, line.as_bytes())
+    }
+
+    // As the build wrote it, so the log has the paths it named
+    fn bytes(&mut self, _stream: Stream, line: &[u8]) -> Result<()> {
+        self.file.write_all(line)?;
+        self.file.write_all(b"\n")
+    }
+
+    fn finished(&mut self, status: ExitStatus, duration: Duration) -> Result<()> {
+        writeln!(
+            self.file,
+            "Exited with code {} after {}",
+            exit_code(status),
+            format_duration(duration)
+        )?;
+        self.file.flush()
+    }
+}
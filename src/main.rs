@@ -1,129 +1,2570 @@
 use std::{
-    env::current_dir,
-    fs::{exists, read_dir},
-    io::Result,
-    os::unix::process::CommandExt,
+    env::{self, current_dir},
+    ffi::OsString,
+    fs,
+    io::{self, stdout, IsTerminal, Write},
+    panic,
     path::{Path, PathBuf},
-    process::Command,
-    thread::available_parallelism,
+    process::{exit, ExitStatus},
+    sync::{mpsc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+
+use smartmake::{
+    artifacts, branch,
+    cache::{CacheStats, CompilerCache},
+    capture::Capture,
+    cargo,
+    ci_cache::{self, Store},
+    cmake, codegen, compare, compdb,
+    config::{Config, PROJECT_CONFIG},
+    daemon::{self, exit_code},
+    debug, detach,
+    diagnostics::{Collector, Severity, WarningFilter},
+    disable_detection_cache, disable_symlink_following,
+    distributed::Distributor,
+    exec::{self, Event, Execution, ForegroundGuard, Stream},
+    explain::Explained,
+    explain_build_dir, history,
+    history::{format_bytes, format_duration},
+    hooks::{self, Stage},
+    jobs_per_project,
+    jobserver::{self, Jobserver},
+    lock::{BuildLock, JobShare},
+    logs::{self, Log},
+    matrix, notify,
+    output::{
+        self, Filtered, FilteredLines, FirstError, GithubAnnotations, GitlabReport, JsonEvents,
+        LspDiagnostics, Quickfix, Sink, Summary, Terminal, TerminalProgress, GITLAB_REPORT,
+    },
+    picker, polyglot,
+    progress::ProgressTracker,
+    registry, remote,
+    retry::{self, FailureCollector},
+    sandbox::Sandbox,
+    set_search_ceiling, single_file, targets,
+    telemetry::{self, Trace},
+    timings::{self, Timed},
+    tui, update,
+    watch::{FileWatcher, DEFAULT_IGNORE},
+    work::{self, Unit, WorkCounter},
+    workspace::{self, Subproject, WorkspaceFile},
+    BuildSystem, CommandPlan, Detection, Invocation, Project, Verb,
+};
 
 // A program to build your project with the build system that you use
 #[derive(Parser)]
 #[command(version, about)]
 struct Args {
-    /// Which build-system target to build
-    target: Option<String>,
+    #[command(subcommand)]
+    action: Option<Action>,
+    /// Which build-system targets to build
+    targets: Vec<String>,
     /// The number of threads to build with
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     threads: Option<usize>,
+    /// The number of threads each project builds with when several projects are built at once
+    #[arg(long, global = true)]
+    jobs_per_project: Option<usize>,
+    /// The configured profile to build with, also passed to custom build systems as `{profile}`
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// Run the compilers through a compiler cache and report how often it hit
+    #[arg(long, global = true, value_enum)]
+    cache: Option<CompilerCache>,
+    /// Spread the compilations over a build farm with distcc or icecc, with as many threads as
+    /// it has room for
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        value_name = "TOOL",
+        require_equals = true
+    )]
+    distributed: Option<Option<Distributor>>,
+    /// Build in a container of this image, with the project mounted into it
+    #[arg(long, global = true, value_name = "IMAGE")]
+    container: Option<String>,
+    /// Stop the build if it uses more memory than this, like 8G, by running it in a cgroup
+    #[arg(long, global = true, value_name = "SIZE")]
+    max_memory: Option<String>,
+    /// Hold the build to this many CPUs, or a percentage of one like 250%, with a cgroup
+    #[arg(long, global = true, value_name = "CPUS")]
+    cpu_quota: Option<String>,
+    /// Build in a bubblewrap sandbox that can write only to the project and its build
+    /// directory, and with `offline` cannot reach the network either
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        value_name = "MODE",
+        require_equals = true
+    )]
+    sandbox: Option<Option<Sandbox>>,
+    /// Host a make jobserver for the build, so that the builds it starts in turn, like
+    /// recursive makes and nested `sm` builds, share its threads
+    #[arg(long, global = true)]
+    jobserver: bool,
+    /// Build for another platform: a target triple for cargo, or a platform configured under
+    /// `[cross.<name>]`
+    #[arg(long = "target", global = true, value_name = "TRIPLE")]
+    cross_target: Option<String>,
+    /// Configure CMake projects without a build directory with this generator, like Ninja or
+    /// "Unix Makefiles"
+    #[arg(long, global = true, value_name = "GENERATOR")]
+    cmake_generator: Option<String>,
+    /// Build the project registered with `sm register` under this name, from wherever you are
+    #[arg(short = 'P', long, global = true, value_name = "NAME")]
+    project: Option<String>,
+    /// Build in this directory instead of the detected one, configuring it first if it is a new
+    /// build directory of a CMake project
+    #[arg(long, global = true, value_name = "PATH")]
+    build_dir: Option<PathBuf>,
+    /// Build this package of the Cargo workspace instead of the one in the current directory;
+    /// `sm packages` lists them
+    #[arg(short, long = "package", global = true, value_name = "NAME")]
+    packages: Vec<String>,
+    /// Build every package of the Cargo workspace
+    #[arg(long, global = true, conflicts_with = "packages")]
+    workspace: bool,
+    /// Build with these Cargo features, separated by commas, or what the configuration says
+    /// they mean for other build systems
+    #[arg(
+        short = 'F',
+        long,
+        global = true,
+        value_name = "FEATURES",
+        value_delimiter = ','
+    )]
+    features: Vec<String>,
+    /// Build with every Cargo feature
+    #[arg(long, global = true)]
+    all_features: bool,
+    /// Build without the default Cargo features
+    #[arg(long, global = true)]
+    no_default_features: bool,
+    /// Configure CMake build directories again without asking when they cannot be built as
+    /// they are, like after the checkout moved
+    #[arg(long, global = true)]
+    reconfigure: bool,
+    /// Show a progress bar with the time remaining, estimated from earlier builds
+    #[arg(long, global = true)]
+    progress: bool,
+    /// Do not print a summary of the errors and warnings after the build
+    #[arg(long, global = true)]
+    no_summary: bool,
+    /// Stop the build once it reported its first error, and show nothing after it
+    #[arg(long, global = true)]
+    first_error_only: bool,
+    /// When the build fails, build only the targets that failed, or run only the tests that
+    /// failed, once more, and report the ones that pass then as flaky
+    #[arg(long, global = true)]
+    retry_failed_targets: bool,
+    /// Record the command, the environment, the tool versions and the revision of the build
+    /// next to its log, for `sm replay`
+    #[arg(long, global = true)]
+    capture_env: bool,
+    /// Send a trace of the build, with spans for finding, configuring and building the project
+    /// and for each target ninja built, to this OTLP/HTTP endpoint
+    #[arg(long, global = true, value_name = "URL")]
+    otel_endpoint: Option<String>,
+    /// Show a desktop notification when the build finishes
+    #[arg(long, global = true)]
+    notify: bool,
+    /// Capture the output of the build through pipes instead of a pseudo-terminal, which
+    /// leaves out the colors and progress bars of most tools
+    #[arg(long, global = true)]
+    no_pty: bool,
+    /// Wait for another build in the same build directory to finish first (the default)
+    #[arg(long, global = true, overrides_with = "no_wait")]
+    wait: bool,
+    /// Fail instead of waiting when another build in the same build directory is running
+    #[arg(long, global = true)]
+    no_wait: bool,
+    /// Stop the build once it ran this long, and exit with 124
+    #[arg(long, global = true, value_name = "SECONDS")]
+    timeout: Option<u64>,
+    /// Build in the background, with the output going to a file, and return right away; `sm
+    /// status` shows how it goes and `sm attach` follows its output
+    #[arg(long, global = true)]
+    detach: bool,
+    /// Look for the build system again instead of using what was found here before
+    #[arg(long, global = true)]
+    no_cache: bool,
+    /// Walk up the path the current directory was reached through, symbolic links and all,
+    /// instead of the directories they lead to, and leave out linked build directories
+    #[arg(long, global = true)]
+    no_follow_symlinks: bool,
+    /// Log what smartmake does to standard error: once for the decisions it takes, the
+    /// commands it runs and how long each phase took, twice for every directory it looked in
+    /// and the environment of the build as well
+    #[arg(short = 'v', long = "debug", global = true, action = clap::ArgAction::Count)]
+    debug: u8,
+    /// Append the log to this file instead
+    #[arg(long, global = true, value_name = "PATH")]
+    debug_file: Option<PathBuf>,
+    /// Let the running `sm daemon` carry out the build
+    #[arg(long, global = true, conflicts_with = "remote")]
+    daemon: bool,
+    /// Build on this machine over SSH, given as `user@host` or a remote from the configuration
+    #[arg(long, global = true, value_name = "HOST")]
+    remote: Option<String>,
+    /// How to report the build
+    #[arg(long, global = true, value_enum, default_value_t = Output::Text)]
+    output: Output,
+    /// Report errors and warnings to a CI service, so they show up on the lines they are about
+    #[arg(long, global = true, value_enum)]
+    ci: Option<Ci>,
+    /// Write the errors and warnings in a format editors can jump to
+    #[arg(long, global = true, value_enum)]
+    diagnostics: Option<DiagnosticsFormat>,
+    /// The file to write `--diagnostics` to instead of stdout
+    #[arg(long, global = true, requires = "diagnostics")]
+    diagnostics_file: Option<PathBuf>,
 }
 
-#[derive(Debug)]
-enum BuildProgram {
-    Make,
-    Ninja,
-    Cargo,
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum GraphFormat {
+    /// A Graphviz digraph, for `dot -Tsvg`
+    Dot,
+    /// The projects with their build systems and dependencies
+    Json,
 }
 
-impl BuildProgram {
-    fn from_filename(s: &str) -> Option<BuildProgram> {
-        match s {
-            "makefile" | "Makefile" | "GNUmakefile" => Some(BuildProgram::Make),
-            "build.ninja" => Some(BuildProgram::Ninja),
-            "Cargo.toml" => Some(BuildProgram::Cargo),
-            _ => None,
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum TargetGraphFormat {
+    /// An indented tree, like `cargo tree`
+    Text,
+    /// A Graphviz digraph, for `dot -Tsvg`
+    Dot,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Then {
+    /// Run the tests after each build that succeeded
+    Test,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum CacheOperation {
+    /// Archive the build outputs into the store
+    Save,
+    /// Unpack the archives of the key from the store, if there are any
+    Restore,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum DiagnosticsFormat {
+    /// One `file:line:column: error: message` line each, for Vim's quickfix list
+    Quickfix,
+    /// A JSON array of LSP `publishDiagnostics` parameters, one for each file
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Ci {
+    /// As workflow commands that GitHub Actions turns into annotations, on stdout or with
+    /// `--output json` on stderr
+    Github,
+    /// As a code quality report in gl-code-quality-report.json
+    Gitlab,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Output {
+    /// The output of the build, as it prints it
+    Text,
+    /// One JSON object per line for each event of the build, for editors and scripts
+    Json,
+}
+
+#[derive(Subcommand)]
+enum ConfigOperation {
+    /// Check the configuration files that apply here for what does not parse and values that
+    /// cannot be used, telling the line and column of each
+    Check,
+    /// Print the configuration files that apply here, in the order they are layered
+    Show {
+        /// Print the configuration they add up to instead, with the flags given applied
+        #[arg(long)]
+        resolved: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum Action {
+    /// Build and run the tests
+    Test {
+        /// Which build-system targets to test
+        targets: Vec<String>,
+    },
+    /// Remove the build outputs
+    Clean {
+        /// Which build-system targets to clean
+        targets: Vec<String>,
+    },
+    /// Rebuild whenever a file in the project changes
+    Watch {
+        /// Which build-system targets to build
+        targets: Vec<String>,
+        /// Patterns of file and directory names whose changes do not trigger a rebuild
+        #[arg(short, long)]
+        ignore: Vec<String>,
+        /// How long to wait for changes to settle before rebuilding, in milliseconds
+        #[arg(short, long)]
+        debounce: Option<u64>,
+        /// Keep the output of previous builds on the screen
+        #[arg(long)]
+        no_clear: bool,
+        /// What to do after each build that succeeded, like running the tests for a TDD loop
+        #[arg(long, value_enum)]
+        then: Option<Then>,
+    },
+    /// Build in a live dashboard with progress, diagnostics counts and keys to rebuild
+    Tui {
+        /// Which build-system targets to build
+        targets: Vec<String>,
+    },
+    /// Pick the targets to build from a list of them that narrows down as you type, or the
+    /// packages with cargo
+    Pick,
+    /// Print the files the build produces, building first with cargo, which only tells then
+    Artifacts {
+        /// Which build-system targets to build
+        targets: Vec<String>,
+    },
+    /// Show how long builds took and how often they failed, per project
+    Stats,
+    /// List the packages of the Cargo workspace, one per line, for `--package` and shell
+    /// completions
+    Packages,
+    /// Register the project in a directory under a name, to build it from anywhere with `-P`
+    Register {
+        name: String,
+        /// The directory of the project, or the current one
+        path: Option<PathBuf>,
+    },
+    /// List the projects registered with `sm register`
+    Projects,
+    /// Show the builds of the project started with `--detach`, and how far they got
+    Status,
+    /// Follow the output of a build started with `--detach` until it finished, and exit with
+    /// its exit code
+    Attach {
+        /// The build to follow, as `sm status` lists it, or the last one
+        id: Option<u32>,
+    },
+    /// Run a build started with `--detach` and record how it exited
+    #[command(hide = true)]
+    Supervise {
+        /// The file the output goes to, which the state of the build is kept next to
+        file: PathBuf,
+        #[arg(last = true)]
+        args: Vec<OsString>,
+    },
+    /// Replace this binary with the latest release, if there is a newer one
+    SelfUpdate,
+    /// Run a build captured with `--capture-env` again, with the same command in the same
+    /// environment, after telling what changed since
+    Replay {
+        /// The capture, next to the log of the build
+        capture: PathBuf,
+    },
+    /// Build, and tell which targets were rebuilt and which of their inputs changed, as ninja,
+    /// make and cargo explain it
+    ExplainRebuild {
+        /// Which build-system targets to build
+        targets: Vec<String>,
+    },
+    /// Build, and tell which targets took longest, from the log of ninja, the report of
+    /// `cargo build --timings` or when make started each with `--trace`
+    Timings {
+        /// Which build-system targets to build
+        targets: Vec<String>,
+        /// How many of the slowest targets to show
+        #[arg(short = 'n', long, default_value_t = 10)]
+        top: usize,
+        /// Write the timings of every target to a JSON file
+        #[arg(long, value_name = "FILE")]
+        json: Option<PathBuf>,
+        /// Write the timeline of the build to an HTML file
+        #[arg(long, value_name = "FILE")]
+        html: Option<PathBuf>,
+    },
+    /// Download what the build needs without building, with `cargo fetch` or the `fetch`
+    /// command of a build system in the configuration, so that the build can run offline
+    Fetch,
+    /// Tell how the build system was found: every directory looked in, the marker files in
+    /// each, which one was chosen and why, and what the configuration and the flags changed
+    Explain,
+    /// Check the configuration, or show it
+    Config {
+        #[command(subcommand)]
+        operation: ConfigOperation,
+    },
+    /// Show the output of the last build of the project again
+    Last {
+        /// Show only the errors, each with the lines that belong to it
+        #[arg(short, long)]
+        errors_only: bool,
+    },
+    /// Build several times and report how long the builds took
+    TimeBuild {
+        /// Which build-system targets to build
+        targets: Vec<String>,
+        /// How many times to build
+        #[arg(short = 'n', long, default_value_t = 5)]
+        runs: usize,
+        /// Clean before each build, to time full builds
+        #[arg(long)]
+        clean: bool,
+    },
+    /// Build two git revisions from scratch with the same settings, each in a temporary
+    /// worktree, and compare how long the builds and each of their targets took
+    Compare {
+        /// The revisions, as `A..B`, where `A..` compares `A` with `HEAD`
+        range: String,
+        /// Which build-system targets to build
+        targets: Vec<String>,
+        /// How many of the targets whose times changed most to show
+        #[arg(short = 'n', long, default_value_t = 10)]
+        top: usize,
+    },
+    /// Build every project in this directory and the ones below it
+    All {
+        /// Which build-system targets to build in each project
+        targets: Vec<String>,
+        /// How many projects to build at once, splitting the threads between them
+        #[arg(short, long, default_value_t = 1)]
+        concurrency: usize,
+        /// Build the remaining projects after one failed
+        #[arg(short, long)]
+        keep_going: bool,
+        /// Build only the projects with files that changed since REF (HEAD by default), and
+        /// the ones that depend on them
+        #[arg(long, value_name = "REF", num_args = 0..=1, require_equals = true)]
+        changed_only: Option<Option<String>>,
+        /// Show the output of each project all at once when it finishes, instead of as it
+        /// comes with the name of the project in front of each line
+        #[arg(long)]
+        grouped: bool,
+    },
+    /// Build each configuration of the build matrix in the configuration
+    Matrix {
+        /// Which configurations to build, or all of them if none are given
+        names: Vec<String>,
+        /// Build the remaining configurations after one failed
+        #[arg(short, long)]
+        keep_going: bool,
+    },
+    /// Print the projects `sm all` builds and the dependencies between them
+    Graph {
+        #[arg(short, long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+    /// Print what a target of the build depends on, as ninja, make or cargo tell
+    GraphTargets {
+        /// The target, or the package for cargo
+        target: String,
+        /// Print what depends on the target instead, like what rebuilds when a header changes
+        #[arg(short, long)]
+        dependents: bool,
+        #[arg(short, long, value_enum, default_value_t = TargetGraphFormat::Text)]
+        format: TargetGraphFormat,
+    },
+    /// Write a compile_commands.json for the project, or the merged one of several projects
+    Compdb {
+        /// The projects to include, or the current one if none are given
+        projects: Vec<PathBuf>,
+        /// Where to write it, instead of the root of the current project
+        #[arg(short = 'o', long)]
+        file: Option<PathBuf>,
+    },
+    /// Save the build outputs and the caches of the build tools of the projects here and
+    /// below for the next CI run, or restore them from the last one
+    Cache {
+        operation: CacheOperation,
+        /// What the cache is for, like the hash of the lock files and the platform
+        #[arg(long)]
+        key: String,
+        /// The directory or `s3://bucket/prefix` URL to keep the cache in, instead of the
+        /// configured one
+        #[arg(long)]
+        store: Option<String>,
+    },
+    /// Serve build requests from `sm --daemon`, one build at a time
+    Daemon {
+        /// The socket to listen on
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+}
+
+// How the build is run and shown
+#[derive(Clone)]
+struct Report {
+    // Whether to wait for other builds in the same directory rather than fail
+    wait: bool,
+    // Whether captured output comes through a pseudo-terminal
+    pty: bool,
+    // Whether to show the progress in the tab or taskbar of the terminal and its title
+    terminal_progress: bool,
+    // How long a build has to take for a desktop notification when it finishes, if it gets one
+    notify: Option<Duration>,
+    progress: bool,
+    summary: bool,
+    first_error_only: bool,
+    // Whether to build or run what failed again, once, to tell flaky targets and tests
+    retry_failed: bool,
+    capture_env: bool,
+    // The OTLP endpoint to send the trace of the build to
+    otel: Option<String>,
+    // When looking for the project started, and how long it took
+    detected: (SystemTime, Duration),
+    output: Output,
+    ci: Option<Ci>,
+    diagnostics: Option<DiagnosticsFormat>,
+    diagnostics_file: Option<PathBuf>,
+    // Where the timings of the targets go, when they are recorded
+    timings: timings::Reports,
+}
+
+// Keep other smartmake builds out of the build directory of `project`, and leave the jobs
+// of the build to it, until dropped
+fn lock(
+    project: &Project,
+    invocation: &Invocation,
+    report: &Report,
+) -> io::Result<(Option<BuildLock>, JobShare)> {
+    let lock = match &invocation.detection(project)? {
+        Some(detection) => Some(BuildLock::acquire(&detection.directory, report.wait)?),
+        None => None,
+    };
+    Ok((lock, JobShare::register(invocation.threads(project)?)?))
+}
+
+// Tell which targets or tests of `project` were flaky, and how often they were before
+fn report_flaky(project: &Project, flaky: &[String]) -> io::Result<()> {
+    let before = history::flaky(&history::load()?, &project.root());
+    for name in flaky {
+        match before.get(name) {
+            None => eprintln!("Flaky: {} failed, and passed when retried", name),
+            Some(1) => eprintln!(
+                "Flaky: {} failed, and passed when retried, as it did once before",
+                name
+            ),
+            Some(count) => eprintln!(
+                "Flaky: {} failed, and passed when retried, as it did {} times before",
+                name, count
+            ),
         }
     }
+    Ok(())
+}
+
+// Print how many of the compilations of the build the compiler cache answered
+fn report_cache(cache: CompilerCache, before: CacheStats) {
+    let Some(after) = cache.stats() else {
+        return;
+    };
+    let stats = after.since(before);
+    if let Some(rate) = stats.hit_rate() {
+        eprintln!(
+            "{}: {} hits, {} misses ({:.0}%)",
+            cache.name(),
+            stats.hits,
+            stats.misses,
+            rate * 100.0
+        );
+    }
+}
+
+// Tell the CPU time the build took, how busy it kept the CPUs while it ran for `wall`, and the
+// most memory one of its processes used
+fn report_usage(cpu: Duration, wall: Duration, max_rss: u64) {
+    let utilization = cpu.as_secs_f64() / wall.as_secs_f64().max(0.001) * 100.0;
+    let mut usage = format!(
+        "CPU time: {} ({:.0}% of {})",
+        format_duration(cpu),
+        utilization,
+        format_duration(wall)
+    );
+    if max_rss > 0 {
+        usage.push_str(&format!(", peak memory: {}", format_bytes(max_rss)));
+    }
+    eprintln!("{}", usage);
+}
 
-    fn build_make_command<P: AsRef<Path>>(
-        threads: usize,
-        directory: P,
-        target: Option<&str>,
-    ) -> Command {
-        let mut command = Command::new("make");
-        command.arg("-j").arg(threads.to_string());
-        command.arg("-C").arg(directory.as_ref().as_os_str());
-        if let Some(selected_target) = target {
-            command.arg(selected_target);
+// Run the build in the foreground and add it to the history
+fn run(
+    project: &Project,
+    invocation: &Invocation,
+    plan: &CommandPlan,
+    report: &Report,
+) -> io::Result<ExitStatus> {
+    let started = SystemTime::now();
+    let timer = Instant::now();
+    let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+    let log = log(project, plan);
+    let log_path = log.as_ref().map(|log| log.path().to_path_buf());
+    if report.summary && report.output == Output::Text {
+        sinks.push(Box::new(Summary::new(plan.to_string(), log_path.clone())));
+    }
+    match report.ci {
+        Some(Ci::Github) => sinks.push(Box::new(GithubAnnotations::new(
+            report.output == Output::Json,
+        ))),
+        Some(Ci::Gitlab) => sinks.push(Box::new(GitlabReport::new(GITLAB_REPORT))),
+        None => {}
+    }
+    let base = plan.cwd.clone().unwrap_or_else(|| project.cwd.clone());
+    let file = report.diagnostics_file.clone();
+    match report.diagnostics {
+        Some(DiagnosticsFormat::Quickfix) => sinks.push(Box::new(Quickfix::new(file))),
+        Some(DiagnosticsFormat::Json) => sinks.push(Box::new(LspDiagnostics::new(file, base))),
+        None => {}
+    }
+    sinks.extend(log.map(|log| Box::new(log) as Box<dyn Sink>));
+    let unit = invocation
+        .detection(project)?
+        .filter(|_| report.summary && report.output == Output::Text)
+        .as_ref()
+        .and_then(Unit::of);
+    let work = unit.map(|unit| {
+        let (counter, count) = WorkCounter::new(unit);
+        sinks.push(Box::new(counter));
+        count
+    });
+    let failures = report.retry_failed.then(|| {
+        let (collector, failures) = FailureCollector::new();
+        sinks.push(Box::new(collector));
+        failures
+    });
+    let expected = match report.progress || report.terminal_progress {
+        true => history::expected_duration(&history::load()?, project, invocation),
+        false => None,
+    };
+    let terminal_progress = report.terminal_progress && report.output == Output::Text;
+    let filter = WarningFilter::new(&project.config.warnings)?;
+    match report.output {
+        Output::Json => {
+            let mut events = JsonEvents::default();
+            events.started(plan)?;
+            sinks.insert(0, Box::new(events));
+        }
+        // Without anything to do with the output the build writes to the terminal itself
+        Output::Text
+            if report.progress
+                || report.first_error_only
+                || invocation.explain
+                || invocation.timings
+                || filter.is_some()
+                || !project.config.output_filters.is_empty()
+                || !sinks.is_empty() =>
+        {
+            let progress = report.progress.then(|| ProgressTracker::new(expected));
+            sinks.insert(0, Box::new(Terminal::new(progress)));
+            if terminal_progress {
+                let name = project_name(project);
+                sinks.push(Box::new(TerminalProgress::new(name, expected)));
+            }
+        }
+        Output::Text => {}
+    }
+    if let Some(filter) = filter {
+        sinks = vec![Box::new(Filtered::new(filter, sinks))];
+    }
+    let filters = &project.config.output_filters;
+    // The build only writes colors to a terminal, so the highlighting goes with them
+    let color = report.pty || (stdout().is_terminal() && report.output == Output::Text);
+    if !filters.is_empty() {
+        sinks = vec![Box::new(FilteredLines::new(filters, color, sinks)?)];
+    }
+    if report.first_error_only {
+        sinks = vec![Box::new(FirstError::new(sinks))];
+    }
+    if let Some(detection) = invocation
+        .detection(project)?
+        .filter(|_| invocation.explain)
+    {
+        sinks = vec![Box::new(Explained::new(detection.build_system, sinks))];
+    }
+    if let Some(detection) = invocation
+        .detection(project)?
+        .filter(|_| invocation.timings)
+    {
+        sinks = vec![Box::new(Timed::new(
+            detection.build_system,
+            detection.directory,
+            report.timings.clone(),
+            sinks,
+        ))];
+    }
+    let _lock = lock(project, invocation, report)?;
+    let mut trace = report
+        .otel
+        .as_ref()
+        .map(|_| Trace::new("sm", report.detected.0));
+    let detection = invocation.detection(project)?;
+    if let Some(trace) = &mut trace {
+        let (found, duration) = report.detected;
+        let span = trace.span(None, "detect", found, duration);
+        if let Some(detection) = &detection {
+            span.attribute("build_system", detection.build_system.name());
+        }
+    }
+    debug!(
+        "time",
+        "Finding the project took {}",
+        format_duration(report.detected.1)
+    );
+    let hooked = invocation.verb != Verb::Clean;
+    if hooked {
+        let preparing = Instant::now();
+        branch::check(project, invocation)?;
+        hooks::run(project, Stage::PreBuild, None)?;
+        codegen::run(project)?;
+        debug!(
+            "time",
+            "The pre-build hooks and code generators took {}",
+            format_duration(preparing.elapsed())
+        );
+    }
+    let fetch_plan = match hooked && project.config.prefetch.unwrap_or(false) {
+        true => invocation.fetch_plan(project)?,
+        false => None,
+    };
+    if let Some(fetch_plan) = &fetch_plan {
+        let fetching = SystemTime::now();
+        let duration = fetch(fetch_plan, report.output)?;
+        if let Some(trace) = &mut trace {
+            trace.span(None, "fetch", fetching, duration);
+        }
+    }
+    // Cargo then has everything, and is kept from looking for more
+    let offline = fetch_plan
+        .filter(|_| {
+            detection.as_ref().map(|detection| &detection.build_system) == Some(&BuildSystem::Cargo)
+        })
+        .map(|_| {
+            let mut plan = plan.clone();
+            plan.envs([("CARGO_NET_OFFLINE", "true")]);
+            plan
+        });
+    let plan = offline.as_ref().unwrap_or(plan);
+    let (configuring, configure_timer) = (SystemTime::now(), Instant::now());
+    invocation.configure(project)?;
+    debug!(
+        "time",
+        "Configuring took {}",
+        format_duration(configure_timer.elapsed())
+    );
+    if let Some(trace) = &mut trace {
+        trace.span(None, "configure", configuring, configure_timer.elapsed());
+    }
+    // Kept open until the build is done, for it and the builds it starts to take their jobs from
+    let host = invocation.jobserver
+        && jobserver::inherited().is_none()
+        && invocation.container(project).is_none();
+    let jobserver = match host {
+        true => Some(Jobserver::new(invocation.threads(project)?)?),
+        false => None,
+    };
+    let hosted = jobserver.as_ref().map(|jobserver| {
+        let mut plan = plan.clone();
+        plan.envs([("MAKEFLAGS", jobserver.makeflags())]);
+        plan
+    });
+    let plan = hosted.as_ref().unwrap_or(plan);
+    if report.capture_env {
+        match &log_path {
+            Some(path) => {
+                let path = path.with_extension("json");
+                let capture = Capture::new(project, plan)?;
+                capture.save(&path)?;
+                eprintln!("Captured the build environment in {}", path.display());
+                if !capture.not_utf8.is_empty() {
+                    eprintln!(
+                        "Left out {}, which {} not UTF-8",
+                        capture.not_utf8.join(", "),
+                        match capture.not_utf8.len() {
+                            1 => "is",
+                            _ => "are",
+                        }
+                    );
+                }
+            }
+            None => eprintln!(
+                "Not capturing the build environment, which is kept next to the log, with \
+                 keep_logs = 0"
+            ),
+        }
+    }
+    let cache = invocation.cache(project);
+    let before = cache.and_then(CompilerCache::stats);
+    // The targets ninja builds are logged with their times, after the ones of earlier builds
+    let ninja_log = detection
+        .as_ref()
+        .map(|detection| &detection.directory)
+        .filter(|dir| trace.is_some() && dir.join("build.ninja").exists())
+        .map(|dir| (dir.clone(), timings::ninja_log_len(dir)));
+    let (building, building_timer) = (SystemTime::now(), Instant::now());
+    let (user_before, system_before) = exec::children_cpu_time();
+    exec::reset_max_rss();
+    let status = match sinks.is_empty() {
+        // Without reading the output only the start and the end can be shown
+        true => {
+            let mut progress =
+                terminal_progress.then(|| TerminalProgress::new(project_name(project), None));
+            if let Some(progress) = &mut progress {
+                progress.tick()?;
+            }
+            let status = exec::run(plan.command())?;
+            if let Some(progress) = &mut progress {
+                progress.finished(status, timer.elapsed())?;
+            }
+            status
+        }
+        false => output::run(plan.command(), &mut sinks, report.pty)?,
+    };
+    let mut flaky = Vec::new();
+    let status = match failures {
+        Some(failures) if !status.success() => {
+            let failures = failures.take();
+            match retry::plan(project, invocation, plan, &failures)? {
+                Some(retry) => {
+                    eprintln!();
+                    eprintln!("Retrying what failed: {}", retry);
+                    let retried = exec::run(retry.command())?;
+                    if retried.success() {
+                        flaky = failures.retried().to_vec();
+                        report_flaky(project, &flaky)?;
+                    }
+                    retried
+                }
+                None => status,
+            }
+        }
+        _ => status,
+    };
+    debug!(
+        "time",
+        "The build took {} and ended with {}",
+        format_duration(building_timer.elapsed()),
+        status
+    );
+    let (user_after, system_after) = exec::children_cpu_time();
+    let (user, system) = (user_after - user_before, system_after - system_before);
+    let max_rss = exec::max_rss();
+    let work = work.map(|count| count.get());
+    if let (Some(unit), Some(count)) = (unit, work) {
+        if status.success() && count > 0 {
+            let previous = history::previous_work(&history::load()?, project, invocation);
+            eprintln!("{}", work::report(unit, count, previous));
         }
-        command
     }
+    if let (Some(cache), Some(before)) = (cache, before) {
+        report_cache(cache, before);
+    }
+    if report.summary && report.output == Output::Text {
+        report_usage(user + system, building_timer.elapsed(), max_rss);
+    }
+    let duration = timer.elapsed();
+    let mut record = history::Record::new(project, invocation, started, duration, status);
+    record.user = Some(user.as_secs_f64());
+    record.system = Some(system.as_secs_f64());
+    record.max_rss = Some(max_rss).filter(|max_rss| *max_rss > 0);
+    record.work = work;
+    record.flaky = flaky;
+    history::save(&record);
+    if hooked {
+        hooks::run(project, Stage::PostBuild, Some(exit_code(status)))?;
+    }
+    if let (Some(mut trace), Some(endpoint)) = (trace, &report.otel) {
+        let span = trace.span(None, "build", building, building_timer.elapsed());
+        span.attribute("command", plan.to_string())
+            .attribute("exit_code", exit_code(status));
+        if !status.success() {
+            span.failed();
+        }
+        let build = span.id().to_string();
+        if let Some((dir, len)) = &ninja_log {
+            for timing in timings::ninja_log(dir, *len) {
+                trace.span(
+                    Some(&build),
+                    &timing.target,
+                    building + timing.start,
+                    timing.duration,
+                );
+            }
+        }
+        let root = trace.root();
+        root.attribute("project", project.root().display().to_string())
+            .attribute("verb", invocation.verb.name())
+            .attribute("targets", invocation.targets(project).join(" "));
+        if !status.success() {
+            root.failed();
+        }
+        if let Err(error) = trace.export(endpoint) {
+            eprintln!("Cannot send the trace of the build: {}", error);
+        }
+    }
+    if report.notify.is_some_and(|after| duration >= after) {
+        notify_finished(&project_name(project), exit_code(status), duration);
+    }
+    Ok(status)
+}
+
+// A new log for the build of `project` with `plan`, unless logs are turned off. Builds go on
+// without one if it cannot be written.
+fn log(project: &Project, plan: &CommandPlan) -> Option<Log> {
+    let keep = project.config.keep_logs.unwrap_or(logs::KEEP_LOGS);
+    if keep == 0 {
+        return None;
+    }
+    Log::new(&project.root(), plan, keep)
+        .inspect_err(|error| eprintln!("Cannot keep a log of the build: {}", error))
+        .ok()
+}
 
-    fn build_ninja_command<P: AsRef<Path>>(
-        threads: usize,
-        directory: P,
-        target: Option<&str>,
-    ) -> Command {
-        let mut command = Command::new("ninja");
-        command.arg("-j").arg(threads.to_string());
-        command.arg("-C").arg(directory.as_ref().as_os_str());
-        if let Some(selected_target) = target {
-            command.arg(selected_target);
+// The name of the directory of `project`, to tell it apart from others
+fn project_name(project: &Project) -> String {
+    let root = project.root();
+    match root.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => root.display().to_string(),
+    }
+}
+
+// Show a desktop notification about how the build of `name` went
+fn notify_finished(name: &str, code: i32, duration: Duration) {
+    let duration = format_duration(duration);
+    match code {
+        0 => notify::send("Build succeeded", &format!("{} in {}", name, duration)),
+        code => notify::send(
+            "Build failed",
+            &format!("{} with exit code {} after {}", name, code, duration),
+        ),
+    }
+}
+
+// Print how the last build or test run went, on a green or red background on a terminal
+fn banner(message: &str, success: bool) {
+    match stdout().is_terminal() {
+        true => println!(
+            "\x1b[1;97;{}m {} \x1b[0m",
+            if success { 42 } else { 41 },
+            message
+        ),
+        false => println!("{}", message),
+    }
+}
+
+// Run the build with `build` and every time the project changes afterwards, until
+// interrupted, and run the tests with `test`, if given, after each build that succeeded
+fn watch(
+    project: &Project,
+    build: (&Invocation, &CommandPlan),
+    test: Option<(&Invocation, &CommandPlan)>,
+    report: &Report,
+    ignore: Vec<String>,
+    debounce: Duration,
+    clear: bool,
+) -> io::Result<()> {
+    // What the build writes would start it over and over again: its build directory, unless
+    // it builds in the project itself, or the program a single source file compiles to
+    let root = project.root();
+    let written = match build.0.detection(project)? {
+        Some(detection) => Some(detection.directory).filter(|dir| !root.starts_with(dir)),
+        None => single_file::find(&project.cwd).map(|single| single.program),
+    };
+    let watcher = FileWatcher::new(&root, ignore, written.into_iter().collect(), debounce)?;
+    loop {
+        if clear && report.output == Output::Text {
+            print!("\x1b[2J\x1b[H");
+            stdout().flush()?;
+        }
+        let mut status = run(project, build.0, build.1, report)?;
+        let mut tested = false;
+        if let Some((invocation, plan)) = test.filter(|_| status.success()) {
+            if exec::interrupted().is_none() {
+                status = run(project, invocation, plan, report)?;
+                tested = true;
+            }
+        }
+        // Ctrl-C stops the watching as well as the build
+        if exec::interrupted().is_some() {
+            return Ok(());
+        }
+        let message = match (tested, status.success()) {
+            (false, true) => "Build succeeded".to_string(),
+            (false, false) => format!("Build failed with {}", status),
+            (true, true) => "Tests passed".to_string(),
+            (true, false) => format!("Tests failed with {}", status),
+        };
+        if report.output == Output::Json {
+            // The finished event already reports how the build went
+        } else {
+            banner(
+                &format!("{}, watching for changes", message),
+                status.success(),
+            );
+        }
+        if !watcher.has_pending_changes()? {
+            watcher.wait_for_change()?;
         }
-        command
     }
+}
 
-    fn build_cargo_command() -> Command {
-        let mut command = Command::new("cargo");
-        command.arg("build");
-        command
+// Download what the build needs with `plan`, the command of its build system that only does
+// that, and tell how long it took apart from the build
+fn fetch(plan: &CommandPlan, output: Output) -> io::Result<Duration> {
+    let timer = Instant::now();
+    let status = exec::run(plan.command())?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "Fetching the dependencies failed with {}",
+            status
+        )));
+    }
+    let duration = timer.elapsed();
+    debug!("time", "Fetching took {}", format_duration(duration));
+    if output == Output::Text {
+        eprintln!("Fetched the dependencies in {}", format_duration(duration));
     }
+    Ok(duration)
+}
+
+// Print the output of the last build of the project in `cwd` again, or only its errors
+fn last(cwd: &Path, errors_only: bool) -> io::Result<()> {
+    let project = Project::find(cwd)?;
+    let Some(path) = logs::latest(&project.root())? else {
+        println!("No build of this project was logged yet");
+        return Ok(());
+    };
+    let log = fs::read(path)?;
+    // As the build wrote it, which may not be UTF-8
+    if !errors_only {
+        return stdout().lock().write_all(&log);
+    }
+    let text = exec::text(&log);
+    let mut collector = Collector::default();
+    let mut diagnostics = text
+        .lines()
+        .filter_map(|line| collector.observe(line))
+        .collect::<Vec<_>>();
+    diagnostics.extend(collector.finish());
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        println!("The last build printed no errors");
+    }
+    for error in errors {
+        println!("{}", error.text.join("\n"));
+    }
+    Ok(())
+}
 
-    fn run<P: AsRef<Path>>(self, threads: usize, directory: P, target: Option<&str>) {
-        let mut command = match self {
-            BuildProgram::Make => BuildProgram::build_make_command(threads, directory, target),
-            BuildProgram::Ninja => BuildProgram::build_ninja_command(threads, directory, target),
-            BuildProgram::Cargo => BuildProgram::build_cargo_command(),
+// Start the build this `sm` was asked for in the background instead
+fn detach_build(cwd: &Path) -> io::Result<()> {
+    let project = Project::find(cwd)?;
+    let args = env::args_os()
+        .skip(1)
+        .filter(|arg| arg != "--detach")
+        .collect();
+    let detached = detach::start(&project.root(), cwd, args)?;
+    println!(
+        "Building in the background as {}; `sm status` shows how it goes, and `sm attach {}` \
+         follows its output",
+        detached.id, detached.id
+    );
+    Ok(())
+}
+
+// Print the builds of the project started in the background, with the last line each printed
+fn status(cwd: &Path) -> io::Result<()> {
+    let project = Project::find(cwd)?;
+    let builds = detach::list(&project.root())?;
+    if builds.is_empty() {
+        println!("No build of this project ran in the background yet");
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    for detached in builds {
+        let state = match (detached.exit_code, detached.running()) {
+            (Some(0), _) => "succeeded".to_string(),
+            (Some(code), _) => format!("failed with {}", code),
+            (None, true) => "building".to_string(),
+            (None, false) => "stopped".to_string(),
         };
-        println!("{}", command.exec());
+        println!(
+            "{}  {}, started {} ago: sm {}",
+            detached.id,
+            state,
+            format_duration(Duration::from_secs(now.saturating_sub(detached.started))),
+            detached.args.join(" ")
+        );
+        if let Some(line) = detached.last_line() {
+            println!("    {}", line);
+        }
+    }
+    Ok(())
+}
+
+// Follow the output of the background build `id`, or of the last one, until it finished
+fn attach(cwd: &Path, id: Option<u32>) -> io::Result<i32> {
+    let project = Project::find(cwd)?;
+    let builds = detach::list(&project.root())?;
+    let detached = match id {
+        Some(id) => builds.into_iter().find(|detached| detached.id == id),
+        None => builds.into_iter().last(),
+    };
+    let Some(detached) = detached else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            match id {
+                Some(id) => format!("No background build {} of this project", id),
+                None => "No build of this project ran in the background yet".to_string(),
+            },
+        ));
+    };
+    detach::attach(&detached)
+}
+
+// Check the configuration files that apply in `cwd`, printing each problem, and return the
+// exit code that tells whether there were any
+fn check_config(cwd: &Path) -> io::Result<i32> {
+    let files = Config::files(cwd);
+    if files.is_empty() {
+        println!("No configuration applies here");
+        return Ok(0);
+    }
+    // A build system defined in one file can be chosen in another
+    let build_systems: Vec<String> = files
+        .iter()
+        .filter_map(|file| Config::load(file).ok())
+        .flat_map(|config| config.build_systems.into_keys())
+        .collect();
+    let mut count = 0;
+    for file in &files {
+        let problems = Config::check(file, &build_systems)?;
+        if problems.is_empty() {
+            println!("{}: ok", file.display());
+        }
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        count += problems.len();
+    }
+    match count {
+        0 => Ok(0),
+        _ => Ok(exec::code::CONFIG),
     }
 }
 
-fn get_build_system<P: AsRef<Path>>(path: P) -> Result<Option<BuildProgram>> {
-    for entry in read_dir(path)? {
-        if let Some(name) = entry?.file_name().to_str() {
-            let build_program = BuildProgram::from_filename(name);
-            if build_program.is_some() {
-                return Ok(build_program);
+// Print the configuration files that apply in `cwd`, in the order they are layered
+fn show_config(cwd: &Path) -> io::Result<()> {
+    let files = Config::files(cwd);
+    if files.is_empty() {
+        println!("No configuration applies here");
+    }
+    for (index, file) in files.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        println!("# {}", file.display());
+        let text = fs::read_to_string(file)?;
+        print!("{}", text);
+        if !text.ends_with('\n') {
+            println!();
+        }
+    }
+    Ok(())
+}
+
+// Print how long the builds of each project took
+fn stats() -> io::Result<()> {
+    let stats = history::stats(&history::load()?);
+    if stats.is_empty() {
+        println!("No builds recorded yet");
+        return Ok(());
+    }
+    println!(
+        "{:>6} {:>8} {:>8} {:>8} {:>8} {:>8} {:>9}  project",
+        "builds", "failed", "mean", "median", "p90", "max", "total"
+    );
+    for project in &stats {
+        println!(
+            "{:>6} {:>7.0}% {:>8} {:>8} {:>8} {:>8} {:>9}  {}",
+            project.builds,
+            100.0 * project.failures as f64 / project.builds as f64,
+            format_duration(project.mean),
+            format_duration(project.median),
+            format_duration(project.p90),
+            format_duration(project.max),
+            format_duration(project.total),
+            project.project.display()
+        );
+    }
+    if stats.iter().any(|project| !project.flaky.is_empty()) {
+        println!();
+        println!("{:>6}  flaky target or test", "times");
+        for project in &stats {
+            for (name, count) in &project.flaky {
+                println!("{:>6}  {} ({})", count, name, project.project.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+// How `sm all` shows the output of a project
+enum Layout {
+    /// Directly, as the only project building
+    Alone,
+    /// As it comes, with this tag in front of each line
+    Prefixed(String),
+    /// All at once when the project finishes
+    Grouped,
+}
+
+// The colors of the project tags, told apart from the colors of errors and warnings
+const TAG_COLORS: &[u8] = &[36, 35, 34, 32, 96, 95, 94, 92];
+
+// The tags in front of the output lines of `names`, padded to the same width and colored on a
+// terminal
+fn tags(names: &[&str]) -> Vec<String> {
+    let width = names
+        .iter()
+        .map(|name| name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let color = stdout().is_terminal() && io::stderr().is_terminal();
+    names
+        .iter()
+        .enumerate()
+        .map(|(index, name)| match color {
+            true => format!(
+                "\x1b[{}m{:<width$} |\x1b[0m",
+                TAG_COLORS[index % TAG_COLORS.len()],
+                name
+            ),
+            false => format!("{:<width$} |", name),
+        })
+        .collect()
+}
+
+// Build one of the projects of `sm all`, returning its exit code. Builds that run alongside
+// others have their lines tagged with the project, or their output shown all at once when
+// they finish, so it does not interleave.
+fn build_subproject(
+    subproject: &Subproject,
+    invocation: &Invocation,
+    report: &Report,
+    layout: &Layout,
+    out: &Mutex<()>,
+) -> io::Result<i32> {
+    let project = &subproject.project;
+    let Some(plan) = invocation.plan(project)? else {
+        return Ok(0);
+    };
+    let header = format!("==> {} ({})", subproject.name, plan);
+    match layout {
+        Layout::Alone => {
+            eprintln!("{}", header);
+            return run(project, invocation, &plan, report).map(exit_code);
+        }
+        Layout::Prefixed(tag) => eprintln!("{} ==> {}", tag, plan),
+        Layout::Grouped => {}
+    }
+    let build_lock = lock(project, invocation, report)?;
+    let hooked = invocation.verb != Verb::Clean;
+    if hooked {
+        branch::check(project, invocation)?;
+        hooks::run(project, Stage::PreBuild, None)?;
+        codegen::run(project)?;
+    }
+    invocation.configure(project)?;
+    let started = SystemTime::now();
+    let timer = Instant::now();
+    let (status, lines) = match layout {
+        Layout::Prefixed(tag) => capture_with(&plan, report.pty, |stream, line| {
+            print_line(stream, &[format!("{} ", tag).as_bytes(), line].concat())
+        })?,
+        _ => capture(&plan, report.pty)?,
+    };
+    history::record(project, invocation, started, timer.elapsed(), status);
+    if let Some(mut log) = log(project, &plan) {
+        for (stream, line) in &lines {
+            log.bytes(*stream, line)?;
+        }
+        log.finished(status, timer.elapsed())?;
+    }
+    if hooked {
+        hooks::run(project, Stage::PostBuild, Some(exit_code(status)))?;
+    }
+    drop(build_lock);
+    match layout {
+        Layout::Prefixed(tag) => eprintln!("{} finished with {}", tag, status),
+        _ => {
+            let _lock = out.lock().unwrap();
+            eprintln!("{}", header);
+            print_lines(lines);
+        }
+    }
+    Ok(exit_code(status))
+}
+
+// The lines of output of a build, as it wrote them
+type Lines = Vec<(Stream, Vec<u8>)>;
+
+// Run `plan` and keep its output to show later
+fn capture(plan: &CommandPlan, pty: bool) -> io::Result<(ExitStatus, Lines)> {
+    capture_with(plan, pty, |_, _| {})
+}
+
+// Run `plan` and keep its output, handing each line to `each` as it comes
+fn capture_with(
+    plan: &CommandPlan,
+    pty: bool,
+    mut each: impl FnMut(Stream, &[u8]),
+) -> io::Result<(ExitStatus, Lines)> {
+    let execution = match pty {
+        true => Execution::spawn_pty(plan.command())?,
+        false => Execution::spawn(plan.command())?,
+    };
+    let mut lines = Vec::new();
+    for event in execution.events() {
+        match event {
+            Event::Line(stream, line) => {
+                each(stream, &line);
+                lines.push((stream, line));
             }
+            Event::Status(_) => {}
+            Event::Finished(status) => return Ok((status, lines)),
         }
     }
-    Ok(None)
+    Err(io::Error::other("The build stopped without an exit status"))
+}
+
+// Print a line of output as the build wrote it, which need not be UTF-8
+fn print_line(stream: Stream, line: &[u8]) {
+    // Output that cannot be written is left out, rather than failing the build
+    let _ = match stream {
+        Stream::Stdout => stdout().lock().write_all(&[line, b"\n"].concat()),
+        Stream::Stderr => io::stderr().lock().write_all(&[line, b"\n"].concat()),
+    };
 }
 
-fn find_build_dir() -> Option<(BuildProgram, PathBuf)> {
-    let mut cwd = current_dir().unwrap();
-    if let Some(program) = get_build_system(&cwd).unwrap() {
-        return Some((program, cwd));
+// Print the output kept by `capture`
+fn print_lines(lines: Lines) {
+    for (stream, line) in lines {
+        print_line(stream, &line);
     }
-    let mut build_dir = cwd.clone();
-    build_dir.push("build");
-    if exists(&build_dir).unwrap() {
-        if let Some(program) = get_build_system(&build_dir).unwrap() {
-            return Some((program, build_dir));
+}
+
+// Build `runs` times, after cleaning each time if `clean` is set, without showing the output
+// unless a build fails, and print how long the builds took
+fn time_build(
+    project: &Project,
+    invocation: &Invocation,
+    report: &Report,
+    runs: usize,
+    clean: bool,
+) -> io::Result<i32> {
+    let Some(plan) = invocation.plan(project)? else {
+        return Err(exec::no_build_system("No build system found"));
+    };
+    let clean_plan = match clean {
+        true => Invocation {
+            verb: Verb::Clean,
+            ..invocation.clone()
         }
+        .plan(project)?,
+        false => None,
+    };
+    let _lock = lock(project, invocation, report)?;
+    let _guard = ForegroundGuard::new();
+    let (mut wall, mut user, mut system) = (Vec::new(), Vec::new(), Vec::new());
+    for run in 1..=runs {
+        if exec::interrupted().is_some() {
+            break;
+        }
+        if let Some(clean_plan) = &clean_plan {
+            let (status, lines) = capture(clean_plan, report.pty)?;
+            if !status.success() {
+                print_lines(lines);
+                eprintln!("Cleaning failed with {}", status);
+                return Ok(exit_code(status));
+            }
+        }
+        let (user_before, system_before) = exec::children_cpu_time();
+        exec::reset_max_rss();
+        let started = SystemTime::now();
+        let timer = Instant::now();
+        let (status, lines) = capture(&plan, report.pty)?;
+        let duration = timer.elapsed();
+        let (user_after, system_after) = exec::children_cpu_time();
+        let mut record = history::Record::new(project, invocation, started, duration, status);
+        record.user = Some((user_after - user_before).as_secs_f64());
+        record.system = Some((system_after - system_before).as_secs_f64());
+        record.max_rss = Some(exec::max_rss()).filter(|max_rss| *max_rss > 0);
+        history::save(&record);
+        if !status.success() {
+            print_lines(lines);
+            eprintln!("Run {} failed with {}", run, status);
+            return Ok(exit_code(status));
+        }
+        wall.push(duration);
+        user.push(user_after - user_before);
+        system.push(system_after - system_before);
+        eprintln!(
+            "Run {}/{}: {} wall, {} user, {} system",
+            run,
+            runs,
+            format_duration(duration),
+            format_duration(user_after - user_before),
+            format_duration(system_after - system_before)
+        );
     }
-    while cwd.pop() {
-        if let Some(program) = get_build_system(&cwd).unwrap() {
-            return Some((program, cwd));
+    println!("{:<8} {:>8} {:>8} {:>8}", "", "min", "mean", "stddev");
+    for (name, samples) in [("wall", wall), ("user", user), ("system", system)] {
+        if let Some(spread) = history::spread(&samples) {
+            println!(
+                "{:<8} {:>8} {:>8} {:>8}",
+                name,
+                format_duration(spread.min),
+                format_duration(spread.mean),
+                format_duration(spread.stddev)
+            );
         }
     }
-    None
+    Ok(0)
+}
+
+// Build the two revisions of `range` of the repository `cwd` is in, each from scratch in a
+// worktree of its own and in the same directory of it as `cwd`, and print how much longer
+// the second took than the first, and the targets whose times changed most
+fn compare(
+    cwd: &Path,
+    invocation: &Invocation,
+    report: &Report,
+    range: &str,
+    top: usize,
+) -> io::Result<i32> {
+    let (before, after) = compare::parse_range(range)?;
+    let (repository, prefix) = compare::location(cwd)?;
+    compare::remove_stale(&repository)?;
+    let mut builds = Vec::new();
+    for (name, revision) in [("before", &before), ("after", &after)] {
+        let worktree = compare::Worktree::add(&repository, revision, name)?;
+        // Not the configuration or the build system of the temporary directory around it
+        set_search_ceiling(&worktree.dir);
+        let project = Project::find(worktree.dir.join(&prefix))?;
+        let Some(plan) = invocation.plan(&project)? else {
+            return Err(exec::no_build_system(format!(
+                "No build system found at {}",
+                revision
+            )));
+        };
+        eprintln!("==> {} at {} ({})", revision, worktree.commit, plan);
+        let (sender, receiver) = mpsc::channel();
+        let mut report = report.clone();
+        report.timings.collect = Some(sender);
+        let timer = Instant::now();
+        let code = exit_code(run(&project, invocation, &plan, &report)?);
+        let duration = timer.elapsed();
+        if code != 0 {
+            eprintln!(
+                "The build at {} failed, so there is nothing to compare",
+                revision
+            );
+            return Ok(code);
+        }
+        builds.push((duration, receiver.try_recv().unwrap_or_default()));
+    }
+    let [(before_duration, before_timings), (after_duration, after_timings)] = &builds[..] else {
+        return Ok(0);
+    };
+    eprintln!();
+    eprintln!(
+        "{} built in {} and {} in {}: {}",
+        before,
+        format_duration(*before_duration),
+        after,
+        format_duration(*after_duration),
+        compare::change(*before_duration, *after_duration)
+    );
+    let deltas = compare::deltas(before_timings, after_timings);
+    if deltas.is_empty() {
+        return Ok(0);
+    }
+    eprintln!("The targets whose times changed most:");
+    let show = |duration: Option<Duration>| duration.map_or("-".to_string(), format_duration);
+    for delta in deltas.iter().take(top) {
+        eprintln!(
+            "  {:>8}  {:>8}  {:>14}  {}",
+            show(delta.before),
+            show(delta.after),
+            compare::change(
+                delta.before.unwrap_or_default(),
+                delta.after.unwrap_or_default()
+            ),
+            delta.target
+        );
+    }
+    Ok(0)
+}
+
+// Projects, with the indices of the ones each of them depends on
+type ProjectGraph = (Vec<Subproject>, Vec<Vec<usize>>);
+
+// The projects `sm all` builds from `cwd` with the dependencies between them, and the
+// directories they were found in: the ones a workspace file lists, unless `cwd` is inside one
+// of them, or else `cwd`
+fn find_projects(cwd: &Path, top: &Project) -> io::Result<(ProjectGraph, Vec<PathBuf>)> {
+    if let Some((file, base)) = WorkspaceFile::find(cwd)? {
+        let roots = file.roots(&base);
+        if !roots
+            .iter()
+            .any(|root| cwd.starts_with(root) && *root != base)
+        {
+            return Ok((file.discover(&base)?, roots));
+        }
+    }
+    let subprojects = workspace::discover(cwd, &top.config)?;
+    let base = top.config_dir.as_deref().unwrap_or(cwd);
+    let dependencies = workspace::dependencies(&subprojects, &top.config, base)?;
+    Ok(((subprojects, dependencies), vec![cwd.to_path_buf()]))
+}
+
+// Build every project found below `cwd` and print how each of them went
+fn all(
+    cwd: &Path,
+    invocation: &Invocation,
+    report: &Report,
+    concurrency: usize,
+    keep_going: bool,
+    changed_since: Option<&str>,
+    grouped: bool,
+) -> io::Result<i32> {
+    let top = Project::find(cwd)?;
+    let ((mut subprojects, mut dependencies), roots) = find_projects(cwd, &top)?;
+    if let Some(reference) = changed_since {
+        let mut changed = Vec::new();
+        for root in &roots {
+            changed.extend(workspace::changed_files(root, reference)?);
+        }
+        let affected = workspace::affected(&subprojects, &dependencies, &changed);
+        (subprojects, dependencies) = workspace::retain(subprojects, &dependencies, &affected);
+        if subprojects.is_empty() {
+            println!("No projects changed since {}", reference);
+            return Ok(0);
+        }
+    }
+    if subprojects.is_empty() {
+        println!("No projects found");
+        return Ok(0);
+    }
+    let concurrency = concurrency.clamp(1, subprojects.len());
+    let threads = invocation.threads(&top)?;
+    let invocation = Invocation {
+        threads: Some(jobs_per_project(
+            threads,
+            concurrency,
+            invocation.jobs_per_project,
+        )),
+        jobs_per_project: None,
+        ..invocation.clone()
+    };
+    // One notification for all of them
+    let notify = report.notify;
+    let report = &Report {
+        notify: None,
+        ..report.clone()
+    };
+    let names = subprojects
+        .iter()
+        .map(|subproject| subproject.name.as_str())
+        .collect::<Vec<_>>();
+    let layouts = match (concurrency, grouped) {
+        (1, _) => names.iter().map(|_| Layout::Alone).collect(),
+        (_, true) => names.iter().map(|_| Layout::Grouped).collect(),
+        (_, false) => tags(&names)
+            .into_iter()
+            .map(Layout::Prefixed)
+            .collect::<Vec<_>>(),
+    };
+    let timer = Instant::now();
+    let out = Mutex::new(());
+    let durations = Mutex::new(vec![None; subprojects.len()]);
+    let _guard = ForegroundGuard::new();
+    let codes = workspace::schedule(concurrency, &dependencies, keep_going, |index| {
+        // Without a duration the project counts as skipped
+        if let Some(signal) = exec::interrupted() {
+            return 128 + signal;
+        }
+        let timer = Instant::now();
+        let subproject = &subprojects[index];
+        let code = build_subproject(subproject, &invocation, report, &layouts[index], &out)
+            .unwrap_or_else(|error| {
+                eprintln!("{}: {}", subproject.name, error);
+                1
+            });
+        durations.lock().unwrap()[index] = Some(timer.elapsed());
+        code
+    });
+    let durations = durations.into_inner().unwrap();
+    print_results(names.into_iter().zip(codes.iter().copied()).zip(durations));
+    let code = codes
+        .iter()
+        .flatten()
+        .copied()
+        .find(|&code| code != 0)
+        .unwrap_or(0);
+    if notify.is_some_and(|after| timer.elapsed() >= after) {
+        let name = format!("{} projects in {}", subprojects.len(), project_name(&top));
+        notify_finished(&name, code, timer.elapsed());
+    }
+    Ok(code)
+}
+
+// Print how each of several builds went, given their names, exit codes and durations, where
+// those that did not run have neither
+fn print_results<'a, I>(results: I)
+where
+    I: IntoIterator<Item = ((&'a str, Option<i32>), Option<Duration>)>,
+{
+    eprintln!();
+    for ((name, code), duration) in results {
+        let (status, duration) = match (code, duration) {
+            (Some(0), Some(duration)) => ("ok".to_string(), format_duration(duration)),
+            (Some(code), Some(duration)) => {
+                (format!("failed ({})", code), format_duration(duration))
+            }
+            _ => ("skipped".to_string(), String::new()),
+        };
+        eprintln!("{:<12} {:>8}  {}", status, duration, name);
+    }
+}
+
+// Build each configuration of the matrix of the project in `cwd`, or only those in `names`,
+// and print how each of them went
+fn matrix(
+    cwd: &Path,
+    invocation: &Invocation,
+    report: &Report,
+    names: &[String],
+    keep_going: bool,
+) -> io::Result<i32> {
+    let top = Project::find(cwd)?;
+    for name in names {
+        if !top.config.matrix.contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("No configuration {} in the matrix", name),
+            ));
+        }
+    }
+    let entries = top
+        .config
+        .matrix
+        .iter()
+        .filter(|(name, _)| names.is_empty() || names.contains(name))
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        println!("No build matrix configured");
+        return Ok(0);
+    }
+    let mut results = Vec::new();
+    let mut failed = false;
+    for (name, entry) in entries {
+        if failed && !keep_going || exec::interrupted().is_some() {
+            results.push(((name.as_str(), None), None));
+            continue;
+        }
+        let project = matrix::project(cwd, name, entry)?;
+        // The directory of the entry, rather than the one of its profile
+        let build_dir = match &entry.directory {
+            Some(directory) => Some(project.root().join(directory)),
+            None => invocation.build_dir.clone(),
+        };
+        let invocation = Invocation {
+            profile: entry.profile.clone().or(invocation.profile.clone()),
+            build_dir,
+            ..invocation.clone()
+        };
+        let Some(plan) = invocation.plan(&project)? else {
+            continue;
+        };
+        eprintln!("==> {} ({})", name, plan);
+        let timer = Instant::now();
+        let code = exit_code(run(&project, &invocation, &plan, report)?);
+        failed |= code != 0;
+        results.push(((name.as_str(), Some(code)), Some(timer.elapsed())));
+    }
+    let code = results
+        .iter()
+        .filter_map(|((_, code), _)| *code)
+        .find(|&code| code != 0)
+        .unwrap_or(0);
+    print_results(results);
+    Ok(code)
+}
+
+// Build `project` with each of the build systems its configuration builds one after the other,
+// stopping at the first that fails, and print how each of them went
+fn polyglot(project: &Project, invocation: &Invocation, report: &Report) -> io::Result<i32> {
+    let projects = polyglot::projects(project)?;
+    let mut results = Vec::new();
+    let mut failed = false;
+    for (name, project) in &projects {
+        if failed || exec::interrupted().is_some() {
+            results.push(((name.as_str(), None), None));
+            continue;
+        }
+        let Some(plan) = invocation.plan(project)? else {
+            continue;
+        };
+        eprintln!("==> {} ({})", name, plan);
+        let timer = Instant::now();
+        let code = exit_code(run(project, invocation, &plan, report)?);
+        failed |= code != 0;
+        results.push(((name.as_str(), Some(code)), Some(timer.elapsed())));
+    }
+    let code = results
+        .iter()
+        .filter_map(|((_, code), _)| *code)
+        .find(|&code| code != 0)
+        .unwrap_or(0);
+    print_results(results);
+    Ok(code)
+}
+
+// Print the files the build produces, one path or JSON object per line
+fn print_artifacts(project: &Project, invocation: &Invocation, output: Output) -> io::Result<()> {
+    for artifact in artifacts::find(project, invocation)? {
+        match output {
+            Output::Text => println!("{}", artifact.path.display()),
+            Output::Json => println!("{}", serde_json::to_string(&artifact)?),
+        }
+    }
+    Ok(())
+}
+
+// Run the build captured in `path` again, returning its exit code
+fn replay(path: &Path) -> io::Result<i32> {
+    let capture = Capture::load(path)?;
+    for difference in capture.differences() {
+        eprintln!("Note: {}", difference);
+    }
+    eprintln!("{}", capture.command);
+    Ok(exit_code(exec::run(capture.command())?))
+}
+
+fn cache(cwd: &Path, operation: CacheOperation, key: &str, store: Option<&str>) -> io::Result<()> {
+    let project = Project::find(cwd)?;
+    let base = project
+        .config_dir
+        .clone()
+        .unwrap_or_else(|| cwd.to_path_buf());
+    let store = Store::new(store, &project.config.ci_cache, &base)?;
+    match operation {
+        CacheOperation::Save => {
+            let paths = ci_cache::save(&project, &store, key)?;
+            match paths.len() {
+                0 => eprintln!("Nothing to cache"),
+                1 => eprintln!("Saved 1 path under {}:", key),
+                n => eprintln!("Saved {} paths under {}:", n, key),
+            }
+            for path in paths {
+                eprintln!("  {}", path.display());
+            }
+        }
+        CacheOperation::Restore => match ci_cache::restore(&project, &store, key)? {
+            true => eprintln!("Restored the cache of {}", key),
+            // A missing cache is the first run with the key, which only builds from scratch
+            false => eprintln!("No cache for {}", key),
+        },
+    }
+    Ok(())
+}
+
+fn explain_detection(cwd: &Path, project: &Project, invocation: &Invocation) -> io::Result<()> {
+    let (config, config_dir) = Config::resolve(cwd)?;
+    match Config::find_all(cwd)?.as_slice() {
+        [] => println!("Configuration: none"),
+        [(_, dir), outer @ ..] => {
+            println!("Configuration: {}", dir.join(PROJECT_CONFIG).display());
+            for (_, dir) in outer {
+                println!("  layered over {}", dir.join(PROJECT_CONFIG).display());
+            }
+        }
+    }
+    match (&config.build_system, &config_dir, &project.detection) {
+        (Some(build_system), Some(_), Some(detection)) => println!(
+            "The configuration pins build_system = {:?}, in {}, so nothing was looked for",
+            build_system.name(),
+            detection.directory.display()
+        ),
+        _ => {
+            println!("Looking for the build system of {}:", cwd.display());
+            let (steps, detection) = explain_build_dir(cwd, &config)?;
+            for step in &steps {
+                let found = match (&step.unreadable, step.exists, step.found.as_slice()) {
+                    (Some(error), _, _) => format!("cannot be read ({})", error),
+                    (None, false, _) => "does not exist".to_string(),
+                    (None, true, []) => "nothing".to_string(),
+                    (None, true, found) => found
+                        .iter()
+                        .map(|(marker, build_system)| {
+                            format!("{} ({})", marker, build_system.name())
+                        })
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                };
+                println!(
+                    "  {} ({}): {}",
+                    step.directory.display(),
+                    step.reason,
+                    found
+                );
+                // The first build system found in a directory is the one it builds with
+                if let [(_, chosen), others @ ..] = step.found.as_slice() {
+                    for (other, build_system) in others {
+                        println!(
+                            "    {} is left out, as {} comes before {}",
+                            other,
+                            chosen.name(),
+                            build_system.name()
+                        );
+                    }
+                }
+                for (marker, build_system) in &step.disabled {
+                    println!(
+                        "    {} is left out, as the configuration does not allow {}",
+                        marker,
+                        build_system.name()
+                    );
+                }
+            }
+            match &detection {
+                None => println!("No build system found"),
+                Some(Detection {
+                    source: Some(source),
+                    directory,
+                    ..
+                }) => println!(
+                    "Found a CMake project in {} without a build directory, to configure into {}",
+                    source.display(),
+                    directory.display()
+                ),
+                Some(detection) => println!(
+                    "Found {} in {}",
+                    detection.build_system.name(),
+                    detection.directory.display()
+                ),
+            }
+        }
+    }
+    let mut overrides = Vec::new();
+    if let Some(dir) = invocation.build_dir(project) {
+        let cross = invocation.cross_target.as_ref().filter(|name| {
+            config
+                .cross
+                .get(*name)
+                .is_some_and(|cross| cross.directory.is_some())
+        });
+        let reason = match (&invocation.build_dir, cross, &invocation.profile) {
+            (Some(_), _, _) => "--build-dir".to_string(),
+            (None, Some(name), _) => format!("[cross.{}]", name),
+            (None, None, Some(name)) => format!("--profile {}", name),
+            (None, None, None) => "The invocation".to_string(),
+        };
+        overrides.push(format!("{} builds in {}", reason, dir.display()));
+    }
+    if let Some(generator) = &invocation.cmake_generator {
+        overrides.push(format!("--cmake-generator {:?}", generator));
+    } else if let Some(generator) = &config.cmake_generator {
+        overrides.push(format!("cmake_generator = {:?}", generator));
+    }
+    if let Some(detection) = invocation.detection(project)? {
+        let generator = invocation
+            .cmake_generator
+            .as_deref()
+            .or(config.cmake_generator.as_deref());
+        if let Some(reason) = cmake::stale(&detection.directory, generator)
+            .filter(|_| cmake::is_configured(&detection.directory))
+        {
+            overrides.push(format!(
+                "{} is stale: {}, so it is configured again and built through CMake",
+                detection.directory.display(),
+                reason
+            ));
+        }
+        let member = match (&detection.build_system, &invocation.packages[..]) {
+            (BuildSystem::Cargo, []) if !invocation.workspace => {
+                cargo::workspace_member(&detection.directory)
+            }
+            _ => None,
+        };
+        if let Some((name, root)) = member {
+            overrides.push(format!(
+                "{} is a member of the Cargo workspace in {}, so only it is built, with \
+                 --package {}; --workspace builds them all",
+                name,
+                root.display(),
+                name
+            ));
+        }
+        for line in &overrides {
+            println!("  {}", line);
+        }
+        println!(
+            "Builds with {} in {}",
+            detection.build_system.name(),
+            detection.directory.display()
+        );
+    }
+    Ok(())
+}
+
+fn packages(cwd: &Path) -> io::Result<()> {
+    let project = Project::find(cwd)?;
+    match &project.detection {
+        Some(detection) if detection.build_system == BuildSystem::Cargo => {
+            for name in cargo::packages(&detection.directory)? {
+                println!("{}", name);
+            }
+            Ok(())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "This is not a Cargo project",
+        )),
+    }
+}
+
+fn register(name: &str, dir: &Path) -> io::Result<()> {
+    let dir = dir.canonicalize()?;
+    match registry::register(name, &dir)? {
+        Some(previous) if previous != dir => println!(
+            "Registered {} as {} instead of {}",
+            name,
+            dir.display(),
+            previous.display()
+        ),
+        _ => println!("Registered {} as {}", name, dir.display()),
+    }
+    Ok(())
+}
+
+fn list_projects() -> io::Result<()> {
+    let projects = registry::load()?;
+    if projects.is_empty() {
+        println!("No projects are registered; `sm register <name> [path]` registers one");
+    }
+    let width = projects.keys().map(|name| name.len()).max().unwrap_or(0);
+    for (name, dir) in projects {
+        let missing = if dir.is_dir() { "" } else { " (missing)" };
+        println!(
+            "{:width$}  {}{}",
+            name,
+            dir.display(),
+            missing,
+            width = width
+        );
+    }
+    Ok(())
+}
+
+// Print the projects below `cwd` and their dependencies
+fn graph(cwd: &Path, format: GraphFormat) -> io::Result<()> {
+    let top = Project::find(cwd)?;
+    let ((subprojects, dependencies), _) = find_projects(cwd, &top)?;
+    match format {
+        GraphFormat::Dot => print!("{}", workspace::to_dot(&subprojects, &dependencies)),
+        GraphFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&workspace::to_json(&subprojects, &dependencies))?
+        ),
+    }
+    Ok(())
+}
+
+fn graph_targets(
+    cwd: &Path,
+    target: &str,
+    dependents: bool,
+    format: TargetGraphFormat,
+) -> io::Result<()> {
+    let project = Project::find(cwd)?;
+    let Some(detection) = &project.detection else {
+        return Err(exec::no_build_system("No build system found"));
+    };
+    let graph = targets::graph(detection, &project.config, target, dependents)?;
+    match format {
+        TargetGraphFormat::Text => print!("{}", graph.to_tree()),
+        TargetGraphFormat::Dot => print!("{}", graph.to_dot()),
+    }
+    Ok(())
+}
+
+// Generate the compilation databases of `projects` and write them to `file` as one
+fn compdb(
+    cwd: &Path,
+    projects: Vec<PathBuf>,
+    file: Option<PathBuf>,
+    threads: Option<usize>,
+) -> io::Result<()> {
+    let projects = match projects.is_empty() {
+        true => vec![cwd.to_path_buf()],
+        false => projects,
+    };
+    let mut databases = Vec::new();
+    for dir in &projects {
+        let project = Project::find(cwd.join(dir))?;
+        let Some(detection) = &project.detection else {
+            return Err(exec::no_build_system(format!(
+                "No build system found in {}",
+                dir.display()
+            )));
+        };
+        let invocation = Invocation {
+            threads,
+            ..Invocation::default()
+        };
+        databases.push(compdb::generate(
+            detection,
+            &project.config,
+            invocation.threads(&project)?,
+        )?);
+    }
+    let file = match file {
+        Some(file) => file,
+        None => Project::find(cwd)?.root().join(compdb::COMPDB),
+    };
+    let entries = compdb::merge(databases);
+    fs::write(&file, serde_json::to_vec_pretty(&entries)?)?;
+    let noun = match entries.len() {
+        1 => "entry",
+        _ => "entries",
+    };
+    println!("Wrote {} {} to {}", entries.len(), noun, file.display());
+    Ok(())
+}
+
+// What to do once the build command is known
+enum Mode {
+    Run,
+    Watch {
+        ignore: Vec<String>,
+        debounce: Option<u64>,
+        no_clear: bool,
+        then: Option<Then>,
+    },
+    Tui,
+    Pick,
+    All {
+        concurrency: usize,
+        keep_going: bool,
+        changed_since: Option<String>,
+        grouped: bool,
+    },
+    Matrix {
+        names: Vec<String>,
+        keep_going: bool,
+    },
+    TimeBuild {
+        runs: usize,
+        clean: bool,
+    },
+    Compare {
+        range: String,
+        top: usize,
+    },
+    Artifacts,
+    Explain,
+    Fetch,
+}
+
+// Print the error and exit with the code for its kind, for failures that leave nothing to do
+fn or_exit<T>(result: io::Result<T>) -> T {
+    result.unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        exit(exec::final_exit_code(exec::error_exit_code(&error)));
+    })
+}
+
+// Say that there is nothing to build here, and exit with the code that tells scripts so
+fn no_build_system(output: Output) -> ! {
+    if output == Output::Text {
+        eprintln!("No build system found");
+    }
+    exit(exec::code::NO_BUILD_SYSTEM);
 }
 
 fn main() {
-    let args = Args::parse();
+    // A bug is reported in a sentence, with the backtrace only when asked for
+    if env::var_os("RUST_BACKTRACE").is_none() {
+        panic::set_hook(Box::new(|info| {
+            let message = match info.payload().downcast_ref::<String>() {
+                Some(message) => message.as_str(),
+                None => info.payload().downcast_ref::<&str>().unwrap_or(&"panic"),
+            };
+            eprintln!(
+                "smartmake hit a bug: {}; RUST_BACKTRACE=1 shows where",
+                message
+            );
+        }));
+    }
+    // Usage errors exit with the code of sysexits.h rather than the 2 of clap, which failed
+    // builds exit with too
+    let args = Args::try_parse().unwrap_or_else(|error| {
+        let _ = error.print();
+        exit(match error.use_stderr() {
+            true => exec::code::USAGE,
+            false => 0,
+        })
+    });
+    or_exit(debug::init(args.debug, args.debug_file.as_deref()));
+    if args.no_cache {
+        disable_detection_cache();
+    }
+    if args.no_follow_symlinks {
+        disable_symlink_following();
+    }
+    if let Some(seconds) = args.timeout {
+        exec::time_limit(Duration::from_secs(seconds));
+    }
 
-    let threads = args
-        .threads
-        .unwrap_or(available_parallelism().unwrap().get());
+    let cwd = match &args.project {
+        Some(name) => or_exit(registry::resolve(name)),
+        None => or_exit(current_dir().map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!("The current directory cannot be read: {}", error),
+            )
+        })),
+    };
+    if args.detach {
+        or_exit(detach_build(&cwd));
+        return;
+    }
+    let explain = matches!(args.action, Some(Action::ExplainRebuild { .. }));
+    let timed = matches!(
+        args.action,
+        Some(Action::Timings { .. } | Action::Compare { .. })
+    );
+    let mut reports = timings::Reports::default();
+    let (verb, targets, mode) = match args.action {
+        None => (Verb::Build, args.targets, Mode::Run),
+        Some(Action::Test { targets }) => (Verb::Test, targets, Mode::Run),
+        Some(Action::Clean { targets }) => (Verb::Clean, targets, Mode::Run),
+        Some(Action::Watch {
+            targets,
+            ignore,
+            debounce,
+            no_clear,
+            then,
+        }) => (
+            Verb::Build,
+            targets,
+            Mode::Watch {
+                ignore,
+                debounce,
+                no_clear,
+                then,
+            },
+        ),
+        Some(Action::Tui { targets }) => (Verb::Build, targets, Mode::Tui),
+        Some(Action::Pick) => (Verb::Build, Vec::new(), Mode::Pick),
+        Some(Action::ExplainRebuild { targets }) => (Verb::Build, targets, Mode::Run),
+        Some(Action::Timings {
+            targets,
+            top,
+            json,
+            html,
+        }) => {
+            reports = timings::Reports {
+                top,
+                json,
+                html,
+                collect: None,
+            };
+            (Verb::Build, targets, Mode::Run)
+        }
+        Some(Action::Artifacts { targets }) => (Verb::Build, targets, Mode::Artifacts),
+        Some(Action::Explain) => (Verb::Build, Vec::new(), Mode::Explain),
+        Some(Action::Fetch) => (Verb::Build, Vec::new(), Mode::Fetch),
+        Some(Action::TimeBuild {
+            targets,
+            runs,
+            clean,
+        }) => (Verb::Build, targets, Mode::TimeBuild { runs, clean }),
+        Some(Action::Compare {
+            range,
+            targets,
+            top,
+        }) => (Verb::Build, targets, Mode::Compare { range, top }),
+        Some(Action::Last { errors_only }) => {
+            or_exit(last(&cwd, errors_only));
+            return;
+        }
+        Some(Action::Config {
+            operation: ConfigOperation::Check,
+        }) => exit(or_exit(check_config(&cwd))),
+        Some(Action::Config {
+            operation: ConfigOperation::Show { resolved: false },
+        }) => {
+            or_exit(show_config(&cwd));
+            return;
+        }
+        Some(Action::Config {
+            operation: ConfigOperation::Show { resolved: true },
+        }) => {
+            // The flags that take the place of settings
+            let flags = Config {
+                jobs: args.threads,
+                cache: args.cache,
+                distributed: args.distributed.flatten(),
+                container: args.container,
+                max_memory: args.max_memory,
+                cpu_quota: args.cpu_quota,
+                cmake_generator: args.cmake_generator,
+                directory: args.build_dir.map(|dir| cwd.join(dir)),
+                sandbox: args
+                    .sandbox
+                    .map(|sandbox| sandbox.unwrap_or(Sandbox::Project)),
+                follow_symlinks: args.no_follow_symlinks.then_some(false),
+                progress: args.progress.then_some(true),
+                summary: args.no_summary.then_some(false),
+                notify: args.notify.then_some(true),
+                otel_endpoint: args.otel_endpoint,
+                ..Config::default()
+            };
+            let (config, _) = or_exit(Config::resolve(&cwd));
+            print!("{}", or_exit(flags.merge(config).to_toml()));
+            return;
+        }
+        Some(Action::Stats) => {
+            or_exit(stats());
+            return;
+        }
+        Some(Action::Packages) => {
+            or_exit(packages(&cwd));
+            return;
+        }
+        Some(Action::Register { name, path }) => {
+            or_exit(register(&name, &path.unwrap_or(cwd)));
+            return;
+        }
+        Some(Action::Projects) => {
+            or_exit(list_projects());
+            return;
+        }
+        Some(Action::Status) => {
+            or_exit(status(&cwd));
+            return;
+        }
+        Some(Action::Attach { id }) => exit(or_exit(attach(&cwd, id))),
+        Some(Action::Supervise { file, args }) => exit(or_exit(detach::supervise(&file, &args))),
+        Some(Action::Replay { capture }) => exit(or_exit(replay(&capture))),
+        Some(Action::Cache {
+            operation,
+            key,
+            store,
+        }) => {
+            or_exit(cache(&cwd, operation, &key, store.as_deref()));
+            return;
+        }
+        Some(Action::SelfUpdate) => {
+            match or_exit(update::self_update()) {
+                update::Update::UpToDate(version) => {
+                    println!("sm {} is the latest release", version)
+                }
+                update::Update::Updated { from, to } => {
+                    println!("Updated sm from {} to {}", from, to)
+                }
+            }
+            return;
+        }
+        Some(Action::All {
+            targets,
+            concurrency,
+            keep_going,
+            changed_only,
+            grouped,
+        }) => (
+            Verb::Build,
+            targets,
+            Mode::All {
+                concurrency,
+                keep_going,
+                changed_since: changed_only.map(|base| base.unwrap_or("HEAD".to_string())),
+                grouped,
+            },
+        ),
+        Some(Action::Matrix { names, keep_going }) => {
+            (Verb::Build, Vec::new(), Mode::Matrix { names, keep_going })
+        }
+        Some(Action::Graph { format }) => {
+            or_exit(graph(&cwd, format));
+            return;
+        }
+        Some(Action::GraphTargets {
+            target,
+            dependents,
+            format,
+        }) => {
+            or_exit(graph_targets(&cwd, &target, dependents, format));
+            return;
+        }
+        Some(Action::Compdb { projects, file }) => {
+            or_exit(compdb(&cwd, projects, file, args.threads));
+            return;
+        }
+        Some(Action::Daemon { socket }) => {
+            or_exit(daemon::serve(socket.unwrap_or_else(daemon::default_socket)));
+            return;
+        }
+    };
+    let mut invocation = Invocation {
+        verb,
+        targets,
+        threads: args.threads,
+        jobs_per_project: args.jobs_per_project,
+        profile: args.profile,
+        cache: args.cache,
+        distributed: args.distributed.is_some(),
+        distributor: args.distributed.flatten(),
+        container: args.container,
+        max_memory: args.max_memory,
+        cpu_quota: args.cpu_quota,
+        cross_target: args.cross_target,
+        cmake_generator: args.cmake_generator,
+        build_dir: args.build_dir,
+        packages: args.packages,
+        workspace: args.workspace,
+        features: args.features,
+        all_features: args.all_features,
+        no_default_features: args.no_default_features,
+        reconfigure: args.reconfigure,
+        explain,
+        timings: timed,
+        sandbox: args
+            .sandbox
+            .map(|sandbox| sandbox.unwrap_or(Sandbox::Project)),
+        jobserver: args.jobserver,
+    };
 
-    if let Some((build_program, path)) = find_build_dir() {
-        build_program.run(threads, path, args.target.as_deref());
-    } else {
-        println!("No build system found");
+    if args.daemon {
+        exit(or_exit(daemon::request(
+            daemon::default_socket(),
+            cwd,
+            invocation,
+        )));
+    }
+
+    let (finding, timer) = (SystemTime::now(), Instant::now());
+    let project = or_exit(Project::find(&cwd));
+    let detected = (finding, timer.elapsed());
+    if let Some(host) = &args.remote {
+        let remote = remote::resolve(&project, host);
+        exit(or_exit(remote::build(&project, &remote, &invocation)));
+    }
+    if let Mode::Explain = mode {
+        or_exit(explain_detection(&cwd, &project, &invocation));
+        return;
+    }
+    if let Mode::Fetch = mode {
+        let Some(detection) = &project.detection else {
+            no_build_system(args.output);
+        };
+        let Some(plan) = or_exit(invocation.fetch_plan(&project)) else {
+            eprintln!(
+                "{} has no command that only downloads what the build needs; one can be \
+                 configured as the `fetch` of a build system under [build_systems]",
+                detection.build_system.name()
+            );
+            exit(exec::code::UNSUPPORTED);
+        };
+        or_exit(fetch(&plan, args.output));
+        return;
+    }
+    if let Mode::Artifacts = mode {
+        or_exit(print_artifacts(&project, &invocation, args.output));
+        return;
+    }
+    if args.output == Output::Json {
+        or_exit(JsonEvents::default().detected(&project));
+    }
+    if let Mode::Tui = mode {
+        if project.detection.is_none() {
+            no_build_system(args.output);
+        }
+        // CMake would write over the dashboard
+        or_exit(invocation.configure(&project));
+        exit(or_exit(tui::run(&project, invocation)).unwrap_or(0));
+    }
+    if let Mode::Pick = mode {
+        let Some(detection) = &project.detection else {
+            no_build_system(args.output);
+        };
+        let cargo = detection.build_system == BuildSystem::Cargo;
+        let names = match cargo {
+            true => or_exit(cargo::packages(&detection.directory)),
+            false => or_exit(targets::list(detection, &project.config)),
+        };
+        let picked = or_exit(picker::pick(names));
+        if picked.is_empty() {
+            return;
+        }
+        match cargo {
+            true => invocation.packages = picked,
+            false => invocation.targets = picked,
+        }
+    }
+    let report = Report {
+        wait: !args.no_wait,
+        pty: !args.no_pty && args.output == Output::Text && stdout().is_terminal(),
+        terminal_progress: project.config.terminal_progress.unwrap_or(true)
+            && io::stderr().is_terminal(),
+        notify: (args.notify || project.config.notify.unwrap_or(false))
+            .then(|| Duration::from_secs(project.config.notify_after.unwrap_or(0))),
+        progress: args.progress || project.config.progress.unwrap_or(false),
+        summary: !args.no_summary && project.config.summary.unwrap_or(true),
+        first_error_only: args.first_error_only,
+        retry_failed: args.retry_failed_targets,
+        capture_env: args.capture_env,
+        otel: telemetry::endpoint(args.otel_endpoint, project.config.otel_endpoint.clone()),
+        detected,
+        output: args.output,
+        ci: args.ci,
+        diagnostics: args.diagnostics,
+        diagnostics_file: args.diagnostics_file,
+        timings: reports,
+    };
+    if let Mode::All {
+        concurrency,
+        keep_going,
+        changed_since,
+        grouped,
+    } = mode
+    {
+        exit(exec::final_exit_code(or_exit(all(
+            &cwd,
+            &invocation,
+            &report,
+            concurrency,
+            keep_going,
+            changed_since.as_deref(),
+            grouped,
+        ))));
+    }
+    if let Mode::Matrix { names, keep_going } = mode {
+        exit(exec::final_exit_code(or_exit(matrix(
+            &cwd,
+            &invocation,
+            &report,
+            &names,
+            keep_going,
+        ))));
+    }
+    if let Mode::TimeBuild { runs, clean } = mode {
+        exit(exec::final_exit_code(or_exit(time_build(
+            &project,
+            &invocation,
+            &report,
+            runs,
+            clean,
+        ))));
+    }
+    if let Mode::Compare { range, top } = mode {
+        exit(exec::final_exit_code(or_exit(compare(
+            &cwd,
+            &invocation,
+            &report,
+            &range,
+            top,
+        ))));
     }
+    // Watching rebuilds only the build system detected, as one saved file seldom concerns more
+    if !project.config.polyglot.is_empty() && !matches!(mode, Mode::Watch { .. }) {
+        exit(exec::final_exit_code(or_exit(polyglot(
+            &project,
+            &invocation,
+            &report,
+        ))));
+    }
+    let plan = match or_exit(invocation.plan(&project)) {
+        Some(plan) => plan,
+        None => {
+            let Some(single) = single_file::find(&project.cwd) else {
+                no_build_system(args.output);
+            };
+            let mut plan = or_exit(single.plan(invocation.verb, invocation.profile.as_deref()));
+            plan.envs(&project.config.env);
+            if args.output == Output::Text {
+                eprintln!(
+                    "No build system found, running `{}` for {} on its own",
+                    plan,
+                    single
+                        .source
+                        .file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                );
+            }
+            plan
+        }
+    };
+
+    let Mode::Watch {
+        ignore,
+        debounce,
+        no_clear,
+        then,
+    } = mode
+    else {
+        exit(exec::final_exit_code(exit_code(or_exit(run(
+            &project,
+            &invocation,
+            &plan,
+            &report,
+        )))));
+    };
+    let config = &project.config;
+    let ignore = DEFAULT_IGNORE
+        .iter()
+        .map(|pattern| pattern.to_string())
+        .chain(config.watch.ignore.iter().cloned())
+        .chain(ignore)
+        .collect();
+    let debounce = Duration::from_millis(debounce.or(config.watch.debounce).unwrap_or(200));
+    let clear = !no_clear && config.watch.clear.unwrap_or(true);
+    let test = then.map(|Then::Test| Invocation {
+        verb: Verb::Test,
+        ..invocation.clone()
+    });
+    let test_plan = match &test {
+        Some(test) => or_exit(test.plan(&project)),
+        None => None,
+    };
+    or_exit(watch(
+        &project,
+        (&invocation, &plan),
+        test.as_ref().zip(test_plan.as_ref()),
+        &report,
+        ignore,
+        debounce,
+        clear,
+    ));
+    exit(exec::final_exit_code(0));
 }
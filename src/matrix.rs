@@ -0,0 +1,55 @@
+use std::{io::Result, path::Path};
+
+use crate::{cmake, config::MatrixEntry, detect::detect, exec, BuildSystem, Project};
+
+/// The project in `cwd` as configured by the matrix entry `entry` called `name`: with its
+/// arguments, environment variables and targets added, and building in its own directory,
+/// which smartmake configures first if it is a CMake build directory that is not there yet
+pub fn project(cwd: &Path, name: &str, entry: &MatrixEntry) -> Result<Project> {
+    let mut project = Project::find(cwd)?;
+    let root = project.root();
+    let config = &mut project.config;
+    config.args.extend(entry.args.iter().cloned());
+    config.env.extend(entry.env.clone());
+    if !entry.targets.is_empty() {
+        config.targets = entry.targets.clone();
+    }
+    match &entry.directory {
+        Some(directory) => {
+            let dir = root.join(directory);
+            let detection = match dir.exists() {
+                true => detect(&dir, config)?.into_iter().next(),
+                false => None,
+            };
+            let generator = cmake::generator(
+                config.cmake_generator.as_deref(),
+                config.ninja_program.as_deref(),
+            );
+            project.detection = match (detection, cmake::source_root(&root)) {
+                (Some(detection), _) => Some(detection),
+                (None, Some(source)) => Some(cmake::detection(&source, dir, generator.as_deref())),
+                (None, None) => {
+                    return Err(exec::no_build_system(format!(
+                        "No build system found in {}",
+                        dir.display()
+                    )))
+                }
+            };
+        }
+        // cargo keeps the outputs of each configuration apart by itself given a directory
+        None => {
+            let cargo = project
+                .detection
+                .as_ref()
+                .is_some_and(|detection| detection.build_system == BuildSystem::Cargo);
+            if cargo && !config.env.contains_key("CARGO_TARGET_DIR") {
+                let target = root.join("target/matrix").join(name);
+                config.env.insert(
+                    "CARGO_TARGET_DIR".to_string(),
+                    target.to_string_lossy().into_owned(),
+                );
+            }
+        }
+    }
+    Ok(project)
+}
@@ -0,0 +1,52 @@
+use std::process::{Command, Stdio};
+
+// Quote `text` as an AppleScript or PowerShell string
+fn quote(text: &str, escape: char) -> String {
+    let mut quoted = String::from('"');
+    for c in text.chars() {
+        if c == '"' || c == escape {
+            quoted.push(escape);
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Show a desktop notification with `title` and `body`: with notify-send on Linux and the BSDs,
+/// Notification Center on macOS and a toast on Windows. Nothing happens where none of them
+/// can be shown.
+pub fn send(title: &str, body: &str) {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification {} with title {}",
+            quote(body, '\\'),
+            quote(title, '\\')
+        ));
+        command
+    } else if cfg!(windows) {
+        let script = format!(
+            "$type = [Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime]; \
+             $xml = $type::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02); \
+             $text = $xml.GetElementsByTagName('text'); \
+             $text.Item(0).InnerText = {}; $text.Item(1).InnerText = {}; \
+             $type::CreateToastNotifier('smartmake').Show([Windows.UI.Notifications.ToastNotification]::new($xml))",
+            quote(title, '`'),
+            quote(body, '`')
+        );
+        let mut command = Command::new("powershell");
+        command.args(["-NoProfile", "-Command", &script]);
+        command
+    } else {
+        let mut command = Command::new("notify-send");
+        command.arg("--app-name=smartmake").arg(title).arg(body);
+        command
+    };
+    // A notification that cannot be shown is not worth failing the build over
+    let _ = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+}
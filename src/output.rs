@@ -0,0 +1,966 @@
+use std::{
+    collections::VecDeque,
+    fs::write,
+    io::{stderr, stdout, Error, ErrorKind, IsTerminal, Result, Stdout, Write},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    config::{FilterAction, OutputFilter},
+    daemon::exit_code,
+    diagnostics::{self, strip_ansi, Collector, Diagnostic, Severity, WarningFilter},
+    exec::{text, Event, Execution, ForegroundGuard, Stream},
+    history::format_duration,
+    progress::ProgressTracker,
+    CommandPlan, Project,
+};
+
+// How often sinks are ticked while the build prints nothing
+const TICK: Duration = Duration::from_millis(250);
+
+/// Something that consumes the output of a build as it runs
+pub trait Sink {
+    /// A line of output, without its line ending
+    fn line(&mut self, stream: Stream, line: &str) -> Result<()>;
+
+    /// A line of output as the build wrote it, which need not be UTF-8. It goes on to
+    /// [`line`](Sink::line) as text, unless the sink keeps the bytes as they are.
+    fn bytes(&mut self, stream: Stream, line: &[u8]) -> Result<()> {
+        self.line(stream, &text(line))
+    }
+
+    /// The line the build is still writing on its terminal, see [`Event::Status`]
+    fn status(&mut self, _status: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called regularly while the build runs, even when it prints nothing
+    fn tick(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the build is to be stopped, as nothing more of its output is wanted
+    fn stop(&self) -> bool {
+        false
+    }
+
+    /// The build exited, after all of its output was passed on
+    fn finished(&mut self, _status: ExitStatus, _duration: Duration) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Run `command`, passing its output to each of the `sinks` in turn. With `pty` it writes to
+/// a pseudo-terminal, so it keeps its colors and progress bars.
+pub fn run(command: Command, sinks: &mut [Box<dyn Sink>], pty: bool) -> Result<ExitStatus> {
+    let started = Instant::now();
+    let execution = match pty {
+        true => Execution::spawn_pty(command)?,
+        false => Execution::spawn(command)?,
+    };
+    let _guard = ForegroundGuard::new();
+    let mut stopping = false;
+    loop {
+        match execution.events().recv_timeout(TICK) {
+            Ok(Event::Line(stream, line)) => {
+                for sink in sinks.iter_mut() {
+                    sink.bytes(stream, &line)?;
+                }
+                if !stopping && sinks.iter().any(|sink| sink.stop()) {
+                    stopping = true;
+                    execution.kill();
+                }
+            }
+            Ok(Event::Status(status)) => {
+                for sink in sinks.iter_mut() {
+                    sink.status(&status)?;
+                }
+            }
+            Ok(Event::Finished(status)) => {
+                for sink in sinks.iter_mut() {
+                    sink.finished(status, started.elapsed())?;
+                }
+                return Ok(status);
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                for sink in sinks.iter_mut() {
+                    sink.tick()?;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Error::other("The build stopped without an exit status"))
+            }
+        }
+    }
+}
+
+/// Passes the output on to the terminal, optionally with a progress bar kept below it on
+/// stderr. The line the build is still writing takes the place of the bar until it is done.
+pub struct Terminal {
+    progress: Option<ProgressTracker>,
+    status: Option<String>,
+}
+
+impl Terminal {
+    pub fn new(progress: Option<ProgressTracker>) -> Terminal {
+        // A progress bar only makes sense where it can be redrawn
+        Terminal {
+            progress: progress.filter(|_| stderr().is_terminal()),
+            status: None,
+        }
+    }
+
+    fn clear_bar(&self) {
+        if self.progress.is_some() || self.status.is_some() {
+            eprint!("\r\x1b[K");
+        }
+    }
+
+    fn draw_bar(&self) {
+        if let Some(status) = &self.status {
+            eprint!("{}", status);
+        } else if let Some(progress) = &self.progress {
+            eprint!("{}", progress.render());
+        }
+    }
+}
+
+impl Sink for Terminal {
+    fn line(&mut self, stream: Stream, line: &str) -> Result<()> {
+        self.bytes(stream, line.as_bytes())
+    }
+
+    // The terminal gets the bytes the build wrote, as it would without smartmake
+    fn bytes(&mut self, stream: Stream, line: &[u8]) -> Result<()> {
+        self.clear_bar();
+        self.status = None;
+        if let Some(progress) = &mut self.progress {
+            progress.observe(&text(line));
+        }
+        match stream {
+            Stream::Stdout => {
+                let mut out = stdout().lock();
+                out.write_all(line)?;
+                out.write_all(b"\n")?;
+                out.flush()?;
+            }
+            Stream::Stderr => {
+                let mut err = stderr().lock();
+                err.write_all(line)?;
+                err.write_all(b"\n")?;
+            }
+        }
+        self.draw_bar();
+        Ok(())
+    }
+
+    fn status(&mut self, status: &str) -> Result<()> {
+        self.clear_bar();
+        if let Some(progress) = &mut self.progress {
+            progress.observe(status);
+        }
+        self.status = Some(status.to_string());
+        self.draw_bar();
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        self.clear_bar();
+        self.draw_bar();
+        Ok(())
+    }
+
+    fn finished(&mut self, _status: ExitStatus, _duration: Duration) -> Result<()> {
+        self.clear_bar();
+        Ok(())
+    }
+}
+
+/// Passes the output on to `sinks` without the warnings `filter` leaves out, and tells how
+/// many it left out once the build finished
+pub struct Filtered {
+    filter: WarningFilter,
+    sinks: Vec<Box<dyn Sink>>,
+    // The lines the filter holds on to, as the build wrote them, to pass on those it lets
+    // through unchanged
+    held: VecDeque<(String, Vec<u8>)>,
+}
+
+impl Filtered {
+    pub fn new(filter: WarningFilter, sinks: Vec<Box<dyn Sink>>) -> Filtered {
+        Filtered {
+            filter,
+            sinks,
+            held: VecDeque::new(),
+        }
+    }
+
+    fn pass(&mut self, lines: Vec<(Stream, String)>) -> Result<()> {
+        for (stream, line) in lines {
+            // The filter lets lines through in the order they came, leaving some out
+            let bytes = loop {
+                match self.held.pop_front() {
+                    Some((text, bytes)) if text == line => break bytes,
+                    Some(_) => {}
+                    None => break line.clone().into_bytes(),
+                }
+            };
+            for sink in self.sinks.iter_mut() {
+                sink.bytes(stream, &bytes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for Filtered {
+    fn line(&mut self, stream: Stream, line: &str) -> Result<()> {
+        self.bytes(stream, line.as_bytes())
+    }
+
+    fn bytes(&mut self, stream: Stream, line: &[u8]) -> Result<()> {
+        let text = text(line).into_owned();
+        let lines = self.filter.observe(stream, &text);
+        self.held.push_back((text, line.to_vec()));
+        self.pass(lines)
+    }
+
+    fn status(&mut self, status: &str) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.status(status)?;
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.tick()?;
+        }
+        Ok(())
+    }
+
+    fn finished(&mut self, status: ExitStatus, duration: Duration) -> Result<()> {
+        let lines = self.filter.finish();
+        self.pass(lines)?;
+        for sink in self.sinks.iter_mut() {
+            sink.finished(status, duration)?;
+        }
+        let left_out = [
+            (self.filter.duplicates, "duplicate"),
+            (self.filter.suppressed, "suppressed"),
+        ]
+        .into_iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, what)| format!("{} {}", count, what))
+        .collect::<Vec<_>>();
+        if !left_out.is_empty() {
+            eprintln!("Left out warnings: {}", left_out.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// Shows how far the build got in the tab or taskbar of the terminal, with the progress
+/// sequence (OSC 9;4) of Windows Terminal, ConEmu and iTerm2 and in the window title
+pub struct TerminalProgress {
+    name: String,
+    progress: ProgressTracker,
+    // The percentage last shown, to write only when it changes
+    shown: Option<Option<u32>>,
+}
+
+impl TerminalProgress {
+    /// Progress of the build of the project called `name`, estimated from how long it is
+    /// `expected` to take or from the progress it prints
+    pub fn new(name: String, expected: Option<Duration>) -> TerminalProgress {
+        TerminalProgress {
+            name,
+            progress: ProgressTracker::new(expected),
+            shown: None,
+        }
+    }
+
+    fn show(&mut self) {
+        let percent = self
+            .progress
+            .fraction()
+            .map(|fraction| (fraction * 100.0) as u32);
+        if self.shown == Some(percent) {
+            return;
+        }
+        self.shown = Some(percent);
+        match percent {
+            Some(percent) => eprint!(
+                "\x1b]9;4;1;{}\x07\x1b]0;{}: building {}%\x07",
+                percent, self.name, percent
+            ),
+            // Without an estimate the taskbar shows that something is going on
+            None => eprint!("\x1b]9;4;3;0\x07\x1b]0;{}: building\x07", self.name),
+        }
+    }
+}
+
+impl Sink for TerminalProgress {
+    fn line(&mut self, _stream: Stream, line: &str) -> Result<()> {
+        self.progress.observe(line);
+        self.show();
+        Ok(())
+    }
+
+    fn status(&mut self, status: &str) -> Result<()> {
+        self.progress.observe(status);
+        self.show();
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        self.show();
+        Ok(())
+    }
+
+    fn finished(&mut self, status: ExitStatus, _duration: Duration) -> Result<()> {
+        let outcome = match status.success() {
+            true => "build succeeded",
+            false => "build failed",
+        };
+        eprint!("\x1b]9;4;0;0\x07\x1b]0;{}: {}\x07", self.name, outcome);
+        Ok(())
+    }
+}
+
+/// An event of `--output json`, printed as one JSON object per line
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JsonEvent<'a> {
+    /// The build system that was found, if any
+    Detected {
+        build_system: Option<&'a str>,
+        directory: Option<&'a Path>,
+        project: PathBuf,
+    },
+    /// The build command started
+    Started {
+        command: String,
+        program: String,
+        args: Vec<String>,
+        cwd: Option<&'a Path>,
+    },
+    /// A line of build output
+    Output { stream: Stream, line: &'a str },
+    /// An error or warning found in the build output, once all of its lines were read
+    Diagnostic(&'a Diagnostic),
+    /// The build exited
+    Finished { exit_code: i32, duration: f64 },
+}
+
+/// Prints the build as newline-delimited JSON events on stdout
+pub struct JsonEvents {
+    out: Stdout,
+    diagnostics: Collector,
+}
+
+impl Default for JsonEvents {
+    fn default() -> Self {
+        JsonEvents {
+            out: stdout(),
+            diagnostics: Collector::default(),
+        }
+    }
+}
+
+impl JsonEvents {
+    pub fn emit(&mut self, event: &JsonEvent) -> Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        self.out.write_all(&line)?;
+        self.out.flush()
+    }
+
+    pub fn detected(&mut self, project: &Project) -> Result<()> {
+        let detection = project.detection.as_ref();
+        self.emit(&JsonEvent::Detected {
+            build_system: detection.map(|detection| detection.build_system.name()),
+            directory: detection.map(|detection| detection.directory.as_path()),
+            project: project.root(),
+        })
+    }
+
+    pub fn started(&mut self, plan: &CommandPlan) -> Result<()> {
+        self.emit(&JsonEvent::Started {
+            command: plan.to_string(),
+            program: plan.program.to_string_lossy().into_owned(),
+            args: plan
+                .args
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            cwd: plan.cwd.as_deref(),
+        })
+    }
+}
+
+impl Sink for JsonEvents {
+    fn line(&mut self, stream: Stream, line: &str) -> Result<()> {
+        if let Some(diagnostic) = self.diagnostics.observe(line) {
+            self.emit(&JsonEvent::Diagnostic(&diagnostic))?;
+        }
+        self.emit(&JsonEvent::Output { stream, line })
+    }
+
+    fn finished(&mut self, status: ExitStatus, duration: Duration) -> Result<()> {
+        if let Some(diagnostic) = self.diagnostics.finish() {
+            self.emit(&JsonEvent::Diagnostic(&diagnostic))?;
+        }
+        self.emit(&JsonEvent::Finished {
+            exit_code: exit_code(status),
+            duration: duration.as_secs_f64(),
+        })
+    }
+}
+
+/// Collects the diagnostics in the output and prints a summary of them on stderr once the
+/// build finished. When the build failed, it ends with what failed to build, the command and
+/// where the log of the build is.
+pub struct Summary {
+    collector: Collector,
+    diagnostics: Vec<Diagnostic>,
+    // The targets the build tools reported failing, in the order they did
+    failed: Vec<String>,
+    command: String,
+    log: Option<PathBuf>,
+}
+
+impl Summary {
+    /// A summary of the build that `command` runs, with its log in `log` if it has one
+    pub fn new(command: String, log: Option<PathBuf>) -> Summary {
+        Summary {
+            collector: Collector::default(),
+            diagnostics: Vec::new(),
+            failed: Vec::new(),
+            command,
+            log,
+        }
+    }
+}
+
+impl Sink for Summary {
+    fn line(&mut self, _stream: Stream, line: &str) -> Result<()> {
+        self.diagnostics.extend(self.collector.observe(line));
+        if let Some(target) = diagnostics::failed_target(line) {
+            if !self.failed.contains(&target) {
+                self.failed.push(target);
+            }
+        }
+        Ok(())
+    }
+
+    fn finished(&mut self, status: ExitStatus, duration: Duration) -> Result<()> {
+        self.diagnostics.extend(self.collector.finish());
+        if !self.diagnostics.is_empty() {
+            eprint!("\n{}", diagnostics::summary(&self.diagnostics));
+        }
+        if status.success() {
+            return Ok(());
+        }
+        match self.diagnostics.is_empty() {
+            true => eprintln!(),
+            false => eprintln!("--"),
+        }
+        eprintln!(
+            "Build failed with exit code {} after {}",
+            exit_code(status),
+            format_duration(duration)
+        );
+        if !self.failed.is_empty() {
+            eprintln!("Failed: {}", self.failed.join(", "));
+        }
+        eprintln!("Command: {}", self.command);
+        if let Some(log) = &self.log {
+            eprintln!("Log: {}", log.display());
+        }
+        Ok(())
+    }
+}
+
+/// Passes the output on to `sinks` after applying the configured output filters to each line,
+/// and tells how many lines they hid once the build finished
+pub struct FilteredLines {
+    filters: Vec<(Regex, OutputFilter)>,
+    color: bool,
+    sinks: Vec<Box<dyn Sink>>,
+    hidden: usize,
+}
+
+impl FilteredLines {
+    /// Apply `filters`, where highlighting only colors lines with `color`
+    pub fn new(
+        filters: &[OutputFilter],
+        color: bool,
+        sinks: Vec<Box<dyn Sink>>,
+    ) -> Result<FilteredLines> {
+        let filters = filters
+            .iter()
+            .map(|filter| {
+                let regex = Regex::new(&filter.pattern).map_err(|error| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Invalid output filter pattern: {}", error),
+                    )
+                })?;
+                Ok((regex, filter.clone()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(FilteredLines {
+            filters,
+            color,
+            sinks,
+            hidden: 0,
+        })
+    }
+
+    // The line as the filters leave it, or `None` if one of them hides it
+    fn apply(&self, line: &str) -> Option<String> {
+        let mut line = line.to_string();
+        let mut highlighted = false;
+        for (regex, filter) in &self.filters {
+            let plain = strip_ansi(&line);
+            if !regex.is_match(&plain) {
+                continue;
+            }
+            match filter.action {
+                FilterAction::Hide => return None,
+                FilterAction::Highlight => highlighted = true,
+                // Colors that went with what it said would end up in the wrong places
+                FilterAction::Rewrite => {
+                    line = regex
+                        .replace_all(&plain, filter.replace.as_str())
+                        .into_owned()
+                }
+            }
+        }
+        Some(match highlighted && self.color {
+            true => format!("\x1b[1;33m{}\x1b[0m", strip_ansi(&line)),
+            false => line,
+        })
+    }
+}
+
+impl Sink for FilteredLines {
+    fn line(&mut self, stream: Stream, line: &str) -> Result<()> {
+        self.bytes(stream, line.as_bytes())
+    }
+
+    fn bytes(&mut self, stream: Stream, line: &[u8]) -> Result<()> {
+        let text = text(line);
+        let Some(filtered) = self.apply(&text) else {
+            self.hidden += 1;
+            return Ok(());
+        };
+        // A line the filters leave as it was goes on as the build wrote it
+        let line = match filtered == text {
+            true => line,
+            false => filtered.as_bytes(),
+        };
+        for sink in self.sinks.iter_mut() {
+            sink.bytes(stream, line)?;
+        }
+        Ok(())
+    }
+
+    fn status(&mut self, status: &str) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.status(status)?;
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.tick()?;
+        }
+        Ok(())
+    }
+
+    fn finished(&mut self, status: ExitStatus, duration: Duration) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.finished(status, duration)?;
+        }
+        if self.hidden > 0 {
+            let noun = if self.hidden == 1 { "line" } else { "lines" };
+            eprintln!("Hid {} {} matching the output filters", self.hidden, noun);
+        }
+        Ok(())
+    }
+}
+
+/// Passes the output on to `sinks` up to the end of the first error, and then asks for the
+/// build to be stopped
+pub struct FirstError {
+    collector: Collector,
+    sinks: Vec<Box<dyn Sink>>,
+    stopped: bool,
+}
+
+impl FirstError {
+    pub fn new(sinks: Vec<Box<dyn Sink>>) -> FirstError {
+        FirstError {
+            collector: Collector::default(),
+            sinks,
+            stopped: false,
+        }
+    }
+}
+
+impl Sink for FirstError {
+    fn line(&mut self, stream: Stream, line: &str) -> Result<()> {
+        self.bytes(stream, line.as_bytes())
+    }
+
+    fn bytes(&mut self, stream: Stream, line: &[u8]) -> Result<()> {
+        if self.stopped {
+            return Ok(());
+        }
+        let complete = self.collector.observe(&text(line));
+        if complete.is_some_and(|diagnostic| diagnostic.severity == Severity::Error) {
+            self.stopped = true;
+            return Ok(());
+        }
+        for sink in self.sinks.iter_mut() {
+            sink.bytes(stream, line)?;
+        }
+        Ok(())
+    }
+
+    fn status(&mut self, status: &str) -> Result<()> {
+        if self.stopped {
+            return Ok(());
+        }
+        for sink in self.sinks.iter_mut() {
+            sink.status(status)?;
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.tick()?;
+        }
+        Ok(())
+    }
+
+    fn stop(&self) -> bool {
+        self.stopped
+    }
+
+    fn finished(&mut self, status: ExitStatus, duration: Duration) -> Result<()> {
+        if self.stopped {
+            eprintln!("Stopped the build after the first error");
+        }
+        for sink in self.sinks.iter_mut() {
+            sink.finished(status, duration)?;
+        }
+        Ok(())
+    }
+}
+
+// Escape text for a GitHub Actions workflow command, where properties also need `:` and `,`
+// escaped
+fn escape_workflow(text: &str, property: bool) -> String {
+    let text = text
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A");
+    match property {
+        true => text.replace(':', "%3A").replace(',', "%2C"),
+        false => text,
+    }
+}
+
+/// Prints each diagnostic as a GitHub Actions workflow command, so it shows up on the lines
+/// of the pull request it is about
+pub struct GithubAnnotations {
+    collector: Collector,
+    // Whether they go to stderr, to keep them out of the events printed on stdout
+    stderr: bool,
+}
+
+impl GithubAnnotations {
+    pub fn new(stderr: bool) -> GithubAnnotations {
+        GithubAnnotations {
+            collector: Collector::default(),
+            stderr,
+        }
+    }
+
+    fn annotate(&self, diagnostic: &Diagnostic) -> Result<()> {
+        let command = match diagnostic.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let mut properties = Vec::new();
+        if let Some(file) = &diagnostic.file {
+            properties.push(format!(
+                "file={}",
+                escape_workflow(&file.to_string_lossy(), true)
+            ));
+        }
+        if let Some(line) = diagnostic.line {
+            properties.push(format!("line={}", line));
+        }
+        if let Some(column) = diagnostic.column {
+            properties.push(format!("col={}", column));
+        }
+        if let Some(code) = &diagnostic.code {
+            properties.push(format!("title={}", escape_workflow(code, true)));
+        }
+        let command = format!(
+            "::{} {}::{}\n",
+            command,
+            properties.join(","),
+            escape_workflow(&diagnostic.message, false)
+        );
+        match self.stderr {
+            true => stderr().write_all(command.as_bytes()),
+            false => {
+                let mut out = stdout();
+                out.write_all(command.as_bytes())?;
+                out.flush()
+            }
+        }
+    }
+}
+
+impl Sink for GithubAnnotations {
+    fn line(&mut self, _stream: Stream, line: &str) -> Result<()> {
+        match self.collector.observe(line) {
+            Some(diagnostic) => self.annotate(&diagnostic),
+            None => Ok(()),
+        }
+    }
+
+    fn finished(&mut self, _status: ExitStatus, _duration: Duration) -> Result<()> {
+        match self.collector.finish() {
+            Some(diagnostic) => self.annotate(&diagnostic),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The file GitLab reads code quality reports from, unless the job names another
+pub const GITLAB_REPORT: &str = "gl-code-quality-report.json";
+
+// GitLab tells issues apart by their fingerprint, which has to stay the same from one
+// pipeline to the next to compare them, so this is FNV-1a rather than the std hasher
+fn fingerprint(diagnostic: &Diagnostic) -> String {
+    let file = diagnostic.file.as_deref().unwrap_or(Path::new(""));
+    let key = format!(
+        "{}:{}:{}",
+        file.display(),
+        diagnostic.line.unwrap_or(0),
+        diagnostic.message
+    );
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Writes the diagnostics to a GitLab code quality report once the build finished
+pub struct GitlabReport {
+    path: PathBuf,
+    collector: Collector,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl GitlabReport {
+    pub fn new<P: Into<PathBuf>>(path: P) -> GitlabReport {
+        GitlabReport {
+            path: path.into(),
+            collector: Collector::default(),
+            diagnostics: Vec::new(),
+        }
+    }
+}
+
+impl Sink for GitlabReport {
+    fn line(&mut self, _stream: Stream, line: &str) -> Result<()> {
+        self.diagnostics.extend(self.collector.observe(line));
+        Ok(())
+    }
+
+    fn finished(&mut self, _status: ExitStatus, _duration: Duration) -> Result<()> {
+        self.diagnostics.extend(self.collector.finish());
+        let issues = self
+            .diagnostics
+            .iter()
+            // GitLab only shows issues it can place in a file
+            .filter(|diagnostic| diagnostic.file.is_some())
+            .map(|diagnostic| {
+                json!({
+                    "description": diagnostic.message,
+                    "check_name": diagnostic.code.as_deref().unwrap_or("compiler"),
+                    "fingerprint": fingerprint(diagnostic),
+                    "severity": match diagnostic.severity {
+                        Severity::Error => "major",
+                        Severity::Warning => "minor",
+                    },
+                    "location": {
+                        "path": diagnostic.file,
+                        "lines": {"begin": diagnostic.line.unwrap_or(1)},
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+        write(&self.path, serde_json::to_vec_pretty(&issues)?)
+    }
+}
+
+// Write a report to `path`, or to stdout if there is none
+fn write_report(path: Option<&Path>, report: &[u8]) -> Result<()> {
+    match path {
+        Some(path) => write(path, report),
+        None => {
+            let mut out = stdout();
+            out.write_all(report)?;
+            out.flush()
+        }
+    }
+}
+
+/// Writes the diagnostics in Vim's quickfix format once the build finished
+pub struct Quickfix {
+    path: Option<PathBuf>,
+    collector: Collector,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Quickfix {
+    /// Write to `path`, or to stdout if there is none
+    pub fn new(path: Option<PathBuf>) -> Quickfix {
+        Quickfix {
+            path,
+            collector: Collector::default(),
+            diagnostics: Vec::new(),
+        }
+    }
+}
+
+impl Sink for Quickfix {
+    fn line(&mut self, _stream: Stream, line: &str) -> Result<()> {
+        self.diagnostics.extend(self.collector.observe(line));
+        Ok(())
+    }
+
+    fn finished(&mut self, _status: ExitStatus, _duration: Duration) -> Result<()> {
+        self.diagnostics.extend(self.collector.finish());
+        let mut report = String::new();
+        for diagnostic in &self.diagnostics {
+            let severity = match diagnostic.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            // The format `%f:%l:%c: %t%*[^:]: %m` reads this, which Vim's default for gcc
+            // covers
+            let location = match (&diagnostic.file, diagnostic.line, diagnostic.column) {
+                (Some(file), Some(line), Some(column)) => {
+                    format!("{}:{}:{}: ", file.display(), line, column)
+                }
+                (Some(file), Some(line), None) => format!("{}:{}: ", file.display(), line),
+                _ => String::new(),
+            };
+            report.push_str(&format!(
+                "{}{}: {}\n",
+                location, severity, diagnostic.message
+            ));
+        }
+        write_report(self.path.as_deref(), report.as_bytes())
+    }
+}
+
+// A `file://` URI for `path`, with the characters that URIs reserve percent-encoded
+fn file_uri(path: &Path) -> String {
+    let mut uri = String::from("file://");
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    uri
+}
+
+/// Writes the diagnostics as LSP `textDocument/publishDiagnostics` parameters, one for each
+/// file, once the build finished
+pub struct LspDiagnostics {
+    path: Option<PathBuf>,
+    // The directory the compilers ran in, which relative file names are relative to
+    base: PathBuf,
+    collector: Collector,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl LspDiagnostics {
+    /// Write to `path`, or to stdout if there is none, resolving file names against `base`
+    pub fn new(path: Option<PathBuf>, base: PathBuf) -> LspDiagnostics {
+        LspDiagnostics {
+            path,
+            base,
+            collector: Collector::default(),
+            diagnostics: Vec::new(),
+        }
+    }
+}
+
+impl Sink for LspDiagnostics {
+    fn line(&mut self, _stream: Stream, line: &str) -> Result<()> {
+        self.diagnostics.extend(self.collector.observe(line));
+        Ok(())
+    }
+
+    fn finished(&mut self, _status: ExitStatus, _duration: Duration) -> Result<()> {
+        self.diagnostics.extend(self.collector.finish());
+        let mut files: Vec<(String, Vec<serde_json::Value>)> = Vec::new();
+        for diagnostic in &self.diagnostics {
+            // Diagnostics have to be about a document
+            let Some(file) = &diagnostic.file else {
+                continue;
+            };
+            let uri = file_uri(&self.base.join(file));
+            // LSP counts lines and characters from 0, compilers from 1
+            let position = json!({
+                "line": diagnostic.line.unwrap_or(1).saturating_sub(1),
+                "character": diagnostic.column.unwrap_or(1).saturating_sub(1),
+            });
+            let entry = json!({
+                "range": {"start": position, "end": position},
+                "severity": match diagnostic.severity {
+                    Severity::Error => 1,
+                    Severity::Warning => 2,
+                },
+                "code": diagnostic.code,
+                "source": "smartmake",
+                "message": diagnostic.message,
+            });
+            match files.iter_mut().find(|(other, _)| *other == uri) {
+                Some((_, entries)) => entries.push(entry),
+                None => files.push((uri, vec![entry])),
+            }
+        }
+        let params = files
+            .into_iter()
+            .map(|(uri, diagnostics)| json!({"uri": uri, "diagnostics": diagnostics}))
+            .collect::<Vec<_>>();
+        let mut report = serde_json::to_vec_pretty(&params)?;
+        report.push(b'\n');
+        write_report(self.path.as_deref(), &report)
+    }
+}
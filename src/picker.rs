@@ -0,0 +1,216 @@
+use std::{collections::BTreeSet, io::Result};
+
+use ratatui::{
+    crossterm::event::{self, Event as TerminalEvent, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::Paragraph,
+    DefaultTerminal, Frame,
+};
+
+// Whether the character before a match makes it the start of a word, like the `b` of
+// `src/bar` or `foo_bar`
+fn starts_word(before: Option<char>) -> bool {
+    before.is_none_or(|c| "/_-. ".contains(c))
+}
+
+/// How well `name` matches `query`, with the characters of the query in order but anywhere
+/// in the name and regardless of case, or `None` if it does not. Matches that run on or start
+/// words score higher, like in skim and fzf.
+pub fn score(query: &str, name: &str) -> Option<i64> {
+    let mut score = 0;
+    let mut wanted = query.chars().flat_map(char::to_lowercase).peekable();
+    let mut before = None;
+    let mut matched_before = false;
+    for c in name.chars() {
+        let Some(&next) = wanted.peek() else {
+            break;
+        };
+        let matched = c.to_lowercase().eq(next.to_lowercase());
+        if matched {
+            wanted.next();
+            score += 1;
+            if matched_before {
+                score += 4;
+            }
+            if starts_word(before) {
+                score += 3;
+            }
+        }
+        matched_before = matched;
+        before = Some(c);
+    }
+    // Shorter names are closer to what was typed
+    wanted
+        .peek()
+        .is_none()
+        .then(|| score * 100 - name.chars().count() as i64)
+}
+
+struct Picker {
+    names: Vec<String>,
+    query: String,
+    // The indices of the names that match the query, best first
+    matches: Vec<usize>,
+    cursor: usize,
+    // The first match shown
+    top: usize,
+    selected: BTreeSet<usize>,
+}
+
+impl Picker {
+    fn new(names: Vec<String>) -> Picker {
+        let mut picker = Picker {
+            matches: Vec::new(),
+            names,
+            query: String::new(),
+            cursor: 0,
+            top: 0,
+            selected: BTreeSet::new(),
+        };
+        picker.filter();
+        picker
+    }
+
+    fn filter(&mut self) {
+        let mut scored: Vec<(i64, usize)> = self
+            .names
+            .iter()
+            .enumerate()
+            .filter_map(|(index, name)| Some((score(&self.query, name)?, index)))
+            .collect();
+        scored.sort_by_key(|&(score, index)| (-score, index));
+        self.matches = scored.into_iter().map(|(_, index)| index).collect();
+        self.cursor = 0;
+        self.top = 0;
+    }
+
+    fn move_by(&mut self, lines: isize) {
+        let last = self.matches.len().saturating_sub(1);
+        self.cursor = self.cursor.saturating_add_signed(lines).min(last);
+    }
+
+    // Handle a key press, returning what was picked once done, and no names when cancelled
+    fn key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> Option<Vec<String>> {
+        if modifiers.contains(KeyModifiers::CONTROL) {
+            match code {
+                KeyCode::Char('c') => return Some(Vec::new()),
+                KeyCode::Char('n') => self.move_by(1),
+                KeyCode::Char('p') => self.move_by(-1),
+                _ => {}
+            }
+            return None;
+        }
+        match code {
+            KeyCode::Esc => return Some(Vec::new()),
+            KeyCode::Enter => {
+                let mut picked: Vec<usize> = self.selected.iter().copied().collect();
+                if picked.is_empty() {
+                    picked.extend(self.matches.get(self.cursor));
+                }
+                return Some(
+                    picked
+                        .into_iter()
+                        .map(|index| self.names[index].clone())
+                        .collect(),
+                );
+            }
+            KeyCode::Tab => {
+                if let Some(&index) = self.matches.get(self.cursor) {
+                    if !self.selected.remove(&index) {
+                        self.selected.insert(index);
+                    }
+                    self.move_by(1);
+                }
+            }
+            KeyCode::Up => self.move_by(-1),
+            KeyCode::Down => self.move_by(1),
+            KeyCode::PageUp => self.move_by(-10),
+            KeyCode::PageDown => self.move_by(10),
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.filter();
+            }
+            KeyCode::Char(c) => {
+                self.query.push(c);
+                self.filter();
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [prompt, list, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let line = Line::from(vec![
+            Span::styled("> ", Style::new().fg(Color::Cyan).bold()),
+            Span::raw(format!("{}▏", self.query)),
+        ]);
+        frame.render_widget(Paragraph::new(line), prompt);
+
+        // Scrolled so that the cursor stays in view
+        let height = (list.height as usize).max(1);
+        if self.cursor < self.top {
+            self.top = self.cursor;
+        } else if self.cursor >= self.top + height {
+            self.top = self.cursor + 1 - height;
+        }
+        let lines = self
+            .matches
+            .iter()
+            .enumerate()
+            .skip(self.top)
+            .take(height)
+            .map(|(position, &index)| {
+                let mark = match self.selected.contains(&index) {
+                    true => "● ",
+                    false => "  ",
+                };
+                let text = format!("{}{}", mark, self.names[index]);
+                match position == self.cursor {
+                    true => Line::styled(text, Style::new().fg(Color::Black).bg(Color::Cyan)),
+                    false => Line::raw(text),
+                }
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(Paragraph::new(lines), list);
+
+        let help = format!(
+            "{}/{}  {} selected  ↑↓ move  tab select  enter build  esc cancel",
+            self.matches.len(),
+            self.names.len(),
+            self.selected.len()
+        );
+        frame.render_widget(Paragraph::new(Line::from(help.dark_gray())), footer);
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<Vec<String>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if let TerminalEvent::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                if let Some(picked) = self.key(key.code, key.modifiers) {
+                    return Ok(picked);
+                }
+            }
+        }
+    }
+}
+
+/// Let the user pick some of `names` by typing part of them, and return the ones picked, or
+/// none if they cancelled
+pub fn pick(names: Vec<String>) -> Result<Vec<String>> {
+    let mut picker = Picker::new(names);
+    let mut terminal = ratatui::try_init()?;
+    let result = picker.run(&mut terminal);
+    ratatui::try_restore()?;
+    result
+}
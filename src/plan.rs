@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt::{self, Display, Formatter},
+    path::PathBuf,
+    process::Command,
+};
+
+/// A fully resolved command that a build system would run, which can be inspected before
+/// being turned into a [`Command`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct CommandPlan {
+    /// The program to run
+    pub program: OsString,
+    /// Its arguments
+    pub args: Vec<OsString>,
+    /// Environment variables set on top of the inherited environment
+    pub env: HashMap<String, String>,
+    /// The directory to run it in, or the current directory if not set
+    pub cwd: Option<PathBuf>,
+}
+
+impl CommandPlan {
+    pub fn new<S: Into<OsString>>(program: S) -> CommandPlan {
+        CommandPlan {
+            program: program.into(),
+            args: Vec::new(),
+            env: HashMap::new(),
+            cwd: None,
+        }
+    }
+
+    pub fn arg<S: Into<OsString>>(&mut self, arg: S) -> &mut CommandPlan {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut CommandPlan
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn envs<I, K, V>(&mut self, vars: I) -> &mut CommandPlan
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.env.extend(
+            vars.into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        );
+        self
+    }
+
+    pub fn current_dir<P: Into<PathBuf>>(&mut self, dir: P) -> &mut CommandPlan {
+        self.cwd = Some(dir.into());
+        self
+    }
+
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        command.envs(&self.env);
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        command
+    }
+}
+
+// `word` quoted for the shell of the platform if it has to be, like a path with spaces, so
+// that the command can be pasted into a terminal
+fn quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word.chars().all(|c| {
+            c.is_alphanumeric() || "-_./:=,+@".contains(c) || (c == '\\' && cfg!(windows))
+        });
+    match (plain, cfg!(windows)) {
+        (true, _) => word.to_string(),
+        (false, true) => format!("\"{}\"", word.replace('"', "\"\"")),
+        (false, false) => format!("'{}'", word.replace('\'', "'\\''")),
+    }
+}
+
+impl Display for CommandPlan {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", quote(&self.program.to_string_lossy()))?;
+        for arg in &self.args {
+            write!(f, " {}", quote(&arg.to_string_lossy()))?;
+        }
+        Ok(())
+    }
+}
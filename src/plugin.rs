@@ -0,0 +1,115 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    env::{split_paths, var_os},
+    fs::read_dir,
+    io::{Error, ErrorKind, Result, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    exec::{find_program, is_executable},
+    BuildOptions, CommandPlan,
+};
+
+// Executables on PATH with this prefix provide build systems, like cargo subcommands
+const PREFIX: &str = "smartmake-";
+
+// The reply to `smartmake-<name> detect <dir>`
+#[derive(Deserialize)]
+struct Detection {
+    detected: bool,
+}
+
+// The reply to `smartmake-<name> command`, which receives the build options as JSON on stdin
+#[derive(Deserialize)]
+struct PluginCommand {
+    program: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    cwd: Option<PathBuf>,
+}
+
+/// The names of all plugins on PATH, in a stable order
+pub fn discover() -> Vec<String> {
+    let mut names = BTreeSet::new();
+    for dir in split_paths(&var_os("PATH").unwrap_or_default()) {
+        let Ok(entries) = read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if let Some(name) = entry.file_name().to_str() {
+                if let Some(plugin) = name.strip_prefix(PREFIX) {
+                    if is_executable(&entry.path()) {
+                        names.insert(plugin.to_string());
+                    }
+                }
+            }
+        }
+    }
+    crate::trace!("plugin", "Plugins on PATH: {:?}", names);
+    names.into_iter().collect()
+}
+
+/// The executable of the plugin called `name`, if there is one on PATH
+pub fn find(name: &str) -> Option<PathBuf> {
+    find_program(&format!("{}{}", PREFIX, name))
+}
+
+fn invalid_reply(name: &str, error: serde_json::Error) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{}{} replied with invalid JSON: {}", PREFIX, name, error),
+    )
+}
+
+/// Ask the plugin whether `path` is a project it can build
+pub fn detect<P: AsRef<Path>>(name: &str, path: P) -> Result<bool> {
+    let Some(executable) = find(name) else {
+        return Ok(false);
+    };
+    let output = Command::new(executable)
+        .arg("detect")
+        .arg(path.as_ref())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Ok(false);
+    }
+    let detection: Detection =
+        serde_json::from_slice(&output.stdout).map_err(|error| invalid_reply(name, error))?;
+    Ok(detection.detected)
+}
+
+/// Ask the plugin for the command that carries out `options`
+pub fn command(name: &str, options: &BuildOptions) -> Result<Option<CommandPlan>> {
+    let Some(executable) = find(name) else {
+        return Ok(None);
+    };
+    let mut child = Command::new(executable)
+        .arg("command")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    let request = serde_json::to_vec(options).map_err(|error| invalid_reply(name, error))?;
+    child.stdin.take().unwrap().write_all(&request)?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(Error::other(format!(
+            "{}{} command failed with {}",
+            PREFIX, name, output.status
+        )));
+    }
+    let reply: PluginCommand =
+        serde_json::from_slice(&output.stdout).map_err(|error| invalid_reply(name, error))?;
+    let mut command = CommandPlan::new(reply.program);
+    command.args(reply.args);
+    command.envs(reply.env);
+    command.current_dir(reply.cwd.unwrap_or_else(|| options.directory.clone()));
+    Ok(Some(command))
+}
@@ -0,0 +1,34 @@
+use std::io::Result;
+
+use crate::{detect::find_build_dir, exec, Project};
+
+/// The project in `project` once for each build system its configuration builds one after the
+/// other, in that order, each found in the top directory of the project as if it were the only
+/// one enabled
+pub fn projects(project: &Project) -> Result<Vec<(String, Project)>> {
+    let root = project.root();
+    let mut projects = Vec::new();
+    for name in &project.config.polyglot {
+        let mut polyglot = Project::find(&project.cwd)?;
+        polyglot.config.enable = vec![name.clone()];
+        polyglot.config.polyglot.clear();
+        // Not the one of a parent directory, which belongs to another project
+        let detection = find_build_dir(&root, &polyglot.config)?.filter(|detection| {
+            detection
+                .source
+                .as_ref()
+                .unwrap_or(&detection.directory)
+                .starts_with(&root)
+        });
+        if detection.is_none() {
+            return Err(exec::no_build_system(format!(
+                "No {} build found in {}",
+                name,
+                root.display()
+            )));
+        }
+        polyglot.detection = detection;
+        projects.push((name.clone(), polyglot));
+    }
+    Ok(projects)
+}
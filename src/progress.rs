@@ -0,0 +1,110 @@
+use std::time::{Duration, Instant};
+
+use crate::history::format_duration;
+
+// The width of the bar itself, without the numbers around it
+const BAR_WIDTH: usize = 30;
+
+/// How far along a running build is, from how long earlier builds took or else from the
+/// progress the build system reports
+pub struct ProgressTracker {
+    started: Instant,
+    expected: Option<Duration>,
+    reported: Option<f64>,
+}
+
+impl ProgressTracker {
+    /// Start tracking a build that is expected to take `expected`, if that is known
+    pub fn new(expected: Option<Duration>) -> ProgressTracker {
+        ProgressTracker {
+            started: Instant::now(),
+            expected,
+            reported: None,
+        }
+    }
+
+    /// Take in a line of build output
+    pub fn observe(&mut self, line: &str) {
+        if let Some(progress) = parse_progress(line) {
+            self.reported = Some(progress);
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The estimated fraction of the build that is done. A build that takes longer than
+    /// expected stays just short of done.
+    pub fn fraction(&self) -> Option<f64> {
+        match self.expected {
+            Some(expected) if !expected.is_zero() => {
+                Some((self.elapsed().as_secs_f64() / expected.as_secs_f64()).min(0.99))
+            }
+            _ => self.reported,
+        }
+    }
+
+    /// The estimated time until the build is done
+    pub fn remaining(&self) -> Option<Duration> {
+        let elapsed = self.elapsed();
+        match self.expected {
+            Some(expected) => Some(expected.saturating_sub(elapsed)),
+            None => {
+                let fraction = self.reported.filter(|&fraction| fraction > 0.0)?;
+                Duration::try_from_secs_f64(elapsed.as_secs_f64() * (1.0 - fraction) / fraction)
+                    .ok()
+            }
+        }
+    }
+
+    /// The progress bar, like `[=========>          ]  45%  1m12s left`
+    pub fn render(&self) -> String {
+        let elapsed = format_duration(self.elapsed());
+        let Some(fraction) = self.fraction() else {
+            return format!("Building for {}", elapsed);
+        };
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        let bar = format!(
+            "{}>{}",
+            "=".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled.min(BAR_WIDTH))
+        );
+        match self.remaining() {
+            Some(remaining) => format!(
+                "[{}] {:>3.0}%  {} left",
+                bar,
+                fraction * 100.0,
+                format_duration(remaining)
+            ),
+            None => format!("[{}] {:>3.0}%  {}", bar, fraction * 100.0, elapsed),
+        }
+    }
+}
+
+/// How far along a build is, from 0 to 1, judging by a line of its output: ninja's
+/// `[3/40]`, the `[ 45%]` of CMake's makefiles or cargo's `Building [===> ] 12/40`
+pub fn parse_progress(line: &str) -> Option<f64> {
+    let line = line.trim_start();
+    if let Some(rest) = line.strip_prefix('[') {
+        let (inside, _) = rest.split_once(']')?;
+        if let Some(percent) = inside.trim().strip_suffix('%') {
+            return percent
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .map(|percent| percent / 100.0);
+        }
+        return fraction(inside);
+    }
+    let rest = line.strip_prefix("Building")?;
+    let (_, counts) = rest.rsplit_once(']')?;
+    fraction(counts.split_whitespace().next()?)
+}
+
+fn fraction(text: &str) -> Option<f64> {
+    let (done, total) = text.trim().split_once('/')?;
+    let done: f64 = done.trim().parse().ok()?;
+    let total: f64 = total.trim().parse().ok()?;
+    (total > 0.0).then(|| (done / total).min(1.0))
+}
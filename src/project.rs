@@ -0,0 +1,633 @@
+use std::{
+    collections::HashMap,
+    fs::exists,
+    io::{Error, ErrorKind, Result},
+    path::PathBuf,
+    thread::available_parallelism,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    build_system::ninja_program,
+    cache::CompilerCache,
+    capture, cargo, cmake,
+    config::{Config, CrossConfig},
+    container, detect,
+    distributed::Distributor,
+    environment::{self, Environment},
+    exec::{self, find_program},
+    explain, find_build_dir, jobs_per_project, jobserver, limits, lock,
+    sandbox::{self, Sandbox},
+    timings, toolchain, BuildOptions, BuildSystem, CommandPlan, Detection, Verb,
+};
+
+/// The configuration and build system that apply to a directory
+#[derive(Debug)]
+pub struct Project {
+    /// The directory the project was looked up from
+    pub cwd: PathBuf,
+    pub config: Config,
+    /// The directory of the project configuration, if there is one
+    pub config_dir: Option<PathBuf>,
+    /// The build system, or `None` if none was found
+    pub detection: Option<Detection>,
+}
+
+impl Project {
+    /// Load the configuration for `cwd` and find its build system, unless the configuration
+    /// pins one
+    pub fn find<P: Into<PathBuf>>(cwd: P) -> Result<Project> {
+        let cwd = cwd.into();
+        let (mut config, config_dir) = Config::resolve(&cwd).map_err(|error| {
+            Error::new(error.kind(), format!("Invalid configuration: {}", error))
+        })?;
+        let detection = match (config.build_system.take(), &config_dir) {
+            (Some(build_system), Some(dir)) => Some(Detection {
+                build_system,
+                directory: match &config.directory {
+                    Some(directory) => dir.join(directory),
+                    None => dir.clone(),
+                },
+                source: None,
+            }),
+            _ => find_build_dir(&cwd, &config)?,
+        };
+        Ok(Project {
+            cwd,
+            config,
+            config_dir,
+            detection,
+        })
+    }
+
+    /// The top directory of the project, which contains both the build directory and the
+    /// directory it was looked up from
+    pub fn root(&self) -> PathBuf {
+        match (&self.config_dir, &self.detection) {
+            (Some(dir), _) => dir.clone(),
+            (
+                None,
+                Some(Detection {
+                    source: Some(source),
+                    ..
+                }),
+            ) => source.clone(),
+            (None, Some(detection)) if self.cwd.starts_with(&detection.directory) => {
+                detection.directory.clone()
+            }
+            _ => self.cwd.clone(),
+        }
+    }
+}
+
+/// A request to build, as given on the command line
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Invocation {
+    pub verb: Verb,
+    /// The targets to build, or the configured ones if empty
+    pub targets: Vec<String>,
+    pub threads: Option<usize>,
+    pub jobs_per_project: Option<usize>,
+    pub profile: Option<String>,
+    /// The compiler cache to build with, instead of the configured one
+    pub cache: Option<CompilerCache>,
+    /// Whether to spread the compilations over a build farm
+    pub distributed: bool,
+    /// The tool to do that with, instead of the configured one
+    pub distributor: Option<Distributor>,
+    /// The container image to build in, instead of the configured one
+    pub container: Option<String>,
+    /// The memory the build may use, instead of the configured limit
+    pub max_memory: Option<String>,
+    /// The CPU time the build may use, instead of the configured limit
+    pub cpu_quota: Option<String>,
+    /// The platform to build for, instead of the one smartmake runs on
+    pub cross_target: Option<String>,
+    /// The generator to configure new CMake build directories with, instead of the configured
+    /// one
+    pub cmake_generator: Option<String>,
+    /// The directory to build in, relative to the directory the build was started in
+    pub build_dir: Option<PathBuf>,
+    /// The packages of the Cargo workspace to build, instead of the one the build is in
+    pub packages: Vec<String>,
+    /// Whether to build every package of the Cargo workspace
+    pub workspace: bool,
+    /// The Cargo features to build with, or the configured equivalents for other build systems
+    pub features: Vec<String>,
+    /// Whether to build with every Cargo feature
+    pub all_features: bool,
+    /// Whether to build without the default Cargo features
+    pub no_default_features: bool,
+    /// Whether to configure stale CMake build directories again without asking
+    pub reconfigure: bool,
+    /// Whether to have the build system tell why it rebuilds what it does
+    pub explain: bool,
+    /// Whether to have the build system record how long each target took
+    pub timings: bool,
+    /// The sandbox to build in, instead of the configured one
+    pub sandbox: Option<Sandbox>,
+    /// Whether to host a make jobserver for the build, unless it runs under one already
+    pub jobserver: bool,
+}
+
+impl Invocation {
+    /// The number of threads the build uses in `project`
+    pub fn threads(&self, project: &Project) -> Result<usize> {
+        // A build farm runs many more compilations at once than there are local threads
+        let distributed = self
+            .distributor(project)
+            .and_then(|distributor| project.config.distributed_jobs.or(distributor.jobs()));
+        let threads = match self.threads.or(distributed).or(project.config.jobs) {
+            Some(threads) => threads,
+            // Sharing the machine with the other builds running on it
+            None if project.config.share_jobs.unwrap_or(true) => {
+                lock::share(available_parallelism()?.get())
+            }
+            None => available_parallelism()?.get(),
+        };
+        Ok(jobs_per_project(threads, 1, self.jobs_per_project))
+    }
+
+    /// The targets the build builds in `project`
+    pub fn targets<'a>(&'a self, project: &'a Project) -> &'a [String] {
+        if self.targets.is_empty() {
+            &project.config.targets
+        } else {
+            &self.targets
+        }
+    }
+
+    /// The compiler cache the build uses in `project`, if any
+    pub fn cache(&self, project: &Project) -> Option<CompilerCache> {
+        self.cache
+            .or(project.config.cache)
+            .filter(|&cache| cache != CompilerCache::Off)
+    }
+
+    /// The tool that spreads the compilations of the build over a build farm, if any
+    pub fn distributor(&self, project: &Project) -> Option<Distributor> {
+        self.distributed.then(|| {
+            self.distributor
+                .or(project.config.distributed)
+                .unwrap_or(Distributor::Distcc)
+        })
+    }
+
+    /// The container image the build in `project` runs in, if it runs in one
+    pub fn container<'a>(&'a self, project: &'a Project) -> Option<&'a String> {
+        self.container
+            .as_ref()
+            .or(project.config.container.as_ref())
+    }
+
+    /// Whether the build in `project` with `build_system` takes its jobs from a make
+    /// jobserver, that smartmake hosts or runs under, rather than being told a number of them.
+    /// A build in a container cannot reach the pipe of the jobserver.
+    pub fn joins_jobserver(&self, project: &Project, build_system: &BuildSystem) -> bool {
+        (self.jobserver || jobserver::inherited().is_some())
+            && self.container(project).is_none()
+            && jobserver::joins(build_system, &project.config)
+    }
+
+    /// The program CMake runs the C and C++ compilers of the build in `project` through: the
+    /// compiler cache, which hands what it cannot answer on to the build farm itself, or the
+    /// tool that spreads the compilations over the build farm
+    pub fn compiler_launcher(&self, project: &Project) -> Option<&'static str> {
+        self.cache(project)
+            .map(CompilerCache::name)
+            .or(self.distributor(project).map(Distributor::name))
+    }
+
+    /// The settings for the platform the build is for in `project`, if they are configured
+    pub fn cross<'a>(&self, project: &'a Project) -> Option<&'a CrossConfig> {
+        project.config.cross.get(self.cross_target.as_ref()?)
+    }
+
+    /// The generator CMake projects are configured with, or `None` for the default of CMake
+    pub fn cmake_generator(&self, project: &Project) -> Option<String> {
+        cmake::generator(
+            self.explicit_generator(project),
+            project.config.ninja_program.as_deref(),
+        )
+    }
+
+    /// The build directory the build uses in `project` instead of the detected one: the one
+    /// given with `--build-dir`, the configured one of the platform it is for or of its
+    /// profile, or else `build/<profile>` in a CMake project
+    pub fn build_dir(&self, project: &Project) -> Option<PathBuf> {
+        if let Some(dir) = &self.build_dir {
+            return Some(project.cwd.join(dir));
+        }
+        let root = project.root();
+        if let Some(dir) = self
+            .cross(project)
+            .and_then(|cross| cross.directory.as_ref())
+        {
+            return Some(root.join(dir));
+        }
+        let name = self.profile.as_ref()?;
+        let profile = project.config.profile.get(name);
+        if let Some(dir) = profile.and_then(|profile| profile.directory.as_ref()) {
+            return Some(root.join(dir));
+        }
+        // Configuring one build directory for one profile after the other would rebuild
+        // everything every time
+        let source = cmake::source_root(&root)?;
+        Some(source.join(cmake::BUILD_DIR).join(name))
+    }
+
+    /// The build system the build uses in `project`: the one in its build directory, if it
+    /// builds in another one than the detected one. A stale CMake build directory has to be
+    /// configured again, which can change its build tool, so it is built through CMake.
+    pub fn detection(&self, project: &Project) -> Result<Option<Detection>> {
+        let Some(mut detection) = self.build_dir_detection(project)? else {
+            return Ok(None);
+        };
+        let dir = &detection.directory;
+        if cmake::is_configured(dir)
+            && cmake::stale(dir, self.explicit_generator(project)).is_some()
+        {
+            let source = cmake::home(dir).or_else(|| cmake::source_root(&project.root()));
+            if source.is_some() {
+                detection.build_system = BuildSystem::CMake;
+                detection.source = source;
+            }
+        }
+        Ok(Some(detection))
+    }
+
+    // The generator asked for on the command line or in the configuration, if any
+    fn explicit_generator<'a>(&'a self, project: &'a Project) -> Option<&'a str> {
+        self.cmake_generator
+            .as_deref()
+            .or(project.config.cmake_generator.as_deref())
+    }
+
+    fn build_dir_detection(&self, project: &Project) -> Result<Option<Detection>> {
+        let generator = self.cmake_generator(project);
+        let Some(dir) = self.build_dir(project) else {
+            // A generator given on the command line changes what builds a new build directory
+            return Ok(match &project.detection {
+                Some(Detection {
+                    source: Some(source),
+                    directory,
+                    ..
+                }) => Some(cmake::detection(
+                    source,
+                    directory.clone(),
+                    generator.as_deref(),
+                )),
+                detection => detection.clone(),
+            });
+        };
+        let root = project.root();
+        let detection = match exists(&dir)? {
+            true => detect(&dir, &project.config)?.into_iter().next(),
+            false => None,
+        };
+        match (detection, cmake::source_root(&root)) {
+            (Some(detection), _) => Ok(Some(detection)),
+            (None, Some(source)) => Ok(Some(cmake::detection(&source, dir, generator.as_deref()))),
+            (None, None) => Err(exec::no_build_system(format!(
+                "No build system found in {}",
+                dir.display()
+            ))),
+        }
+    }
+
+    /// Prepare the build in `project`: check that the programs it needs are installed and
+    /// recent enough, and configure its CMake build directory first, if it has not been configured yet, with
+    /// the toolchain file of the platform it is for and the build type its profile names
+    pub fn configure(&self, project: &Project) -> Result<()> {
+        toolchain::check(&project.config.requires)?;
+        let Some(mut detection) = self.detection(project)? else {
+            return Ok(());
+        };
+        // Before CMake configures the build for a build tool that is not there
+        if let Some(plan) = self.plan(project)? {
+            exec::require(&plan.program)?;
+        }
+        let mut args = Vec::new();
+        if let Some(file) = self
+            .cross(project)
+            .and_then(|cross| cross.toolchain_file.as_ref())
+        {
+            let file = project.root().join(file);
+            args.push(format!("-DCMAKE_TOOLCHAIN_FILE={}", file.display()));
+        }
+        if let Some(build_type) = self.profile.as_deref().and_then(cmake::build_type) {
+            args.push(format!("-DCMAKE_BUILD_TYPE={}", build_type));
+        }
+        // CMake only looks for a ninja called `ninja` for its Ninja generators
+        let ninja = ninja_program(project.config.ninja_program.as_deref());
+        let generator = self.cmake_generator(project);
+        if ninja != "ninja"
+            && generator
+                .as_deref()
+                .is_some_and(|name| name.starts_with("Ninja"))
+        {
+            args.push(format!("-DCMAKE_MAKE_PROGRAM={}", ninja));
+        }
+        let launcher = self.compiler_launcher(project);
+        if let Some(launcher) = launcher {
+            args.push(format!("-DCMAKE_C_COMPILER_LAUNCHER={}", launcher));
+            args.push(format!("-DCMAKE_CXX_COMPILER_LAUNCHER={}", launcher));
+            // To configure it again if it was configured without the launcher
+            if detection.source.is_none() {
+                detection.source = cmake::home(&detection.directory);
+            }
+        }
+        let dir = &detection.directory;
+        let stale = match cmake::is_configured(dir) && detection.source.is_some() {
+            true => cmake::stale(dir, self.explicit_generator(project))
+                .or_else(|| launcher.and_then(|launcher| cmake::without_launcher(dir, launcher))),
+            false => None,
+        };
+        if let Some(reason) = stale {
+            let question = format!(
+                "{} cannot be built as it is: {}. Configure it again?",
+                dir.display(),
+                reason
+            );
+            if !self.reconfigure && !cmake::confirm(&question) {
+                exec::message(&format!(
+                    "{} is stale: {}; --reconfigure configures it again",
+                    dir.display(),
+                    reason
+                ));
+                return Ok(());
+            }
+            cmake::reset(dir)?;
+        }
+        cmake::configure(&detection, generator.as_deref(), &args)
+    }
+
+    // Run `plan` in the container or the environment that the build of `project` runs in
+    fn surround(&self, project: &Project, plan: CommandPlan) -> Result<CommandPlan> {
+        let config = &project.config;
+        if let Some(image) = self.container(project) {
+            let runtime = container::runtime(config.container_runtime.as_deref())?;
+            return Ok(container::wrap(
+                plan,
+                &runtime,
+                image,
+                &project.root(),
+                &project.cwd,
+            ));
+        }
+        // The container brings its own toolchain, so this is only for builds outside one. Builds
+        // fail in puzzling ways outside of the Python environment of the project, so it is used
+        // unless another one is configured.
+        let environment = config.use_environment.unwrap_or(Environment::Python);
+        environment::wrap(plan, environment, &project.cwd, &project.root())
+    }
+
+    /// The command that downloads what the build of `project` needs without building
+    /// anything, in the container or environment the build runs in but outside its sandbox,
+    /// if its build system has one
+    pub fn fetch_plan(&self, project: &Project) -> Result<Option<CommandPlan>> {
+        let Some(detection) = &self.detection(project)? else {
+            return Ok(None);
+        };
+        let build_system = &detection.build_system;
+        let options = BuildOptions {
+            verb: self.verb,
+            threads: self.threads(project)?,
+            directory: detection.directory.clone(),
+            targets: self.targets(project),
+            args: Vec::new(),
+            profile: self.profile.as_deref(),
+            cross_target: self.cross_target.as_deref(),
+            jobserver: false,
+        };
+        let Some(plan) = build_system.fetch(&options, &project.config) else {
+            return Ok(None);
+        };
+        let plan = self.surround(project, plan)?;
+        crate::debug!("plan", "Fetch command: {}", plan);
+        Ok(Some(plan))
+    }
+
+    /// The command that carries out this invocation in `project`, or `None` if the project
+    /// has no build system
+    pub fn plan(&self, project: &Project) -> Result<Option<CommandPlan>> {
+        let Some(detection) = &self.detection(project)? else {
+            return Ok(None);
+        };
+        let config = &project.config;
+        let build_system = &detection.build_system;
+        let cross = self.cross(project);
+        if let (Some(target), None) = (&self.cross_target, cross) {
+            // Make and Ninja leave the compilers to whatever wrote the build files
+            if matches!(
+                build_system,
+                BuildSystem::Make | BuildSystem::Ninja | BuildSystem::CMake
+            ) {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!(
+                        "{} cannot build for {} without a [cross.{}] table in the configuration",
+                        build_system.name(),
+                        target,
+                        target
+                    ),
+                ));
+            }
+        }
+        let mut args = config.args_for(build_system);
+        let profile = match &self.profile {
+            Some(name) => match config.profile.get(name) {
+                Some(profile) => Some(profile),
+                // Custom build systems can take any profile through `{profile}`, cargo knows
+                // its own, and CMake projects build each one in a directory of its own
+                None if matches!(build_system, BuildSystem::Custom(_) | BuildSystem::Cargo) => None,
+                None if cmake::source_root(&project.root()).is_some() => None,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("No profile {} in the configuration", name),
+                    ))
+                }
+            },
+            None => None,
+        };
+        if let Some(profile) = profile {
+            args.extend(profile.args_for(build_system));
+        }
+        // Cargo has profiles of its own, like `release` and the ones in `Cargo.toml`
+        if let (Some(name), None, BuildSystem::Cargo) = (&self.profile, profile, build_system) {
+            args.extend(["--profile".to_string(), name.clone()]);
+        }
+        if let Some(cross) = cross {
+            args.extend(cross.args.iter().cloned());
+        }
+        if !self.packages.is_empty() || self.workspace {
+            if *build_system != BuildSystem::Cargo {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("{} has no packages to choose from", build_system.name()),
+                ));
+            }
+            for package in &self.packages {
+                args.extend(["--package".to_string(), package.clone()]);
+            }
+            if self.workspace {
+                args.push("--workspace".to_string());
+            }
+        } else if *build_system == BuildSystem::Cargo {
+            // A member of a workspace is named, so that what is built does not depend on the
+            // directory cargo runs in or on the `default-members` of the workspace
+            if let Some((name, _)) = cargo::workspace_member(&detection.directory) {
+                args.extend(["--package".to_string(), name]);
+            }
+        }
+        let mut feature_env = HashMap::new();
+        if *build_system == BuildSystem::Cargo {
+            if !self.features.is_empty() {
+                args.extend(["--features".to_string(), self.features.join(",")]);
+            }
+            if self.all_features {
+                args.push("--all-features".to_string());
+            }
+            if self.no_default_features {
+                args.push("--no-default-features".to_string());
+            }
+        } else {
+            for name in &self.features {
+                match config.features.get(name) {
+                    Some(feature) => {
+                        args.extend(feature.args.iter().cloned());
+                        feature_env.extend(feature.env.clone());
+                    }
+                    None => exec::message(&format!(
+                        "Ignoring the feature {}, which {} does not have; it can be configured \
+                         under [features.{}]",
+                        name,
+                        build_system.name(),
+                        name
+                    )),
+                }
+            }
+            if self.all_features || self.no_default_features {
+                exec::message(
+                    "Ignoring --all-features and --no-default-features, which only cargo has",
+                );
+            }
+        }
+        if self.explain {
+            match explain::args(build_system) {
+                Some(explain) => args.extend(explain.iter().map(|arg| arg.to_string())),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::Unsupported,
+                        format!("{} cannot tell why it rebuilds", build_system.name()),
+                    ))
+                }
+            }
+        }
+        if self.timings {
+            match timings::args(build_system) {
+                Some(timings) => args.extend(timings.iter().map(|arg| arg.to_string())),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::Unsupported,
+                        format!(
+                            "{} cannot tell how long each target took",
+                            build_system.name()
+                        ),
+                    ))
+                }
+            }
+        }
+        let options = BuildOptions {
+            verb: self.verb,
+            threads: self.threads(project)?,
+            directory: detection.directory.clone(),
+            targets: self.targets(project),
+            args,
+            profile: self.profile.as_deref(),
+            cross_target: self.cross_target.as_deref(),
+            jobserver: self.joins_jobserver(project, build_system),
+        };
+        let mut plan = build_system.plan(&options, config)?;
+        if let Some(profile) = profile {
+            plan.envs(profile.env_for(build_system));
+        }
+        if let Some(cross) = cross {
+            plan.envs(cross.env.clone());
+        }
+        plan.envs(feature_env);
+        let cache = self.cache(project);
+        let distributor = self.distributor(project);
+        let wrappers = [
+            cache.map(CompilerCache::name),
+            distributor.map(Distributor::name),
+        ];
+        for program in wrappers.into_iter().flatten() {
+            if find_program(program).is_none() {
+                return Err(exec::missing_tool(format!("{} is not installed", program)));
+            }
+        }
+        if let Some(cache) = cache {
+            cache.apply(&mut plan, detection);
+        }
+        if let Some(distributor) = distributor {
+            if cache == Some(CompilerCache::Sccache) {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!(
+                        "sccache cannot hand compilations on to {}",
+                        distributor.name()
+                    ),
+                ));
+            }
+            distributor.apply(&mut plan, detection, cache);
+        }
+        plan = self.surround(project, plan)?;
+        if let Some(sandbox) = self.sandbox.or(config.sandbox) {
+            if self.container(project).is_some() {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "A build in a container cannot be sandboxed as well",
+                ));
+            }
+            let root = project.root();
+            let base = project.config_dir.clone().unwrap_or(root.clone());
+            let mut writable = vec![root];
+            let dirs = config
+                .sandbox_writable
+                .iter()
+                .map(|dir| sandbox::expand(dir, &base));
+            for dir in [detection.directory.clone()].into_iter().chain(dirs) {
+                if !writable.iter().any(|other| dir.starts_with(other)) {
+                    writable.push(dir);
+                }
+            }
+            plan = sandbox::wrap(plan, sandbox, &writable, &project.cwd)?;
+        }
+        let max_memory = self.max_memory.as_ref().or(config.max_memory.as_ref());
+        let cpu_quota = self.cpu_quota.as_ref().or(config.cpu_quota.as_ref());
+        if max_memory.is_some() || cpu_quota.is_some() {
+            plan = limits::wrap(
+                plan,
+                max_memory.map(String::as_str),
+                cpu_quota.map(String::as_str),
+            )?;
+        }
+        crate::debug!("plan", "Command: {}", plan);
+        if let Some(cwd) = &plan.cwd {
+            crate::debug!("plan", "Run in {}", cwd.display());
+        }
+        let mut env: Vec<_> = plan.env.iter().collect();
+        env.sort();
+        for (name, value) in env {
+            let value = match capture::is_secret(name) {
+                true => "(secret)",
+                false => value,
+            };
+            crate::trace!("plan", "Environment: {}={}", name, value);
+        }
+        Ok(Some(plan))
+    }
+}
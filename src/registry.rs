@@ -0,0 +1,62 @@
+use std::{
+    collections::BTreeMap,
+    fs::{canonicalize, create_dir_all, read_to_string, write},
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use crate::config::Config;
+
+/// Where the registered projects are kept, next to the user configuration
+pub fn path() -> Option<PathBuf> {
+    Config::global_path().map(|config| config.with_file_name("projects.toml"))
+}
+
+/// The registered projects, by name
+pub fn load() -> Result<BTreeMap<String, PathBuf>> {
+    let Some(path) = path() else {
+        return Ok(BTreeMap::new());
+    };
+    match read_to_string(&path) {
+        Ok(text) => toml::from_str(&text).map_err(|error| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), error),
+            )
+        }),
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Register the project in `dir` as `name`, returning the directory it was registered as
+/// before, if any
+pub fn register(name: &str, dir: &Path) -> Result<Option<PathBuf>> {
+    let path = path().ok_or_else(|| {
+        Error::new(
+            ErrorKind::NotFound,
+            "Registering projects needs $XDG_CONFIG_HOME or $HOME",
+        )
+    })?;
+    let mut projects = load()?;
+    let previous = projects.insert(name.to_string(), canonicalize(dir)?);
+    let text = toml::to_string(&projects).map_err(Error::other)?;
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    write(&path, text)?;
+    Ok(previous)
+}
+
+/// The directory of the project registered as `name`
+pub fn resolve(name: &str) -> Result<PathBuf> {
+    load()?.remove(name).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "No project is registered as {}; `sm register {} <path>` registers one",
+                name, name
+            ),
+        )
+    })
+}
@@ -0,0 +1,235 @@
+use std::{
+    io::{stdin, stdout, Error, IsTerminal, Result},
+    path::Path,
+    process::{Command, ExitStatus},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{daemon::exit_code, exec, watch::DEFAULT_IGNORE, Invocation, Project, Verb};
+
+/// How the working tree gets to the remote machine
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMethod {
+    /// Copy the files, changed or not, with rsync
+    #[default]
+    Rsync,
+    /// Push the current commit to a clone on the remote machine and check it out there
+    Git,
+}
+
+/// A machine to build on over SSH, as configured under `[remotes.<name>]`
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteConfig {
+    /// The SSH destination, like `user@host`
+    pub host: String,
+    /// The directory the project is copied to, relative to the remote home directory
+    pub path: Option<String>,
+    pub sync: SyncMethod,
+    /// Files and directories, relative to the project, copied back after a successful build
+    pub artifacts: Vec<String>,
+}
+
+/// The configured remote called `name`, or else `name` taken as an SSH destination
+pub fn resolve(project: &Project, name: &str) -> RemoteConfig {
+    match project.config.remotes.get(name) {
+        Some(remote) => remote.clone(),
+        None => RemoteConfig {
+            host: name.to_string(),
+            ..RemoteConfig::default()
+        },
+    }
+}
+
+// Quote `word` for the remote shell
+fn quote(word: &str) -> String {
+    if !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:@%+,".contains(c))
+    {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+fn check(status: ExitStatus, what: &str) -> Result<()> {
+    match status.success() {
+        true => Ok(()),
+        false => Err(Error::other(format!("{} failed with {}", what, status))),
+    }
+}
+
+// The arguments for `sm` on the remote machine that carry out `invocation`
+fn sm_args(invocation: &Invocation) -> Vec<String> {
+    let mut args = Vec::new();
+    match invocation.verb {
+        _ if invocation.explain => args.push("explain-rebuild".to_string()),
+        Verb::Build => {}
+        verb => args.push(verb.name().to_string()),
+    }
+    if let Some(threads) = invocation.threads {
+        args.extend(["-t".to_string(), threads.to_string()]);
+    }
+    if let Some(jobs) = invocation.jobs_per_project {
+        args.extend(["--jobs-per-project".to_string(), jobs.to_string()]);
+    }
+    if let Some(profile) = &invocation.profile {
+        args.extend(["--profile".to_string(), profile.clone()]);
+    }
+    if let Some(cache) = invocation.cache {
+        args.extend(["--cache".to_string(), cache.name().to_string()]);
+    }
+    match (invocation.distributed, invocation.distributor) {
+        (true, Some(distributor)) => args.push(format!("--distributed={}", distributor.name())),
+        (true, None) => args.push("--distributed".to_string()),
+        (false, _) => {}
+    }
+    if let Some(image) = &invocation.container {
+        args.extend(["--container".to_string(), image.clone()]);
+    }
+    if let Some(size) = &invocation.max_memory {
+        args.extend(["--max-memory".to_string(), size.clone()]);
+    }
+    if let Some(quota) = &invocation.cpu_quota {
+        args.extend(["--cpu-quota".to_string(), quota.clone()]);
+    }
+    if let Some(target) = &invocation.cross_target {
+        args.extend(["--target".to_string(), target.clone()]);
+    }
+    if let Some(generator) = &invocation.cmake_generator {
+        args.extend(["--cmake-generator".to_string(), generator.clone()]);
+    }
+    // Relative to the same place within the project on the remote machine
+    if let Some(dir) = &invocation.build_dir {
+        args.extend([
+            "--build-dir".to_string(),
+            dir.to_string_lossy().into_owned(),
+        ]);
+    }
+    for package in &invocation.packages {
+        args.extend(["--package".to_string(), package.clone()]);
+    }
+    if invocation.workspace {
+        args.push("--workspace".to_string());
+    }
+    if !invocation.features.is_empty() {
+        args.extend(["--features".to_string(), invocation.features.join(",")]);
+    }
+    if invocation.all_features {
+        args.push("--all-features".to_string());
+    }
+    if invocation.no_default_features {
+        args.push("--no-default-features".to_string());
+    }
+    if let Some(sandbox) = invocation.sandbox {
+        args.push(format!("--sandbox={}", sandbox.name()));
+    }
+    if invocation.jobserver {
+        args.push("--jobserver".to_string());
+    }
+    if invocation.reconfigure {
+        args.push("--reconfigure".to_string());
+    }
+    args.push("--".to_string());
+    args.extend(invocation.targets.iter().cloned());
+    args
+}
+
+/// Copy `project` to `remote`, carry out `invocation` there with its own smartmake while
+/// showing the output here, copy the artifacts back if it succeeded, and return the exit code
+/// of the build
+pub fn build(project: &Project, remote: &RemoteConfig, invocation: &Invocation) -> Result<i32> {
+    let root = project.root();
+    let name = root
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "project".to_string());
+    let path = remote
+        .path
+        .clone()
+        .unwrap_or_else(|| format!("smartmake/{}", name));
+    sync(&root, remote, &path)?;
+
+    // Build in the same place within the project as here
+    let mut dir = Path::new(&path).to_path_buf();
+    if let Ok(relative) = project.cwd.strip_prefix(&root) {
+        dir.push(relative);
+    }
+    let script = format!(
+        "cd {} && sm {}",
+        quote(&dir.to_string_lossy()),
+        sm_args(invocation)
+            .iter()
+            .map(|arg| quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    let mut ssh = Command::new("ssh");
+    // A terminal on the remote side lets Ctrl-C stop the build there and keeps its colors
+    if stdin().is_terminal() && stdout().is_terminal() {
+        ssh.arg("-t");
+    }
+    ssh.arg(&remote.host).arg(script);
+    let code = exit_code(exec::run(ssh)?);
+    if code == 0 {
+        for artifact in &remote.artifacts {
+            let mut rsync = Command::new("rsync");
+            rsync
+                .arg("-az")
+                .arg("--protect-args")
+                .arg(format!("{}:{}/{}", remote.host, path, artifact))
+                .arg(root.join(artifact).parent().unwrap_or(&root));
+            check(exec::run(rsync)?, "Copying back the artifacts")?;
+        }
+    }
+    Ok(code)
+}
+
+// Bring the copy of the working tree at `path` on `remote` up to date
+fn sync(root: &Path, remote: &RemoteConfig, path: &str) -> Result<()> {
+    match remote.sync {
+        SyncMethod::Rsync => {
+            let mut rsync = Command::new("rsync");
+            rsync.arg("-az").arg("--delete").arg("--protect-args");
+            // The remote machine keeps its own build outputs
+            for pattern in DEFAULT_IGNORE {
+                rsync.arg(format!("--exclude={}", pattern));
+            }
+            rsync
+                .arg("--rsync-path")
+                .arg(format!("mkdir -p {} && rsync", quote(path)))
+                .arg(format!("{}/", root.display()))
+                .arg(format!("{}:{}", remote.host, path));
+            check(exec::run(rsync)?, "Copying the project")
+        }
+        SyncMethod::Git => {
+            let destination = format!("{}:{}", remote.host, path);
+            let path = quote(path);
+            // Pushing to the branch that is checked out there updates the files as well
+            let mut init = Command::new("ssh");
+            init.arg(&remote.host).arg(format!(
+                "git init -q {} && git -C {} config receive.denyCurrentBranch updateInstead",
+                path, path
+            ));
+            check(exec::run(init)?, "Preparing the remote clone")?;
+            let mut push = Command::new("git");
+            push.arg("-C")
+                .arg(root)
+                .arg("push")
+                .arg("--force")
+                .arg("--quiet")
+                .arg(&destination)
+                .arg("HEAD:refs/heads/smartmake");
+            check(exec::run(push)?, "Pushing the project")?;
+            let mut checkout = Command::new("ssh");
+            checkout
+                .arg(&remote.host)
+                .arg(format!("git -C {} checkout -q --force smartmake", path));
+            check(exec::run(checkout)?, "Checking out the project")
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::{cell::RefCell, io::Result, rc::Rc};
+
+use crate::{
+    diagnostics::{failed_target, failed_test},
+    exec::Stream,
+    output::Sink,
+    BuildSystem, CommandPlan, Invocation, Project, Verb,
+};
+
+/// What a build reported as failed
+#[derive(Clone, Debug, Default)]
+pub struct Failures {
+    /// The targets that failed to build, or the crates for cargo
+    pub targets: Vec<String>,
+    pub tests: Vec<String>,
+}
+
+impl Failures {
+    /// What a retry builds or runs again, which are the tests if any failed
+    pub fn retried(&self) -> &[String] {
+        match self.tests.is_empty() {
+            true => &self.targets,
+            false => &self.tests,
+        }
+    }
+}
+
+/// Collects the failed targets and tests from the output of a build, into failures that stay
+/// readable once the collector went to the sinks
+pub struct FailureCollector {
+    failures: Rc<RefCell<Failures>>,
+}
+
+impl FailureCollector {
+    pub fn new() -> (FailureCollector, Rc<RefCell<Failures>>) {
+        let failures = Rc::new(RefCell::new(Failures::default()));
+        let collector = FailureCollector {
+            failures: failures.clone(),
+        };
+        (collector, failures)
+    }
+}
+
+impl Sink for FailureCollector {
+    fn line(&mut self, _: Stream, line: &str) -> Result<()> {
+        let mut failures = self.failures.borrow_mut();
+        if let Some(target) = failed_target(line) {
+            if !failures.targets.contains(&target) {
+                failures.targets.push(target);
+            }
+        } else if let Some(test) = failed_test(line) {
+            if !failures.tests.contains(&test) {
+                failures.tests.push(test);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The command that builds or runs only what failed in the build of `plan` again, if the
+/// build system can: the failed targets of ninja, make and CMake, the crates cargo could not
+/// compile, the tests of `cargo test` by their exact names, and the ones CTest keeps itself
+pub fn plan(
+    project: &Project,
+    invocation: &Invocation,
+    plan: &CommandPlan,
+    failures: &Failures,
+) -> Result<Option<CommandPlan>> {
+    let Some(detection) = invocation.detection(project)? else {
+        return Ok(None);
+    };
+    let retry = |invocation: Invocation| invocation.plan(project);
+    match (&detection.build_system, invocation.verb) {
+        (BuildSystem::CMake, Verb::Test) => {
+            let mut retry = plan.clone();
+            retry.arg("--rerun-failed");
+            Ok(Some(retry))
+        }
+        (BuildSystem::Cargo, Verb::Test) if !failures.tests.is_empty() => {
+            let mut retry = plan.clone();
+            // The arguments after `--` go to the test binaries, and may be there already
+            if !plan.args.iter().any(|arg| arg == "--") {
+                retry.arg("--");
+            }
+            retry.arg("--exact").args(&failures.tests);
+            Ok(Some(retry))
+        }
+        (BuildSystem::Cargo, Verb::Build) if !failures.targets.is_empty() => retry(Invocation {
+            packages: failures.targets.clone(),
+            workspace: false,
+            ..invocation.clone()
+        }),
+        (BuildSystem::Make | BuildSystem::Ninja | BuildSystem::CMake, Verb::Build)
+            if !failures.targets.is_empty() =>
+        {
+            retry(Invocation {
+                targets: failures.targets.clone(),
+                ..invocation.clone()
+            })
+        }
+        _ => Ok(None),
+    }
+}
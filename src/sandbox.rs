@@ -0,0 +1,90 @@
+use std::{
+    env::var_os,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    exec::{self, find_program},
+    CommandPlan,
+};
+
+/// How far a sandboxed build is kept in
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Sandbox {
+    /// The build can read everything, but only write to the project and its build directory
+    Project,
+    /// As with `project`, and without network access either
+    Offline,
+}
+
+impl Sandbox {
+    pub fn name(self) -> &'static str {
+        match self {
+            Sandbox::Project => "project",
+            Sandbox::Offline => "offline",
+        }
+    }
+}
+
+/// `path` with a leading `~` for the home directory, and relative to `base`
+pub fn expand(path: &Path, base: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), var_os("HOME")) {
+        (Ok(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => base.join(path),
+    }
+}
+
+/// Run `plan` in a bubblewrap sandbox where the whole file system is read-only except for
+/// the `writable` directories and a private `/tmp`, so that a build writing outside of them
+/// fails instead of going unnoticed
+pub fn wrap(
+    plan: CommandPlan,
+    sandbox: Sandbox,
+    writable: &[PathBuf],
+    cwd: &Path,
+) -> Result<CommandPlan> {
+    if !cfg!(target_os = "linux") {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Sandboxed builds need the namespaces of Linux",
+        ));
+    }
+    if find_program("bwrap").is_none() {
+        return Err(exec::missing_tool(
+            "Sandboxed builds need bubblewrap (bwrap)",
+        ));
+    }
+    let mut command = CommandPlan::new("bwrap");
+    command
+        .arg("--ro-bind")
+        .arg("/")
+        .arg("/")
+        .arg("--dev")
+        .arg("/dev")
+        .arg("--proc")
+        .arg("/proc")
+        .arg("--tmpfs")
+        .arg("/tmp");
+    for dir in writable {
+        // A directory that does not exist yet cannot be mounted, and is made outside
+        command.arg("--bind-try").arg(dir).arg(dir);
+    }
+    if sandbox == Sandbox::Offline {
+        command.arg("--unshare-net");
+    }
+    command
+        .arg("--die-with-parent")
+        .arg("--chdir")
+        .arg(plan.cwd.as_deref().unwrap_or(cwd));
+    command.arg("--").arg(plan.program).args(plan.args);
+    command.envs(plan.env);
+    if let Some(dir) = plan.cwd {
+        command.current_dir(dir);
+    }
+    Ok(command)
+}
@@ -0,0 +1,110 @@
+use std::{
+    env::consts::EXE_SUFFIX,
+    fs::read_dir,
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use crate::{CommandPlan, Verb};
+
+/// A project that is a single source file without a build system, like a quick experiment
+#[derive(Clone, Debug, PartialEq)]
+pub struct SingleFile {
+    pub source: PathBuf,
+    /// The program it compiles to, next to it and named after it
+    pub program: PathBuf,
+}
+
+// The compiler for a source file called `name` on its own: `main.c`, `main.cpp` or `main.go`,
+// or any Rust file, which need no build system
+fn compiler(name: &str) -> Option<&'static str> {
+    match name {
+        "main.c" => Some("cc"),
+        "main.cpp" | "main.cc" | "main.cxx" => Some("c++"),
+        "main.go" => Some("go"),
+        _ if name.ends_with(".rs") => Some("rustc"),
+        _ => None,
+    }
+}
+
+/// The single source file in `dir`, if there is one smartmake knows how to compile and no
+/// other it could compile instead
+pub fn find(dir: &Path) -> Option<SingleFile> {
+    let mut sources = read_dir(dir)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| compiler(name).is_some());
+    let source = sources.next()?;
+    if sources.next().is_some() {
+        return None;
+    }
+    let stem = Path::new(&source)
+        .file_stem()?
+        .to_string_lossy()
+        .into_owned();
+    Some(SingleFile {
+        source: dir.join(&source),
+        program: dir.join(format!("{}{}", stem, EXE_SUFFIX)),
+    })
+}
+
+impl SingleFile {
+    /// The command that compiles the file in its directory, with the optimizations on for the
+    /// `release` profile, or removes the program it compiled to
+    pub fn plan(&self, verb: Verb, profile: Option<&str>) -> Result<CommandPlan> {
+        let name = self
+            .source
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let program = self.program.file_name().unwrap_or_default();
+        let release = profile == Some("release");
+        let mut command = match (verb, compiler(&name)) {
+            (Verb::Build, Some(compiler @ ("cc" | "c++"))) => {
+                let mut command = CommandPlan::new(compiler);
+                command.args(["-Wall", "-Wextra"]);
+                command.arg(if release { "-O2" } else { "-g" });
+                command.arg("-o").arg(program).arg(&name);
+                command
+            }
+            (Verb::Build, Some("go")) => {
+                let mut command = CommandPlan::new("go");
+                command.arg("build").arg("-o").arg(program).arg(&name);
+                command
+            }
+            (Verb::Build, Some(_)) => {
+                let mut command = CommandPlan::new("rustc");
+                command.args(["--edition", "2021"]);
+                command.arg(if release { "-O" } else { "-g" });
+                command.arg("-o").arg(program).arg(&name);
+                command
+            }
+            (Verb::Clean, _) if cfg!(windows) => {
+                let mut command = CommandPlan::new("cmd");
+                command.args(["/C", "del", "/F", "/Q"]).arg(program);
+                command
+            }
+            (Verb::Clean, _) => {
+                let mut command = CommandPlan::new("rm");
+                command.arg("-f").arg(program);
+                command
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    format!(
+                        "{} is compiled on its own, without a build system to {} it",
+                        name,
+                        verb.name()
+                    ),
+                ))
+            }
+        };
+        if let Some(dir) = self.source.parent() {
+            command.current_dir(dir);
+        }
+        Ok(command)
+    }
+}
@@ -0,0 +1,46 @@
+use std::{
+    env::var_os,
+    fs::{create_dir_all, metadata, read, rename, write},
+    io::Result,
+    path::{Path, PathBuf},
+    process,
+    time::UNIX_EPOCH,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The file called `name` in the cache directory of smartmake, in `$XDG_CACHE_HOME`, falling
+/// back to `~/.cache`
+pub fn cache_file(name: &str) -> Option<PathBuf> {
+    var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+        .map(|dir| dir.join("smartmake").join(name))
+}
+
+/// What `path` holds as JSON, or the default if it is missing or cannot be read
+pub fn load<T: DeserializeOwned + Default>(path: &Path) -> T {
+    read(path)
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Write `value` to `path` as JSON, to a new file first, so that other invocations of
+/// smartmake never read half of it
+pub fn store<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    let temporary = path.with_extension(format!("{}.tmp", process::id()));
+    write(&temporary, serde_json::to_vec(value)?)?;
+    rename(temporary, path)
+}
+
+/// The modification time of `path` in nanoseconds, if it exists
+pub fn modified(path: &Path) -> Option<u64> {
+    let modified = metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_nanos() as u64)
+}
@@ -0,0 +1,406 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Error, ErrorKind, Result},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use serde_json::Value;
+
+use crate::{
+    build_system::{make_command, ninja_program},
+    config::Config,
+    exec, BuildSystem, Detection,
+};
+
+/// The targets reachable from one of them, each with the targets it depends on, or with the
+/// ones that depend on it
+pub struct TargetGraph {
+    pub root: String,
+    pub reverse: bool,
+    pub edges: BTreeMap<String, Vec<String>>,
+}
+
+// The standard output of `command` in `dir`, failing with `what` when it fails and `check`
+fn output(mut command: Command, dir: &Path, what: &str, check: bool) -> Result<String> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = match command
+        .current_dir(dir)
+        .env("LC_ALL", "C")
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+    {
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            return Err(exec::missing_tool(format!(
+                "{} needs {} on PATH",
+                what, program
+            )))
+        }
+        output => output?,
+    };
+    if check && !output.status.success() {
+        return Err(Error::other(format!(
+            "{} failed with {}",
+            what, output.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// What `ninja -t query` tells about each of `targets`: its inputs, or the outputs of the
+// edges that read it
+fn ninja_query(
+    dir: &Path,
+    config: &Config,
+    targets: &[String],
+    reverse: bool,
+) -> Result<Vec<(String, Vec<String>)>> {
+    let mut command = Command::new(ninja_program(config.ninja_program.as_deref()));
+    command.args(["-t", "query"]).args(targets);
+    let text = output(command, dir, "Querying ninja", true)?;
+    let mut found: Vec<(String, Vec<String>)> = Vec::new();
+    let mut section = "";
+    for line in text.lines() {
+        let indent = line.len() - line.trim_start().len();
+        let line = line.trim();
+        match indent {
+            0 if line.starts_with("ninja:") => {}
+            0 => {
+                let node = line.strip_suffix(':').unwrap_or(line);
+                found.push((node.to_string(), Vec::new()));
+            }
+            2 => section = line.split(':').next().unwrap_or_default(),
+            _ => {
+                // Implicit inputs come after `|` and order-only ones after `||`
+                let name = line.trim_start_matches('|').trim_start();
+                let wanted = match reverse {
+                    true => section == "outputs",
+                    false => section == "input",
+                };
+                if let (true, Some((_, edges))) = (wanted, found.last_mut()) {
+                    edges.push(name.to_string());
+                }
+            }
+        }
+    }
+    Ok(found)
+}
+
+// The part of ninja's graph around `target`, asking for the targets of each step away from it
+// at once
+fn ninja(
+    dir: &Path,
+    config: &Config,
+    target: &str,
+    reverse: bool,
+) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut edges = BTreeMap::new();
+    let mut queue = vec![target.to_string()];
+    while !queue.is_empty() {
+        let mut next = Vec::new();
+        // Without running into the limit on the length of a command line
+        for batch in queue.chunks(256) {
+            for (node, neighbours) in ninja_query(dir, config, batch, reverse)? {
+                for neighbour in &neighbours {
+                    if !edges.contains_key(neighbour) && !next.contains(neighbour) {
+                        next.push(neighbour.clone());
+                    }
+                }
+                edges.insert(node, neighbours);
+            }
+        }
+        next.retain(|node| !edges.contains_key(node));
+        queue = next;
+    }
+    Ok(edges)
+}
+
+// Every explicit rule in the database `make -qp` prints, with its prerequisites, leaving out
+// the built-in and pattern rules
+fn make(dir: &Path, config: &Config) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut command = make_command(dir, config)?;
+    // Asking whether the targets are up to date runs none of their recipes
+    command.args(["-q", "-p"]);
+    let text = output(command.command(), dir, "Reading the make database", false)?;
+    let mut edges: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut files = false;
+    let mut not_a_target = false;
+    for line in text.lines() {
+        if line == "# Files" {
+            files = true;
+        } else if line.starts_with("# files hash-table stats") {
+            files = false;
+        }
+        if !files || line.starts_with('\t') {
+            continue;
+        }
+        if line.starts_with('#') {
+            not_a_target |= line == "# Not a target:";
+            continue;
+        }
+        if line.is_empty() {
+            not_a_target = false;
+            continue;
+        }
+        let Some((targets, prerequisites)) = line.split_once(':') else {
+            continue;
+        };
+        // Target-specific variables look like rules too
+        let prerequisites = prerequisites.trim_start_matches(':');
+        if not_a_target || prerequisites.contains('=') || targets.contains('%') {
+            continue;
+        }
+        let prerequisites: Vec<String> = prerequisites
+            .split_whitespace()
+            .filter(|prerequisite| *prerequisite != "|")
+            .map(str::to_string)
+            .collect();
+        for target in targets.split_whitespace() {
+            edges
+                .entry(target.to_string())
+                .or_default()
+                .extend(prerequisites.iter().cloned());
+        }
+    }
+    Ok(edges)
+}
+
+// The platform rustc builds for by default
+fn host() -> Option<String> {
+    let output = Command::new("rustc").arg("-vV").output().ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .map(str::to_string)
+}
+
+// The packages of the cargo workspace in `dir` and everything they depend on, each with its
+// direct dependencies, named like `cargo tree` names them
+fn cargo(dir: &Path) -> Result<BTreeMap<String, Vec<String>>> {
+    let mut command = Command::new("cargo");
+    command.args(["metadata", "--format-version", "1"]);
+    // Only the dependencies of this platform, as `cargo tree` shows them
+    if let Some(host) = host() {
+        command.arg("--filter-platform").arg(host);
+    }
+    let metadata: Value = serde_json::from_str(&output(command, dir, "cargo metadata", true)?)?;
+    let names: BTreeMap<&str, String> = metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|package| {
+            let name = format!(
+                "{} v{}",
+                package["name"].as_str()?,
+                package["version"].as_str()?
+            );
+            Some((package["id"].as_str()?, name))
+        })
+        .collect();
+    let name = |id: &Value| id.as_str().and_then(|id| names.get(id)).cloned();
+    Ok(metadata["resolve"]["nodes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|node| {
+            let dependencies = node["deps"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|dependency| name(&dependency["pkg"]))
+                .collect();
+            Some((name(&node["id"])?, dependencies))
+        })
+        .collect())
+}
+
+// `edges` the other way round: each node with the ones that have it as an edge
+fn invert(edges: &BTreeMap<String, Vec<String>>) -> BTreeMap<String, Vec<String>> {
+    let mut inverted: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (node, neighbours) in edges {
+        inverted.entry(node.clone()).or_default();
+        for neighbour in neighbours {
+            inverted
+                .entry(neighbour.clone())
+                .or_default()
+                .push(node.clone());
+        }
+    }
+    inverted
+}
+
+/// What `target` of the build in `detection` depends on, or with `reverse` what depends on it,
+/// as ninja, make or cargo tell. Cargo's targets are its packages, named like `serde` or
+/// `serde v1.0.200`.
+pub fn graph(
+    detection: &Detection,
+    config: &Config,
+    target: &str,
+    reverse: bool,
+) -> Result<TargetGraph> {
+    let dir = &detection.directory;
+    if detection.source.is_some() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "The CMake project has no build directory yet; build it once first",
+        ));
+    }
+    let ninja_files = dir.join("build.ninja").exists();
+    let (mut edges, root) = match &detection.build_system {
+        BuildSystem::Ninja => (ninja(dir, config, target, reverse)?, target.to_string()),
+        BuildSystem::CMake if ninja_files => {
+            (ninja(dir, config, target, reverse)?, target.to_string())
+        }
+        BuildSystem::Make | BuildSystem::CMake => (make(dir, config)?, target.to_string()),
+        BuildSystem::Cargo => {
+            let edges = cargo(dir)?;
+            let prefix = format!("{} v", target);
+            let root = edges
+                .keys()
+                .find(|name| *name == target || name.starts_with(&prefix))
+                .cloned()
+                .unwrap_or_else(|| target.to_string());
+            (edges, root)
+        }
+        BuildSystem::Custom(name) => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("The targets of {} builds cannot be told", name),
+            ))
+        }
+    };
+    let queried = matches!(detection.build_system, BuildSystem::Ninja) || ninja_files;
+    if reverse && !queried {
+        edges = invert(&edges);
+    }
+    if !edges.contains_key(&root) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("No target {} in the build", target),
+        ));
+    }
+    Ok(TargetGraph {
+        root,
+        reverse,
+        edges,
+    })
+}
+
+// The targets `ninja -t targets` lists with `mode`, without the rules they are built by,
+// which it prints after them in every mode but `rule`
+fn ninja_targets(dir: &Path, config: &Config, mode: &[&str]) -> Result<Vec<String>> {
+    let mut command = Command::new(ninja_program(config.ninja_program.as_deref()));
+    command.args(["-t", "targets"]).args(mode);
+    let text = output(command, dir, "Listing the targets of ninja", true)?;
+    Ok(text
+        .lines()
+        .map(str::trim_start)
+        .filter(|line| !line.is_empty())
+        .map(|line| match mode[0] {
+            "rule" => line,
+            _ => line.rsplit_once(": ").map_or(line, |(target, _)| target),
+        })
+        .map(str::to_string)
+        .collect())
+}
+
+/// The targets of the build in `detection` worth building on their own: the phony targets of
+/// ninja, like the ones CMake names after each library and executable, and the final outputs,
+/// or the explicit targets of make
+pub fn list(detection: &Detection, config: &Config) -> Result<Vec<String>> {
+    let dir = &detection.directory;
+    let ninja_files = dir.join("build.ninja").exists();
+    let mut targets = match &detection.build_system {
+        _ if detection.source.is_some() => {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "The CMake project has no build directory yet; build it once first",
+            ))
+        }
+        BuildSystem::Ninja | BuildSystem::CMake if ninja_files => {
+            let mut targets = ninja_targets(dir, config, &["rule", "phony"])?;
+            // The ones nothing else depends on
+            targets.extend(ninja_targets(dir, config, &["depth", "1"])?);
+            targets
+        }
+        BuildSystem::Make | BuildSystem::CMake => make(dir, config)?.into_keys().collect(),
+        build_system => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "The targets of {} builds cannot be listed",
+                    build_system.name()
+                ),
+            ))
+        }
+    };
+    // The internal targets of CMake and the special ones of make, like `.PHONY`
+    targets.retain(|target| {
+        !target.starts_with('.')
+            && !target.contains("CMakeFiles/")
+            && !target.starts_with("cmake_object_order_depends_target_")
+    });
+    targets.sort();
+    targets.dedup();
+    Ok(targets)
+}
+
+impl TargetGraph {
+    /// The targets as a tree below the root, like `cargo tree`, where a target shown before is
+    /// marked with `(*)` instead of repeated
+    pub fn to_tree(&self) -> String {
+        let mut tree = format!("{}\n", self.root);
+        let mut shown = BTreeSet::from([self.root.clone()]);
+        self.branch(&self.root, "", &mut shown, &mut tree);
+        tree
+    }
+
+    fn branch(&self, node: &str, prefix: &str, shown: &mut BTreeSet<String>, tree: &mut String) {
+        let neighbours = self.edges.get(node).map(Vec::as_slice).unwrap_or_default();
+        for (index, neighbour) in neighbours.iter().enumerate() {
+            let last = index + 1 == neighbours.len();
+            let (branch, indent) = match last {
+                true => ("└── ", "    "),
+                false => ("├── ", "│   "),
+            };
+            let expands = self
+                .edges
+                .get(neighbour)
+                .is_some_and(|next| !next.is_empty());
+            if !shown.insert(neighbour.clone()) && expands {
+                tree.push_str(&format!("{}{}{} (*)\n", prefix, branch, neighbour));
+                continue;
+            }
+            tree.push_str(&format!("{}{}{}\n", prefix, branch, neighbour));
+            self.branch(neighbour, &format!("{}{}", prefix, indent), shown, tree);
+        }
+    }
+
+    /// The targets reachable from the root as a Graphviz digraph, with the arrows pointing
+    /// from each target to what it depends on
+    pub fn to_dot(&self) -> String {
+        let escape = |text: &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut dot = String::from("digraph targets {\n");
+        let mut seen = BTreeSet::from([self.root.as_str()]);
+        let mut queue = vec![self.root.as_str()];
+        while let Some(node) = queue.pop() {
+            for neighbour in self.edges.get(node).into_iter().flatten() {
+                let (from, to) = match self.reverse {
+                    true => (neighbour.as_str(), node),
+                    false => (node, neighbour.as_str()),
+                };
+                dot.push_str(&format!(
+                    "    \"{}\" -> \"{}\";\n",
+                    escape(from),
+                    escape(to)
+                ));
+                if seen.insert(neighbour) {
+                    queue.push(neighbour);
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
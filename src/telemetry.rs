@@ -0,0 +1,230 @@
+use std::{
+    collections::hash_map::RandomState,
+    env::{temp_dir, var},
+    fs::{remove_file, write},
+    hash::{BuildHasher, Hasher},
+    io::{Error, ErrorKind, Result, Write},
+    process::{id, Command, Stdio},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
+use crate::exec;
+
+/// The OTLP endpoint to send the traces of builds to: `explicit`, or else the one in the
+/// configuration, or else the one in the environment as OpenTelemetry SDKs read it
+pub fn endpoint(explicit: Option<String>, configured: Option<String>) -> Option<String> {
+    explicit
+        .or(configured)
+        .or_else(|| var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").ok())
+        .or_else(|| {
+            var("OTEL_EXPORTER_OTLP_ENDPOINT")
+                .ok()
+                .map(|endpoint| format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        })
+        .filter(|endpoint| !endpoint.is_empty())
+}
+
+// `bytes` random bytes as hex, for the IDs of traces and spans
+fn random_id(bytes: usize) -> String {
+    (0..bytes.div_ceil(8))
+        .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+        .collect::<String>()[..bytes * 2]
+        .to_string()
+}
+
+fn nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// A part of a build that took time, like configuring it or building one target
+pub struct Span {
+    id: String,
+    parent: Option<String>,
+    name: String,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(String, Value)>,
+    failed: bool,
+}
+
+impl Span {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Set the attribute `key`, a string, number or bool
+    pub fn attribute(&mut self, key: &str, value: impl Into<Value>) -> &mut Span {
+        self.attributes.push((key.to_string(), value.into()));
+        self
+    }
+
+    /// Mark the span as failed
+    pub fn failed(&mut self) -> &mut Span {
+        self.failed = true;
+        self
+    }
+
+    // The span in the JSON encoding of OTLP
+    fn to_json(&self, trace: &str) -> Value {
+        let attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    Value::Bool(value) => json!({ "boolValue": value }),
+                    // 64-bit integers are strings in the JSON of protobuf
+                    Value::Number(number) if number.is_i64() || number.is_u64() => {
+                        json!({ "intValue": number.to_string() })
+                    }
+                    Value::Number(number) => json!({ "doubleValue": number }),
+                    Value::String(text) => json!({ "stringValue": text }),
+                    other => json!({ "stringValue": other.to_string() }),
+                };
+                json!({ "key": key, "value": value })
+            })
+            .collect();
+        let mut span = json!({
+            "traceId": trace,
+            "spanId": self.id,
+            "name": self.name,
+            // Internal
+            "kind": 1,
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(self.end),
+            "attributes": attributes,
+            // Error or unset
+            "status": { "code": if self.failed { 2 } else { 0 } },
+        });
+        if let Some(parent) = &self.parent {
+            span["parentSpanId"] = json!(parent);
+        }
+        span
+    }
+}
+
+/// The spans of one run of smartmake, under a span for the whole of it
+pub struct Trace {
+    id: String,
+    root: Span,
+    spans: Vec<Span>,
+}
+
+impl Trace {
+    /// A trace of a run named `name` that started at `started`
+    pub fn new(name: &str, started: SystemTime) -> Trace {
+        Trace {
+            id: random_id(16),
+            root: Span {
+                id: random_id(8),
+                parent: None,
+                name: name.to_string(),
+                start: started,
+                end: started,
+                attributes: Vec::new(),
+                failed: false,
+            },
+            spans: Vec::new(),
+        }
+    }
+
+    /// The span for the whole run
+    pub fn root(&mut self) -> &mut Span {
+        &mut self.root
+    }
+
+    /// Add a span that started at `start` and took `duration`, under `parent` or the root
+    pub fn span(
+        &mut self,
+        parent: Option<&str>,
+        name: &str,
+        start: SystemTime,
+        duration: Duration,
+    ) -> &mut Span {
+        self.spans.push(Span {
+            id: random_id(8),
+            parent: Some(parent.unwrap_or(&self.root.id).to_string()),
+            name: name.to_string(),
+            start,
+            end: start + duration,
+            attributes: Vec::new(),
+            failed: false,
+        });
+        self.spans.last_mut().unwrap()
+    }
+
+    /// End the whole run now, and send the trace to the OTLP/HTTP `endpoint`
+    pub fn export(mut self, endpoint: &str) -> Result<()> {
+        self.root.end = SystemTime::now();
+        let spans: Vec<Value> = [&self.root]
+            .into_iter()
+            .chain(&self.spans)
+            .map(|span| span.to_json(&self.id))
+            .collect();
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": "smartmake" },
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "smartmake", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        });
+        post(endpoint, &serde_json::to_vec(&body)?)
+    }
+}
+
+// Post `body` as JSON to `endpoint` with curl, with the headers in `OTEL_EXPORTER_OTLP_HEADERS`
+fn post(endpoint: &str, body: &[u8]) -> Result<()> {
+    let file = temp_dir().join(format!("smartmake-trace-{}.json", id()));
+    write(&file, body)?;
+    // The headers often hold API keys, so they go to curl through its standard input rather
+    // than its arguments, where other users could see them
+    let mut config = String::from("header = \"Content-Type: application/json\"\n");
+    let headers = var("OTEL_EXPORTER_OTLP_TRACES_HEADERS")
+        .or_else(|_| var("OTEL_EXPORTER_OTLP_HEADERS"))
+        .unwrap_or_default();
+    for header in headers.split(',') {
+        if let Some((name, value)) = header.split_once('=') {
+            let header = format!("{}: {}", name.trim(), value.trim());
+            config.push_str(&format!("header = {}\n", json!(header)));
+        }
+    }
+    let child = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--max-time", "10"])
+        .args(["--config", "-", "--output"])
+        .arg(if cfg!(windows) { "NUL" } else { "/dev/null" })
+        .arg("--data-binary")
+        .arg(format!("@{}", file.display()))
+        .arg(endpoint)
+        .stdin(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            let _ = remove_file(&file);
+            return Err(exec::missing_tool("Sending traces needs curl on PATH"));
+        }
+        child => child?,
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(config.as_bytes())?;
+    }
+    let status = child.wait();
+    let _ = remove_file(&file);
+    match status?.success() {
+        true => Ok(()),
+        false => Err(Error::other(format!(
+            "Sending the trace to {} failed",
+            endpoint
+        ))),
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    fs::{metadata, read, read_to_string, write},
+    io::Result,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::mpsc::Sender,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    diagnostics::strip_ansi,
+    exec::{text, Stream},
+    history::format_duration,
+    output::Sink,
+    BuildSystem,
+};
+
+/// How long the build system took for a target, or for a crate with cargo
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timing {
+    pub target: String,
+    /// When it started, after the start of the build
+    pub start: Duration,
+    pub duration: Duration,
+}
+
+/// The arguments that make `build_system` record how long each target took, if it can, which
+/// ninja does in its log without being asked
+pub fn args(build_system: &BuildSystem) -> Option<&'static [&'static str]> {
+    match build_system {
+        BuildSystem::Ninja => Some(&[]),
+        BuildSystem::Make => Some(&["--trace"]),
+        BuildSystem::Cargo => Some(&["--timings"]),
+        _ => None,
+    }
+}
+
+const NINJA_LOG: &str = ".ninja_log";
+
+/// How long the `.ninja_log` in `dir` is, to tell the entries of the next build apart
+pub fn ninja_log_len(dir: &Path) -> u64 {
+    metadata(dir.join(NINJA_LOG))
+        .map(|metadata| metadata.len())
+        .unwrap_or(0)
+}
+
+/// The targets ninja built, from the lines it appended to its log in `dir` after the first
+/// `from` bytes of it, as `start end mtime output hash` with the times in milliseconds
+pub fn ninja_log(dir: &Path, from: u64) -> Vec<Timing> {
+    let log = read(dir.join(NINJA_LOG)).unwrap_or_default();
+    // Ninja rewrote the log from scratch when it grew too long, with only the last build of
+    // each target left in it
+    let from = match from as usize <= log.len() {
+        true => from as usize,
+        false => 0,
+    };
+    let mut timings: Vec<Timing> = Vec::new();
+    for line in String::from_utf8_lossy(&log[from..]).lines() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [start, end, _, target, ..] = fields[..] else {
+            continue;
+        };
+        let (Ok(start), Ok(end)) = (start.parse::<u64>(), end.parse::<u64>()) else {
+            continue;
+        };
+        let timing = Timing {
+            target: target.to_string(),
+            start: Duration::from_millis(start),
+            duration: Duration::from_millis(end.saturating_sub(start)),
+        };
+        // An edge with several outputs is logged once for each
+        match timings
+            .iter_mut()
+            .find(|other| other.start == timing.start && other.duration == timing.duration)
+        {
+            Some(other) => {
+                other.target.push(' ');
+                other.target.push_str(target);
+            }
+            None => timings.push(timing),
+        }
+    }
+    timings
+}
+
+// The crates cargo built, from the `UNIT_DATA` of the report `cargo build --timings` wrote
+fn parse_cargo_report(report: &Path) -> Vec<Timing> {
+    let Ok(html) = read_to_string(report) else {
+        return Vec::new();
+    };
+    let Some((_, units)) = html.split_once("const UNIT_DATA = ") else {
+        return Vec::new();
+    };
+    let Some(Ok(Value::Array(units))) = serde_json::Deserializer::from_str(units)
+        .into_iter::<Value>()
+        .next()
+    else {
+        return Vec::new();
+    };
+    let seconds = |value: &Value| Duration::from_secs_f64(value.as_f64().unwrap_or(0.0).max(0.0));
+    units
+        .iter()
+        .map(|unit| {
+            let name = unit["name"].as_str().unwrap_or_default();
+            // Like ` tcg "bin"` or ` build script`, to tell the build script from the library
+            let target = unit["target"].as_str().unwrap_or_default().trim();
+            Timing {
+                target: match target {
+                    "" => name.to_string(),
+                    target => format!("{} ({})", name, target.replace('"', "")),
+                },
+                start: seconds(&unit["start"]),
+                duration: seconds(&unit["duration"]),
+            }
+        })
+        .collect()
+}
+
+// The target make updates, from a line of `make --trace`
+fn parse_make(line: &str) -> Option<&str> {
+    let (_, rest) = line
+        .split_once(": update target '")
+        .or_else(|| line.split_once(": target '"))?;
+    rest.split_once('\'').map(|(target, _)| target)
+}
+
+/// Where to write the timings besides printing the slowest
+#[derive(Clone, Debug, Default)]
+pub struct Reports {
+    /// How many of the slowest targets to print
+    pub top: usize,
+    pub json: Option<PathBuf>,
+    pub html: Option<PathBuf>,
+    /// Where to send the timings of every target instead, for `sm compare` to compare them
+    pub collect: Option<Sender<Vec<Timing>>>,
+}
+
+/// A JSON array of `timings`, with the times in seconds, slowest first
+pub fn to_json(timings: &[Timing]) -> Value {
+    Value::Array(
+        timings
+            .iter()
+            .map(|timing| {
+                json!({
+                    "target": timing.target,
+                    "start": timing.start.as_secs_f64(),
+                    "duration": timing.duration.as_secs_f64(),
+                })
+            })
+            .collect(),
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A page showing `timings` as bars on the timeline of the build, slowest first
+pub fn to_html(timings: &[Timing]) -> String {
+    let end = timings
+        .iter()
+        .map(|timing| timing.start + timing.duration)
+        .max()
+        .unwrap_or_default()
+        .as_secs_f64()
+        .max(0.001);
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Build timings</title>\n\
+         <style>\nbody { font-family: sans-serif; }\n\
+         td { padding: 2px 8px; white-space: nowrap; }\n\
+         .bar { position: relative; width: 600px; height: 14px; background: #eee; }\n\
+         .bar div { position: absolute; height: 100%; background: #4a7fc1; }\n\
+         </style>\n</head>\n<body>\n<h1>Build timings</h1>\n<table>\n",
+    );
+    for timing in timings {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td><div class=\"bar\"><div style=\"left: {:.2}%; \
+             width: {:.2}%\"></div></div></td></tr>\n",
+            format_duration(timing.duration),
+            escape(&timing.target),
+            100.0 * timing.start.as_secs_f64() / end,
+            (100.0 * timing.duration.as_secs_f64() / end).max(0.2)
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+/// Passes the output on to `sinks`, without the lines `make --trace` adds, and prints the
+/// targets that took longest once the build finished. Make does not time its targets, so each
+/// is taken to last until make moves on to the next, which only holds without `-j`.
+pub struct Timed {
+    build_system: BuildSystem,
+    directory: PathBuf,
+    reports: Reports,
+    sinks: Vec<Box<dyn Sink>>,
+    started: Instant,
+    // The length of the ninja log before the build, after which it logs the targets it builds
+    ninja_log: u64,
+    // The report `cargo build --timings` saved
+    cargo_report: Option<PathBuf>,
+    // The target make is updating, and since when
+    making: Option<(String, Instant)>,
+    timings: Vec<Timing>,
+}
+
+impl Timed {
+    pub fn new(
+        build_system: BuildSystem,
+        directory: PathBuf,
+        reports: Reports,
+        sinks: Vec<Box<dyn Sink>>,
+    ) -> Timed {
+        let ninja_log = ninja_log_len(&directory);
+        Timed {
+            build_system,
+            directory,
+            reports,
+            sinks,
+            started: Instant::now(),
+            ninja_log,
+            cargo_report: None,
+            making: None,
+            timings: Vec::new(),
+        }
+    }
+
+    // The target make was updating is done, as it moved on or finished
+    fn made(&mut self, now: Instant) {
+        if let Some((target, since)) = self.making.take() {
+            self.timings.push(Timing {
+                target,
+                start: since - self.started,
+                duration: now - since,
+            });
+        }
+    }
+}
+
+impl Sink for Timed {
+    fn line(&mut self, stream: Stream, line: &str) -> Result<()> {
+        self.bytes(stream, line.as_bytes())
+    }
+
+    fn bytes(&mut self, stream: Stream, line: &[u8]) -> Result<()> {
+        let plain = strip_ansi(&text(line));
+        match self.build_system {
+            BuildSystem::Make => {
+                if let Some(target) = parse_make(&plain) {
+                    // Make tells that a target does not exist before it updates it
+                    if self
+                        .making
+                        .as_ref()
+                        .is_none_or(|(making, _)| making != target)
+                    {
+                        let now = Instant::now();
+                        self.made(now);
+                        self.making = Some((target.to_string(), now));
+                    }
+                    return Ok(());
+                }
+            }
+            BuildSystem::Cargo => {
+                if let Some(report) = plain.trim().strip_prefix("Timing report saved to ") {
+                    self.cargo_report = Some(PathBuf::from(report));
+                }
+            }
+            _ => {}
+        }
+        for sink in self.sinks.iter_mut() {
+            sink.bytes(stream, line)?;
+        }
+        Ok(())
+    }
+
+    fn status(&mut self, status: &str) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.status(status)?;
+        }
+        Ok(())
+    }
+
+    fn tick(&mut self) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.tick()?;
+        }
+        Ok(())
+    }
+
+    fn finished(&mut self, status: ExitStatus, duration: Duration) -> Result<()> {
+        for sink in self.sinks.iter_mut() {
+            sink.finished(status, duration)?;
+        }
+        self.made(Instant::now());
+        match self.build_system {
+            BuildSystem::Ninja => self.timings = ninja_log(&self.directory, self.ninja_log),
+            BuildSystem::Cargo => {
+                let report = self.cargo_report.clone().unwrap_or_else(|| {
+                    self.directory
+                        .join("target/cargo-timings/cargo-timing.html")
+                });
+                self.timings = parse_cargo_report(&report);
+            }
+            _ => {}
+        }
+        self.timings
+            .sort_by(|a, b| b.duration.cmp(&a.duration).then(a.start.cmp(&b.start)));
+        if let Some(sender) = &self.reports.collect {
+            let _ = sender.send(self.timings.clone());
+            return Ok(());
+        }
+        eprintln!();
+        match self.timings.len() {
+            0 => eprintln!(
+                "Nothing was rebuilt, so nothing was timed; `sm clean` first times a full build"
+            ),
+            1 => eprintln!("The 1 target built took:"),
+            n if n <= self.reports.top => eprintln!("The {} targets built took:", n),
+            n => eprintln!(
+                "The slowest {} of the {} targets built took:",
+                self.reports.top, n
+            ),
+        }
+        for timing in self.timings.iter().take(self.reports.top) {
+            eprintln!(
+                "  {:>8}  {}",
+                format_duration(timing.duration),
+                timing.target
+            );
+        }
+        if let Some(path) = &self.reports.json {
+            write(path, serde_json::to_vec_pretty(&to_json(&self.timings))?)?;
+            eprintln!("Wrote the timings of every target to {}", path.display());
+        }
+        if let Some(path) = &self.reports.html {
+            write(path, to_html(&self.timings))?;
+            eprintln!("Wrote the timeline of the build to {}", path.display());
+        }
+        Ok(())
+    }
+}
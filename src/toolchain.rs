@@ -0,0 +1,109 @@
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    io::{Error, ErrorKind, Result},
+    process::{Command, Stdio},
+};
+
+use crate::exec;
+
+/// The numbers of `version`, like `[3, 24, 1]` for `3.24.1` or `v3.24.1-rc1`, up to the first
+/// part that is not a number
+pub fn version_numbers(version: &str) -> Vec<u64> {
+    version
+        .trim_start_matches('v')
+        .split(['.', '-', '+'])
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+// Compare versions as numbers, where missing numbers count as 0, so that 1.75 is 1.75.0
+fn compare(a: &[u64], b: &[u64]) -> Ordering {
+    (0..a.len().max(b.len()))
+        .map(|i| a.get(i).unwrap_or(&0).cmp(b.get(i).unwrap_or(&0)))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// The version `program --version` reports, like `3.28.3` for `cmake version 3.28.3`, or
+/// nothing if it is not installed or does not say
+pub fn version(program: &str) -> Option<String> {
+    let output = Command::new(program)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let first = output.lines().next()?;
+    // The first word starting with a number that has a dot, like in `gcc (GCC) 13.2.0`
+    first.split_whitespace().find_map(|word| {
+        let end = word
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(word.len());
+        let version = word[..end].trim_end_matches('.');
+        (version.contains('.') && word.starts_with(|c: char| c.is_ascii_digit()))
+            .then(|| version.to_string())
+    })
+}
+
+/// Whether `version` meets `requirement`: comparisons like `>= 3.24` or `< 4`, separated by
+/// commas, where a version alone means at least that version
+pub fn satisfies(version: &str, requirement: &str) -> Result<bool> {
+    let version = version_numbers(version);
+    for comparison in requirement.split(',') {
+        let comparison = comparison.trim();
+        let operator = comparison
+            .find(|c: char| c.is_ascii_digit() || c == 'v')
+            .map(|start| comparison[..start].trim())
+            .unwrap_or(comparison);
+        let required = version_numbers(comparison[operator.len()..].trim());
+        if required.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid version requirement: {}", requirement),
+            ));
+        }
+        let ordering = compare(&version, &required);
+        let met = match operator {
+            "" | ">=" => ordering.is_ge(),
+            ">" => ordering.is_gt(),
+            "<=" => ordering.is_le(),
+            "<" => ordering.is_lt(),
+            "=" | "==" => ordering.is_eq(),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid version requirement: {}", requirement),
+                ))
+            }
+        };
+        if !met {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Check that the installed programs meet the version `requirements`, keyed by the program,
+/// failing with what is missing or too old
+pub fn check(requirements: &BTreeMap<String, String>) -> Result<()> {
+    for (program, requirement) in requirements {
+        let Some(installed) = version(program) else {
+            return Err(exec::missing_tool(format!(
+                "This project needs {} {}, which is not installed or does not tell its version",
+                program, requirement
+            )));
+        };
+        let met = satisfies(&installed, requirement).map_err(|error| {
+            Error::new(ErrorKind::InvalidData, format!("{}: {}", program, error))
+        })?;
+        if !met {
+            return Err(Error::other(format!(
+                "This project needs {} {}, but {} {} is installed",
+                program, requirement, program, installed
+            )));
+        }
+    }
+    Ok(())
+}
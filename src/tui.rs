@@ -0,0 +1,304 @@
+use std::{
+    io::Result,
+    time::{Duration, Instant, SystemTime},
+};
+
+use ratatui::{
+    crossterm::event::{self, Event as TerminalEvent, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Gauge, Paragraph},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    daemon::exit_code,
+    diagnostics::{severity, Severity},
+    exec::{self, Event, Execution, Stream},
+    history::{self, format_duration},
+    progress::ProgressTracker,
+    Invocation, Project,
+};
+
+// How often the screen is redrawn while nothing else happens, which keeps the clock going
+const TICK: Duration = Duration::from_millis(100);
+
+struct OutputLine {
+    stream: Stream,
+    text: String,
+    severity: Option<Severity>,
+}
+
+// The state of the dashboard
+struct Dashboard<'a> {
+    project: &'a Project,
+    invocation: Invocation,
+    command: String,
+    execution: Option<Execution>,
+    started: Instant,
+    started_at: SystemTime,
+    // The exit code and duration of the last build, once it finished
+    finished: Option<(i32, Duration)>,
+    lines: Vec<OutputLine>,
+    progress: ProgressTracker,
+    warnings: usize,
+    errors: usize,
+    // The first line shown, or `None` to follow the end of the output
+    scroll: Option<usize>,
+    // The height of the output pane when it was last drawn
+    height: usize,
+    // The targets being typed in, while switching targets
+    input: Option<String>,
+}
+
+impl<'a> Dashboard<'a> {
+    fn new(project: &'a Project, invocation: Invocation) -> Dashboard<'a> {
+        Dashboard {
+            project,
+            invocation,
+            command: String::new(),
+            execution: None,
+            started: Instant::now(),
+            started_at: SystemTime::now(),
+            finished: None,
+            lines: Vec::new(),
+            progress: ProgressTracker::new(None),
+            warnings: 0,
+            errors: 0,
+            scroll: None,
+            height: 0,
+            input: None,
+        }
+    }
+
+    fn start(&mut self) -> Result<()> {
+        if let Some(execution) = self.execution.take() {
+            execution.kill();
+        }
+        let Some(plan) = self.invocation.plan(self.project)? else {
+            return Err(exec::no_build_system("No build system found"));
+        };
+        self.command = plan.to_string();
+        self.lines.clear();
+        let expected = history::expected_duration(
+            &history::load().unwrap_or_default(),
+            self.project,
+            &self.invocation,
+        );
+        self.progress = ProgressTracker::new(expected);
+        self.warnings = 0;
+        self.errors = 0;
+        self.scroll = None;
+        self.finished = None;
+        self.started = Instant::now();
+        self.started_at = SystemTime::now();
+        self.execution = Some(Execution::spawn(plan.command())?);
+        Ok(())
+    }
+
+    // Take in the output that arrived since the last frame
+    fn update(&mut self) {
+        let Some(execution) = &self.execution else {
+            return;
+        };
+        for event in execution.events().try_iter() {
+            match event {
+                Event::Line(stream, line) => {
+                    let text = exec::text(&line).into_owned();
+                    self.progress.observe(&text);
+                    let severity = severity(&text);
+                    match severity {
+                        Some(Severity::Warning) => self.warnings += 1,
+                        Some(Severity::Error) => self.errors += 1,
+                        None => {}
+                    }
+                    self.lines.push(OutputLine {
+                        stream,
+                        text,
+                        severity,
+                    });
+                }
+                // Only builds on a pseudo-terminal have a status
+                Event::Status(_) => {}
+                Event::Finished(status) => {
+                    let duration = self.started.elapsed();
+                    history::record(
+                        self.project,
+                        &self.invocation,
+                        self.started_at,
+                        duration,
+                        status,
+                    );
+                    self.finished = Some((exit_code(status), duration));
+                }
+            }
+        }
+    }
+
+    fn top(&self) -> usize {
+        let last = self.lines.len().saturating_sub(self.height);
+        self.scroll.unwrap_or(last).min(last)
+    }
+
+    fn scroll_by(&mut self, lines: isize) {
+        let top = self.top().saturating_add_signed(lines);
+        let last = self.lines.len().saturating_sub(self.height);
+        self.scroll = (top < last).then_some(top);
+    }
+
+    // Handle a key press, returning false to quit
+    fn key(&mut self, code: KeyCode) -> Result<bool> {
+        if let Some(input) = &mut self.input {
+            match code {
+                KeyCode::Enter => {
+                    self.invocation.targets =
+                        input.split_whitespace().map(str::to_string).collect();
+                    self.input = None;
+                    self.start()?;
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            }
+            return Ok(true);
+        }
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(false),
+            KeyCode::Char('r') => self.start()?,
+            KeyCode::Char('t') => self.input = Some(self.invocation.targets.join(" ")),
+            KeyCode::Char('e') => {
+                if let Some(first) = self
+                    .lines
+                    .iter()
+                    .position(|line| line.severity == Some(Severity::Error))
+                {
+                    self.scroll = Some(first);
+                }
+            }
+            KeyCode::Char('f') | KeyCode::End => self.scroll = None,
+            KeyCode::Home => self.scroll = Some(0),
+            KeyCode::Up | KeyCode::Char('k') => self.scroll_by(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.scroll_by(1),
+            KeyCode::PageUp => self.scroll_by(-(self.height as isize)),
+            KeyCode::PageDown => self.scroll_by(self.height as isize),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, gauge, output, footer] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let (status, color, elapsed) = match self.finished {
+            None => ("Building".to_string(), Color::Cyan, self.started.elapsed()),
+            Some((0, duration)) => ("Succeeded".to_string(), Color::Green, duration),
+            Some((code, duration)) => (format!("Failed ({})", code), Color::Red, duration),
+        };
+        let elapsed = elapsed.as_secs();
+        let summary = Line::from(vec![
+            Span::styled(status, Style::new().fg(color).bold()),
+            Span::raw(format!("  {:02}:{:02}  ", elapsed / 60, elapsed % 60)),
+            Span::styled(
+                format!("{} warnings", self.warnings),
+                Style::new().fg(Color::Yellow),
+            ),
+            Span::raw("  "),
+            Span::styled(
+                format!("{} errors", self.errors),
+                Style::new().fg(Color::Red),
+            ),
+        ]);
+        frame.render_widget(
+            Paragraph::new(summary).block(Block::bordered().title(self.command.as_str())),
+            header,
+        );
+
+        let fraction = match self.finished {
+            Some(_) => Some(1.0),
+            None => self.progress.fraction(),
+        };
+        let label = match (self.finished, fraction, self.progress.remaining()) {
+            (None, Some(fraction), Some(remaining)) => format!(
+                "{:.0}%  {} left",
+                fraction * 100.0,
+                format_duration(remaining)
+            ),
+            (_, Some(fraction), _) => format!("{:.0}%", fraction * 100.0),
+            (_, None, _) => "…".to_string(),
+        };
+        frame.render_widget(
+            Gauge::default()
+                .gauge_style(Style::new().fg(color))
+                .ratio(fraction.unwrap_or(0.0))
+                .label(label),
+            gauge,
+        );
+
+        self.height = output.height as usize;
+        let top = self.top();
+        let visible = self.lines[top..]
+            .iter()
+            .take(self.height)
+            .map(|line| {
+                let style = match (line.severity, line.stream) {
+                    (Some(Severity::Error), _) => Style::new().fg(Color::Red),
+                    (Some(Severity::Warning), _) => Style::new().fg(Color::Yellow),
+                    (None, Stream::Stderr) => Style::new().fg(Color::Gray),
+                    (None, Stream::Stdout) => Style::new(),
+                };
+                Line::styled(line.text.as_str(), style)
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(Paragraph::new(visible), output);
+
+        let help = match &self.input {
+            Some(input) => Line::from(format!(
+                "Targets: {}▏ (enter to build, esc to cancel)",
+                input
+            )),
+            None => Line::from(
+                "r rebuild  t targets  e first error  ↑↓ scroll  f follow  q quit".dark_gray(),
+            ),
+        };
+        frame.render_widget(Paragraph::new(help), footer);
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        self.start()?;
+        loop {
+            self.update();
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(TICK)? {
+                if let TerminalEvent::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && !self.key(key.code)? {
+                        break;
+                    }
+                }
+            }
+        }
+        if let Some(execution) = &self.execution {
+            execution.kill();
+        }
+        Ok(())
+    }
+}
+
+/// Show a live dashboard of the build of `project`, with keys to rebuild, switch targets
+/// and jump to the first error, and return the exit code of the last build
+pub fn run(project: &Project, invocation: Invocation) -> Result<Option<i32>> {
+    let mut dashboard = Dashboard::new(project, invocation);
+    let mut terminal = ratatui::try_init()?;
+    let result = dashboard.run(&mut terminal);
+    ratatui::try_restore()?;
+    result.map(|_| dashboard.finished.map(|(code, _)| code))
+}
@@ -0,0 +1,167 @@
+use std::{
+    env::{
+        consts::{ARCH, EXE_SUFFIX, OS},
+        current_exe,
+    },
+    fs::{remove_file, rename},
+    io::{Error, ErrorKind, Result},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use serde_json::Value;
+
+use crate::{
+    exec::{self, find_program},
+    toolchain::version_numbers,
+};
+
+/// The GitHub repository the releases are published in
+const REPOSITORY: &str = "janAsuli/smartmake";
+
+/// The release asset with the SHA-256 checksums of the binaries, as `sha256sum` prints them
+const CHECKSUMS: &str = "SHA256SUMS";
+
+/// What `self-update` did
+pub enum Update {
+    /// The running binary is the latest release
+    UpToDate(String),
+    /// The running binary was replaced with release `to`
+    Updated { from: String, to: String },
+}
+
+// Fetch `url` with curl, to `output` if given and to the returned bytes otherwise
+fn fetch(url: &str, output: Option<&Path>) -> Result<Vec<u8>> {
+    let mut curl = Command::new("curl");
+    curl.args(["--fail", "--silent", "--show-error", "--location"])
+        .arg("--header")
+        .arg("Accept: application/vnd.github+json");
+    if let Some(output) = output {
+        curl.arg("--output").arg(output);
+    }
+    let result = curl.arg(url).stderr(Stdio::inherit()).output();
+    let output = match result {
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            return Err(exec::missing_tool("Updating needs curl on PATH"))
+        }
+        result => result?,
+    };
+    match output.status.success() {
+        true => Ok(output.stdout),
+        false => Err(Error::other(format!("Downloading {} failed", url))),
+    }
+}
+
+// The SHA-256 checksum of `path`, as a hex string
+fn sha256(path: &Path) -> Result<String> {
+    let mut command = match find_program("sha256sum") {
+        Some(program) => Command::new(program),
+        None => {
+            let mut shasum = Command::new("shasum");
+            shasum.args(["-a", "256"]);
+            shasum
+        }
+    };
+    let output = command.arg(path).stderr(Stdio::null()).output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    match stdout.split_whitespace().next() {
+        Some(checksum) if output.status.success() => Ok(checksum.to_lowercase()),
+        _ => Err(Error::other(
+            "Verifying the download needs sha256sum or shasum on PATH",
+        )),
+    }
+}
+
+/// The name of the release asset with the binary for this platform, like
+/// `sm-x86_64-linux`
+pub fn asset_name() -> String {
+    format!("sm-{}-{}{}", ARCH, OS, EXE_SUFFIX)
+}
+
+/// Replace the running binary with the latest release on GitHub, if it is newer, after
+/// checking the download against the checksums published with it
+pub fn self_update() -> Result<Update> {
+    let current = env!("CARGO_PKG_VERSION").to_string();
+    let url = format!(
+        "https://api.github.com/repos/{}/releases/latest",
+        REPOSITORY
+    );
+    let release: Value = serde_json::from_slice(&fetch(&url, None)?)?;
+    let Some(tag) = release["tag_name"].as_str() else {
+        return Err(Error::other("The latest release has no version"));
+    };
+    let latest = tag.trim_start_matches('v').to_string();
+    if version_numbers(&latest) <= version_numbers(&current) {
+        return Ok(Update::UpToDate(current));
+    }
+
+    let assets = release["assets"].as_array().cloned().unwrap_or_default();
+    let download = |name: &str| {
+        assets
+            .iter()
+            .find(|asset| asset["name"] == name)
+            .and_then(|asset| asset["browser_download_url"].as_str())
+            .map(str::to_string)
+    };
+    let name = asset_name();
+    let Some(binary) = download(&name) else {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!(
+                "Release {} has no binary for this platform ({})",
+                latest, name
+            ),
+        ));
+    };
+    // An unverified binary is not worth the risk of replacing a working one
+    let Some(checksums) = download(CHECKSUMS) else {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("Release {} publishes no {} to verify it", latest, CHECKSUMS),
+        ));
+    };
+    let checksums = String::from_utf8_lossy(&fetch(&checksums, None)?).into_owned();
+    let Some(expected) = checksums.lines().find_map(|line| {
+        let (checksum, file) = line.split_once(char::is_whitespace)?;
+        (file.trim_start().trim_start_matches('*') == name).then(|| checksum.to_lowercase())
+    }) else {
+        return Err(Error::other(format!(
+            "{} of release {} has no checksum for {}",
+            CHECKSUMS, latest, name
+        )));
+    };
+
+    // Downloaded next to the binary, so that renaming it over the binary cannot cross file
+    // systems
+    let exe = current_exe()?.canonicalize()?;
+    let new = exe.with_extension("new");
+    fetch(&binary, Some(&new))?;
+    if sha256(&new)? != expected {
+        let _ = remove_file(&new);
+        return Err(Error::other(format!(
+            "The download of {} does not match its checksum, so it was thrown away",
+            name
+        )));
+    }
+    #[cfg(unix)]
+    {
+        use std::{
+            fs::{set_permissions, Permissions},
+            os::unix::fs::PermissionsExt,
+        };
+        set_permissions(&new, Permissions::from_mode(0o755))?;
+    }
+    // Windows cannot replace a running binary, but it can rename it out of the way
+    let old = exe.with_extension("old");
+    let _ = remove_file(&old);
+    rename(&exe, &old)?;
+    if let Err(error) = rename(&new, &exe) {
+        let _ = rename(&old, &exe);
+        return Err(error);
+    }
+    let _ = remove_file(&old);
+    Ok(Update::Updated {
+        from: current,
+        to: latest,
+    })
+}
@@ -0,0 +1,108 @@
+use std::{
+    io::{Error, Result},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::build_system::matches_marker;
+
+/// Paths that are never watched, as builds and version control write to them
+pub const DEFAULT_IGNORE: &[&str] = &[".git", ".hg", ".svn", "target", "build", "*~", "*.swp"];
+
+/// Rebuilds a project whenever one of its files changes
+pub struct FileWatcher {
+    root: PathBuf,
+    ignore: Vec<String>,
+    ignored_paths: Vec<PathBuf>,
+    debounce: Duration,
+    events: Receiver<notify::Result<Event>>,
+    // Dropping the watcher stops the notifications
+    _watcher: RecommendedWatcher,
+}
+
+impl FileWatcher {
+    /// Watch every file under `root` except those with a path component matching one of
+    /// the `ignore` patterns, and those in `ignored_paths`, like the build directory
+    pub fn new<P: AsRef<Path>>(
+        root: P,
+        ignore: Vec<String>,
+        ignored_paths: Vec<PathBuf>,
+        debounce: Duration,
+    ) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        let (sender, events) = channel();
+        let mut watcher = notify::recommended_watcher(sender).map_err(Error::other)?;
+        watcher
+            .watch(&root, RecursiveMode::Recursive)
+            .map_err(Error::other)?;
+        Ok(FileWatcher {
+            root,
+            ignore,
+            ignored_paths,
+            debounce,
+            events,
+            _watcher: watcher,
+        })
+    }
+
+    fn is_ignored(&self, path: &Path) -> bool {
+        if self
+            .ignored_paths
+            .iter()
+            .any(|ignored| path.starts_with(ignored))
+        {
+            return true;
+        }
+        let path = path.strip_prefix(&self.root).unwrap_or(path);
+        path.components().any(|component| {
+            self.ignore
+                .iter()
+                .any(|pattern| matches_marker(pattern, component.as_os_str()))
+        })
+    }
+
+    fn is_relevant(&self, event: &notify::Result<Event>) -> bool {
+        match event {
+            Ok(event) => {
+                !matches!(event.kind, EventKind::Access(_))
+                    && event.paths.iter().any(|path| !self.is_ignored(path))
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Block until a relevant change happened and no further change followed it for the
+    /// debounce period
+    pub fn wait_for_change(&self) -> Result<()> {
+        loop {
+            let event = self.events.recv().map_err(Error::other)?;
+            if self.is_relevant(&event) {
+                break;
+            }
+        }
+        self.settle()
+    }
+
+    /// Whether anything relevant changed since the last wait, such as during a build,
+    /// waiting for those changes to settle
+    pub fn has_pending_changes(&self) -> Result<bool> {
+        let changed = self.events.try_iter().any(|event| self.is_relevant(&event));
+        if changed {
+            self.settle()?;
+        }
+        Ok(changed)
+    }
+
+    fn settle(&self) -> Result<()> {
+        loop {
+            match self.events.recv_timeout(self.debounce) {
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => return Ok(()),
+                Err(error) => return Err(Error::other(error)),
+            }
+        }
+    }
+}
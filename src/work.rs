@@ -0,0 +1,153 @@
+use std::{cell::Cell, io::Result, path::Path, rc::Rc};
+
+use crate::{diagnostics::strip_ansi, exec::Stream, output::Sink, BuildSystem, Detection};
+
+/// What the work of a build is counted in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Unit {
+    /// The edges ninja ran, from its `[3/40]`
+    Edges,
+    /// The crates cargo compiled
+    Crates,
+    /// The source files make compiled, from the compiler commands it prints and the
+    /// `Building C object` of CMake's makefiles
+    Sources,
+}
+
+impl Unit {
+    /// The unit the build in `detection` counts its work in, if it tells
+    pub fn of(detection: &Detection) -> Option<Unit> {
+        match detection.build_system {
+            BuildSystem::Ninja => Some(Unit::Edges),
+            BuildSystem::Cargo => Some(Unit::Crates),
+            BuildSystem::Make => Some(Unit::Sources),
+            BuildSystem::CMake => match detection.directory.join("build.ninja").exists() {
+                true => Some(Unit::Edges),
+                false => Some(Unit::Sources),
+            },
+            BuildSystem::Custom(_) => None,
+        }
+    }
+
+    /// `count` of this unit, like `12 crates`
+    pub fn describe(self, count: u64) -> String {
+        let (one, many) = match self {
+            Unit::Edges => ("ninja edge", "ninja edges"),
+            Unit::Crates => ("crate", "crates"),
+            Unit::Sources => ("translation unit", "translation units"),
+        };
+        format!("{} {}", count, if count == 1 { one } else { many })
+    }
+
+    /// The verb for doing the work, like `Compiled` for crates
+    pub fn verb(self) -> &'static str {
+        match self {
+            Unit::Edges => "Ran",
+            Unit::Crates | Unit::Sources => "Compiled",
+        }
+    }
+}
+
+// The programs whose commands compile one source file each with `-c`
+const COMPILERS: &[&str] = &["cc", "c++", "gcc", "g++", "clang", "clang++"];
+
+// Whether `line` is a command that compiles a source file, also of a cross compiler like
+// `aarch64-linux-gnu-gcc` or through a compiler cache
+fn is_compilation(line: &str) -> bool {
+    let mut words = line.split_whitespace();
+    let mut program = words.next().unwrap_or_default();
+    if ["ccache", "sccache"].contains(&program) {
+        program = words.next().unwrap_or_default();
+    }
+    let name = Path::new(program)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    COMPILERS
+        .iter()
+        .any(|compiler| name == *compiler || name.ends_with(&format!("-{}", compiler)))
+        && words.any(|word| word == "-c")
+}
+
+/// Counts the work the build does from its output, into a count that stays readable once the
+/// counter went to the sinks
+pub struct WorkCounter {
+    unit: Unit,
+    count: Rc<Cell<u64>>,
+}
+
+impl WorkCounter {
+    pub fn new(unit: Unit) -> (WorkCounter, Rc<Cell<u64>>) {
+        let count = Rc::new(Cell::new(0));
+        let counter = WorkCounter {
+            unit,
+            count: count.clone(),
+        };
+        (counter, count)
+    }
+
+    fn observe(&self, line: &str) {
+        let line = strip_ansi(line);
+        // A terminal gets ninja's status lines overwritten in place, so only the last one
+        // counts
+        let line = line.rsplit('\r').next().unwrap_or_default().trim_start();
+        match self.unit {
+            Unit::Edges => {
+                let done = line
+                    .strip_prefix('[')
+                    .and_then(|rest| rest.split_once('/'))
+                    .and_then(|(done, _)| done.trim().parse().ok());
+                if let Some(done) = done {
+                    self.count.set(self.count.get().max(done));
+                }
+            }
+            Unit::Crates => {
+                if line.starts_with("Compiling ") {
+                    self.count.set(self.count.get() + 1);
+                }
+            }
+            Unit::Sources => {
+                let cmake = line
+                    .split_once("] Building ")
+                    .is_some_and(|(_, rest)| rest.contains(" object "));
+                // The silent rules of automake and kbuild, like `  CC       main.o`
+                let silent = ["CC ", "CXX "].iter().any(|tag| line.starts_with(tag));
+                if cmake || silent || is_compilation(line) {
+                    self.count.set(self.count.get() + 1);
+                }
+            }
+        }
+    }
+}
+
+impl Sink for WorkCounter {
+    fn line(&mut self, _: Stream, line: &str) -> Result<()> {
+        self.observe(line);
+        Ok(())
+    }
+
+    fn status(&mut self, status: &str) -> Result<()> {
+        // Other status lines, like cargo's progress bar, also come as lines once done
+        if self.unit == Unit::Edges {
+            self.observe(status);
+        }
+        Ok(())
+    }
+}
+
+/// What the build did, like `Compiled 12 crates, 3 fewer than the last build`, compared to
+/// the `previous` build with a count
+pub fn report(unit: Unit, count: u64, previous: Option<u64>) -> String {
+    let mut report = format!("{} {}", unit.verb(), unit.describe(count));
+    match previous {
+        Some(previous) if previous < count => {
+            report.push_str(&format!(", {} more than the last build", count - previous))
+        }
+        Some(previous) if previous > count => {
+            report.push_str(&format!(", {} fewer than the last build", previous - count))
+        }
+        Some(_) => report.push_str(", as many as the last build"),
+        None => {}
+    }
+    report
+}
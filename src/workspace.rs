@@ -0,0 +1,530 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::{exists, read_dir, read_to_string},
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Condvar, Mutex},
+    thread,
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    build_system::matches_marker, config::Config, detect::detect, sandbox, watch::DEFAULT_IGNORE,
+    BuildSystem, Detection, Project,
+};
+
+/// A project found below the directory `sm all` runs in
+#[derive(Debug)]
+pub struct Subproject {
+    /// Its directory relative to where it was found from, or `.` for that directory itself
+    pub name: String,
+    pub project: Project,
+}
+
+fn is_ignored(name: &OsStr) -> bool {
+    DEFAULT_IGNORE
+        .iter()
+        .any(|pattern| matches_marker(pattern, name))
+}
+
+// The build system of `dir`, or of its `build` subdirectory
+fn detect_dir(dir: &Path, config: &Config) -> Result<Option<Detection>> {
+    if let Some(detection) = detect(dir, config)?.into_iter().next() {
+        return Ok(Some(detection));
+    }
+    let build = dir.join("build");
+    if exists(&build)? {
+        return Ok(detect(&build, config)?.into_iter().next());
+    }
+    Ok(None)
+}
+
+fn walk(
+    root: &Path,
+    dir: &Path,
+    config: &Config,
+    // The build systems of the projects that contain `dir`
+    outer: &mut Vec<BuildSystem>,
+    found: &mut Vec<Subproject>,
+) -> Result<()> {
+    let detection = detect_dir(dir, config)?;
+    // A project inside another one of the same build system is part of it, like the members
+    // of a cargo workspace or the directories of a recursive make
+    let nested = detection
+        .as_ref()
+        .is_some_and(|detection| outer.contains(&detection.build_system));
+    let pushed = match detection {
+        Some(detection) if !nested => {
+            let mut project = Project::find(dir)?;
+            // The configuration of an enclosing project does not decide for this one
+            if project.config_dir.as_deref() != Some(dir) {
+                project.detection = Some(detection.clone());
+            }
+            let name = match dir.strip_prefix(root) {
+                Ok(relative) if !relative.as_os_str().is_empty() => {
+                    relative.to_string_lossy().into_owned()
+                }
+                _ => ".".to_string(),
+            };
+            found.push(Subproject { name, project });
+            outer.push(detection.build_system);
+            true
+        }
+        _ => false,
+    };
+    let mut children = Vec::new();
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let name = entry.file_name();
+        if !is_ignored(&name) && !name.as_encoded_bytes().starts_with(b".") {
+            children.push(entry.path());
+        }
+    }
+    // The same order on every run
+    children.sort();
+    for child in children {
+        walk(root, &child, config, outer, found)?;
+    }
+    if pushed {
+        outer.pop();
+    }
+    Ok(())
+}
+
+/// Every project in `root` and the directories below it, each with its own build system.
+/// Version control and build directories are not looked into.
+pub fn discover<P: AsRef<Path>>(root: P, config: &Config) -> Result<Vec<Subproject>> {
+    let root: PathBuf = root.as_ref().to_path_buf();
+    let mut found = Vec::new();
+    walk(&root, &root, config, &mut Vec::new(), &mut found)?;
+    Ok(found)
+}
+
+/// The indices of the projects each of `subprojects` depends on, as declared under
+/// `[projects]` in `config`, which names them by their directory relative to `base`
+pub fn dependencies(
+    subprojects: &[Subproject],
+    config: &Config,
+    base: &Path,
+) -> Result<Vec<Vec<usize>>> {
+    // The projects are named by their directory relative to `base`
+    let index = |name: &str| {
+        let dir = base.join(name);
+        subprojects
+            .iter()
+            .position(|subproject| subproject.project.cwd.components().eq(dir.components()))
+    };
+    let mut dependencies = vec![Vec::new(); subprojects.len()];
+    for (name, settings) in &config.projects {
+        // Only the projects being built are ordered
+        let Some(dependent) = index(name) else {
+            continue;
+        };
+        for dependency in &settings.depends {
+            match index(dependency) {
+                Some(dependency) => dependencies[dependent].push(dependency),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("{} depends on {}, which is not a project", name, dependency),
+                    ))
+                }
+            }
+        }
+    }
+    check_cycles(subprojects, &dependencies)?;
+    Ok(dependencies)
+}
+
+// Fail if a project of `subprojects` depends on itself through `dependencies`
+fn check_cycles(subprojects: &[Subproject], dependencies: &[Vec<usize>]) -> Result<()> {
+    // Depth-first search for a dependency that leads back to where it started
+    fn visit(
+        node: usize,
+        dependencies: &[Vec<usize>],
+        state: &mut [u8],
+        path: &mut Vec<usize>,
+    ) -> Option<Vec<usize>> {
+        match state[node] {
+            1 => {
+                let start = path.iter().position(|&other| other == node).unwrap();
+                let mut cycle = path[start..].to_vec();
+                cycle.push(node);
+                return Some(cycle);
+            }
+            2 => return None,
+            _ => {}
+        }
+        state[node] = 1;
+        path.push(node);
+        for &dependency in &dependencies[node] {
+            if let Some(cycle) = visit(dependency, dependencies, state, path) {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        state[node] = 2;
+        None
+    }
+    let mut state = vec![0; subprojects.len()];
+    for node in 0..subprojects.len() {
+        if let Some(cycle) = visit(node, dependencies, &mut state, &mut Vec::new()) {
+            let names = cycle
+                .iter()
+                .map(|&index| subprojects[index].name.as_str())
+                .collect::<Vec<_>>();
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("The projects depend on each other: {}", names.join(" -> ")),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The file that lists the projects of a product spread over several directories, like sibling
+/// repositories, for `sm all` to build together
+pub const WORKSPACE_FILE: &str = "smartmake.workspace.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkspaceFile {
+    /// The projects, keyed by the name `sm all` shows them under
+    pub projects: BTreeMap<String, WorkspaceProject>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkspaceProject {
+    /// Its directory, relative to the workspace file or to `~`
+    pub path: PathBuf,
+    /// The projects of the workspace that have to be built before this one
+    #[serde(default)]
+    pub depends: Vec<String>,
+}
+
+impl WorkspaceFile {
+    /// The nearest workspace file in `dir` or one of its parents, with the directory it is in
+    pub fn find(dir: &Path) -> Result<Option<(WorkspaceFile, PathBuf)>> {
+        for dir in dir.ancestors() {
+            let path = dir.join(WORKSPACE_FILE);
+            if !exists(&path)? {
+                continue;
+            }
+            let workspace = toml::from_str(&read_to_string(&path)?).map_err(|error| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), error),
+                )
+            })?;
+            return Ok(Some((workspace, dir.to_path_buf())));
+        }
+        Ok(None)
+    }
+
+    /// Every project in and below the directories the workspace in `base` lists, named by the
+    /// name of their directory in the workspace and the path below it, with the indices of the
+    /// projects each one depends on
+    pub fn discover(&self, base: &Path) -> Result<(Vec<Subproject>, Vec<Vec<usize>>)> {
+        let mut subprojects = Vec::new();
+        let mut edges: Vec<Vec<usize>> = Vec::new();
+        // The range of `subprojects` each project of the workspace found
+        let mut ranges = BTreeMap::new();
+        for (name, listed) in &self.projects {
+            let dir = listed.dir(base);
+            if !exists(&dir)? {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Workspace project {}: {} does not exist",
+                        name,
+                        dir.display()
+                    ),
+                ));
+            }
+            let top = Project::find(&dir)?;
+            let found = discover(&dir, &top.config)?;
+            let config_base = top.config_dir.as_deref().unwrap_or(&dir);
+            let offset = subprojects.len();
+            for within in dependencies(&found, &top.config, config_base)? {
+                edges.push(within.iter().map(|index| index + offset).collect());
+            }
+            subprojects.extend(found.into_iter().map(|mut subproject| {
+                subproject.name = match subproject.name.as_str() {
+                    "." => name.clone(),
+                    relative => format!("{}/{}", name, relative),
+                };
+                subproject
+            }));
+            ranges.insert(name.as_str(), offset..subprojects.len());
+        }
+        for (name, listed) in &self.projects {
+            for dependency in &listed.depends {
+                let Some(required) = ranges.get(dependency.as_str()) else {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "{} depends on {}, which is not in the workspace",
+                            name, dependency
+                        ),
+                    ));
+                };
+                // Every project that a directory holds waits for every one of the other
+                for dependent in ranges[name.as_str()].clone() {
+                    edges[dependent].extend(required.clone());
+                }
+            }
+        }
+        check_cycles(&subprojects, &edges)?;
+        Ok((subprojects, edges))
+    }
+
+    /// The directories the workspace in `base` lists
+    pub fn roots(&self, base: &Path) -> Vec<PathBuf> {
+        self.projects
+            .values()
+            .map(|listed| listed.dir(base))
+            .collect()
+    }
+}
+
+impl WorkspaceProject {
+    // Its directory, without the `..` that paths to sibling directories have
+    fn dir(&self, base: &Path) -> PathBuf {
+        let dir = sandbox::expand(&self.path, base);
+        dir.canonicalize().unwrap_or(dir)
+    }
+}
+
+/// The files that differ from `reference` in the git repository `dir` is in, counting files
+/// not yet committed and ones git does not know about yet
+pub fn changed_files(dir: &Path, reference: &str) -> Result<Vec<PathBuf>> {
+    let git = |args: &[&str]| -> Result<String> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .stderr(Stdio::inherit())
+            .output()?;
+        match output.status.success() {
+            true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+            false => Err(Error::other(format!(
+                "git {} failed with {}",
+                args.join(" "),
+                output.status
+            ))),
+        }
+    };
+    let toplevel = PathBuf::from(git(&["rev-parse", "--show-toplevel"])?.trim_end());
+    let mut files = Vec::new();
+    // Both list their paths relative to the top of the repository
+    let changed = git(&["diff", "--name-only", "--no-relative", reference, "--"])?;
+    let untracked = git(&[
+        "ls-files",
+        "--others",
+        "--exclude-standard",
+        "--full-name",
+        ":/",
+    ])?;
+    for line in changed.lines().chain(untracked.lines()) {
+        files.push(toplevel.join(line));
+    }
+    Ok(files)
+}
+
+/// Which of `subprojects` contain one of `files`, or depend on one that does. A file belongs to
+/// the innermost project it is in.
+pub fn affected(
+    subprojects: &[Subproject],
+    dependencies: &[Vec<usize>],
+    files: &[PathBuf],
+) -> Vec<bool> {
+    let mut affected = vec![false; subprojects.len()];
+    for file in files {
+        let owner = subprojects
+            .iter()
+            .enumerate()
+            .filter(|(_, subproject)| file.starts_with(&subproject.project.cwd))
+            .max_by_key(|(_, subproject)| subproject.project.cwd.components().count());
+        if let Some((index, _)) = owner {
+            affected[index] = true;
+        }
+    }
+    // Until no dependent is left out
+    let mut grew = true;
+    while grew {
+        grew = false;
+        for (dependent, dependencies) in dependencies.iter().enumerate() {
+            if !affected[dependent] && dependencies.iter().any(|&dependency| affected[dependency]) {
+                affected[dependent] = true;
+                grew = true;
+            }
+        }
+    }
+    affected
+}
+
+/// Only the projects of `subprojects` that `keep` says to, with `dependencies` renumbered to
+/// match. Dependencies on the projects left out are dropped.
+pub fn retain(
+    subprojects: Vec<Subproject>,
+    dependencies: &[Vec<usize>],
+    keep: &[bool],
+) -> (Vec<Subproject>, Vec<Vec<usize>>) {
+    let mut index = vec![None; keep.len()];
+    let mut kept = Vec::new();
+    for (old, subproject) in subprojects.into_iter().enumerate() {
+        if keep[old] {
+            index[old] = Some(kept.len());
+            kept.push(subproject);
+        }
+    }
+    let dependencies = dependencies
+        .iter()
+        .enumerate()
+        .filter(|(old, _)| keep[*old])
+        .map(|(_, dependencies)| {
+            dependencies
+                .iter()
+                .filter_map(|&dependency| index[dependency])
+                .collect()
+        })
+        .collect();
+    (kept, dependencies)
+}
+
+/// The projects and their dependencies as a Graphviz digraph, with an edge from each project
+/// to each one it depends on
+pub fn to_dot(subprojects: &[Subproject], dependencies: &[Vec<usize>]) -> String {
+    let escape = |text: &str| text.replace('\\', "\\\\").replace('"', "\\\"");
+    let mut dot = String::from("digraph projects {\n");
+    for subproject in subprojects {
+        let build_system = match &subproject.project.detection {
+            Some(detection) => detection.build_system.name(),
+            None => "none",
+        };
+        dot.push_str(&format!(
+            "    \"{}\" [label=\"{}\\n{}\"];\n",
+            escape(&subproject.name),
+            escape(&subproject.name),
+            build_system
+        ));
+    }
+    for (dependent, dependencies) in dependencies.iter().enumerate() {
+        for &dependency in dependencies {
+            dot.push_str(&format!(
+                "    \"{}\" -> \"{}\";\n",
+                escape(&subprojects[dependent].name),
+                escape(&subprojects[dependency].name)
+            ));
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+/// The projects and their dependencies as JSON, for other tools
+pub fn to_json(subprojects: &[Subproject], dependencies: &[Vec<usize>]) -> Value {
+    let projects = subprojects
+        .iter()
+        .zip(dependencies)
+        .map(|(subproject, dependencies)| {
+            let detection = subproject.project.detection.as_ref();
+            json!({
+                "name": subproject.name,
+                "directory": subproject.project.cwd,
+                "build_system": detection.map(|detection| detection.build_system.name()),
+                "build_directory": detection.map(|detection| &detection.directory),
+                "depends": dependencies
+                    .iter()
+                    .map(|&dependency| &subprojects[dependency].name)
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({ "projects": projects })
+}
+
+/// Run `build` for each of `count` projects, at most `concurrency` at once and each only after
+/// the projects it depends on succeeded, and return their exit codes in order. Projects whose
+/// dependencies failed are skipped, and unless `keep_going` is set no further builds start once
+/// one failed. Those that did not run have no exit code.
+pub fn schedule<F>(
+    concurrency: usize,
+    dependencies: &[Vec<usize>],
+    keep_going: bool,
+    build: F,
+) -> Vec<Option<i32>>
+where
+    F: Fn(usize) -> i32 + Sync,
+{
+    let count = dependencies.len();
+    struct State {
+        started: Vec<bool>,
+        // Whether each project finished or was skipped
+        done: Vec<bool>,
+        failed: bool,
+        codes: Vec<Option<i32>>,
+    }
+    let state = Mutex::new(State {
+        started: vec![false; count],
+        done: vec![false; count],
+        failed: false,
+        codes: vec![None; count],
+    });
+    let finished = Condvar::new();
+    thread::scope(|scope| {
+        for _ in 0..concurrency.clamp(1, count.max(1)) {
+            scope.spawn(|| loop {
+                let mut guard = state.lock().unwrap();
+                let index = loop {
+                    let state = &mut *guard;
+                    if state.failed && !keep_going || state.started.iter().all(|&started| started) {
+                        return;
+                    }
+                    let mut ready = None;
+                    let mut skipped = false;
+                    for (index, deps) in dependencies.iter().enumerate() {
+                        if state.started[index] {
+                            continue;
+                        }
+                        if deps.iter().all(|&dep| state.codes[dep] == Some(0)) {
+                            ready = Some(index);
+                            break;
+                        }
+                        if deps
+                            .iter()
+                            .any(|&dep| state.done[dep] && state.codes[dep] != Some(0))
+                        {
+                            state.started[index] = true;
+                            state.done[index] = true;
+                            skipped = true;
+                        }
+                    }
+                    match (ready, skipped) {
+                        (Some(index), _) => break index,
+                        // Skipping may have left nothing to wait for
+                        (None, true) => finished.notify_all(),
+                        (None, false) => guard = finished.wait(guard).unwrap(),
+                    }
+                };
+                guard.started[index] = true;
+                drop(guard);
+                let code = build(index);
+                let mut state = state.lock().unwrap();
+                state.codes[index] = Some(code);
+                state.done[index] = true;
+                state.failed |= code != 0;
+                finished.notify_all();
+            });
+        }
+    });
+    state.into_inner().unwrap().codes
+}